@@ -0,0 +1,9 @@
+//! Script data payloads, the AMF0 of `onMetaData` and anything else
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use waterfall::flv::parse_script_data;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_script_data(data);
+});
@@ -0,0 +1,19 @@
+//! The header and every tag of an input, as the in-memory reader walks them
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use waterfall::flv::{parse_header, parse_script_data, parse_tag};
+
+fuzz_target!(|data: &[u8]| {
+    let mut offset = match parse_header(data) {
+        Ok(offset) => offset,
+        Err(_) => return,
+    };
+    while let Ok(Some((header, payload, next))) = parse_tag(data, offset) {
+        assert!(payload.end <= data.len() && next > offset);
+        if header.tag_type == 18 {
+            let _ = parse_script_data(&data[payload]);
+        }
+        offset = next;
+    }
+});
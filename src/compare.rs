@@ -0,0 +1,44 @@
+//! `waterfall compare`: deltas between two reports.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use crate::report::Report;
+use crate::slo;
+
+pub fn read_report(path: &Path) -> io::Result<Report> {
+    let file = File::open(path)?;
+    serde_json::from_reader(BufReader::new(file)).map_err(From::from)
+}
+
+/// Print every metric of both reports with the change from `a` to `b`
+pub fn run(a: &Path, b: &Path) -> io::Result<()> {
+    let (report_a, report_b) = (read_report(a)?, read_report(b)?);
+    println!("{:<16} {:>16} {:>16} {:>24}", "metric", a.display(), b.display(), "delta");
+    for metric in slo::METRICS {
+        let (value_a, value_b) = (slo::metric(&report_a, metric), slo::metric(&report_b, metric));
+        println!("{:<16} {:>16} {:>16} {:>24}", metric, format_value(value_a), format_value(value_b), delta(value_a, value_b));
+    }
+    Ok(())
+}
+
+fn format_value(v: Option<f64>) -> String {
+    match v {
+        Some(v) if v.fract() == 0.0 => format!("{}", v),
+        Some(v) => format!("{:.4}", v),
+        None => "-".to_owned(),
+    }
+}
+
+fn delta(a: Option<f64>, b: Option<f64>) -> String {
+    match (a, b) {
+        (Some(a), Some(b)) if a != 0.0 => format!("{:+} ({:+.1}%)", round(b - a), (b - a) / a * 100.0),
+        (Some(a), Some(b)) => format!("{:+}", round(b - a)),
+        _ => "-".to_owned(),
+    }
+}
+
+fn round(v: f64) -> f64 {
+    (v * 10_000.0).round() / 10_000.0
+}
@@ -0,0 +1,106 @@
+//! `--config run.toml`: the options of a run in a TOML or YAML file.
+//!
+//! Keys are the long options, `concurrency = 100`, in any section: `[logging] log-file = "run.log"`
+//! is `--log-file run.log`. `true` is a flag, arrays repeat the option. `destinations` lists the
+//! urls inline, `destinations-file` names a list file. Options on the command line take
+//! precedence over the file.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+
+use serde_json::Value;
+
+const OPTION: &str = "--config";
+
+/// Short forms of long options, to tell they are on the command line
+const SHORTS: &[(&str, &str)] = &[("input", "-i"), ("repeat", "-r"), ("concurrency", "-c"), ("prefix", "-p"), ("count", "-n")];
+
+/// The command line with the options of the `--config` file appended, unless given already.
+pub fn merge(args: Vec<OsString>) -> io::Result<Vec<OsString>> {
+    let path = args.iter().enumerate().find_map(|(i, arg)| {
+        let arg = arg.to_str()?;
+        if arg == OPTION {
+            args.get(i + 1).cloned()
+        } else {
+            arg.strip_prefix("--config=").map(OsString::from)
+        }
+    });
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let given = |key: &str| {
+        let long = format!("--{}", key);
+        let short = SHORTS.iter().find(|(k, _)| *k == key).map(|(_, s)| *s);
+        args.iter().filter_map(|a| a.to_str()).any(|a| {
+            a == long || a.starts_with(&format!("{}=", long)) || short.map_or(false, |s| a.starts_with(s))
+        })
+    };
+    // The command line destinations replace those of the file altogether
+    let destinations_given = given("prefix");
+
+    let mut options = Vec::new();
+    flatten(read(Path::new(&path))?, &mut options);
+    let mut merged = args.clone();
+    for (key, value) in options {
+        match key.as_str() {
+            "destinations" | "destinations-file" if destinations_given => (),
+            "destinations" => merged.push(destination_list(&value)?.into()),
+            "destinations-file" => merged.push(scalar(&key, &value)?.into()),
+            _ if given(&key) => (),
+            _ => match value {
+                Value::Bool(true) => merged.push(format!("--{}", key).into()),
+                Value::Bool(false) | Value::Null => (),
+                Value::Array(values) => {
+                    for value in values {
+                        merged.push(format!("--{}={}", key, scalar(&key, &value)?).into());
+                    }
+                }
+                value => merged.push(format!("--{}={}", key, scalar(&key, &value)?).into()),
+            },
+        }
+    }
+    Ok(merged)
+}
+
+fn read(path: &Path) -> io::Result<Value> {
+    let content = std::fs::read_to_string(path)?;
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+    match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content).map_err(|e| invalid(e.to_string())),
+        _ => toml::from_str(&content).map_err(|e| invalid(e.to_string())),
+    }
+}
+
+/// Options of every section
+fn flatten(value: Value, options: &mut Vec<(String, Value)>) {
+    if let Value::Object(map) = value {
+        for (key, value) in map {
+            match value {
+                Value::Object(_) => flatten(value, options),
+                value => options.push((key, value)),
+            }
+        }
+    }
+}
+
+fn scalar(key: &str, value: &Value) -> io::Result<String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected value of `{}` in the config file", key))),
+    }
+}
+
+/// The inline destinations as a list file for the positional argument
+fn destination_list(value: &Value) -> io::Result<String> {
+    let urls = match value {
+        Value::Array(urls) => urls.iter().map(|u| scalar("destinations", u)).collect::<io::Result<Vec<_>>>()?,
+        value => vec![scalar("destinations", value)?],
+    };
+    let path = std::env::temp_dir().join(format!("waterfall-destinations-{}.list", std::process::id()));
+    std::fs::write(&path, urls.join("\n") + "\n")?;
+    Ok(path.to_string_lossy().into_owned())
+}
@@ -0,0 +1,108 @@
+//! `waterfall connect-bench`: session setups at a fixed rate, without media.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use futures::{
+    future::FutureExt,
+    stream::{FuturesUnordered, StreamExt},
+};
+use serde::Serialize;
+
+use crate::report::Percentiles;
+use crate::rtmp::bench::{self, Timings};
+use crate::rtmp_url::Url;
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub attempts: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub tcp_ms: Option<Percentiles>,
+    pub handshake_ms: Option<Percentiles>,
+    /// From the connect command to its result
+    pub connect_ms: Option<Percentiles>,
+    pub total_ms: Option<Percentiles>,
+    /// Failed attempts by error, most frequent first
+    pub errors: Vec<ErrorCount>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorCount {
+    pub error: String,
+    pub count: usize,
+}
+
+/// Start `count` setups at `rate` per second, over the destinations in turn.
+pub async fn run(urls: Vec<Url>, rate: f64, count: usize, timeout: Duration, insecure_tls: bool) -> BenchReport {
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+    let mut attempts = FuturesUnordered::new();
+    let mut results = Vec::with_capacity(count);
+    for url in urls.iter().cycle().take(count) {
+        ticks.tick().await;
+        let url = url.clone();
+        attempts.push(tokio::spawn(async move {
+            match tokio::time::timeout(timeout, bench::connect_once(&url, insecure_tls)).await {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(_) => Err(format!("timed out after {}s", timeout.as_secs_f64())),
+            }
+        }));
+        // Collect what is done already, so the set does not grow with the count
+        while let Some(Some(result)) = attempts.next().now_or_never() {
+            results.push(result.unwrap_or_else(|e| Err(e.to_string())));
+        }
+    }
+    while let Some(result) = attempts.next().await {
+        results.push(result.unwrap_or_else(|e| Err(e.to_string())));
+    }
+    summarize(results)
+}
+
+fn summarize(results: Vec<Result<Timings, String>>) -> BenchReport {
+    let ms = |d: Duration| d.as_millis() as u64;
+    let timings = results.iter().filter_map(|r| r.as_ref().ok()).collect::<Vec<_>>();
+    let percentiles = |f: &dyn Fn(&Timings) -> u64| Percentiles::from_values(timings.iter().map(|&t| f(t)).collect());
+    let mut errors = HashMap::new();
+    for error in results.iter().filter_map(|r| r.as_ref().err()) {
+        *errors.entry(error.clone()).or_insert(0) += 1;
+    }
+    let mut errors = errors.into_iter().map(|(error, count)| ErrorCount { error, count }).collect::<Vec<_>>();
+    errors.sort_by(|a, b| b.count.cmp(&a.count).then(a.error.cmp(&b.error)));
+    BenchReport {
+        attempts: results.len(),
+        succeeded: timings.len(),
+        failed: results.len() - timings.len(),
+        tcp_ms: percentiles(&|t| ms(t.tcp)),
+        handshake_ms: percentiles(&|t| ms(t.handshake)),
+        connect_ms: percentiles(&|t| ms(t.connect)),
+        total_ms: percentiles(&|t| ms(t.tcp + t.handshake + t.connect)),
+        errors,
+    }
+}
+
+pub fn print(report: &BenchReport) {
+    println!("attempts {}, succeeded {}, failed {}", report.attempts, report.succeeded, report.failed);
+    println!("{:<12} {:>8} {:>8} {:>8} {:>8} {:>8}", "ms", "min", "p50", "p90", "p99", "max");
+    for (name, p) in &[
+        ("tcp", report.tcp_ms),
+        ("handshake", report.handshake_ms),
+        ("connect", report.connect_ms),
+        ("total", report.total_ms),
+    ] {
+        match p {
+            Some(p) => println!("{:<12} {:>8} {:>8} {:>8} {:>8} {:>8}", name, p.min, p.p50, p.p90, p.p99, p.max),
+            None => println!("{:<12} {:>8}", name, "-"),
+        }
+    }
+    for e in &report.errors {
+        println!("{:>8}  {}", e.count, e.error);
+    }
+}
+
+pub fn write_json(report: &BenchReport, path: &Path) -> io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, report)?;
+    Ok(())
+}
@@ -0,0 +1,236 @@
+//! `--control-listen`: a small HTTP API to steer a running publish.
+//!
+//! - `GET /stats` the current report as JSON, `GET /stats?url=...` only the clients of that destination
+//! - `POST /destinations` publish to the urls of the body, one per line, they join the broadcast where it is
+//! - `DELETE /destinations?url=...` unpublish that destination, it ends as finished
+//! - `POST /pause` and `POST /resume` hold and restart the broadcast, the sessions stay open
+//! - `POST /shutdown` end the run like a signal does
+//!
+//! Requests are served one at a time, each on its own connection.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use percent_encoding::percent_decode_str;
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    load::Spawner,
+    rtmp_url::Url,
+    shutdown::Shutdown,
+    stats::Stats,
+};
+
+/// Longest request head and body taken
+const MAX_REQUEST: usize = 1 << 20;
+/// A client which does not send its request in time is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    body: String,
+}
+
+impl Request {
+    /// The decoded value of `key` in the query string
+    fn param(&self, key: &str) -> Option<String> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            if parts.next()? != key {
+                return None;
+            }
+            Some(percent_decode_str(parts.next().unwrap_or("")).decode_utf8_lossy().into_owned())
+        })
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: serde::Serialize>(value: &T) -> Self {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Self { status: "200 OK", content_type: "application/json", body },
+            Err(e) => Self::text("500 Internal Server Error", e.to_string()),
+        }
+    }
+
+    fn text<S: Into<String>>(status: &'static str, body: S) -> Self {
+        let mut body = body.into();
+        body.push('\n');
+        Self { status, content_type: "text/plain; charset=utf-8", body }
+    }
+}
+
+/// Serve the API on `addr` until the run is shut down.
+///
+/// `spawner` starts the added destinations, `parse` turns a line of a `POST /destinations` body
+/// into its url like a line of the destination list.
+pub async fn serve<F>(addr: SocketAddr,
+                      mut spawner: Spawner,
+                      parse: F,
+                      stats: Arc<Stats>,
+                      shutdown: Shutdown,
+                      logger: Logger) -> io::Result<()>
+    where F: Fn(&str) -> Result<Url, String>,
+{
+    let mut listener = TcpListener::bind(addr).await?;
+    info!(logger, "Control API listening"; "addr" => %addr);
+    loop {
+        let (mut socket, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.wait() => return Ok(()),
+        };
+        let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut socket)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                let _ = write_response(&mut socket, Response::text("400 Bad Request", e.to_string())).await;
+                continue;
+            }
+            Err(_) => continue,
+        };
+        info!(logger, "Control request"; "method" => &request.method, "path" => &request.path, "peer" => %peer);
+        let response = handle(&request, &mut spawner, &parse, &stats, &shutdown, &logger);
+        if let Err(e) = write_response(&mut socket, response).await {
+            warn!(logger, "Write control response error"; "peer" => %peer, "error" => %e);
+        }
+    }
+}
+
+fn handle<F>(request: &Request, spawner: &mut Spawner, parse: &F, stats: &Stats, shutdown: &Shutdown, logger: &Logger) -> Response
+    where F: Fn(&str) -> Result<Url, String>,
+{
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/stats") => {
+            let mut report = stats.report(false);
+            if let Some(url) = request.param("url") {
+                report.clients.retain(|c| c.url == url);
+                if report.clients.is_empty() {
+                    return Response::text("404 Not Found", format!("no destination `{}`", url));
+                }
+            }
+            Response::json(&report)
+        }
+        ("POST", "/destinations") => {
+            if shutdown.is_triggered() {
+                return Response::text("409 Conflict", "the run is shutting down");
+            }
+            let urls = request.body.lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| parse(line))
+                .collect::<Result<Vec<_>, _>>();
+            match urls {
+                Ok(urls) => {
+                    let added = urls.iter().map(Url::to_string).collect::<Vec<_>>();
+                    for url in urls {
+                        info!(logger, "Destination added"; "url" => %url);
+                        spawner.spawn(url);
+                    }
+                    Response::json(&added)
+                }
+                Err(e) => Response::text("400 Bad Request", e),
+            }
+        }
+        ("DELETE", "/destinations") => {
+            let url = match request.param("url") {
+                Some(url) => url,
+                None => return Response::text("400 Bad Request", "missing `url` parameter"),
+            };
+            let clients = stats.clients().into_iter().filter(|c| c.url() == url).collect::<Vec<_>>();
+            if clients.is_empty() {
+                return Response::text("404 Not Found", format!("no destination `{}`", url));
+            }
+            for client in &clients {
+                client.stop();
+            }
+            info!(logger, "Destination removed"; "url" => &url, "clients" => clients.len());
+            Response::text("200 OK", format!("removed `{}`", url))
+        }
+        ("POST", "/pause") => {
+            shutdown.pause();
+            Response::text("200 OK", "paused")
+        }
+        ("POST", "/resume") => {
+            shutdown.resume();
+            Response::text("200 OK", "resumed")
+        }
+        ("POST", "/shutdown") => {
+            warn!(logger, "Shutdown requested, stop broadcasting and close the sessions");
+            shutdown.trigger();
+            Response::text("200 OK", "shutting down")
+        }
+        (_, "/stats") | (_, "/destinations") | (_, "/pause") | (_, "/resume") | (_, "/shutdown") => {
+            Response::text("405 Method Not Allowed", format!("{} is not allowed on {}", request.method, request.path))
+        }
+        _ => Response::text("404 Not Found", format!("no endpoint {}", request.path)),
+    }
+}
+
+async fn read_request(socket: &mut TcpStream) -> io::Result<Request> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_REQUEST {
+            return Err(invalid("request head too long"));
+        }
+        let mut chunk = [0; 4096];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(invalid("connection closed before the end of the request head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split_whitespace();
+    let method = request_line.next().ok_or_else(|| invalid("missing method"))?.to_owned();
+    let target = request_line.next().ok_or_else(|| invalid("missing path"))?;
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_owned(), Some(target[i + 1..].to_owned())),
+        None => (target.to_owned(), None),
+    };
+    let content_length = lines.find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next()?.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            parts.next()?.trim().parse::<usize>().ok()
+        } else {
+            None
+        }
+    }).unwrap_or(0);
+    if content_length > MAX_REQUEST {
+        return Err(invalid("request body too long"));
+    }
+
+    let mut body = buf.split_off(head_end + 4);
+    while body.len() < content_length {
+        let mut chunk = [0; 4096];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(invalid("connection closed before the end of the request body"));
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+    Ok(Request { method, path, query, body: String::from_utf8_lossy(&body).into_owned() })
+}
+
+async fn write_response(socket: &mut TcpStream, response: Response) -> io::Result<()> {
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                       response.status, response.content_type, response.body.len());
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(response.body.as_bytes()).await?;
+    socket.shutdown(std::net::Shutdown::Write)
+}
@@ -0,0 +1,300 @@
+//! `worker` and `--workers`: one run spread over waterfall processes on other machines.
+//!
+//! The coordinator splits the destinations into one contiguous shard per worker, and sends each
+//! worker its shard with the publish options, as newline delimited JSON over TCP. A worker runs the
+//! shard as a child `waterfall publish` of its own, relays its checkpoints as progress, and sends
+//! the final report back once the child is done. The coordinator merges the clients of the last
+//! progress of every worker into one live report, and those of the final reports into the report
+//! of the run. Stopping the coordinator stops the workers, which unpublish
+//! as on a signal. The inputs are read by each worker, so file paths must exist on every machine.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::panic_hook;
+use crate::report::{Report, Summary};
+use crate::shutdown::Shutdown;
+
+/// How often a worker checkpoints its child and sends the progress
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// A final report of tens of thousands of clients is a long line
+const MAX_MESSAGE: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// From the coordinator, the `publish` arguments and the destination lines of the worker
+    Job { args: Vec<String>, destinations: Vec<String> },
+    /// From the coordinator, unpublish and report
+    Stop,
+    /// From a worker, its last checkpoint
+    Progress { report: Report },
+    /// From a worker, its child exited, `None` for a signal
+    Finished { exit_code: Option<i32>, report: Report },
+    /// From a worker, the job could not run or left no report
+    Error { message: String },
+}
+
+type Connection = Framed<TcpStream, LinesCodec>;
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+async fn send(connection: &mut Connection, message: &Message) -> io::Result<()> {
+    let line = serde_json::to_string(message).map_err(other)?;
+    connection.send(line).await.map_err(other)
+}
+
+/// The next message, `None` once the peer is gone
+async fn recv(connection: &mut Connection) -> io::Result<Option<Message>> {
+    match connection.next().await {
+        Some(Ok(line)) => serde_json::from_str(&line).map(Some).map_err(other),
+        Some(Err(e)) => Err(other(e)),
+        None => Ok(None),
+    }
+}
+
+/// Run the jobs of the coordinators connecting to `addr`, one at a time, never returns but on a
+/// listen error.
+pub async fn serve(addr: SocketAddr, logger: Logger) -> io::Result<()> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!(logger, "Worker listening"; "addr" => %addr);
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let logger = logger.new(o!("coordinator" => peer.to_string()));
+        let mut connection = Framed::new(socket, LinesCodec::new_with_max_length(MAX_MESSAGE));
+        match panic_hook::scope(format!("job of {}", peer), work(&mut connection, &logger)).await {
+            Ok(()) => info!(logger, "Job done"),
+            Err(e) => {
+                warn!(logger, "Job error"; "error" => %e);
+                let _ = send(&mut connection, &Message::Error { message: e.to_string() }).await;
+            }
+        }
+    }
+}
+
+async fn work(connection: &mut Connection, logger: &Logger) -> io::Result<()> {
+    let (args, destinations) = match recv(connection).await? {
+        Some(Message::Job { args, destinations }) => (args, destinations),
+        Some(_) => return Err(other("expected a job")),
+        None => return Ok(()),
+    };
+    let dir = job_dir();
+    std::fs::create_dir_all(&dir)?;
+    let list = dir.join("destinations.txt");
+    let checkpoint = dir.join("checkpoint.json");
+    let report = dir.join("report.json");
+    for stale in &[&checkpoint, &report] {
+        let _ = std::fs::remove_file(stale);
+    }
+    std::fs::write(&list, destinations.join("\n"))?;
+
+    let mut child = tokio::process::Command::new(std::env::current_exe()?)
+        .args(&args)
+        .arg(&list)
+        .arg("--checkpoint").arg(&checkpoint)
+        .arg("--checkpoint-interval").arg(PROGRESS_INTERVAL.as_secs().to_string())
+        .arg("--report").arg(&report)
+        .kill_on_drop(true)
+        .spawn()?;
+    info!(logger, "Job started"; "destinations" => destinations.len(), "pid" => child.id());
+
+    let mut ticks = tokio::time::interval(PROGRESS_INTERVAL);
+    let mut stopping = false;
+    let status: ExitStatus = loop {
+        tokio::select! {
+            status = &mut child => break status?,
+            _ = ticks.tick() => {
+                if let Some(report) = read_report(&checkpoint) {
+                    let _ = send(connection, &Message::Progress { report }).await;
+                }
+            }
+            message = recv(connection), if !stopping => {
+                match message {
+                    Ok(Some(Message::Stop)) => info!(logger, "Stop requested"),
+                    Ok(Some(_)) => {
+                        warn!(logger, "Unexpected message during a job");
+                        continue;
+                    }
+                    Ok(None) | Err(_) => warn!(logger, "Coordinator gone, stopping the job"),
+                }
+                stopping = true;
+                terminate(child.id());
+            }
+        }
+    };
+
+    match read_report(&report) {
+        Some(report) => send(connection, &Message::Finished { exit_code: status.code(), report }).await,
+        None => Err(other(format!("the job exited with {} and no report", status))),
+    }
+}
+
+/// Unpublish as on SIGTERM, the child writes its report
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    let _ = std::process::Command::new("kill").arg("-TERM").arg(pid.to_string()).status();
+}
+
+#[cfg(not(unix))]
+fn terminate(pid: u32) {
+    let _ = std::process::Command::new("taskkill").arg("/PID").arg(pid.to_string()).status();
+}
+
+/// Where a worker keeps the files of its job
+fn job_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("waterfall-worker-{}", std::process::id()))
+}
+
+fn read_report(path: &Path) -> Option<Report> {
+    std::fs::read(path).ok().and_then(|data| serde_json::from_slice(&data).ok())
+}
+
+/// What a worker sent back
+#[derive(Debug)]
+pub struct Outcome {
+    pub worker: String,
+    pub exit_code: Option<i32>,
+    /// `None` when the worker failed, with the error
+    pub report: Option<Report>,
+    pub error: Option<String>,
+}
+
+/// `destinations` split into `parts` contiguous shards, as even as can be
+pub fn shard(destinations: &[String], parts: usize) -> Vec<Vec<String>> {
+    let n = destinations.len();
+    (0..parts).map(|i| destinations[i * n / parts..(i + 1) * n / parts].to_vec()).collect()
+}
+
+/// Run `args` over `destinations` on `workers`. Every `interval` the progress of the workers is
+/// merged into one live report, logged and passed to `live`.
+pub async fn coordinate<F>(workers: Vec<String>,
+                           args: Vec<String>,
+                           destinations: Vec<String>,
+                           interval: Duration,
+                           mut live: F,
+                           shutdown: Shutdown,
+                           logger: Logger) -> Vec<Outcome>
+    where F: FnMut(&Report) + Send + 'static
+{
+    let progress = Arc::new(Mutex::new(vec![None; workers.len()]));
+    let (viewing, viewed) = {
+        let (progress, logger) = (progress.clone(), logger.clone());
+        futures::future::abortable(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                if let Some(report) = live_report(&progress, &logger) {
+                    live(&report);
+                }
+            }
+        })
+    };
+    tokio::spawn(viewing);
+    let shards = shard(&destinations, workers.len());
+    let jobs = workers.into_iter().zip(shards).enumerate().filter(|(_, (_, d))| !d.is_empty()).map(|(i, (worker, destinations))| {
+        let job = Message::Job { args: args.clone(), destinations };
+        let (progress, shutdown) = (progress.clone(), shutdown.clone());
+        let logger = logger.new(o!("worker" => worker.clone()));
+        let session = format!("worker {}", worker);
+        panic_hook::scope(session, async move {
+            match run_job(&worker, job, i, &progress, &shutdown, &logger).await {
+                Ok((exit_code, report)) => Outcome { worker, exit_code, report: Some(report), error: None },
+                Err(e) => {
+                    warn!(logger, "Worker failed"; "error" => %e);
+                    Outcome { worker, exit_code: None, report: None, error: Some(e.to_string()) }
+                }
+            }
+        })
+    });
+    let outcomes = futures::future::join_all(jobs).await;
+    viewed.abort();
+    outcomes
+}
+
+/// The last progress of every worker merged, and logged, `None` before the first one
+fn live_report(progress: &Mutex<Vec<Option<Report>>>, logger: &Logger) -> Option<Report> {
+    let (reports, workers) = {
+        let progress = progress.lock().ok()?;
+        (progress.iter().flatten().cloned().collect::<Vec<_>>(), progress.len())
+    };
+    let reporting = reports.len();
+    let report = merge(reports)?;
+    let summary = &report.summary;
+    info!(logger, "Workers progress";
+          "reporting" => reporting, "workers" => workers,
+          "clients" => summary.clients, "publishing" => summary.publishing,
+          "connecting" => summary.connecting, "failed" => summary.failed,
+          "bytes_sent" => summary.bytes_sent);
+    Some(report)
+}
+
+async fn run_job(worker: &str,
+                 job: Message,
+                 index: usize,
+                 progress: &Mutex<Vec<Option<Report>>>,
+                 shutdown: &Shutdown,
+                 logger: &Logger) -> io::Result<(Option<i32>, Report)> {
+    let socket = TcpStream::connect(worker).await?;
+    let mut connection = Framed::new(socket, LinesCodec::new_with_max_length(MAX_MESSAGE));
+    send(&mut connection, &job).await?;
+    info!(logger, "Job sent"; "destinations" => match &job { Message::Job { destinations, .. } => destinations.len(), _ => 0 });
+    let mut stopped = false;
+    loop {
+        let message = tokio::select! {
+            message = recv(&mut connection) => message?,
+            _ = shutdown.wait(), if !stopped => {
+                stopped = true;
+                send(&mut connection, &Message::Stop).await?;
+                continue;
+            }
+        };
+        match message {
+            Some(Message::Progress { report }) => {
+                if let Ok(mut progress) = progress.lock() {
+                    progress[index] = Some(report);
+                }
+            }
+            Some(Message::Finished { exit_code, report }) => {
+                info!(logger, "Worker finished"; "clients" => report.summary.clients, "failed" => report.summary.failed);
+                return Ok((exit_code, report));
+            }
+            Some(Message::Error { message }) => return Err(other(message)),
+            Some(_) => return Err(other("unexpected message from the worker")),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "worker gone before its report")),
+        }
+    }
+}
+
+/// One report of the clients of every worker, started with the earliest, finished once all are
+pub fn merge(reports: Vec<Report>) -> Option<Report> {
+    let started_at = reports.iter().map(|r| r.started_at).min()?;
+    let duration_secs = reports.iter().map(|r| r.duration_secs).fold(0.0, f64::max);
+    let finished = reports.iter().all(|r| r.finished);
+    let clients = reports.into_iter().flat_map(|r| r.clients).collect::<Vec<_>>();
+    Some(Report {
+        started_at,
+        duration_secs,
+        finished,
+        summary: Summary::from_clients(&clients),
+        capacity: None,
+        steps: Vec::new(),
+        assertions: Vec::new(),
+        timeline: Vec::new(),
+        latency: Vec::new(),
+        integrity: None,
+        clients,
+    })
+}
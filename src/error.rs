@@ -40,6 +40,16 @@ pub enum ErrorKind {
     #[fail(display = "outbound client session error: {}", _0)]
     ClientSession(#[fail(cause)] ClientSessionError),
 
+    #[fail(display = "RTMPT tunnel error: {}", _0)]
+    Tunnel(String),
+
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
+
+    #[cfg(feature = "rtmpe")]
+    #[fail(display = "RTMPE handshake error: {}", _0)]
+    Encryption(String),
+
     #[fail(display = "unknown error: {}", _0)]
     Unknown(String),
 }
@@ -0,0 +1,169 @@
+//! Lifecycle events as newline delimited JSON on stdout, see `--events`, and posted to the
+//! `--webhook` url.
+
+use std::io::{self, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::report::{AssertionReport, Summary};
+#[cfg(feature = "http")]
+use slog::{warn, Logger};
+use crate::stats::{ClientState, Stats};
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The publishers are about to connect
+    Started {
+        clients: usize,
+    },
+    /// A client changed its state, `connecting` first
+    State {
+        url: &'a str,
+        state: ClientState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<&'a str>,
+    },
+    /// A client failed over to the next url of its destination line
+    Failover {
+        url: &'a str,
+        to: &'a str,
+        error: &'a str,
+    },
+    Stats {
+        duration_secs: f64,
+        summary: &'a Summary,
+    },
+    Assertion(&'a AssertionReport),
+    /// The run is over, with the final summary and the exit code of the process
+    Finished {
+        duration_secs: f64,
+        summary: &'a Summary,
+        exit_code: i32,
+    },
+}
+
+impl Event<'_> {
+    /// Those a webhook gets: the start and the end of the run, every failover and every client
+    /// which failed
+    fn is_notable(&self) -> bool {
+        matches!(self, Event::Started { .. } | Event::Finished { .. } | Event::Failover { .. }
+                 | Event::State { state: ClientState::Failed, .. })
+    }
+}
+
+/// How long a post to the webhook may take
+#[cfg(feature = "http")]
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct Line<'a> {
+    /// Unix timestamp in milliseconds
+    ts_ms: u64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Writes the events from a thread of its own, so a slow reader of stdout never blocks a client,
+/// and posts them to the webhook from another
+#[derive(Default)]
+pub struct Events {
+    stdout: Option<Mutex<mpsc::Sender<String>>>,
+    webhook: Mutex<Option<(mpsc::Sender<String>, thread::JoinHandle<()>)>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Print every event on stdout
+    pub fn stdout(mut self) -> Self {
+        let (tx, rx) = mpsc::channel::<String>();
+        thread::Builder::new().name("events".into()).spawn(move || {
+            let stdout = io::stdout();
+            for line in rx {
+                let mut out = stdout.lock();
+                if out.write_all(line.as_bytes()).and_then(|_| out.flush()).is_err() {
+                    // Nobody is reading anymore
+                    break;
+                }
+            }
+        }).expect("Cannot spawn the events thread");
+        self.stdout = Some(Mutex::new(tx));
+        self
+    }
+
+    /// Post the start, the failed clients and the end of the run to `url`, one JSON object each,
+    /// in order
+    #[cfg(feature = "http")]
+    pub fn webhook(self, url: String, logger: Logger) -> Self {
+        let (tx, rx) = mpsc::channel::<String>();
+        let thread = thread::Builder::new().name("webhook".into()).spawn(move || {
+            let mut runtime = match tokio::runtime::Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    warn!(logger, "Cannot start the webhook"; "error" => %e);
+                    return;
+                }
+            };
+            let client = reqwest::Client::new();
+            for body in rx {
+                let posted = runtime.block_on(client.post(&url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .body(body)
+                    .send());
+                if let Err(e) = posted.and_then(|r| r.error_for_status()) {
+                    warn!(logger, "Webhook post error"; "url" => &url, "error" => %e);
+                }
+            }
+        }).expect("Cannot spawn the webhook thread");
+        *self.webhook.lock().unwrap() = Some((tx, thread));
+        self
+    }
+
+    pub fn on_stdout(&self) -> bool {
+        self.stdout.is_some()
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let ts_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+        let line = match serde_json::to_string(&Line { ts_ms, event }) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if event.is_notable() {
+            if let Ok(webhook) = self.webhook.lock() {
+                if let Some((ref tx, _)) = *webhook {
+                    let _ = tx.send(line.clone());
+                }
+            }
+        }
+        if let Some(Ok(tx)) = self.stdout.as_ref().map(Mutex::lock) {
+            let _ = tx.send(line + "\n");
+        }
+    }
+
+    /// Wait for the posts still queued for the webhook, the events after are not posted
+    pub fn close(&self) {
+        let webhook = self.webhook.lock().ok().and_then(|mut w| w.take());
+        if let Some((tx, thread)) = webhook {
+            drop(tx);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Emit the summary every `interval`, never returns.
+pub async fn periodic_stats(stats: Arc<Stats>, events: Arc<Events>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let report = stats.report(false);
+        events.emit(&Event::Stats { duration_secs: report.duration_secs, summary: &report.summary });
+    }
+}
@@ -0,0 +1,298 @@
+//! Fan-out of a broadcast to its clients, each with a bounded queue of its own.
+//!
+//! A client which does not keep up only fills its own queue. Once it is full the drop policy
+//! decides what gives: the oldest media, the video frames other than keyframes first, or the
+//! session of the client. Metadata, sequence headers and the markers of the broadcast are never
+//! dropped, the queue goes past its size for them.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::task::{Context, Poll};
+
+use futures::{task::AtomicWaker, Stream, StreamExt};
+
+use crate::{flv, PacketType};
+
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// What a full queue drops for the next message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DropPolicy {
+    /// The oldest media message
+    DropOldest,
+    /// The oldest video frame which is not a keyframe, the oldest media message without any
+    DropNonKeyframe,
+    /// Everything queued, and the session of the client fails
+    Disconnect,
+}
+
+impl Default for DropPolicy {
+    fn default() -> Self {
+        DropPolicy::DropOldest
+    }
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            "drop-non-keyframe" => Ok(DropPolicy::DropNonKeyframe),
+            "disconnect" => Ok(DropPolicy::Disconnect),
+            _ => Err(format!("unknown drop policy `{}`, use drop-oldest, drop-non-keyframe or disconnect", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecvError {
+    /// Every sender is gone
+    Closed,
+    /// This many messages were dropped since the last one received, the next ones come in as usual
+    Lagged(u64),
+    /// The queue overflowed with `DropPolicy::Disconnect`, the messages after come in as usual
+    Overflowed,
+}
+
+impl std::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RecvError::Closed => f.write_str("broadcast closed"),
+            RecvError::Lagged(n) => write!(f, "broadcast lagged, {} messages dropped", n),
+            RecvError::Overflowed => f.write_str("broadcast queue overflowed"),
+        }
+    }
+}
+
+/// Depth of a queue and what it dropped, readable once the receiver is gone
+#[derive(Debug, Default)]
+pub struct Gauge {
+    depth: AtomicUsize,
+    max_depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+impl Gauge {
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn set_depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<Arc<PacketType>>,
+    /// Dropped since the last message received
+    lagged: u64,
+    overflowed: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    waker: AtomicWaker,
+    gauge: Arc<Gauge>,
+}
+
+impl Shared {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, message: Arc<PacketType>, size: usize, policy: DropPolicy) {
+        let mut queue = self.queue();
+        if queue.messages.len() >= size {
+            let before = queue.messages.len();
+            match policy {
+                DropPolicy::DropOldest => {
+                    drop_first(&mut queue.messages, is_media);
+                }
+                DropPolicy::DropNonKeyframe => {
+                    if !drop_first(&mut queue.messages, is_inter_frame) {
+                        drop_first(&mut queue.messages, is_media);
+                    }
+                }
+                DropPolicy::Disconnect => {
+                    queue.messages.retain(|m| !is_media(m));
+                    queue.overflowed = true;
+                }
+            }
+            let dropped = (before - queue.messages.len()) as u64;
+            if !queue.overflowed {
+                queue.lagged += dropped;
+            }
+            self.gauge.dropped.fetch_add(dropped, Ordering::Relaxed);
+        }
+        queue.messages.push_back(message);
+        self.gauge.set_depth(queue.messages.len());
+        drop(queue);
+        self.waker.wake();
+    }
+
+    fn close(&self) {
+        self.queue().closed = true;
+        self.waker.wake();
+    }
+}
+
+fn drop_first(messages: &mut VecDeque<Arc<PacketType>>, droppable: fn(&PacketType) -> bool) -> bool {
+    match messages.iter().position(|m| droppable(m)) {
+        Some(i) => {
+            messages.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+fn is_media(message: &PacketType) -> bool {
+    match message {
+        PacketType::Video{ data, .. } => !flv::is_video_sequence_header(data),
+        PacketType::Audio{ data, .. } => !flv::is_audio_sequence_header(data),
+        _ => false,
+    }
+}
+
+fn is_inter_frame(message: &PacketType) -> bool {
+    match message {
+        PacketType::Video{ data, .. } => !flv::is_video_sequence_header(data) && !flv::is_video_keyframe(data),
+        _ => false,
+    }
+}
+
+struct Receivers {
+    queues: Mutex<Vec<Weak<Shared>>>,
+    size: usize,
+    policy: DropPolicy,
+}
+
+impl Receivers {
+    fn queues(&self) -> MutexGuard<'_, Vec<Weak<Shared>>> {
+        self.queues.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Call `f` on the queue of every receiver still there, in place, and forget those gone.
+    /// Returns how many there are.
+    fn for_each_live(&self, mut f: impl FnMut(&Shared)) -> usize {
+        let mut queues = self.queues();
+        queues.retain(|q| match q.upgrade() {
+            Some(queue) => {
+                f(&queue);
+                true
+            }
+            None => false,
+        });
+        queues.len()
+    }
+}
+
+impl Drop for Receivers {
+    fn drop(&mut self) {
+        self.for_each_live(Shared::close);
+    }
+}
+
+/// The sending half, the receivers see the end once every clone is dropped
+#[derive(Clone)]
+pub struct Sender {
+    receivers: Arc<Receivers>,
+}
+
+/// A broadcast whose receivers queue up to `size` messages each
+pub fn channel(size: usize, policy: DropPolicy) -> Sender {
+    Sender {
+        receivers: Arc::new(Receivers { queues: Mutex::new(Vec::new()), size, policy }),
+    }
+}
+
+impl Sender {
+    /// Another broadcast with the same queue size and drop policy
+    pub fn like(&self) -> Sender {
+        channel(self.receivers.size, self.receivers.policy)
+    }
+
+    /// A receiver of the messages sent from now on
+    pub fn subscribe(&self) -> Receiver {
+        let shared = Arc::new(Shared::default());
+        self.receivers.queues().push(Arc::downgrade(&shared));
+        Receiver { shared, receivers: Arc::downgrade(&self.receivers) }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.receivers.for_each_live(|_| ())
+    }
+
+    /// Queue `message` for every receiver, returns how many there are
+    pub fn send(&self, message: Arc<PacketType>) -> usize {
+        let (size, policy) = (self.receivers.size, self.receivers.policy);
+        self.receivers.for_each_live(|queue| queue.push(message.clone(), size, policy))
+    }
+}
+
+pub struct Receiver {
+    shared: Arc<Shared>,
+    /// To unsubscribe, gone with the last sender
+    receivers: Weak<Receivers>,
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        if let Some(receivers) = self.receivers.upgrade() {
+            receivers.queues().retain(|q| q.as_ptr() != Arc::as_ptr(&self.shared));
+        }
+    }
+}
+
+impl Receiver {
+    pub async fn recv(&mut self) -> Result<Arc<PacketType>, RecvError> {
+        self.next().await.unwrap_or(Err(RecvError::Closed))
+    }
+
+    pub fn gauge(&self) -> Arc<Gauge> {
+        self.shared.gauge.clone()
+    }
+}
+
+impl Stream for Receiver {
+    type Item = Result<Arc<PacketType>, RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Registered first, a message pushed after the check below wakes the task
+        self.shared.waker.register(cx.waker());
+        let mut queue = self.shared.queue();
+        if queue.overflowed {
+            queue.overflowed = false;
+            queue.lagged = 0;
+            return Poll::Ready(Some(Err(RecvError::Overflowed)));
+        }
+        if queue.lagged > 0 {
+            let lagged = std::mem::take(&mut queue.lagged);
+            return Poll::Ready(Some(Err(RecvError::Lagged(lagged))));
+        }
+        if let Some(message) = queue.messages.pop_front() {
+            self.shared.gauge.set_depth(queue.messages.len());
+            return Poll::Ready(Some(Ok(message)));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+}
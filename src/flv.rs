@@ -1,16 +1,18 @@
 #![allow(dead_code)]
+use std::fmt;
 use std::fs::File;
 use std::io::{
     BufReader,
+    Read,
     Seek,
     SeekFrom,
 };
-use std::time::Duration;
-use std::sync::Arc;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
 
-use bytes::{Bytes, BufMut, BytesMut};
-use bytecodec::io::{IoDecodeExt, IoEncodeExt};
-use bytecodec::EncodeExt;
+use bytes::Bytes;
 
 use futures::{
     future::{
@@ -20,170 +22,235 @@ use futures::{
 };
 use slog::{
     info,
+    warn,
     Logger,
 };
 use async_stream::{try_stream};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::trace_span;
 
-use flv_codec::*;
 use rml_rtmp::{
     time::RtmpTimestamp,
 };
 use crate::PacketType;
 
-pub async fn read_flv_tag(path: &str, repeat: bool, logger: Logger) -> Result<impl Stream<Item = std::io::Result<Arc<PacketType>>>, std::io::Error> {
-    let path = path.to_owned();
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOptions {
+    pub repeat: bool,
+    /// Times the file is played in all with `repeat`, endlessly without
+    pub repeat_count: Option<u32>,
+    /// Start the first loop at the first keyframe at or after this offset into the file.
+    /// Metadata and sequence headers before it are still sent.
+    pub start_offset_ms: u32,
+    /// Multiple of real time the tags are sent at
+    pub speed: f64,
+    /// Send this much of the input at the start as fast as possible, then at `speed`
+    pub burst: Duration,
+    /// Read every tag header as it is instead of resyncing past corrupt bytes
+    pub strict: bool,
+    /// Play only this range of every file, in every loop. The range starts at the keyframe at or
+    /// before `trim_start_ms`, at the one after it in a pipe, and ends before `trim_end_ms`
+    pub trim_start_ms: u32,
+    pub trim_end_ms: Option<u32>,
+    /// Publish the audio, and the video, of the input; see `publish::open_input`
+    pub audio: bool,
+    pub video: bool,
+    /// Read every file into a tag table once, shared by all the readers and loops of the run
+    pub preload: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            repeat: false, repeat_count: None, start_offset_ms: 0, speed: 1.0, burst: Duration::from_secs(0), strict: false,
+            trim_start_ms: 0, trim_end_ms: None, audio: true, video: true, preload: false,
+        }
+    }
+}
+
+/// The tags of the input files one after the other, as one stream with increasing timestamps.
+/// Each file sends its own metadata, a loop plays all of them again.
+///
+/// The timeline is kept in 64 bits, only the RTMP timestamps wrap around at 32 bits, after about
+/// 49.7 days, as servers expect. Timestamps of a recording which wrapped themselves are followed
+/// past the wrap.
+pub async fn read_flv_tag(paths: &[String], options: ReadOptions, cache: &Cache, logger: Logger) -> Result<impl Stream<Item = std::io::Result<Arc<PacketType>>>, std::io::Error> {
+    let paths = paths.to_vec();
+    let repeat = options.repeat;
+    let cache = cache.clone();
     tokio::task::spawn_blocking(move || {
-        let input_file = File::open(path)?;
-        let mut reader = BufReader::new(input_file);
+        let mut files = paths.iter().map(|path| {
+            if options.preload { Tags::preload(path, !options.strict, &cache, &logger) } else { Tags::open(path, &cache) }
+        }).collect::<std::io::Result<Vec<_>>>()?;
+        let playlist = files.len() > 1;
+        // Where the range of each file starts
+        let trim_starts = if options.trim_start_ms > 0 {
+            let trim_start = u64::from(options.trim_start_ms);
+            let starts = files.iter_mut().map(|tags| {
+                if tags.can_rewind() { tags.keyframe_before(trim_start) } else { Ok(trim_start) }
+            }).collect::<std::io::Result<Vec<_>>>()?;
+            Some(starts)
+        } else {
+            None
+        };
+        let trim_end = options.trim_end_ms.map(u64::from);
         let flv_stream: async_stream::AsyncStream<_, _> = try_stream! {
             let mut metadata_sent = false;
-            let mut video_seq_header_sent = false;
-            let mut audio_seq_header_sent = false;
-            let mut base_ts = 0;
-            let mut last_ts = 0;
+            // Last sequence headers sent, only changed ones are sent again (e.g. a resolution
+            // change mid-recording), identical ones at the start of every loop are skipped
+            let mut video_seq_header: Option<Bytes> = None;
+            let mut audio_seq_header: Option<Bytes> = None;
+            let mut base_ts: u64 = 0;
+            let mut last_ts: u64 = 0;
+            // Between the last two video tags, the next loop starts that much after the last tag
+            let mut last_video_ts = None;
+            let mut frame_ms: u64 = 0;
+            let mut loops = 0;
+            let mut started = options.start_offset_ms == 0;
+            let mut skip_ts: u64 = 0;
+            let mut start_at = u64::from(options.start_offset_ms);
+            let mut pacer = Pacer::new(options.speed, options.burst);
+            let mut skipped = Vec::new();
             'outter: loop {
-                let position = reader.seek(SeekFrom::Start(0))?;
-                let mut decoder = flv_codec::FileDecoder::new();
-                'inner: loop {
-                    let tag = match decoder.decode_exact(&mut reader) {
-                        Ok(tag) => Some(tag),
-                        Err(ref e) if *e.kind() == bytecodec::ErrorKind::DecoderTerminated => {
-                            base_ts = last_ts;
-                            break 'inner;
+                for (file, tags) in files.iter_mut().enumerate() {
+                    tags.rewind()?;
+                    let mut file_ts = Timeline::default();
+                    if let Some(ref starts) = trim_starts {
+                        start_at = starts[file];
+                        started = false;
+                    }
+                    if playlist {
+                        if file > 0 {
+                            info!(logger, "Next flv of the playlist"; "path" => &paths[file]);
                         }
-                        Err(e) => {
-                            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))?;
-                            None
+                        metadata_sent = false;
+                    }
+                    'inner: loop {
+                        let tag = {
+                            let _span = trace_span!("flv_decode").entered();
+                            if tags.may_block() {
+                                next_blocking(tags, !options.strict, &mut skipped).await?
+                            } else {
+                                tags.next(!options.strict, &mut skipped)?
+                            }
+                        };
+                        for s in skipped.drain(..) {
+                            warn!(logger, "Skip unreadable input"; "path" => &paths[file], "offset" => s.offset,
+                                  "bytes" => s.bytes, "reason" => s.reason);
                         }
-                    };
-                    let tag = tag.unwrap();
-
-                    match tag {
-                        Tag::Video(mut video_tag) => {
-                            let is_vsh = video_tag.avc_packet_type.map(|t| t == flv_codec::AvcPacketType::SequenceHeader).unwrap_or(false);
-                            if !video_seq_header_sent && is_vsh {
-                                video_seq_header_sent = true;
-                            } else if is_vsh {
-                                continue;
+                        let tag = tag.map(|tag| (file_ts.extend(tag.timestamp), tag))
+                            .filter(|(tag_ts, _)| trim_end.map_or(true, |end| *tag_ts < end));
+                        let (tag_ts, tag) = match tag {
+                            Some(tag) => tag,
+                            None => {
+                                // Later loops and files continue from the last tag instead of
+                                // restarting timestamps, which servers take as a discontinuity
+                                base_ts = last_ts + frame_ms.max(1);
+                                // Later loops and files are always played whole
+                                started = true;
+                                skip_ts = 0;
+                                break 'inner;
                             }
+                        };
 
-                            let timestamp = video_tag.timestamp;
-
-                            let mut timestamp_value = timestamp.value() as u32;
-                            timestamp_value += base_ts;
-
-                            let ts_delta = timestamp_value.checked_sub(last_ts).unwrap_or(0);
-                            let timestamp = RtmpTimestamp::new(timestamp_value);
-                            if last_ts <= 0 {
-                                last_ts = timestamp_value;
-                            }
+                        match tag.kind {
+                            TagKind::Video => {
+                                let data = tag.data;
+                                let is_vsh = is_video_sequence_header(&data);
+                                if is_vsh {
+                                    match video_seq_header {
+                                        Some(ref last) if *last == data => continue,
+                                        Some(_) => info!(logger, "Video codec parameters changed"; "timestamp" => tag.timestamp),
+                                        None => (),
+                                    }
+                                    video_seq_header = Some(data.clone());
+                                }
 
-                            sleep(ts_delta).await;
-                            last_ts = timestamp_value;
+                                if !started && !is_vsh {
+                                    if is_video_keyframe(&data) && tag_ts >= start_at {
+                                        started = true;
+                                        skip_ts = tag_ts;
+                                    } else {
+                                        continue;
+                                    }
+                                }
 
-                            video_tag.timestamp = Timestamp::new(timestamp_value as i32);
-                            let mut data = Vec::new();
-                            let mut tag_encoder = TagEncoder::with_item(Tag::Video(video_tag)).unwrap();
-                            tag_encoder.encode_all(&mut data).unwrap();
+                                let timestamp_value = tag_ts.saturating_sub(skip_ts) + base_ts;
+                                let timestamp = rtmp_timestamp(timestamp_value);
+                                pacer.wait(timestamp_value).await;
+                                last_ts = last_ts.max(timestamp_value);
+                                if !is_vsh {
+                                    if let Some(last) = last_video_ts.replace(timestamp_value) {
+                                        if timestamp_value > last {
+                                            frame_ms = timestamp_value - last;
+                                        }
+                                    }
+                                }
 
-                            let packet = PacketType::Video{ data: Bytes::from(data), ts: timestamp };
-                            yield Arc::new(packet);
-                        }
-                        Tag::Audio(tag) => {
-                            let is_ash = tag.aac_packet_type.map(|t| t == flv_codec::AacPacketType::SequenceHeader).unwrap_or(false);
-                            if !audio_seq_header_sent && is_ash {
-                                audio_seq_header_sent = true;
-                            } else if is_ash {
-                                continue;
+                                let packet = PacketType::Video{ data, ts: timestamp };
+                                yield Arc::new(packet);
                             }
+                            TagKind::Audio => {
+                                let data = tag.data;
+                                if !is_supported_audio(&data) {
+                                    continue;
+                                }
+                                let is_ash = is_audio_sequence_header(&data);
+                                if is_ash {
+                                    match audio_seq_header {
+                                        Some(ref last) if *last == data => continue,
+                                        Some(_) => info!(logger, "Audio codec parameters changed"; "timestamp" => tag.timestamp),
+                                        None => (),
+                                    }
+                                    audio_seq_header = Some(data.clone());
+                                }
 
-                            let sound_format = match tag.sound_format {
-                                SoundFormat::Aac => 10,
-                                _ => continue,
-                            };
-                            let sound_rate = match tag.sound_rate {
-                                SoundRate::Khz5 => 0,
-                                SoundRate::Khz11 => 1,
-                                SoundRate::Khz22 => 2,
-                                SoundRate::Khz44 => 3,
-                            };
-                            let sound_size = match tag.sound_size {
-                                SoundSize::Bit8 => 0,
-                                SoundSize::Bit16 => 1,
-                            };
-                            let sound_type = match tag.sound_type {
-                                SoundType::Mono => 0,
-                                SoundType::Stereo => 1,
-                        };
-                            let aac_packet_type = match tag.aac_packet_type {
-                                Some(AacPacketType::SequenceHeader) => 0,
-                                Some(AacPacketType::Raw) => 1,
-                                None => continue,
-                            };
-                            let mut buf = BytesMut::with_capacity(tag.data.len() + 5);
-                            let infos: u8 = ((sound_format as u8) << 4)
-                                | ((sound_rate as u8) << 2)
-                                | ((sound_size as u8) << 1)
-                                | (sound_type as u8);
-                            buf.put_u8(infos);
-                            buf.put_u8(aac_packet_type);
-                            buf.put_slice(&tag.data[..]);
-                            let data: Bytes = buf.into();
-
-                            let mut timestamp_value = tag.timestamp.value() as u32;
-                            timestamp_value += base_ts;
-                            let ts_delta = timestamp_value.checked_sub(last_ts).unwrap_or(0);
-                            let timestamp = RtmpTimestamp::new(timestamp_value);
-
-                            if last_ts <= 0 {
-                                last_ts = timestamp_value;
-                            }
+                                if !started && !is_ash {
+                                    // Files with video start on a keyframe, see above
+                                    if video_seq_header.is_none() && tag_ts >= start_at {
+                                        started = true;
+                                        skip_ts = tag_ts;
+                                    } else {
+                                        continue;
+                                    }
+                                }
 
-                            sleep(ts_delta).await;
-                            last_ts = timestamp_value;
+                                let timestamp_value = tag_ts.saturating_sub(skip_ts) + base_ts;
+                                let timestamp = rtmp_timestamp(timestamp_value);
+                                pacer.wait(timestamp_value).await;
+                                last_ts = last_ts.max(timestamp_value);
 
-                            let packet = PacketType::Audio{ data, ts: timestamp};
-                            yield Arc::new(packet);
-                        }
-                        Tag::ScriptData(s) => {
-                            let mut timestamp_value = s.timestamp.value() as u32;
-                            timestamp_value += base_ts;
-                            let ts_delta = timestamp_value.checked_sub(last_ts).unwrap_or(0);
-                            // let timestamp = RtmpTimestamp::new(timestamp_value);
-                            if last_ts <= 0 {
-                                last_ts = timestamp_value;
+                                let packet = PacketType::Audio{ data, ts: timestamp};
+                                yield Arc::new(packet);
                             }
+                            TagKind::ScriptData => {
+                                let timestamp_value = tag_ts.saturating_sub(skip_ts) + base_ts;
 
-                            let mut d = s.data.as_slice();
-                            let mut data = rml_amf0::deserialize(&mut d).unwrap();
-                            if metadata_sent {
-                                continue;
-                            }
-                            if data.len() == 2 {
-                                let object = data.pop();
-                                let key = data.pop();
-                                match (key, object) {
-                                    (Some(rml_amf0::Amf0Value::Utf8String(s)), Some(rml_amf0::Amf0Value::Object(metadata_object))) if s == "onMetaData" => {
-                                        let mut metadata = rml_rtmp::sessions::StreamMetadata::new();
-                                        metadata.apply_metadata_values(metadata_object);
-                                        sleep(ts_delta).await;
-                                        last_ts = timestamp_value;
+                                if metadata_sent {
+                                    continue;
+                                }
+                                match parse_script_data(&tag.data) {
+                                    Ok(Some(metadata)) => {
+                                        pacer.wait(timestamp_value).await;
+                                        last_ts = last_ts.max(timestamp_value);
                                         let packet = PacketType::Metadata(Arc::new(metadata));
                                         metadata_sent = true;
                                         yield Arc::new(packet);
                                     }
-                                    _ => {
-                                    }
-                                };
-                            }
-                        },
+                                    Ok(None) => (),
+                                    Err(e) => warn!(logger, "Skip invalid script data"; "timestamp" => tag.timestamp, "error" => %e),
+                                }
+                            },
+                        }
                     }
                 }
 
-                if  !repeat {
+                loops += 1;
+                if !repeat || options.repeat_count.map_or(false, |count| loops >= count) {
                     break;
                 }
                 info!(logger, "Loop flv from beginning");
+                yield Arc::new(PacketType::Loop);
             }
         };
         Ok(flv_stream)
@@ -192,34 +259,1263 @@ pub async fn read_flv_tag(path: &str, repeat: bool, logger: Logger) -> Result<im
     }).await?
 }
 
-async fn sleep(duration: u32) {
-    let delay = duration.checked_sub(10).unwrap_or(0);
-    if delay > 0 {
-        tokio::time::delay_for(Duration::from_millis(delay as u64)).await;
+/// The next tag of a pipe or a download, read on the blocking pool: it waits for its writer, e.g.
+/// a live encoder, and the runtime of `--single-thread` has no other thread to run the clients on
+async fn next_blocking(tags: &mut Tags, resync: bool, skipped: &mut Vec<Skipped>) -> std::io::Result<Option<RawTag>> {
+    let mut taken = std::mem::replace(tags, Tags::Preloaded { tags: Arc::default(), pos: 0 });
+    let (taken, next, new_skipped) = tokio::task::spawn_blocking(move || {
+        let mut skipped = Vec::new();
+        let next = taken.next(resync, &mut skipped);
+        (taken, next, skipped)
+    }).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    *tags = taken;
+    skipped.extend(new_skipped);
+    next
+}
+
+/// The 32 bit timestamps of a file on a 64 bit timeline, a timestamp far below the last one
+/// wrapped around
+#[derive(Default)]
+struct Timeline {
+    wraps: u64,
+    last: Option<u32>,
+}
+
+impl Timeline {
+    fn extend(&mut self, timestamp: u32) -> u64 {
+        if let Some(last) = self.last {
+            if timestamp < last && last - timestamp > u32::MAX / 2 {
+                self.wraps += 1;
+            }
+        }
+        self.last = Some(timestamp);
+        (self.wraps << 32) + u64::from(timestamp)
+    }
+}
+
+/// The RTMP timestamp of a time on the timeline, wrapped to 32 bits
+pub(crate) fn rtmp_timestamp(ms: u64) -> RtmpTimestamp {
+    RtmpTimestamp::new(ms as u32)
+}
+
+#[derive(Clone, Copy)]
+enum TagKind {
+    Audio,
+    Video,
+    ScriptData,
+}
+
+/// A tag with its payload as RTMP carries it
+#[derive(Clone)]
+struct RawTag {
+    kind: TagKind,
+    timestamp: u32,
+    data: Bytes,
+}
+
+/// Tags of the input, in file order.
+///
+/// Regular files are read into memory once per run, and every payload is a slice of that buffer:
+/// no tag is copied, however many loops and readers there are. Anything else, e.g. a named pipe
+/// or stdin, is read tag by tag from a buffered reader, one allocation per payload, unless it is
+/// preloaded into a single buffer too. Either way the payloads go out as they are in the file,
+/// whatever the codec.
+enum Tags {
+    /// The whole input in one buffer
+    Loaded { file: Bytes, first: usize, pos: usize },
+    /// `pos` is the offset read up to
+    Decoded { reader: BufReader<Source>, fresh: bool, pos: u64 },
+    /// `--preload`: every tag read once, `pos` is the index of the next one
+    Preloaded { tags: Arc<Vec<RawTag>>, pos: usize },
+}
+
+/// Bytes of an input given up on to resync, `--strict` reads them as tags instead
+pub(crate) struct Skipped {
+    pub offset: u64,
+    pub bytes: u64,
+    pub reason: &'static str,
+}
+
+/// What `Tags::Decoded` reads from
+enum Source {
+    File(File),
+    /// `-`, which cannot be played more than once
+    Stdin(std::io::Stdin),
+    /// Downloaded again for every loop
+    #[cfg(feature = "http")]
+    Http(crate::http::Body),
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Source::File(file) => file.read(buf),
+            Source::Stdin(stdin) => stdin.read(buf),
+            #[cfg(feature = "http")]
+            Source::Http(body) => body.read(buf),
+        }
+    }
+}
+
+impl Seek for Source {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Source::File(file) => file.seek(pos),
+            Source::Stdin(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot rewind stdin")),
+            #[cfg(feature = "http")]
+            Source::Http(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot seek a download")),
+        }
+    }
+}
+
+/// The input path which reads from stdin
+pub const STDIN: &str = "-";
+
+/// Whether the input is downloaded over HTTP(S)
+pub fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+const FLV_HEADER_SIZE: usize = 9;
+const TAG_HEADER_SIZE: usize = 11;
+const PREVIOUS_TAG_SIZE: usize = 4;
+
+/// The inputs read in a run, shared by all its readers: a file is read into memory, and into a tag
+/// table with `--preload`, once per run. Both are released once the last reader of the run and the
+/// cache are dropped.
+#[derive(Clone, Default)]
+pub struct Cache {
+    /// Contents of the regular files, by canonical path
+    loaded: Arc<Mutex<Vec<(PathBuf, Bytes)>>>,
+    /// Tag tables of `--preload`, by input path
+    preloaded: Arc<Mutex<Vec<(String, Arc<Vec<RawTag>>)>>>,
+}
+
+impl Tags {
+    fn open(path: &str, cache: &Cache) -> std::io::Result<Self> {
+        if path == STDIN {
+            return Ok(Tags::decoded(Source::Stdin(std::io::stdin())));
+        }
+        if is_url(path) {
+            #[cfg(feature = "http")]
+            return Ok(Tags::decoded(Source::Http(crate::http::Body::open(path)?)));
+            #[cfg(not(feature = "http"))]
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "http inputs need a build with the `http` feature"));
+        }
+        let file = File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Ok(Tags::decoded(Source::File(file)));
+        }
+        let file = load(path, file, cache)?;
+        let first = parse_header(&file)?;
+        Ok(Tags::Loaded { file, first, pos: first })
+    }
+
+    /// The tag table of `path`, read to the end by the first reader of the run. The inputs after
+    /// are neither read nor downloaded again, stdin included.
+    fn preload(path: &str, resync: bool, cache: &Cache, logger: &Logger) -> std::io::Result<Self> {
+        let mut preloaded = cache.preloaded.lock().map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "preloaded inputs poisoned"))?;
+        if let Some((_, tags)) = preloaded.iter().find(|(p, _)| p == path) {
+            return Ok(Tags::Preloaded { tags: tags.clone(), pos: 0 });
+        }
+        let started = Instant::now();
+        let mut input = match Tags::open(path, cache)? {
+            // Read into one buffer, the payloads are slices of it as of a regular file
+            Tags::Decoded { mut reader, .. } => {
+                let mut file = Vec::new();
+                reader.read_to_end(&mut file)?;
+                let file = Bytes::from(file);
+                let first = parse_header(&file)?;
+                Tags::Loaded { file, first, pos: first }
+            }
+            tags => tags,
+        };
+        let (mut tags, mut skipped) = (Vec::new(), Vec::new());
+        while let Some(tag) = input.next(resync, &mut skipped)? {
+            tags.push(tag);
+        }
+        for s in skipped {
+            warn!(logger, "Skip unreadable input"; "path" => path, "offset" => s.offset, "bytes" => s.bytes, "reason" => s.reason);
+        }
+        info!(logger, "Preloaded input"; "path" => path, "tags" => tags.len(),
+              "bytes" => tags.iter().map(|t| t.data.len()).sum::<usize>(), "elapsed_ms" => started.elapsed().as_millis() as u64);
+        let tags = Arc::new(tags);
+        preloaded.push((path.to_owned(), tags.clone()));
+        Ok(Tags::Preloaded { tags, pos: 0 })
+    }
+
+    /// Reads from a file descriptor, which a loaded file no longer does
+    fn may_block(&self) -> bool {
+        matches!(self, Tags::Decoded { .. })
+    }
+
+    fn decoded(source: Source) -> Self {
+        Tags::Decoded { reader: BufReader::new(source), fresh: true, pos: 0 }
+    }
+
+    fn rewind(&mut self) -> std::io::Result<()> {
+        match self {
+            Tags::Loaded { first, pos, .. } => *pos = *first,
+            Tags::Preloaded { pos, .. } => *pos = 0,
+            // Nothing read yet, pipes need no seek to play once
+            Tags::Decoded { fresh: true, .. } => (),
+            #[cfg(feature = "http")]
+            Tags::Decoded { reader, fresh, .. } if matches!(reader.get_ref(), Source::Http(_)) => {
+                if let Source::Http(body) = reader.get_ref() {
+                    *reader = BufReader::new(Source::Http(body.reopen()?));
+                }
+                *fresh = true;
+            }
+            Tags::Decoded { reader, fresh, .. } => {
+                reader.seek(SeekFrom::Start(0))?;
+                *fresh = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the tags can be read again, i.e. the file can be scanned before it is played
+    fn can_rewind(&self) -> bool {
+        match self {
+            Tags::Loaded { .. } | Tags::Preloaded { .. } => true,
+            #[cfg(feature = "http")]
+            Tags::Decoded { reader, .. } => matches!(reader.get_ref(), Source::Http(_)),
+            #[cfg(not(feature = "http"))]
+            Tags::Decoded { .. } => false,
+        }
+    }
+
+    /// The timestamp of the last video keyframe at or before `ms`, `ms` itself without any
+    fn keyframe_before(&mut self, ms: u64) -> std::io::Result<u64> {
+        self.rewind()?;
+        let (mut timeline, mut keyframe) = (Timeline::default(), None);
+        while let Some(tag) = self.next(true, &mut Vec::new())? {
+            let tag_ts = timeline.extend(tag.timestamp);
+            if tag_ts > ms {
+                break;
+            }
+            if matches!(tag.kind, TagKind::Video) && is_video_keyframe(&tag.data) {
+                keyframe = Some(tag_ts);
+            }
+        }
+        Ok(keyframe.unwrap_or(ms))
+    }
+
+    /// `None` at the end of the file, a truncated last tag ends it too. With `resync` whatever does
+    /// not look like a tag is skipped up to the next one that does, and added to `skipped`.
+    fn next(&mut self, resync: bool, skipped: &mut Vec<Skipped>) -> std::io::Result<Option<RawTag>> {
+        match self {
+            Tags::Loaded { file, pos, .. } => loop {
+                if resync && *pos < file.len() && !plausible_tag(file, *pos) {
+                    let from = *pos;
+                    *pos = (from + 1..file.len()).find(|&offset| plausible_tag(file, offset)).unwrap_or(file.len());
+                    let reason = if *pos == file.len() { "truncated tag" } else { "corrupt or junk bytes" };
+                    skipped.push(Skipped { offset: from as u64, bytes: (*pos - from) as u64, reason });
+                }
+                let (header, payload, next) = match parse_tag(file, *pos) {
+                    Ok(Some(tag)) => tag,
+                    // A recording cut short, play what is complete
+                    Ok(None) | Err(ParseError::Truncated { .. }) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                };
+                *pos = next;
+                let kind = match header.tag_type {
+                    _ if header.filtered => continue,
+                    8 => TagKind::Audio,
+                    9 => TagKind::Video,
+                    18 => TagKind::ScriptData,
+                    _ => continue,
+                };
+                return Ok(Some(RawTag { kind, timestamp: header.timestamp, data: file.slice(payload) }));
+            },
+            Tags::Decoded { reader, fresh, pos } => loop {
+                if std::mem::take(fresh) {
+                    *pos = read_header(reader)?;
+                }
+                let mut header = [0; TAG_HEADER_SIZE];
+                if !read_or_end(reader, &mut header)? {
+                    return Ok(None);
+                }
+                let start = *pos;
+                *pos += TAG_HEADER_SIZE as u64;
+                // Without a look ahead only the header tells, one byte in at a time
+                while resync && !plausible_header(&header) {
+                    header.copy_within(1.., 0);
+                    if !read_or_end(reader, &mut header[TAG_HEADER_SIZE - 1..])? {
+                        skipped.push(Skipped { offset: start, bytes: *pos - start, reason: "corrupt or junk bytes" });
+                        return Ok(None);
+                    }
+                    *pos += 1;
+                }
+                if *pos - start > TAG_HEADER_SIZE as u64 {
+                    let bytes = *pos - start - TAG_HEADER_SIZE as u64;
+                    skipped.push(Skipped { offset: start, bytes, reason: "corrupt or junk bytes" });
+                }
+                let header = parse_tag_header(&header);
+                let mut data = vec![0; header.size];
+                if !read_or_end(reader, &mut data)? {
+                    if resync {
+                        let bytes = (TAG_HEADER_SIZE + header.size) as u64;
+                        skipped.push(Skipped { offset: *pos - TAG_HEADER_SIZE as u64, bytes, reason: "truncated tag" });
+                    }
+                    return Ok(None);
+                }
+                // The trailing `PreviousTagSize` of the last tag may be missing, or wrong
+                read_or_end(reader, &mut [0; PREVIOUS_TAG_SIZE])?;
+                *pos += (header.size + PREVIOUS_TAG_SIZE) as u64;
+                let kind = match header.tag_type {
+                    _ if header.filtered => continue,
+                    8 => TagKind::Audio,
+                    9 => TagKind::Video,
+                    18 => TagKind::ScriptData,
+                    _ => continue,
+                };
+                return Ok(Some(RawTag { kind, timestamp: header.timestamp, data: Bytes::from(data) }));
+            },
+            // Skipped bytes were reported as the table was read
+            Tags::Preloaded { tags, pos } => {
+                let tag = tags.get(*pos).cloned();
+                *pos += 1;
+                Ok(tag)
+            }
+        }
+    }
+}
+
+/// Malformed input found by the `parse_*` functions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// No `FLV` signature
+    NotFlv,
+    /// The header or the tag at `offset` ends past the end of the input
+    Truncated { offset: usize, needed: usize },
+    /// The header points before its own end or past the end of the input
+    DataOffset(u32),
+    /// Script data which is not valid AMF0
+    ScriptData(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::NotFlv => f.write_str("not an FLV file"),
+            ParseError::Truncated { offset, needed } => write!(f, "truncated at {}, {} bytes needed", offset, needed),
+            ParseError::DataOffset(offset) => write!(f, "invalid data offset {}", offset),
+            ParseError::ScriptData(e) => write!(f, "invalid script data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for std::io::Error {
+    fn from(e: ParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The 11 bytes before each tag payload
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TagHeader {
+    /// 8 audio, 9 video, 18 script data, anything else is unknown
+    pub tag_type: u8,
+    /// The payload is encrypted, which is not supported
+    pub filtered: bool,
+    pub size: usize,
+    pub timestamp: u32,
+}
+
+/// Offset of the first tag, after the header and the first `PreviousTagSize`
+pub fn parse_header(input: &[u8]) -> Result<usize, ParseError> {
+    if input.len() < FLV_HEADER_SIZE {
+        return if b"FLV".starts_with(&input[..input.len().min(3)]) {
+            Err(ParseError::Truncated { offset: 0, needed: FLV_HEADER_SIZE })
+        } else {
+            Err(ParseError::NotFlv)
+        };
+    }
+    if !input.starts_with(b"FLV") {
+        return Err(ParseError::NotFlv);
+    }
+    let data_offset = u32::from_be_bytes([input[5], input[6], input[7], input[8]]);
+    let first = Some(data_offset as usize)
+        .filter(|&offset| offset >= FLV_HEADER_SIZE)
+        .and_then(|offset| offset.checked_add(PREVIOUS_TAG_SIZE))
+        .ok_or(ParseError::DataOffset(data_offset))?;
+    if first > input.len() {
+        return Err(ParseError::Truncated { offset: 0, needed: first });
+    }
+    Ok(first)
+}
+
+pub fn parse_tag_header(header: &[u8; TAG_HEADER_SIZE]) -> TagHeader {
+    TagHeader {
+        tag_type: header[0] & 0x1f,
+        filtered: header[0] & 0x20 != 0,
+        size: u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize,
+        // The fourth byte extends the timestamp to 32 bits
+        timestamp: u32::from_be_bytes([header[7], header[4], header[5], header[6]]),
+    }
+}
+
+/// The tag at `offset`: its header, where its payload is and the offset of the next tag.
+/// `Ok(None)` exactly at the end of the input.
+pub fn parse_tag(input: &[u8], offset: usize) -> Result<Option<(TagHeader, Range<usize>, usize)>, ParseError> {
+    if offset >= input.len() {
+        return Ok(None);
+    }
+    let mut header = [0; TAG_HEADER_SIZE];
+    match input.get(offset..).and_then(|rest| rest.get(..TAG_HEADER_SIZE)) {
+        Some(bytes) => header.copy_from_slice(bytes),
+        None => return Err(ParseError::Truncated { offset, needed: TAG_HEADER_SIZE }),
+    }
+    let header = parse_tag_header(&header);
+    let start = offset + TAG_HEADER_SIZE;
+    let end = start.checked_add(header.size)
+        .filter(|&end| end <= input.len())
+        .ok_or(ParseError::Truncated { offset, needed: TAG_HEADER_SIZE + header.size })?;
+    // The trailing `PreviousTagSize` of the last tag may be missing
+    Ok(Some((header, start..end, end.saturating_add(PREVIOUS_TAG_SIZE))))
+}
+
+/// The metadata of an `onMetaData` script tag, `None` for any other script data
+pub fn parse_script_data(data: &[u8]) -> Result<Option<rml_rtmp::sessions::StreamMetadata>, ParseError> {
+    let mut cursor = data;
+    let mut values = rml_amf0::deserialize(&mut cursor).map_err(|e| ParseError::ScriptData(format!("{:?}", e)))?;
+    if values.len() != 2 {
+        return Ok(None);
+    }
+    let object = values.pop();
+    let key = values.pop();
+    match (key, object) {
+        (Some(rml_amf0::Amf0Value::Utf8String(s)), Some(rml_amf0::Amf0Value::Object(properties))) if s == "onMetaData" => {
+            let mut metadata = rml_rtmp::sessions::StreamMetadata::new();
+            metadata.apply_metadata_values(properties);
+            Ok(Some(metadata))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Skip the file header of a reader, up to the first tag, returns its offset
+fn read_header(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut header = [0; FLV_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    if !header.starts_with(b"FLV") {
+        return Err(ParseError::NotFlv.into());
+    }
+    let data_offset = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    let rest = (data_offset as usize).checked_sub(FLV_HEADER_SIZE).ok_or(ParseError::DataOffset(data_offset))?;
+    let skip = (rest + PREVIOUS_TAG_SIZE) as u64;
+    if std::io::copy(&mut reader.take(skip), &mut std::io::sink())? < skip {
+        return Err(ParseError::Truncated { offset: 0, needed: data_offset as usize + PREVIOUS_TAG_SIZE }.into());
+    }
+    Ok(FLV_HEADER_SIZE as u64 + skip)
+}
+
+/// A known tag type without the reserved bits, in stream 0
+fn plausible_header(header: &[u8]) -> bool {
+    header.len() >= TAG_HEADER_SIZE
+        && header[0] & 0xc0 == 0
+        && matches!(header[0] & 0x1f, 8 | 9 | 18)
+        && header[8..TAG_HEADER_SIZE] == [0, 0, 0]
+}
+
+/// A plausible header at `offset` whose payload ends within the input, followed by its own
+/// `PreviousTagSize`, by another plausible header or by the end of the input. A bogus
+/// `PreviousTagSize` alone does not make a tag corrupt, nothing else reads it.
+fn plausible_tag(input: &[u8], offset: usize) -> bool {
+    let header = match input.get(offset..).and_then(|rest| rest.get(..TAG_HEADER_SIZE)) {
+        Some(header) if plausible_header(header) => header,
+        _ => return false,
+    };
+    let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+    let end = offset + TAG_HEADER_SIZE + size;
+    if end > input.len() {
+        return false;
+    }
+    let next = end + PREVIOUS_TAG_SIZE;
+    match input.get(end..next) {
+        Some(pts) if u32::from_be_bytes([pts[0], pts[1], pts[2], pts[3]]) as usize == TAG_HEADER_SIZE + size => true,
+        Some(_) => next >= input.len() || input.get(next..).map_or(false, plausible_header),
+        None => true,
+    }
+}
+
+/// Fill `buf`, `false` if the input ends first: a recording cut short, play what is complete
+fn read_or_end(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the whole file, or reuse the buffer another reader of the same path read in the run
+fn load(path: &str, mut file: File, cache: &Cache) -> std::io::Result<Bytes> {
+    let path = std::fs::canonicalize(path)?;
+    let mut loaded = cache.loaded.lock().map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "loaded inputs poisoned"))?;
+    if let Some((_, bytes)) = loaded.iter().find(|(p, _)| *p == path) {
+        return Ok(bytes.clone());
+    }
+    let mut buf = Vec::with_capacity(file.metadata()?.len() as usize);
+    file.read_to_end(&mut buf)?;
+    let bytes = Bytes::from(buf);
+    loaded.push((path, bytes.clone()));
+    Ok(bytes)
+}
+
+/// The input files of `--input`: FLV files, `-` for stdin, http and rtmp urls as they are, and the files listed by playlists
+/// (`.m3u`, `.m3u8` or `.txt`, one path per line, relative to the playlist, `#` comments).
+pub fn inputs<'a>(args: impl IntoIterator<Item = &'a str>) -> std::io::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for arg in args {
+        let lower = arg.to_lowercase();
+        if lower.ends_with(".m3u") || lower.ends_with(".m3u8") || lower.ends_with(".txt") {
+            let dir = std::path::Path::new(arg).parent().map(PathBuf::from).unwrap_or_default();
+            let content = std::fs::read_to_string(arg)?;
+            let listed = content.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| if line.contains("://") { line.to_owned() } else { dir.join(line).to_string_lossy().into_owned() });
+            paths.extend(listed);
+        } else {
+            paths.push(arg.to_owned());
+        }
+    }
+    for path in &paths {
+        if path == STDIN || is_url(path) || crate::rtmp_url::is_rtmp_url(path) || crate::generator::is_synthetic(path) {
+            continue;
+        }
+        if !is_flv(path)? {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("only FLV files are supported: {}", path)));
+        }
+    }
+    if paths.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty playlist"));
+    }
+    Ok(paths)
+}
+
+/// Whether the file starts with the FLV signature, whatever its name. Pipes are not read ahead.
+fn is_flv(path: &str) -> std::io::Result<bool> {
+    let mut file = File::open(path).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    if !file.metadata()?.is_file() {
+        return Ok(true);
+    }
+    let mut signature = [0; 3];
+    Ok(read_or_end(&mut file, &mut signature)? && signature == *b"FLV")
+}
+
+/// Timestamp of the last tag, i.e. the length of one loop of the file.
+pub fn duration(path: &str) -> std::io::Result<Duration> {
+    // Not read ahead, a live source never ends
+    if path == STDIN || is_url(path) || crate::rtmp_url::is_rtmp_url(path) || crate::generator::is_synthetic(path) {
+        return Ok(Duration::from_secs(0));
+    }
+    let mut tags = Tags::open(path, &Cache::default())?;
+    tags.rewind()?;
+    let (mut timeline, mut last_ts) = (Timeline::default(), 0);
+    while let Some(tag) = tags.next(true, &mut Vec::new())? {
+        last_ts = last_ts.max(timeline.extend(tag.timestamp));
+    }
+    Ok(Duration::from_millis(last_ts))
+}
+
+/// What `waterfall probe` finds in an input
+#[derive(Clone, Debug, Default)]
+pub struct Probe {
+    /// Of the first video tag, e.g. CodecID 7 for AVC or `hvc1` for enhanced HEVC
+    pub video_codec: Option<Codec>,
+    /// Of the first audio tag, e.g. SoundFormat 10 for AAC or `Opus`
+    pub audio_codec: Option<Codec>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f32>,
+    pub duration: Duration,
+    pub video_frames: u64,
+    pub audio_frames: u64,
+    pub keyframes: u64,
+    /// Payload bytes of all tags
+    pub bytes: u64,
+    /// Corrupt or junk bytes between the tags, and truncated tags
+    pub skipped_bytes: u64,
+    pub avg_bitrate_bps: u64,
+    /// Highest bitrate over one second of timestamps
+    pub peak_bitrate_bps: u64,
+    pub avg_keyframe_interval: Option<Duration>,
+    pub max_keyframe_interval: Option<Duration>,
+}
+
+/// Read the whole input once and describe it.
+pub fn probe(path: &str) -> std::io::Result<Probe> {
+    let mut tags = Tags::open(path, &Cache::default())?;
+    tags.rewind()?;
+    let mut probe = Probe::default();
+    let (mut timeline, mut first_ts, mut last_ts) = (Timeline::default(), None, 0);
+    let mut seconds = std::collections::BTreeMap::<u64, u64>::new();
+    let (mut last_keyframe, mut keyframe_intervals) = (None, Vec::new());
+    let mut skipped = Vec::new();
+    while let Some(tag) = tags.next(true, &mut skipped)? {
+        let tag_ts = timeline.extend(tag.timestamp);
+        first_ts.get_or_insert(tag_ts);
+        last_ts = last_ts.max(tag_ts);
+        probe.bytes += tag.data.len() as u64;
+        *seconds.entry(tag_ts / 1000).or_insert(0) += tag.data.len() as u64;
+        match tag.kind {
+            TagKind::Video if !tag.data.is_empty() => {
+                if let Some(codec) = video_codec(&tag.data) {
+                    probe.video_codec.get_or_insert(codec);
+                }
+                if is_video_sequence_header(&tag.data) || is_video_sequence_end(&tag.data) {
+                    continue;
+                }
+                probe.video_frames += 1;
+                if is_video_keyframe(&tag.data) {
+                    probe.keyframes += 1;
+                    if let Some(last) = last_keyframe.replace(tag_ts) {
+                        keyframe_intervals.push(tag_ts.saturating_sub(last));
+                    }
+                }
+            }
+            TagKind::Audio if !tag.data.is_empty() => {
+                if let Some(codec) = audio_codec(&tag.data) {
+                    probe.audio_codec.get_or_insert(codec);
+                }
+                if !is_audio_sequence_header(&tag.data) {
+                    probe.audio_frames += 1;
+                }
+            }
+            TagKind::ScriptData => match parse_script_data(&tag.data) {
+                Ok(Some(metadata)) => {
+                    probe.width = probe.width.or(metadata.video_width);
+                    probe.height = probe.height.or(metadata.video_height);
+                    probe.fps = probe.fps.or(metadata.video_frame_rate);
+                }
+                Ok(None) => (),
+                Err(e) => return Err(e.into()),
+            },
+            _ => (),
+        }
+    }
+    probe.skipped_bytes = skipped.iter().map(|s| s.bytes).sum();
+    probe.duration = Duration::from_millis(last_ts.saturating_sub(first_ts.unwrap_or(0)));
+    let secs = probe.duration.as_secs_f64();
+    if secs > 0.0 {
+        probe.avg_bitrate_bps = (probe.bytes as f64 * 8.0 / secs) as u64;
+    }
+    probe.peak_bitrate_bps = seconds.values().max().map_or(0, |bytes| bytes * 8);
+    if !keyframe_intervals.is_empty() {
+        let total = keyframe_intervals.iter().sum::<u64>();
+        probe.avg_keyframe_interval = Some(Duration::from_millis(total / keyframe_intervals.len() as u64));
+        probe.max_keyframe_interval = keyframe_intervals.iter().max().map(|&i| Duration::from_millis(i));
+    }
+    Ok(probe)
+}
+
+fn video_codec_name(codec: Codec) -> &'static str {
+    match codec {
+        Codec::Id(id) => match id {
+            2 => "Sorenson H.263",
+            3 => "Screen video",
+            4 => "On2 VP6",
+            5 => "On2 VP6 with alpha",
+            6 => "Screen video 2",
+            7 => "AVC",
+            12 => "HEVC",
+            13 => "AV1",
+            _ => "unknown",
+        },
+        Codec::FourCc(fourcc) => match &fourcc {
+            b"avc1" => "AVC",
+            b"hvc1" => "HEVC",
+            b"av01" => "AV1",
+            b"vp08" => "VP8",
+            b"vp09" => "VP9",
+            _ => "unknown",
+        },
+    }
+}
+
+fn audio_codec_name(codec: Codec) -> &'static str {
+    match codec {
+        Codec::Id(id) => match id {
+            0 | 3 => "PCM",
+            1 => "ADPCM",
+            2 => "MP3",
+            4..=6 => "Nellymoser",
+            7 => "G.711 A-law",
+            8 => "G.711 mu-law",
+            10 => "AAC",
+            11 => "Speex",
+            _ => "unknown",
+        },
+        Codec::FourCc(fourcc) => match &fourcc {
+            b"mp4a" => "AAC",
+            b".mp3" => "MP3",
+            b"Opus" => "Opus",
+            b"fLaC" => "FLAC",
+            b"ac-3" => "AC-3",
+            b"ec-3" => "E-AC-3",
+            _ => "unknown",
+        },
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Id(id) => write!(f, "{}", id),
+            Codec::FourCc(fourcc) => f.write_str(&String::from_utf8_lossy(fourcc)),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".into());
+        let secs = |d: Option<Duration>| or_dash(d.map(|d| format!("{:.2}s", d.as_secs_f64())));
+        writeln!(f, "video codec     {}", or_dash(self.video_codec.map(|c| format!("{} ({})", c, video_codec_name(c)))))?;
+        writeln!(f, "audio codec     {}", or_dash(self.audio_codec.map(|c| format!("{} ({})", c, audio_codec_name(c)))))?;
+        let resolution = match (self.width, self.height) {
+            (Some(w), Some(h)) => Some(format!("{}x{}", w, h)),
+            _ => None,
+        };
+        writeln!(f, "resolution      {}", or_dash(resolution))?;
+        writeln!(f, "fps             {}", or_dash(self.fps.map(|fps| format!("{:.2}", fps))))?;
+        writeln!(f, "duration        {:.2}s", self.duration.as_secs_f64())?;
+        writeln!(f, "frames          {} video, {} audio, {} keyframes", self.video_frames, self.audio_frames, self.keyframes)?;
+        writeln!(f, "bitrate         {} kbps average, {} kbps peak", self.avg_bitrate_bps / 1000, self.peak_bitrate_bps / 1000)?;
+        write!(f, "keyframe every  {} average, {} at most", secs(self.avg_keyframe_interval), secs(self.max_keyframe_interval))?;
+        if self.skipped_bytes > 0 {
+            write!(f, "\nskipped         {} corrupt bytes", self.skipped_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Holds each tag back until its timestamp is due, against the wall clock since the first tag,
+/// so the sleeps never add up to a drift.
+///
+/// The tags of the first `burst` of media are not held back at all, like an encoder dumping its
+/// buffer on connect. The pacing starts over from the first tag after them, the stream stays that
+/// much ahead of the wall clock.
+pub(crate) struct Pacer {
+    speed: f64,
+    /// In media milliseconds, still to go
+    burst_ms: u64,
+    /// When the first tag went out, and its time on the timeline
+    origin: Option<(Instant, u64)>,
+}
+
+impl Pacer {
+    pub(crate) fn new(speed: f64, burst: Duration) -> Self {
+        Self { speed, burst_ms: burst.as_millis() as u64, origin: None }
+    }
+
+    /// `timestamp` on the 64 bit timeline, a wrapped RTMP timestamp would stop the pacing
+    pub(crate) async fn wait(&mut self, timestamp: u64) {
+        if self.burst_ms > 0 {
+            let (_, first) = *self.origin.get_or_insert_with(|| (Instant::now(), timestamp));
+            if timestamp < first + self.burst_ms {
+                return;
+            }
+            self.burst_ms = 0;
+            self.origin = None;
+        }
+        let (started, first) = *self.origin.get_or_insert_with(|| (Instant::now(), timestamp));
+        let offset = Duration::from_secs_f64(timestamp.saturating_sub(first) as f64 / 1000.0 / self.speed);
+        tokio::time::delay_until(tokio::time::Instant::from_std(started + offset)).await;
+    }
+}
+
+pub const TAG_AUDIO: u8 = 8;
+pub const TAG_VIDEO: u8 = 9;
+pub const TAG_SCRIPT_DATA: u8 = 18;
+
+/// Muxes tags back into an FLV file, the streams received by `waterfall play --record-dir`
+pub struct Writer<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    /// Write the file header, announcing both audio and video
+    pub async fn new(mut inner: W) -> std::io::Result<Self> {
+        let mut header = Vec::with_capacity(FLV_HEADER_SIZE + PREVIOUS_TAG_SIZE);
+        header.extend_from_slice(b"FLV\x01\x05");
+        header.extend_from_slice(&(FLV_HEADER_SIZE as u32).to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes());
+        inner.write_all(&header).await?;
+        Ok(Self { inner })
+    }
+
+    pub async fn write_tag(&mut self, tag_type: u8, timestamp: u32, data: &[u8]) -> std::io::Result<()> {
+        let mut tag = Vec::with_capacity(TAG_HEADER_SIZE + data.len() + PREVIOUS_TAG_SIZE);
+        tag.push(tag_type);
+        tag.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+        // The lower 24 bits, then the upper 8
+        tag.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+        tag.push((timestamp >> 24) as u8);
+        // stream id
+        tag.extend_from_slice(&[0, 0, 0]);
+        tag.extend_from_slice(data);
+        tag.extend_from_slice(&((TAG_HEADER_SIZE + data.len()) as u32).to_be_bytes());
+        self.inner.write_all(&tag).await
+    }
+
+    pub async fn finish(mut self) -> std::io::Result<()> {
+        self.inner.flush().await
+    }
+}
+
+/// The codec of a tag, the legacy CodecID / SoundFormat or the FourCC of an enhanced RTMP header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Id(u8),
+    FourCc([u8; 4]),
+}
+
+/// What a VIDEODATA or AUDIODATA carries, whatever the codec
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Packet {
+    SequenceHeader,
+    /// Of the enhanced audio header, the channel order which goes with the sequence header
+    MultichannelConfig,
+    Coded,
+    SequenceEnd,
+    /// Command frames, HDR metadata and reserved types
+    Other,
+}
+
+const VIDEO_FRAME_KEY: u8 = 1;
+const VIDEO_FRAME_COMMAND: u8 = 5;
+/// The legacy codecs whose tags have an `AVCPacketType`: AVC, and HEVC and AV1 as the extensions
+/// before enhanced RTMP numbered them
+const PACKETIZED_VIDEO_CODECS: &[u8] = &[7, 12, 13];
+const SOUND_FORMAT_AAC: u8 = 10;
+/// The SoundFormat of an enhanced audio header
+const SOUND_FORMAT_EX_HEADER: u8 = 9;
+/// `VideoPacketType` / `AudioPacketType` of a `ModEx` prefix, `Multitrack` differs
+const PACKET_TYPE_MOD_EX: u8 = 7;
+const VIDEO_PACKET_TYPE_MULTITRACK: u8 = 6;
+const AUDIO_PACKET_TYPE_MULTITRACK: u8 = 5;
+
+/// The packet type of an enhanced header past its `ModEx` prefixes and the multitrack header, and the
+/// FourCC after it if there is one: of the first track when the tracks have different codecs
+fn ex_packet_type(data: &[u8], multitrack: u8) -> Option<(u8, Option<[u8; 4]>)> {
+    let mut packet_type = data.first()? & 0x0f;
+    let mut pos = 1;
+    while packet_type == PACKET_TYPE_MOD_EX {
+        let mut size = *data.get(pos)? as usize + 1;
+        pos += 1;
+        if size == 256 {
+            let bytes = data.get(pos..pos + 2)?;
+            size = u16::from_be_bytes([bytes[0], bytes[1]]) as usize + 1;
+            pos += 2;
+        }
+        pos += size;
+        packet_type = data.get(pos)? & 0x0f;
+        pos += 1;
+    }
+    if packet_type == multitrack {
+        // `AvMultitrackType` in the upper half
+        packet_type = data.get(pos)? & 0x0f;
+        pos += 1;
+    }
+    let fourcc = data.get(pos..pos + 4).map(|b| [b[0], b[1], b[2], b[3]]);
+    Some((packet_type, fourcc))
+}
+
+fn is_ex_video(data: &[u8]) -> bool {
+    data.first().map_or(false, |b| b & 0x80 != 0)
+}
+
+fn is_ex_audio(data: &[u8]) -> bool {
+    data.first().map_or(false, |b| b >> 4 == SOUND_FORMAT_EX_HEADER)
+}
+
+/// The frame type and the packet of a VIDEODATA, legacy or enhanced
+pub fn video_packet(data: &[u8]) -> Option<(u8, Packet)> {
+    let first = *data.first()?;
+    if is_ex_video(data) {
+        let frame_type = (first >> 4) & 0x07;
+        if frame_type == VIDEO_FRAME_COMMAND {
+            return Some((frame_type, Packet::Other));
+        }
+        let packet = match ex_packet_type(data, VIDEO_PACKET_TYPE_MULTITRACK)?.0 {
+            // `SequenceStart` and `MPEG2TSSequenceStart`
+            0 | 5 => Packet::SequenceHeader,
+            // `CodedFrames` and `CodedFramesX`, the latter without composition time
+            1 | 3 => Packet::Coded,
+            2 => Packet::SequenceEnd,
+            _ => Packet::Other,
+        };
+        return Some((frame_type, packet));
+    }
+    let frame_type = first >> 4;
+    if frame_type == VIDEO_FRAME_COMMAND {
+        return Some((frame_type, Packet::Other));
+    }
+    if !PACKETIZED_VIDEO_CODECS.contains(&(first & 0x0f)) {
+        return Some((frame_type, Packet::Coded));
+    }
+    let packet = match *data.get(1)? {
+        0 => Packet::SequenceHeader,
+        1 => Packet::Coded,
+        2 => Packet::SequenceEnd,
+        _ => Packet::Other,
+    };
+    Some((frame_type, packet))
+}
+
+/// The packet of an AUDIODATA, legacy or enhanced
+pub fn audio_packet(data: &[u8]) -> Option<Packet> {
+    let first = *data.first()?;
+    if is_ex_audio(data) {
+        return Some(match ex_packet_type(data, AUDIO_PACKET_TYPE_MULTITRACK)?.0 {
+            0 => Packet::SequenceHeader,
+            1 => Packet::Coded,
+            2 => Packet::SequenceEnd,
+            4 => Packet::MultichannelConfig,
+            _ => Packet::Other,
+        });
+    }
+    if first >> 4 != SOUND_FORMAT_AAC {
+        return Some(Packet::Coded);
     }
+    Some(match *data.get(1)? {
+        0 => Packet::SequenceHeader,
+        _ => Packet::Coded,
+    })
+}
+
+pub fn video_codec(data: &[u8]) -> Option<Codec> {
+    if is_ex_video(data) {
+        return ex_packet_type(data, VIDEO_PACKET_TYPE_MULTITRACK)?.1.map(Codec::FourCc);
+    }
+    data.first().map(|b| Codec::Id(b & 0x0f))
+}
+
+pub fn audio_codec(data: &[u8]) -> Option<Codec> {
+    if is_ex_audio(data) {
+        return ex_packet_type(data, AUDIO_PACKET_TYPE_MULTITRACK)?.1.map(Codec::FourCc);
+    }
+    data.first().map(|b| Codec::Id(b >> 4))
+}
+
+/// AAC, or any codec of the enhanced audio header, the only audio the inputs send
+pub fn is_supported_audio(data: &[u8]) -> bool {
+    data.len() >= 2 && (data[0] >> 4 == SOUND_FORMAT_AAC || is_ex_audio(data))
 }
 
 pub fn is_video_sequence_header(data: &[u8]) -> bool {
-    // This is assuming h264.
-    return data.len() >= 2 && data[0] == 0x17 && data[1] == 0x00;
+    matches!(video_packet(data), Some((_, Packet::SequenceHeader)))
 }
 
 pub fn is_video_sequence_end(data: &[u8]) -> bool {
-    // This is assuming h264.
-    return data.len() >= 2 && data[0] == 0x17 && data[1] == 0x02;
+    matches!(video_packet(data), Some((_, Packet::SequenceEnd)))
 }
 
+/// The decoder configuration, including the multichannel configuration of enhanced audio, which
+/// every player needs before the first frame
 pub fn is_audio_sequence_header(data: &[u8]) -> bool {
-    // This is assuming aac
-    return data.len() >= 2 && data[0] == 0xaf && data[1] == 0x00;
+    matches!(audio_packet(data), Some(Packet::SequenceHeader) | Some(Packet::MultichannelConfig))
+}
+
+/// Of `aac_frame_ms`, by sampling frequency index
+const AAC_SAMPLING_FREQUENCIES: [u32; 13] = [96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350];
+
+/// How long the frames of the legacy AAC sequence header `data` last, 1024 samples at its
+/// sampling frequency
+pub fn aac_frame_ms(data: &[u8]) -> Option<f64> {
+    if data.first()? >> 4 != SOUND_FORMAT_AAC || *data.get(1)? != 0 {
+        return None;
+    }
+    let config = data.get(2..4)?;
+    let index = ((config[0] & 7) << 1) | (config[1] >> 7);
+    let frequency = *AAC_SAMPLING_FREQUENCIES.get(index as usize)?;
+    Some(1_024_000.0 / f64::from(frequency))
+}
+
+/// A raw frame of legacy AAC
+pub fn is_aac_frame(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] >> 4 == SOUND_FORMAT_AAC && data[1] == 1
+}
+
+pub fn is_audio_multichannel_config(data: &[u8]) -> bool {
+    audio_packet(data) == Some(Packet::MultichannelConfig)
 }
 
 pub fn is_video_keyframe(data: &[u8]) -> bool {
-    // assumings h264
-    return data.len() >= 2 && data[0] == 0x17 && data[1] != 0x00; // 0x00 is the sequence header, don't count that for now
+    video_packet(data) == Some((VIDEO_FRAME_KEY, Packet::Coded))
 }
 
+/// A keyframe or a sequence header, where a stream can be resumed
 pub fn is_video_keyframe_or_header(data: &[u8]) -> bool {
-    // assumings h264
-    return data.len() >= 2 && data[0] == 0x17 && data[1] != 0x02; // 0x02 is the sequence end, don't count that for now
+    match video_packet(data) {
+        Some((VIDEO_FRAME_KEY, packet)) => packet != Packet::SequenceEnd,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use rml_amf0::Amf0Value;
+
+    use super::*;
+
+    fn tag(tag_type: u8, timestamp: u32, payload: &[u8]) -> Vec<u8> {
+        let size = payload.len() as u32;
+        let mut tag = vec![tag_type];
+        tag.extend_from_slice(&size.to_be_bytes()[1..]);
+        tag.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+        tag.push((timestamp >> 24) as u8);
+        tag.extend_from_slice(&[0, 0, 0]);
+        tag.extend_from_slice(payload);
+        tag.extend_from_slice(&(TAG_HEADER_SIZE as u32 + size).to_be_bytes());
+        tag
+    }
+
+    fn flv(tags: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"FLV\x01\x05\x00\x00\x00\x09\x00\x00\x00\x00".to_vec();
+        for tag in tags {
+            file.extend_from_slice(tag);
+        }
+        file
+    }
+
+    fn on_metadata() -> Vec<u8> {
+        let mut properties = HashMap::new();
+        properties.insert("width".to_owned(), Amf0Value::Number(1280.0));
+        properties.insert("height".to_owned(), Amf0Value::Number(720.0));
+        rml_amf0::serialize(&vec![Amf0Value::Utf8String("onMetaData".into()), Amf0Value::Object(properties)]).unwrap()
+    }
+
+    fn sample() -> Vec<u8> {
+        flv(&[
+            tag(18, 0, &on_metadata()),
+            tag(9, 0, &[0x17, 0, 0, 0, 0, 1, 0x64, 0, 0x1f]),
+            tag(8, 0, &[0xaf, 0, 0x12, 0x10]),
+            tag(9, 40, &[0x17, 1, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88]),
+            tag(8, 23, &[0xaf, 1, 0x21, 0x00]),
+        ])
+    }
+
+    /// Every tag of `input`, script data parsed, up to the first error
+    fn walk(input: &[u8]) -> Result<usize, ParseError> {
+        let mut offset = parse_header(input)?;
+        let mut tags = 0;
+        while let Some((header, payload, next)) = parse_tag(input, offset)? {
+            assert!(payload.start >= offset && payload.end <= input.len());
+            assert!(next > offset);
+            if header.tag_type == 18 {
+                match parse_script_data(&input[payload]) {
+                    Ok(_) | Err(ParseError::ScriptData(_)) => (),
+                    Err(e) => panic!("unexpected script data error {:?}", e),
+                }
+            }
+            offset = next;
+            tags += 1;
+        }
+        Ok(tags)
+    }
+
+    #[test]
+    fn parses_every_tag_of_a_file() {
+        assert_eq!(walk(&sample()), Ok(5));
+        let (header, payload, _) = parse_tag(&sample(), 13).unwrap().unwrap();
+        assert_eq!(header.tag_type, 18);
+        let metadata = parse_script_data(&sample()[payload]).unwrap().unwrap();
+        assert_eq!(metadata.video_width, Some(1280));
+    }
+
+    #[test]
+    fn truncated_input_is_a_truncated_error() {
+        let file = sample();
+        for len in 0..file.len() {
+            match walk(&file[..len]) {
+                Ok(tags) => assert!(tags < 5, "{} bytes", len),
+                Err(ParseError::Truncated { .. }) => (),
+                Err(e) => panic!("{} bytes: {:?}", len, e),
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_a_parse_error_not_a_panic() {
+        let mut rng = StdRng::seed_from_u64(0x666c76);
+        for _ in 0..20_000 {
+            let len = rng.gen_range(0, 512);
+            let mut input = (0..len).map(|_| rng.gen::<u8>()).collect::<Vec<_>>();
+            // Half of them past the header, into the tags
+            if rng.gen() {
+                input = [flv(&[]), input].concat();
+            }
+            let _ = walk(&input);
+            let _ = parse_script_data(&input);
+        }
+    }
+
+    #[test]
+    fn corrupt_bytes_are_a_parse_error_not_a_panic() {
+        let file = sample();
+        let mut rng = StdRng::seed_from_u64(0x747367);
+        for _ in 0..20_000 {
+            let mut input = file.clone();
+            for _ in 0..rng.gen_range(1, 8) {
+                let i = rng.gen_range(0, input.len());
+                input[i] = rng.gen();
+            }
+            input.truncate(rng.gen_range(0, file.len() + 1));
+            let _ = walk(&input);
+        }
+    }
+
+    /// The timestamps of the video `read_flv_tag` sends for `file`, played `loops` times at once
+    async fn video_timestamps(name: &str, file: &[u8], loops: u32) -> Vec<u32> {
+        let path = std::env::temp_dir().join(format!("waterfall-{}-{}.flv", std::process::id(), name));
+        std::fs::write(&path, file).unwrap();
+        let options = ReadOptions {
+            repeat: loops > 1,
+            repeat_count: Some(loops),
+            burst: Duration::from_secs(1 << 40),
+            ..Default::default()
+        };
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let tags = read_flv_tag(&[path.to_string_lossy().into_owned()], options, &Cache::default(), logger).await.unwrap();
+        futures::pin_mut!(tags);
+        let mut timestamps = Vec::new();
+        while let Some(packet) = futures::StreamExt::next(&mut tags).await {
+            if let PacketType::Video { ts, .. } = *packet.unwrap() {
+                timestamps.push(ts.value);
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        timestamps
+    }
+
+    fn keyframes(timestamps: &[u32]) -> Vec<u8> {
+        flv(&timestamps.iter().map(|&ts| tag(9, ts, &[0x17, 1, 0, 0, 0, 0, 0, 0, 1, 0x65])).collect::<Vec<_>>())
+    }
+
+    const WRAP: u64 = 1 << 32;
+
+    #[test]
+    fn timeline_follows_the_32_bit_wrap() {
+        let mut timeline = Timeline::default();
+        assert_eq!(timeline.extend(u32::MAX - 10), WRAP - 11);
+        assert_eq!(timeline.extend(u32::MAX), WRAP - 1);
+        assert_eq!(timeline.extend(5), WRAP + 5);
+        // A timestamp slightly back is a reordered tag, not a wrap
+        assert_eq!(timeline.extend(2), WRAP + 2);
+        assert_eq!(timeline.extend(u32::MAX - 10), WRAP - 11 + WRAP);
+        assert_eq!(timeline.extend(7), 2 * WRAP + 7);
+    }
+
+    #[test]
+    fn rtmp_timestamps_wrap_at_32_bits() {
+        assert_eq!(rtmp_timestamp(WRAP - 1).value, u32::MAX);
+        assert_eq!(rtmp_timestamp(WRAP).value, 0);
+        assert_eq!(rtmp_timestamp(WRAP + 40).value, 40);
+        assert_eq!(rtmp_timestamp(3 * WRAP + 7).value, 7);
+    }
+
+    #[tokio::test]
+    async fn loop_rebase_crosses_the_wrap() {
+        // Almost 50 days, the next loop starts a frame after the last tag, past 2^32 ms
+        let file = keyframes(&[0, u32::MAX - 135, u32::MAX - 95]);
+        let base = (WRAP - 96) + 40;
+        let expected: Vec<u64> = vec![0, WRAP - 136, WRAP - 96, base, base + WRAP - 136, base + WRAP - 96];
+        assert!(expected.windows(2).all(|w| w[0] < w[1]));
+        let expected = expected.into_iter().map(|ms| ms as u32).collect::<Vec<_>>();
+        assert_eq!(video_timestamps("rebase", &file, 2).await, expected);
+    }
+
+    #[tokio::test]
+    async fn monotonic_across_a_wrap_in_the_file_and_a_loop() {
+        // A recording whose own timestamps wrapped, played twice
+        let file = keyframes(&[u32::MAX - 80, u32::MAX - 40, u32::MAX, 39, 79]);
+        let first = [WRAP - 81, WRAP - 41, WRAP - 1, WRAP + 39, WRAP + 79];
+        let base = WRAP + 79 + 40;
+        let expected = first.iter().copied().chain(first.iter().map(|ms| ms + base)).collect::<Vec<u64>>();
+        assert!(expected.windows(2).all(|w| w[0] < w[1]));
+        let expected = expected.into_iter().map(|ms| ms as u32).collect::<Vec<_>>();
+        assert_eq!(video_timestamps("wrapped", &file, 2).await, expected);
+    }
+
+    #[tokio::test]
+    async fn negative_composition_time_survives_reading_and_chunking() {
+        use rml_rtmp::chunk_io::{ChunkDeserializer, ChunkSerializer};
+        use rml_rtmp::messages::RtmpMessage;
+
+        // A B-frame shown 100 ms before it is decoded, SI24 -100, longer than a chunk
+        let mut payload = vec![0x27, 1, 0xff, 0xff, 0x9c];
+        payload.extend((0..300).map(|i| i as u8));
+        let file = flv(&[tag(9, 0, &[0x17, 1, 0, 0, 0x50, 0, 0, 0, 1, 0x65]), tag(9, 40, &payload)]);
+        let path = std::env::temp_dir().join(format!("waterfall-{}-cts.flv", std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let tags = read_flv_tag(&[path.to_string_lossy().into_owned()], ReadOptions::default(), &Cache::default(), logger).await.unwrap();
+        futures::pin_mut!(tags);
+        let mut video = Vec::new();
+        while let Some(packet) = futures::StreamExt::next(&mut tags).await {
+            if let PacketType::Video { ref data, ts } = *packet.unwrap() {
+                video.push((data.clone(), ts));
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        let (data, ts) = video.pop().unwrap();
+        assert_eq!(&data[..], &payload[..]);
+
+        let (mut serializer, mut deserializer) = (ChunkSerializer::new(), ChunkDeserializer::new());
+        let message = RtmpMessage::VideoData { data }.into_message_payload(ts, 1).unwrap();
+        let chunks = serializer.serialize(&message, false, false).unwrap();
+        let received = deserializer.get_next_message(&chunks.bytes).unwrap().unwrap();
+        let data = match received.to_rtmp_message().unwrap() {
+            RtmpMessage::VideoData { data } => data,
+            other => panic!("not video: {:?}", other),
+        };
+        assert_eq!(&data[..], &payload[..]);
+        let cts = (i32::from(data[2]) << 16 | i32::from(data[3]) << 8 | i32::from(data[4])) << 8 >> 8;
+        assert_eq!(cts, -100);
+    }
+
+    #[test]
+    fn header_errors() {
+        assert_eq!(parse_header(b"FL"), Err(ParseError::Truncated { offset: 0, needed: FLV_HEADER_SIZE }));
+        assert_eq!(parse_header(b"MP4\x01\x05\x00\x00\x00\x09"), Err(ParseError::NotFlv));
+        assert_eq!(parse_header(b"FLV\x01\x05\x00\x00\x00\x03\x00\x00\x00\x00"), Err(ParseError::DataOffset(3)));
+        assert_eq!(parse_header(b"FLV\x01\x05\x00\x00\x00\x09\x00\x00"), Err(ParseError::Truncated { offset: 0, needed: 13 }));
+    }
+
+    /// Where the payloads of every tag of `tags` are in memory
+    fn payloads(mut tags: Tags) -> Vec<*const u8> {
+        let mut payloads = Vec::new();
+        while let Some(tag) = tags.next(true, &mut Vec::new()).unwrap() {
+            payloads.push(tag.data.as_ptr());
+        }
+        payloads
+    }
+
+    #[test]
+    fn readers_of_a_run_share_one_buffer() {
+        let path = std::env::temp_dir().join(format!("waterfall-{}-cached.flv", std::process::id()));
+        std::fs::write(&path, sample()).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let cache = Cache::default();
+        let first = payloads(Tags::open(&path, &cache).unwrap());
+        assert_eq!(first.len(), 5);
+        assert_eq!(payloads(Tags::open(&path, &cache).unwrap()), first);
+        // The tag table is sliced from the same buffer, and read once
+        let preloaded = payloads(Tags::preload(&path, true, &cache, &logger).unwrap());
+        assert_eq!(preloaded, first);
+        assert_eq!(payloads(Tags::preload(&path, true, &cache, &logger).unwrap()), first);
+        // Another run reads the file again
+        assert_ne!(payloads(Tags::open(&path, &Cache::default()).unwrap()), first);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,310 @@
+//! `--input synthetic:bitrate=3M,fps=30,keyint=60`: H.264 and AAC made up on the fly.
+//!
+//! The frames are well-formed for a server, which only looks at the FLV and NAL unit headers:
+//! a short slice NAL unit padded to the wanted size with a filler data NAL unit, after real
+//! sequence headers. They do not decode to a picture.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_stream::stream;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::stream::Stream;
+use rml_rtmp::{sessions::StreamMetadata, time::RtmpTimestamp};
+
+use crate::flv::{rtmp_timestamp, Pacer};
+use crate::units;
+use crate::PacketType;
+
+const PREFIX: &str = "synthetic";
+const AAC_SAMPLE_RATE: u32 = 44_100;
+const AAC_FRAME_SAMPLES: u32 = 1024;
+/// A keyframe is this many times the size of the other frames of its GOP
+const KEYFRAME_WEIGHT: u64 = 5;
+
+#[derive(Clone, Debug)]
+pub struct Synthetic {
+    pub video_bitrate_bps: u64,
+    pub fps: f64,
+    /// Frames from one keyframe to the next
+    pub keyint: u32,
+    pub width: u32,
+    pub height: u32,
+    /// No audio at 0
+    pub audio_bitrate_bps: u64,
+    /// Endless without
+    pub duration: Option<Duration>,
+}
+
+impl Default for Synthetic {
+    fn default() -> Self {
+        Self {
+            video_bitrate_bps: 2_000_000,
+            fps: 30.0,
+            keyint: 60,
+            width: 1280,
+            height: 720,
+            audio_bitrate_bps: 128_000,
+            duration: None,
+        }
+    }
+}
+
+/// Whether `--input` asks for a generated stream
+pub fn is_synthetic(input: &str) -> bool {
+    input == PREFIX || input.starts_with("synthetic:")
+}
+
+/// Parse `synthetic[:key=value,...]`, keys are `bitrate`, `fps`, `keyint`, `size` (`1280x720`),
+/// `audio` (its bitrate, `0` for none) and `duration`.
+pub fn parse(input: &str) -> Result<Synthetic, String> {
+    let spec = input.strip_prefix(PREFIX).ok_or_else(|| format!("not a synthetic input: `{}`", input))?;
+    let spec = spec.strip_prefix(':').unwrap_or(spec);
+    let mut synthetic = Synthetic::default();
+    let mut keyint = None;
+    for option in spec.split(',').filter(|o| !o.is_empty()) {
+        let mut kv = option.splitn(2, '=');
+        let (key, value) = match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => return Err(format!("expected `key=value` in `{}`", option)),
+        };
+        match key {
+            "bitrate" => synthetic.video_bitrate_bps = units::parse_size(value)?,
+            "fps" => synthetic.fps = value.parse::<f64>().ok().filter(|f| *f > 0.0)
+                .ok_or_else(|| format!("invalid fps `{}`", value))?,
+            "keyint" => keyint = Some(value.parse::<u32>().ok().filter(|k| *k > 0)
+                .ok_or_else(|| format!("invalid keyint `{}`", value))?),
+            "size" => {
+                let mut wh = value.splitn(2, 'x').map(|n| n.parse::<u32>().ok().filter(|n| *n > 0));
+                match (wh.next().flatten(), wh.next().flatten()) {
+                    (Some(w), Some(h)) => {
+                        synthetic.width = w;
+                        synthetic.height = h;
+                    }
+                    _ => return Err(format!("invalid size `{}`, e.g. `1280x720`", value)),
+                }
+            }
+            "audio" => synthetic.audio_bitrate_bps = units::parse_size(value)?,
+            "duration" => synthetic.duration = Some(units::parse_duration(value)?),
+            _ => return Err(format!("unknown synthetic option `{}`", key)),
+        }
+    }
+    // Every two seconds by default
+    synthetic.keyint = keyint.unwrap_or_else(|| (synthetic.fps * 2.0).round().max(1.0) as u32);
+    Ok(synthetic)
+}
+
+/// The generated stream, paced like a file at `speed` times real time, the first `burst` of it at once.
+pub fn generate(config: Synthetic, speed: f64, burst: Duration) -> impl Stream<Item = std::io::Result<Arc<PacketType>>> {
+    stream! {
+        let mut pacer = Pacer::new(speed, burst);
+        let end_ms = config.duration.map(|d| d.as_millis() as u64);
+        let audio = config.audio_bitrate_bps > 0;
+
+        yield Ok(Arc::new(PacketType::Metadata(Arc::new(metadata(&config)))));
+        yield Ok(Arc::new(PacketType::Video { data: video_sequence_header(config.width, config.height), ts: RtmpTimestamp::new(0) }));
+        if audio {
+            yield Ok(Arc::new(PacketType::Audio { data: audio_sequence_header(), ts: RtmpTimestamp::new(0) }));
+        }
+
+        // Sizes in a GOP add up to the bitrate, a keyframe weighs as much as KEYFRAME_WEIGHT frames
+        let gop_bytes = config.video_bitrate_bps as f64 / 8.0 / config.fps * config.keyint as f64;
+        let unit = gop_bytes / (KEYFRAME_WEIGHT + config.keyint as u64 - 1) as f64;
+        let keyframe_bytes = (unit * KEYFRAME_WEIGHT as f64) as usize;
+        let frame_bytes = unit as usize;
+        let audio_frame_bytes = (config.audio_bitrate_bps * AAC_FRAME_SAMPLES as u64 / AAC_SAMPLE_RATE as u64 / 8) as usize;
+
+        let (mut frame, mut audio_frame) = (0u64, 0u64);
+        loop {
+            let video_ms = (frame as f64 * 1000.0 / config.fps).round() as u64;
+            let audio_ms = audio_frame * AAC_FRAME_SAMPLES as u64 * 1000 / AAC_SAMPLE_RATE as u64;
+            let (ms, packet) = if !audio || video_ms <= audio_ms {
+                let keyframe = frame % config.keyint as u64 == 0;
+                let data = video_frame(keyframe, if keyframe { keyframe_bytes } else { frame_bytes });
+                frame += 1;
+                (video_ms, PacketType::Video { data, ts: rtmp_timestamp(video_ms) })
+            } else {
+                audio_frame += 1;
+                (audio_ms, PacketType::Audio { data: audio_frame_data(audio_frame_bytes), ts: rtmp_timestamp(audio_ms) })
+            };
+            if end_ms.map_or(false, |end| ms >= end) {
+                break;
+            }
+            pacer.wait(ms).await;
+            yield Ok(Arc::new(packet));
+        }
+    }
+}
+
+fn metadata(config: &Synthetic) -> StreamMetadata {
+    let mut metadata = StreamMetadata::new();
+    metadata.video_width = Some(config.width);
+    metadata.video_height = Some(config.height);
+    metadata.video_frame_rate = Some(config.fps as f32);
+    metadata.video_bitrate_kbps = Some((config.video_bitrate_bps / 1000) as u32);
+    metadata.video_codec = Some("avc1".to_owned());
+    if config.audio_bitrate_bps > 0 {
+        metadata.audio_codec = Some("mp4a".to_owned());
+        metadata.audio_bitrate_kbps = Some((config.audio_bitrate_bps / 1000) as u32);
+        metadata.audio_sample_rate = Some(AAC_SAMPLE_RATE);
+        metadata.audio_channels = Some(2);
+        metadata.audio_is_stereo = Some(true);
+    }
+    metadata.encoder = Some("waterfall synthetic".to_owned());
+    metadata
+}
+
+/// VIDEODATA of an AVC NALU packet: one slice NAL unit and the filler to reach `size` bytes
+fn video_frame(keyframe: bool, size: usize) -> Bytes {
+    // FLV header, AVC packet type and composition time, two NAL length prefixes, slice header
+    const OVERHEAD: usize = 5 + 4 + 4 + 4;
+    let filler = size.saturating_sub(OVERHEAD).max(1);
+    let mut buf = BytesMut::with_capacity(OVERHEAD + filler + 1);
+    buf.put_u8(if keyframe { 0x17 } else { 0x27 });
+    buf.put_u8(1);
+    buf.put_slice(&[0, 0, 0]);
+    // IDR or non-IDR slice, with first_mb_in_slice 0 and an I or P slice type
+    let slice: [u8; 4] = if keyframe { [0x65, 0x88, 0x84, 0x00] } else { [0x41, 0x9a, 0x02, 0x00] };
+    buf.put_u32(slice.len() as u32);
+    buf.put_slice(&slice);
+    // Filler data NAL unit: 0xff bytes then the trailing bits
+    buf.put_u32(filler as u32 + 1);
+    buf.put_u8(0x0c);
+    buf.put_slice(&vec![0xff; filler - 1]);
+    buf.put_u8(0x80);
+    buf.into()
+}
+
+/// AUDIODATA of a raw AAC frame of `size` bytes
+fn audio_frame_data(size: usize) -> Bytes {
+    let mut buf = BytesMut::with_capacity(2 + size);
+    buf.put_u8(0xaf);
+    buf.put_u8(1);
+    // A silent stereo frame, padded with fill elements
+    buf.put_slice(&[0x21, 0x10, 0x04, 0x60, 0x8c, 0x1c]);
+    buf.put_slice(&vec![0; size.saturating_sub(6)]);
+    buf.into()
+}
+
+/// AAC LC, 44.1kHz, stereo
+fn audio_sequence_header() -> Bytes {
+    Bytes::from_static(&[0xaf, 0x00, 0x12, 0x10])
+}
+
+/// The AVCDecoderConfigurationRecord of a Baseline 3.1 stream of that size
+fn video_sequence_header(width: u32, height: u32) -> Bytes {
+    let (sps, pps) = (sps(width, height), pps());
+    let mut buf = BytesMut::with_capacity(16 + sps.len() + pps.len());
+    buf.put_slice(&[0x17, 0x00, 0, 0, 0]);
+    // Version, profile, compatibility, level, 4 byte NAL lengths, one SPS
+    buf.put_slice(&[1, sps[1], sps[2], sps[3], 0xff, 0xe1]);
+    buf.put_u16(sps.len() as u16);
+    buf.put_slice(&sps);
+    buf.put_u8(1);
+    buf.put_u16(pps.len() as u16);
+    buf.put_slice(&pps);
+    buf.into()
+}
+
+fn sps(width: u32, height: u32) -> Vec<u8> {
+    let (mbs_w, mbs_h) = ((width + 15) / 16, (height + 15) / 16);
+    let mut bits = BitWriter::default();
+    bits.bits(66, 8); // profile_idc, Baseline
+    bits.bits(0xc0, 8); // constraint_set0 and 1
+    bits.bits(31, 8); // level_idc
+    bits.ue(0); // seq_parameter_set_id
+    bits.ue(0); // log2_max_frame_num_minus4
+    bits.ue(2); // pic_order_cnt_type
+    bits.ue(1); // max_num_ref_frames
+    bits.bits(0, 1); // gaps_in_frame_num_value_allowed_flag
+    bits.ue(mbs_w - 1);
+    bits.ue(mbs_h - 1);
+    bits.bits(1, 1); // frame_mbs_only_flag
+    bits.bits(1, 1); // direct_8x8_inference_flag
+    // Cropping is in 2 pixel units for 4:2:0
+    let (crop_right, crop_bottom) = ((mbs_w * 16 - width) / 2, (mbs_h * 16 - height) / 2);
+    if crop_right > 0 || crop_bottom > 0 {
+        bits.bits(1, 1);
+        bits.ue(0);
+        bits.ue(crop_right);
+        bits.ue(0);
+        bits.ue(crop_bottom);
+    } else {
+        bits.bits(0, 1);
+    }
+    bits.bits(0, 1); // vui_parameters_present_flag
+    nal(0x67, bits.finish())
+}
+
+fn pps() -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.ue(0); // pic_parameter_set_id
+    bits.ue(0); // seq_parameter_set_id
+    bits.bits(0, 1); // entropy_coding_mode_flag, CAVLC
+    bits.bits(0, 1); // bottom_field_pic_order_in_frame_present_flag
+    bits.ue(0); // num_slice_groups_minus1
+    bits.ue(0); // num_ref_idx_l0_default_active_minus1
+    bits.ue(0); // num_ref_idx_l1_default_active_minus1
+    bits.bits(0, 1); // weighted_pred_flag
+    bits.bits(0, 2); // weighted_bipred_idc
+    bits.ue(0); // pic_init_qp_minus26, se(0)
+    bits.ue(0); // pic_init_qs_minus26, se(0)
+    bits.ue(0); // chroma_qp_index_offset, se(0)
+    bits.bits(1, 1); // deblocking_filter_control_present_flag
+    bits.bits(0, 1); // constrained_intra_pred_flag
+    bits.bits(0, 1); // redundant_pic_cnt_present_flag
+    nal(0x68, bits.finish())
+}
+
+/// The NAL unit with its header byte, emulation prevention bytes inserted
+fn nal(header: u8, rbsp: Vec<u8>) -> Vec<u8> {
+    let mut nal = vec![header];
+    let mut zeros = 0;
+    for byte in rbsp {
+        if zeros >= 2 && byte <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        nal.push(byte);
+    }
+    nal
+}
+
+/// MSB first, for the exp-Golomb fields of the parameter sets
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u8,
+    used: u8,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u8) {
+        for i in (0..count).rev() {
+            self.current = (self.current << 1) | ((value >> i) & 1) as u8;
+            self.used += 1;
+            if self.used == 8 {
+                self.bytes.push(self.current);
+                self.current = 0;
+                self.used = 0;
+            }
+        }
+    }
+
+    fn ue(&mut self, value: u32) {
+        let value = value + 1;
+        let len = 32 - value.leading_zeros() as u8;
+        self.bits(0, len - 1);
+        self.bits(value, len);
+    }
+
+    /// With the RBSP trailing bits
+    fn finish(mut self) -> Vec<u8> {
+        self.bits(1, 1);
+        while self.used != 0 {
+            self.bits(0, 1);
+        }
+        self.bytes
+    }
+}
@@ -0,0 +1,144 @@
+//! Single file HTML report with inline SVG charts, no scripts or external resources.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::report::Report;
+use crate::slo;
+
+const WIDTH: f64 = 720.0;
+const HEIGHT: f64 = 240.0;
+const MARGIN: f64 = 48.0;
+
+pub fn write(report: &Report, path: &Path) -> io::Result<()> {
+    fs::write(path, render(report))
+}
+
+fn render(report: &Report) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>waterfall report</title>\n<style>\
+                   body{font-family:sans-serif;margin:2em;color:#222}table{border-collapse:collapse}\
+                   td,th{padding:2px 12px;text-align:right;border-bottom:1px solid #ddd}\
+                   th:first-child,td:first-child{text-align:left}svg{display:block;margin:1em 0}\
+                   .pass{color:#2a7}.fail{color:#c33}</style></head><body>\n");
+    let _ = writeln!(html, "<h1>waterfall report</h1>\n<p>Started at {} (unix), ran {:.1}s{}.</p>",
+                     report.started_at, report.duration_secs, if report.finished { "" } else { ", not finished" });
+
+    html.push_str("<h2>Summary</h2>\n<table>\n");
+    for metric in slo::METRICS {
+        let value = slo::metric(report, metric).map_or_else(|| "-".to_owned(), |v| format!("{}", (v * 1000.0).round() / 1000.0));
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", metric, value);
+    }
+    html.push_str("</table>\n");
+
+    if !report.assertions.is_empty() {
+        html.push_str("<h2>Assertions</h2>\n<table>\n");
+        for a in &report.assertions {
+            let (class, result) = if a.passed { ("pass", "PASS") } else { ("fail", "FAIL") };
+            let actual = a.actual.map_or_else(|| "no samples".to_owned(), |v| v.to_string());
+            let _ = writeln!(html, "<tr><td>{}</td><td class=\"{}\">{}</td><td>{}</td></tr>", escape(&a.assertion), class, result, actual);
+        }
+        html.push_str("</table>\n");
+    }
+
+    let timeline = &report.timeline;
+    if timeline.len() > 1 {
+        let points = |f: &dyn Fn(usize) -> f64| (0..timeline.len()).map(|i| (timeline[i].elapsed_secs, f(i))).collect::<Vec<_>>();
+        html.push_str("<h2>Ramp</h2>\n");
+        html.push_str(&line_chart("publishers", &[
+            ("clients", "#888", points(&|i| timeline[i].clients as f64)),
+            ("publishing", "#2a7", points(&|i| timeline[i].publishing as f64)),
+            ("failed", "#c33", points(&|i| timeline[i].failed as f64)),
+        ]));
+        html.push_str("<h2>Throughput</h2>\n");
+        let throughput = timeline.windows(2).map(|w| {
+            let secs = w[1].elapsed_secs - w[0].elapsed_secs;
+            let bits = (w[1].bytes_sent - w[0].bytes_sent) as f64 * 8.0;
+            (w[1].elapsed_secs, if secs > 0.0 { bits / secs / 1_000_000.0 } else { 0.0 })
+        }).collect();
+        html.push_str(&line_chart("Mbit/s", &[("sent", "#37c", throughput)]));
+    }
+
+    if let Some(p) = report.summary.connect_ms {
+        html.push_str("<h2>Connect time</h2>\n");
+        html.push_str(&bar_chart("ms", &[
+            ("min".to_owned(), p.min as f64),
+            ("p50".to_owned(), p.p50 as f64),
+            ("p90".to_owned(), p.p90 as f64),
+            ("p99".to_owned(), p.p99 as f64),
+            ("max".to_owned(), p.max as f64),
+        ]));
+    }
+
+    let mut errors = HashMap::new();
+    for error in report.clients.iter().filter_map(|c| c.error.as_ref()) {
+        *errors.entry(error.as_str()).or_insert(0usize) += 1;
+    }
+    if !errors.is_empty() {
+        let mut errors = errors.into_iter().collect::<Vec<_>>();
+        errors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        html.push_str("<h2>Errors</h2>\n<table>\n<tr><th>error</th><th>clients</th></tr>\n");
+        for (error, count) in errors {
+            let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", escape(error), count);
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn line_chart(unit: &str, series: &[(&str, &str, Vec<(f64, f64)>)]) -> String {
+    let all = series.iter().flat_map(|s| s.2.iter());
+    let (x_max, y_max) = all.fold((0f64, 0f64), |(x, y), p| (x.max(p.0), y.max(p.1)));
+    let (x_max, y_max) = (x_max.max(1.0), y_max.max(1.0));
+    let scale = |(x, y): (f64, f64)| {
+        (MARGIN + x / x_max * (WIDTH - 2.0 * MARGIN), HEIGHT - MARGIN - y / y_max * (HEIGHT - 2.0 * MARGIN))
+    };
+
+    let mut svg = axes(unit, &format!("{:.0}s", x_max), y_max);
+    for (i, (name, color, points)) in series.iter().enumerate() {
+        let path = points.iter().map(|&p| {
+            let (x, y) = scale(p);
+            format!("{:.1},{:.1}", x, y)
+        }).collect::<Vec<_>>().join(" ");
+        let _ = writeln!(svg, "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>", color, path);
+        let _ = writeln!(svg, "<text x=\"{}\" y=\"16\" fill=\"{}\" font-size=\"12\">{}</text>", MARGIN + 100.0 * i as f64, color, name);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn bar_chart(unit: &str, bars: &[(String, f64)]) -> String {
+    let y_max = bars.iter().fold(1f64, |m, b| m.max(b.1));
+    let slot = (WIDTH - 2.0 * MARGIN) / bars.len() as f64;
+    let mut svg = axes(unit, "", y_max);
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let h = value / y_max * (HEIGHT - 2.0 * MARGIN);
+        let x = MARGIN + slot * i as f64 + slot * 0.2;
+        let _ = writeln!(svg, "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#37c\"/>",
+                         x, HEIGHT - MARGIN - h, slot * 0.6, h);
+        let _ = writeln!(svg, "<text x=\"{:.1}\" y=\"{}\" font-size=\"12\">{} ({})</text>", x, HEIGHT - MARGIN + 16.0, label, value);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Opens the `<svg>` element, with both axes and the maximum of each
+fn axes(unit: &str, x_label: &str, y_max: f64) -> String {
+    let mut svg = String::new();
+    let _ = writeln!(svg, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">", WIDTH, HEIGHT);
+    let (left, right, top, bottom) = (MARGIN, WIDTH - MARGIN, MARGIN, HEIGHT - MARGIN);
+    let _ = writeln!(svg, "<polyline fill=\"none\" stroke=\"#444\" points=\"{},{} {},{} {},{}\"/>", left, top, left, bottom, right, bottom);
+    let _ = writeln!(svg, "<text x=\"4\" y=\"{}\" font-size=\"12\">{}</text>", top, (y_max * 100.0).round() / 100.0);
+    let _ = writeln!(svg, "<text x=\"4\" y=\"{}\" font-size=\"12\">{}</text>", top + 14.0, unit);
+    let _ = writeln!(svg, "<text x=\"{}\" y=\"{}\" font-size=\"12\" text-anchor=\"end\">{}</text>", right, bottom + 32.0, x_label);
+    svg
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
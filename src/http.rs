@@ -0,0 +1,83 @@
+//! `http://` and `https://` inputs: the FLV body of a file or a live HTTP-FLV source.
+
+use std::io::{self, Read};
+use std::sync::mpsc;
+use std::thread;
+
+use bytes::{Buf, Bytes};
+
+/// Chunks buffered between the download and the FLV reader
+const CHUNKS: usize = 64;
+
+/// The response body as a blocking reader for `flv::Tags`.
+///
+/// The download runs on its own thread and runtime, so reads may block the caller without
+/// stalling the runtime of the clients. It stops when the reader is dropped.
+pub struct Body {
+    url: String,
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl Body {
+    pub fn open(url: &str) -> io::Result<Self> {
+        let (tx, chunks) = mpsc::sync_channel(CHUNKS);
+        let target = url.to_owned();
+        thread::Builder::new()
+            .name("http-input".to_owned())
+            .spawn(move || {
+                let mut runtime = match tokio::runtime::Runtime::new() {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        return;
+                    }
+                };
+                runtime.block_on(download(&target, &tx));
+            })?;
+        Ok(Self { url: url.to_owned(), chunks, chunk: Bytes::new() })
+    }
+
+    /// A new request for the same url, to play it again
+    pub fn reopen(&self) -> io::Result<Self> {
+        Self::open(&self.url)
+    }
+}
+
+async fn download(url: &str, tx: &mpsc::SyncSender<io::Result<Bytes>>) {
+    let mut response = match reqwest::get(url).await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response,
+        Err(e) => {
+            let _ = tx.send(Err(io::Error::new(io::ErrorKind::Other, e)));
+            return;
+        }
+    };
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => Ok(chunk),
+            // The end of the body, the reader sees it as the end of the file
+            Ok(None) => return,
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        };
+        let failed = chunk.is_err();
+        // The reader is gone
+        if tx.send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.chunks.recv() {
+                Ok(chunk) => self.chunk = chunk?,
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk[..n]);
+        self.chunk.advance(n);
+        Ok(n)
+    }
+}
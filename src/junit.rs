@@ -0,0 +1,66 @@
+//! JUnit XML of the final report: one test case per destination and per assertion.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::report::Report;
+use crate::stats::ClientState;
+
+pub fn write(report: &Report, path: &Path) -> io::Result<()> {
+    fs::write(path, render(report))
+}
+
+fn render(report: &Report) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"waterfall\">\n");
+
+    let failed = report.clients.iter().filter(|c| c.state == ClientState::Failed).count();
+    let _ = writeln!(xml, "  <testsuite name=\"destinations\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+                     report.clients.len(), failed, report.duration_secs);
+    for client in &report.clients {
+        let _ = write!(xml, "    <testcase classname=\"destinations\" name=\"{}\" time=\"{:.3}\"",
+                       escape(&client.url), client.duration_secs);
+        if client.state == ClientState::Failed {
+            let message = client.error.as_deref().unwrap_or("failed");
+            let _ = writeln!(xml, ">\n      <failure message=\"{}\"/>\n    </testcase>", escape(message));
+        } else {
+            xml.push_str("/>\n");
+        }
+    }
+    xml.push_str("  </testsuite>\n");
+
+    if !report.assertions.is_empty() {
+        let failed = report.assertions.iter().filter(|a| !a.passed).count();
+        let _ = writeln!(xml, "  <testsuite name=\"assertions\" tests=\"{}\" failures=\"{}\">", report.assertions.len(), failed);
+        for assertion in &report.assertions {
+            let _ = write!(xml, "    <testcase classname=\"assertions\" name=\"{}\"", escape(&assertion.assertion));
+            if assertion.passed {
+                xml.push_str("/>\n");
+            } else {
+                let actual = assertion.actual.map_or_else(|| "no samples".to_owned(), |v| v.to_string());
+                let _ = writeln!(xml, ">\n      <failure message=\"actual: {}\"/>\n    </testcase>", escape(&actual));
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
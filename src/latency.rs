@@ -0,0 +1,122 @@
+//! `--measure-latency`: the time from a publisher writing a message to a player receiving it.
+//!
+//! Every publisher stamps its stream with an `onFI` data message carrying its wall clock, once a
+//! `STAMP_INTERVAL`. A companion player on one of the destinations reads the stamps back as the
+//! server relays them, the difference to its own clock is the latency through the server, the
+//! queues of the publisher included. Both ends run in this process, so the clocks agree.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rml_amf0::Amf0Value;
+use slog::{info, warn, Logger};
+
+use crate::report::{LatencyInterval, Percentiles};
+
+/// How often a publisher stamps its stream
+pub const STAMP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the percentiles are reported
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// The property of `onFI` with the milliseconds since the Unix epoch
+pub const STAMP_KEY: &str = "wf_ms";
+
+pub fn epoch_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// `dd-mm-yyyy` and `hh:mm:ss.mmm` in UTC, the `sd` and `st` of `onFI`
+pub fn date_time(epoch_ms: u64) -> (String, String) {
+    let (days, ms) = ((epoch_ms / 86_400_000) as i64, epoch_ms % 86_400_000);
+    // Days to the civil date, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let (era, doe) = (z.div_euclid(146_097), z.rem_euclid(146_097));
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:02}-{:02}-{:04}", day, month, year);
+    let time = format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000);
+    (date, time)
+}
+
+/// The wall clock of an `onFI` stamp, `None` for any other data message
+pub fn parse_stamp(data: &[u8]) -> Option<u64> {
+    let mut cursor = data;
+    let values = rml_amf0::deserialize(&mut cursor).ok()?;
+    match values.as_slice() {
+        [Amf0Value::Utf8String(name), Amf0Value::Object(properties)] if name == "onFI" => {
+            match properties.get(STAMP_KEY) {
+                Some(Amf0Value::Number(ms)) if *ms >= 0.0 => Some(*ms as u64),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Latencies of the whole run and of the current interval
+#[derive(Debug)]
+pub struct Recorder {
+    started: Instant,
+    samples: Mutex<Samples>,
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    current: Vec<u64>,
+    all: Vec<u64>,
+    intervals: Vec<LatencyInterval>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self { started: Instant::now(), samples: Mutex::new(Samples::default()) }
+    }
+}
+
+impl Recorder {
+    pub fn add(&self, latency_ms: u64) {
+        if let Ok(mut samples) = self.samples.lock() {
+            samples.current.push(latency_ms);
+            samples.all.push(latency_ms);
+        }
+    }
+
+    /// End the current interval, `None` if it had no stamps
+    pub fn close_interval(&self) -> Option<LatencyInterval> {
+        let mut samples = self.samples.lock().ok()?;
+        let current = std::mem::take(&mut samples.current);
+        let interval = LatencyInterval {
+            elapsed_secs: self.started.elapsed().as_millis() as f64 / 1000.0,
+            samples: current.len(),
+            latency_ms: Percentiles::from_values(current)?,
+        };
+        samples.intervals.push(interval);
+        Some(interval)
+    }
+
+    pub fn intervals(&self) -> Vec<LatencyInterval> {
+        self.samples.lock().map(|s| s.intervals.clone()).unwrap_or_default()
+    }
+
+    /// Over the whole run
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        self.samples.lock().ok().and_then(|s| Percentiles::from_values(s.all.clone()))
+    }
+}
+
+/// Close an interval of `recorder` and log its percentiles every `interval`, never returns.
+pub async fn log_intervals(recorder: Arc<Recorder>, interval: Duration, logger: Logger) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        match recorder.close_interval() {
+            Some(i) => info!(logger, "Latency"; "samples" => i.samples, "p50_ms" => i.latency_ms.p50,
+                             "p90_ms" => i.latency_ms.p90, "p99_ms" => i.latency_ms.p99, "max_ms" => i.latency_ms.max),
+            None => warn!(logger, "No latency stamps received in the interval"),
+        }
+    }
+}
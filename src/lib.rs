@@ -0,0 +1,81 @@
+//! Publish and play load for RTMP servers. The `waterfall` binary is a command line over this
+//! crate, `PublishRun` runs the publish side from another program.
+#![recursion_limit="1024"]
+use std::sync::Arc;
+
+use bytes::Bytes;
+use rml_rtmp::{
+    sessions::StreamMetadata,
+    time::RtmpTimestamp,
+};
+
+pub mod compare;
+pub mod config;
+pub mod connect_bench;
+pub mod control;
+pub mod distributed;
+pub mod error;
+pub mod events;
+pub mod fanout;
+pub mod rtmp;
+pub mod flv;
+pub mod generator;
+pub mod html;
+#[cfg(feature = "http")]
+mod http;
+pub mod junit;
+pub mod latency;
+pub mod load;
+pub mod logger;
+pub mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod panic_hook;
+pub mod play;
+pub mod publish;
+pub mod report;
+pub mod rtmp_url;
+mod run;
+pub mod shutdown;
+pub mod sink;
+pub mod slo;
+pub mod srt;
+pub mod stats;
+pub mod transmit;
+pub mod ts;
+#[cfg(feature = "tui")]
+pub mod ui;
+pub mod units;
+pub mod verify;
+pub mod watch;
+pub mod whip;
+
+pub use run::{LineParser, PublishRun, PublishRunBuilder};
+
+#[derive(Clone, Debug)]
+pub enum PacketType {
+    Metadata(Arc<StreamMetadata>),
+    Video {
+        data: Bytes,
+        ts: RtmpTimestamp,
+    },
+    Audio {
+        data: Bytes,
+        ts: RtmpTimestamp,
+    },
+    /// The input restarted from the beginning
+    Loop,
+    /// The broadcast stopped, tear the sessions down
+    End,
+}
+
+#[derive(Debug)]
+pub enum ReceivedType {
+    FromClient {
+        message: rml_rtmp::messages::MessagePayload,
+        bytes_read: usize,
+    },
+    Broadcast(Arc<PacketType>),
+    /// The server closed the connection
+    ServerClosed,
+}
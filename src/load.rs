@@ -0,0 +1,382 @@
+//! Load profiles which add publishers while the input is already being broadcast.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::vec;
+
+use slog::{info, warn, Logger};
+
+use crate::{
+    fanout,
+    report::{CapacityReport, Percentiles, Report, StepReport, Summary},
+    rtmp::client::{Client, Config, HeaderCache},
+    rtmp_url::Url,
+    shutdown::Shutdown,
+    stats::{ClientState, ClientStats, Stats},
+    transmit,
+    units,
+    PacketType,
+};
+
+/// How publishers are added over the run
+#[derive(Clone, Debug)]
+pub enum Profile {
+    Adaptive(Adaptive),
+    Steps(Vec<Step>),
+    Schedule(Vec<Point>),
+}
+
+/// Thresholds of the adaptive mode
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    /// Publishers added per second
+    pub rate: f64,
+    /// Stop adding publishers once this share of them failed
+    pub max_error_rate: f64,
+    /// Stop adding publishers once the p90 time to publish exceeds this
+    pub max_connect_ms: u64,
+}
+
+/// A plateau of `--steps`
+#[derive(Clone, Copy, Debug)]
+pub struct Step {
+    pub concurrency: usize,
+    pub duration: Duration,
+}
+
+/// A point of `--profile`, the concurrency from this far into the run on
+#[derive(Clone, Copy, Debug)]
+pub struct Point {
+    pub at: Duration,
+    pub concurrency: usize,
+}
+
+/// Connects of `--ramp-up`, spread over a window before the broadcast starts
+#[derive(Clone, Copy, Debug)]
+pub struct RampUp {
+    pub window: Duration,
+    /// Start the publishers in this many equal batches instead of one by one
+    pub steps: Option<usize>,
+}
+
+impl RampUp {
+    /// When the `i`th of `n` publishers connects, from the start of the run
+    pub fn offset(&self, i: usize, n: usize) -> Duration {
+        if n == 0 {
+            return Duration::from_secs(0);
+        }
+        match self.steps {
+            Some(steps) if steps > 0 => {
+                let batch = i * steps / n;
+                self.window.mul_f64(batch as f64 / steps as f64)
+            }
+            _ => self.window.mul_f64(i as f64 / n as f64),
+        }
+    }
+}
+
+/// Parse `500:5m,1000:5m,2000:5m`, the concurrency must not decrease from one step to the next.
+pub fn parse_steps(s: &str) -> Result<Vec<Step>, String> {
+    let mut steps: Vec<Step> = Vec::new();
+    for step in s.split(',') {
+        let mut parts = step.splitn(2, ':');
+        let concurrency = parts.next().unwrap_or("").trim().parse::<usize>()
+            .map_err(|_| format!("invalid concurrency in step `{}`", step))?;
+        let duration = units::parse_duration(parts.next().ok_or_else(|| format!("missing duration in step `{}`", step))?)?;
+        if let Some(last) = steps.last() {
+            if concurrency < last.concurrency {
+                return Err(format!("step `{}` lowers the concurrency, publishers are never stopped", step));
+            }
+        }
+        steps.push(Step { concurrency, duration });
+    }
+    Ok(steps)
+}
+
+/// Parse `0s:100,5m:1000,20m:1000`, the points in order of time. They may also be given one per
+/// line, as `5m 1000`, with `#` starting a comment.
+pub fn parse_schedule(s: &str) -> Result<Vec<Point>, String> {
+    let mut points: Vec<Point> = Vec::new();
+    let fields = s.lines().map(|l| l.split('#').next().unwrap_or("")).flat_map(|l| l.split(','));
+    for point in fields.map(str::trim).filter(|p| !p.is_empty()) {
+        let mut parts = point.splitn(2, |c: char| c == ':' || c.is_whitespace());
+        let at = units::parse_duration(parts.next().unwrap_or(""))?;
+        let concurrency = parts.next().unwrap_or("").trim().parse::<usize>()
+            .map_err(|_| format!("invalid concurrency in point `{}`", point))?;
+        if let Some(last) = points.last() {
+            if at <= last.at {
+                return Err(format!("point `{}` is not after the previous one", point));
+            }
+        }
+        points.push(Point { at, concurrency });
+    }
+    if points.is_empty() {
+        return Err("no point in the profile".into());
+    }
+    Ok(points)
+}
+
+/// Starts publish clients one at a time, late ones get the headers they missed replayed.
+pub struct Spawner {
+    urls: vec::IntoIter<Url>,
+    tx: fanout::Sender,
+    headers: Arc<Mutex<HeaderCache>>,
+    config: Arc<Config>,
+    stats: Arc<Stats>,
+    logger: Logger,
+    spawned: usize,
+}
+
+impl Spawner {
+    pub fn new(urls: Vec<Url>,
+               tx: fanout::Sender,
+               headers: Arc<Mutex<HeaderCache>>,
+               config: Arc<Config>,
+               stats: Arc<Stats>,
+               logger: Logger) -> Self
+    {
+        Self { urls: urls.into_iter(), tx, headers, config, stats, logger, spawned: 0 }
+    }
+
+    /// Start the next client, `false` once all destinations are used
+    pub fn spawn_next(&mut self) -> bool {
+        match self.urls.next() {
+            Some(url) => {
+                self.spawn(url);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start a client for `url`, which joins the broadcast where it is
+    pub fn spawn(&mut self, url: Url) -> Arc<ClientStats> {
+        // Subscribe before taking the headers, so none falls in between
+        let rx = self.tx.subscribe();
+        let seed = self.headers.lock().map(|h| h.packets().collect()).unwrap_or_default();
+        let client_stats = self.stats.register(url.to_string());
+        let config = self.config.clone();
+        let logger = self.logger.clone();
+        let registered = client_stats.clone();
+        tokio::spawn(async move {
+            if transmit::is_transmitted(url.scheme) {
+                transmit::publish(url, rx, seed, config, client_stats, &logger).await;
+            } else {
+                Client::new(url, rx, seed, config, client_stats, &logger).await;
+            }
+        });
+        self.spawned += 1;
+        registered
+    }
+}
+
+/// Keep the headers of a broadcast in `headers` until it ends, for the clients a `Spawner` adds later.
+pub async fn cache_headers(mut rx: fanout::Receiver, headers: Arc<Mutex<HeaderCache>>) {
+    loop {
+        match rx.recv().await {
+            Ok(msg) => {
+                if let PacketType::End = *msg {
+                    break;
+                }
+                if let Ok(mut headers) = headers.lock() {
+                    headers.observe(&msg);
+                }
+            }
+            // Headers are never dropped, only media
+            Err(fanout::RecvError::Lagged(_)) | Err(fanout::RecvError::Overflowed) => (),
+            Err(fanout::RecvError::Closed) => break,
+        }
+    }
+}
+
+/// Run the profile until it is done or the run is shut down, its results go into `stats`.
+pub async fn run(profile: Profile, spawner: Spawner, stats: Arc<Stats>, shutdown: Shutdown, logger: Logger) {
+    match profile {
+        Profile::Adaptive(params) => {
+            let capacity = adaptive(params, spawner, stats.clone(), shutdown, logger).await;
+            stats.set_capacity(capacity);
+        }
+        Profile::Steps(steps) => run_steps(steps, spawner, stats, shutdown, logger).await,
+        Profile::Schedule(points) => run_schedule(points, spawner, stats, shutdown, logger).await,
+    }
+}
+
+impl Adaptive {
+    /// Why the load is no longer sustained, if it is not
+    fn limit(&self, report: &Report) -> Option<String> {
+        let summary = &report.summary;
+        let error_rate = error_rate(report);
+        if error_rate > self.max_error_rate {
+            return Some(format!("error rate {:.2}% over {:.2}%", error_rate * 100.0, self.max_error_rate * 100.0));
+        }
+        if let Some(p90) = connect_p90(report, self.max_connect_ms) {
+            if p90 > self.max_connect_ms {
+                return Some(format!("p90 connect {}ms over {}ms", p90, self.max_connect_ms));
+            }
+        }
+        if summary.clients > 0 && summary.clients == summary.finished + summary.failed {
+            return Some("all publishers ended".into());
+        }
+        None
+    }
+}
+
+fn error_rate(report: &Report) -> f64 {
+    let summary = &report.summary;
+    if summary.clients == 0 { 0.0 } else { summary.failed as f64 / summary.clients as f64 }
+}
+
+/// Publishers still connecting for longer than `max_connect_ms` count with their time so far,
+/// so a server which stops answering is caught too.
+fn connect_p90(report: &Report, max_connect_ms: u64) -> Option<u64> {
+    let values = report.clients.iter().filter_map(|c| {
+        let waiting_ms = (c.duration_secs * 1000.0) as u64;
+        match c.connect_ms {
+            Some(ms) => Some(ms),
+            None if c.state == ClientState::Connecting && waiting_ms > max_connect_ms => Some(waiting_ms),
+            None => None,
+        }
+    }).collect();
+    Percentiles::from_values(values).map(|p| p.p90)
+}
+
+/// Hold each plateau for its duration, publishers which fail in a step are not replaced
+async fn run_steps(steps: Vec<Step>, mut spawner: Spawner, stats: Arc<Stats>, shutdown: Shutdown, logger: Logger) {
+    for step in steps {
+        let first_client = spawner.spawned;
+        let before = stats.report(false).summary;
+        let started = Instant::now();
+        while spawner.spawned < step.concurrency {
+            if !spawner.spawn_next() {
+                warn!(logger, "Not enough destinations for the step"; "concurrency" => step.concurrency, "destinations" => spawner.spawned);
+                break;
+            }
+        }
+        info!(logger, "Step started"; "concurrency" => step.concurrency, "duration_secs" => step.duration.as_secs());
+
+        let stopped = shutdown.wait();
+        let completed = tokio::time::timeout(step.duration, stopped).await.is_err();
+        let report = stats.report(false);
+        let step_report = step_report(step, &before, &report, first_client, started.elapsed());
+        info!(logger, "Step ended";
+              "concurrency" => step_report.concurrency,
+              "publishing" => step_report.publishing,
+              "failed" => step_report.failed,
+              "throughput_bps" => step_report.throughput_bps);
+        stats.add_step(step_report);
+        if !completed {
+            break;
+        }
+    }
+}
+
+/// Bring the publishers to the concurrency of each point at its time, starting new ones or
+/// unpublishing the latest, and hold the last point until the run is shut down. Publishers which
+/// failed or ended are replaced at the next point, the destinations of those gone are used again.
+async fn run_schedule(points: Vec<Point>, mut spawner: Spawner, stats: Arc<Stats>, shutdown: Shutdown, logger: Logger) {
+    let started = Instant::now();
+    let mut active: Vec<(Url, Arc<ClientStats>)> = Vec::new();
+    let mut released = VecDeque::new();
+    // Nobody publishes before the first point
+    if tokio::time::timeout(points[0].at, shutdown.wait()).await.is_ok() {
+        return;
+    }
+    for (i, point) in points.iter().enumerate() {
+        let first_client = spawner.spawned;
+        let before = stats.report(false).summary;
+        let step_started = Instant::now();
+        let (live, gone): (Vec<_>, Vec<_>) = active.into_iter().partition(|(_, client)| {
+            !matches!(client.state(), ClientState::Failed | ClientState::Finished)
+        });
+        active = live;
+        released.extend(gone.into_iter().map(|(url, _)| url));
+        while active.len() > point.concurrency {
+            if let Some((url, client)) = active.pop() {
+                client.stop();
+                released.push_back(url);
+            }
+        }
+        while active.len() < point.concurrency {
+            let url = match released.pop_front().or_else(|| spawner.urls.next()) {
+                Some(url) => url,
+                None => {
+                    warn!(logger, "Not enough destinations for the point"; "concurrency" => point.concurrency, "active" => active.len());
+                    break;
+                }
+            };
+            let client = spawner.spawn(url.clone());
+            active.push((url, client));
+        }
+        info!(logger, "Profile point"; "at_secs" => point.at.as_secs(), "concurrency" => point.concurrency, "active" => active.len());
+
+        let completed = match points.get(i + 1) {
+            Some(next) => {
+                let wait = (started + next.at).saturating_duration_since(Instant::now());
+                tokio::time::timeout(wait, shutdown.wait()).await.is_err()
+            }
+            None => {
+                shutdown.wait().await;
+                false
+            }
+        };
+        let step = Step { concurrency: point.concurrency, duration: step_started.elapsed() };
+        let report = stats.report(false);
+        stats.add_step(step_report(step, &before, &report, first_client, step_started.elapsed()));
+        if !completed {
+            break;
+        }
+    }
+}
+
+fn step_report(step: Step, before: &Summary, report: &Report, first_client: usize, elapsed: Duration) -> StepReport {
+    let bytes_sent = report.summary.bytes_sent - before.bytes_sent;
+    let secs = elapsed.as_millis() as f64 / 1000.0;
+    StepReport {
+        concurrency: step.concurrency,
+        duration_secs: secs,
+        publishing: report.summary.publishing,
+        failed: report.summary.failed - before.failed,
+        bytes_sent,
+        throughput_bps: if secs > 0.0 { (bytes_sent as f64 * 8.0 / secs) as u64 } else { 0 },
+        connect_ms: Percentiles::from_values(report.clients.iter().skip(first_client).filter_map(|c| c.connect_ms).collect()),
+    }
+}
+
+/// Add publishers at `params.rate` until a threshold is crossed, the destinations are used up
+/// or the run is shut down. Returns the highest count of publishers seen within the thresholds.
+async fn adaptive(params: Adaptive, mut spawner: Spawner, stats: Arc<Stats>, shutdown: Shutdown, logger: Logger) -> CapacityReport {
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / params.rate));
+    let mut sustained = 0;
+    let limit = loop {
+        ticks.tick().await;
+        if shutdown.is_triggered() {
+            break "shut down".to_owned();
+        }
+        let report = stats.report(false);
+        if let Some(limit) = params.limit(&report) {
+            break limit;
+        }
+        if report.summary.publishing > sustained {
+            sustained = report.summary.publishing;
+            info!(logger, "Publishers sustained"; "publishing" => sustained, "started" => report.summary.clients);
+        }
+        if !spawner.spawn_next() {
+            break "destinations used up".to_owned();
+        }
+    };
+
+    let report = stats.report(false);
+    let capacity = CapacityReport {
+        sustained_publishers: sustained,
+        started_publishers: report.summary.clients,
+        error_rate: error_rate(&report),
+        connect_p90_ms: connect_p90(&report, params.max_connect_ms),
+        limit,
+    };
+    info!(logger, "Adaptive load stopped";
+          "sustained_publishers" => capacity.sustained_publishers,
+          "started_publishers" => capacity.started_publishers,
+          "limit" => &capacity.limit);
+    capacity
+}
@@ -1,5 +1,13 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
 use slog::{self, o, Drain, Logger};
 
+use crate::units;
+
 const DEFAULT_CHAN_SIZE: usize = 1024;
 
 pub struct LogGuard {
@@ -11,10 +19,158 @@ impl Drop for LogGuard {
     }
 }
 
-pub fn init() -> (Logger, LogGuard) {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+/// When to start a new log file
+#[derive(Clone, Copy, Debug)]
+pub enum RotateWhen {
+    Size(u64),
+    Age(Duration),
+}
+
+/// Rotation of the log file, `keep` compressed files are kept besides the current one
+#[derive(Clone, Copy, Debug)]
+pub struct Rotate {
+    pub when: RotateWhen,
+    pub keep: usize,
+}
+
+/// Parse `100MB,7` or `1d,7`. Sizes take precedence, so minutes are spelled `min`.
+pub fn parse_rotate(s: &str) -> Result<Rotate, String> {
+    let mut parts = s.splitn(2, ',');
+    let limit = parts.next().unwrap_or("");
+    let when = match units::parse_size(limit) {
+        Ok(size) => RotateWhen::Size(size),
+        Err(_) => RotateWhen::Age(units::parse_duration(limit)?),
+    };
+    let keep = match parts.next() {
+        Some(keep) => keep.trim().parse::<usize>().map_err(|_| format!("invalid count of kept files in `{}`", s))?,
+        None => 7,
+    };
+    Ok(Rotate { when, keep })
+}
+
+/// How each record is written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One line per record, the values after the message as `key: value`
+    Text,
+    /// One JSON object per line with `ts`, `level` and `msg`, the values of the record and of its
+    /// loggers, e.g. `app` and `stream` of a client, as fields of their own
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format `{}`, use text or json", s)),
+        }
+    }
+}
+
+/// `--log-level`: the least level logged, and others for some of the modules. Records below it
+/// are dropped by the logging task, they never reach the logging thread.
+#[derive(Clone, Debug)]
+pub struct Levels {
+    default: slog::Level,
+    /// Module paths within the crate, e.g. `rtmp::client`, the longest match applies
+    modules: Vec<(String, slog::Level)>,
+}
+
+impl Levels {
+    pub fn new(default: slog::Level) -> Self {
+        Self { default, modules: Vec::new() }
+    }
+
+    fn level(&self, module: &str) -> slog::Level {
+        let module = module.strip_prefix("waterfall::").unwrap_or(module);
+        self.modules.iter()
+            .filter(|(m, _)| module == m.as_str() || (module.starts_with(m.as_str()) && module[m.len()..].starts_with("::")))
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+fn parse_level(s: &str) -> Result<slog::Level, String> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "crit" | "critical" => Ok(slog::Level::Critical),
+        "error" => Ok(slog::Level::Error),
+        "warn" | "warning" => Ok(slog::Level::Warning),
+        "info" => Ok(slog::Level::Info),
+        "debug" => Ok(slog::Level::Debug),
+        "trace" => Ok(slog::Level::Trace),
+        _ => Err(format!("unknown log level `{}`, use crit, error, warn, info, debug or trace", s)),
+    }
+}
+
+/// Parse `info` or `warn,flv=error,rtmp::client=debug`: a level for all, then per module
+pub fn parse_levels(s: &str) -> Result<Levels, String> {
+    let mut levels = Levels::new(slog::Level::Info);
+    for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+        match part.find('=') {
+            Some(i) => levels.modules.push((part[..i].trim().to_owned(), parse_level(&part[i + 1..])?)),
+            None => levels.default = parse_level(part)?,
+        }
+    }
+    Ok(levels)
+}
+
+struct Filtered<D> {
+    drain: D,
+    levels: Levels,
+}
+
+impl<D: Drain> Drain for Filtered<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.levels.level(record.module())) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Where the logs go
+pub enum Output {
+    Terminal,
+    /// Colored, one aligned line per record, for watching a run live
+    Pretty,
+    File(PathBuf, Option<Rotate>),
+    /// Nowhere, the terminal shows the dashboard
+    Discard,
+}
+
+pub fn init(output: Output, format: Format, levels: Levels) -> io::Result<(Logger, LogGuard)> {
+    let logger = match output {
+        Output::File(path, rotate) if format == Format::Json => async_drain(json(RotatingFile::open(path, rotate)?), levels),
+        Output::Terminal if format == Format::Json => async_drain(json(io::stderr()), levels),
+        Output::File(path, rotate) => {
+            let decorator = slog_term::PlainDecorator::new(RotatingFile::open(path, rotate)?);
+            async_drain(slog_term::FullFormat::new(decorator).build().fuse(), levels)
+        }
+        Output::Pretty => async_drain(Pretty { started: Instant::now() }.fuse(), levels),
+        Output::Terminal => {
+            let decorator = slog_term::TermDecorator::new().build();
+            async_drain(slog_term::FullFormat::new(decorator).build().fuse(), levels)
+        }
+        Output::Discard => async_drain(slog::Discard, levels),
+    };
+    Ok(logger)
+}
+
+/// Flushed after every record, which is when `RotatingFile` rotates
+fn json<W: Write + Send + 'static>(out: W) -> impl Drain<Err = slog::Never, Ok = ()> {
+    slog_json::Json::new(out).set_flush(true).add_default_keys().build().fuse()
+}
 
+fn async_drain<D>(drain: D, levels: Levels) -> (Logger, LogGuard)
+    where D: Drain<Err = slog::Never, Ok = ()> + Send + 'static,
+{
     // TODO Maybe set a larger channel size to prevent logs drop
     // See https://github.com/slog-rs/async/issues/4
     let (drain, async_guard) = slog_async::Async::new(drain)
@@ -23,7 +179,7 @@ pub fn init() -> (Logger, LogGuard) {
         .thread_name("slog_async".to_owned())
         .build_with_guard();
 
-    let root = Logger::root(drain.ignore_res(), o!());
+    let root = Logger::root(Filtered { drain, levels }.ignore_res(), o!());
 
     let guard = LogGuard {
         _async_guard: async_guard,
@@ -31,3 +187,162 @@ pub fn init() -> (Logger, LogGuard) {
 
     (root, guard)
 }
+
+/// Width of the client column, `app/stream` of the client a record comes from
+const CLIENT_WIDTH: usize = 24;
+
+/// Format of `--pretty` on stderr: time since start, colored level, the client and the message,
+/// followed by the other values with urls shortened to host and stream.
+struct Pretty {
+    started: Instant,
+}
+
+impl Drain for Pretty {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> io::Result<()> {
+        let mut kv = Collect(Vec::new());
+        let _ = slog::KV::serialize(record.kv(), record, &mut kv);
+        let _ = slog::KV::serialize(values, record, &mut kv);
+        let mut kv = kv.0;
+        let mut take = |key: &str| kv.iter().position(|(k, _)| k == key).map(|i| kv.remove(i).1);
+        let client = match (take("app"), take("stream")) {
+            (Some(app), Some(stream)) => format!("{}/{}", app, stream),
+            (_, Some(stream)) => stream,
+            _ => String::new(),
+        };
+
+        let (color, level) = match record.level() {
+            slog::Level::Critical => ("\x1b[1;35m", "CRIT"),
+            slog::Level::Error => ("\x1b[31m", "ERRO"),
+            slog::Level::Warning => ("\x1b[33m", "WARN"),
+            slog::Level::Info => ("\x1b[32m", "INFO"),
+            slog::Level::Debug => ("\x1b[36m", "DEBG"),
+            slog::Level::Trace => ("\x1b[90m", "TRCE"),
+        };
+        let mut line = format!("{:>9.3} {}{}\x1b[0m \x1b[1m{:<width$}\x1b[0m {}",
+                               self.started.elapsed().as_secs_f64(), color, level,
+                               truncate(&client, CLIENT_WIDTH), record.msg(), width = CLIENT_WIDTH);
+        for (key, value) in &kv {
+            let value = if value.starts_with("rtmp") { abbreviate_url(value) } else { value.clone() };
+            line.push_str(&format!(" \x1b[90m{}=\x1b[0m{}", key, value));
+        }
+        line.push('\n');
+        io::stderr().write_all(line.as_bytes())
+    }
+}
+
+struct Collect(Vec<(String, String)>);
+
+impl slog::Serializer for Collect {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), val.to_string()));
+        Ok(())
+    }
+}
+
+/// Keep the end, which tells clients apart
+fn truncate(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+    if len <= width {
+        return s.to_owned();
+    }
+    let tail = s.chars().skip(len - (width - 1)).collect::<String>();
+    format!("…{}", tail)
+}
+
+/// `rtmp://host:1935/app/stream` to `host/…/stream`
+fn abbreviate_url(url: &str) -> String {
+    let rest = url.splitn(2, "://").nth(1).unwrap_or(url);
+    let host = rest.split(|c| c == '/' || c == ':').next().unwrap_or(rest);
+    match rest.rsplit('/').next() {
+        Some(last) if rest.contains('/') => format!("{}/…/{}", host, last),
+        _ => host.to_owned(),
+    }
+}
+
+/// Appends to `path`, and on rotation shifts `path.1.gz` .. `path.<keep>.gz` and compresses
+/// the full file into `path.1.gz`. Compression runs on the logging thread, which blocks the
+/// loggers for a moment since the channel never drops records.
+struct RotatingFile {
+    path: PathBuf,
+    rotate: Option<Rotate>,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotate: Option<Rotate>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, rotate, file: BufWriter::new(file), size, opened: Instant::now() })
+    }
+
+    fn due(&self) -> bool {
+        match self.rotate.map(|r| r.when) {
+            Some(RotateWhen::Size(max)) => self.size >= max,
+            Some(RotateWhen::Age(max)) => self.opened.elapsed() >= max,
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotate.map(|r| r.keep).unwrap_or(0);
+        self.file.flush()?;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}.gz", self.path.display(), n));
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(keep));
+            for n in (1..keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            compress(&self.path, &rotated(1))?;
+            fs::remove_file(&self.path)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(from)?);
+    let mut output = flate2::write::GzEncoder::new(BufWriter::new(File::create(to)?), flate2::Compression::fast());
+    io::copy(&mut input, &mut output)?;
+    output.finish()?.flush()
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    /// Called once per record, so records never straddle two files
+    fn flush(&mut self) -> io::Result<()> {
+        if self.due() {
+            self.rotate()
+        } else {
+            self.file.flush()
+        }
+    }
+}
+
+/// Record `tracing` spans into `path` as folded stacks, e.g. for `inferno-flamegraph`.
+///
+/// Spans are recorded alongside slog, the returned guard flushes the file when dropped.
+#[cfg(feature = "flame")]
+pub fn init_flame(path: &str) -> Result<tracing_flame::FlushGuard<std::io::BufWriter<std::fs::File>>, String> {
+    use tracing_subscriber::prelude::*;
+
+    let (flame_layer, guard) = tracing_flame::FlameLayer::with_file(path).map_err(|e| e.to_string())?;
+    let subscriber = tracing_subscriber::registry::Registry::default().with(flame_layer);
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())?;
+    Ok(guard)
+}
@@ -1,47 +1,40 @@
-#![recursion_limit="1024"]
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::OsString;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::pin::Pin;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{
     crate_version, crate_authors,
-    App, Arg, ArgGroup,
+    App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand,
 };
 
-use bytes::Bytes;
+use futures::FutureExt;
 
-use futures::{
-    stream::{
-        StreamExt,
-    },
-};
-use pin_utils::pin_mut;
+use slog::{error, info, o, warn, Logger};
 
-use rml_rtmp::{
-    sessions::StreamMetadata,
-    time::RtmpTimestamp,
+use waterfall::{
+    compare, config, connect_bench, distributed, events, fanout, flv, html, junit, latency, load, logger, metrics, panic_hook, play, publish,
+    report, rtmp, rtmp_url, shutdown, sink, slo, stats, units, verify,
 };
-use slog::{info, warn};
-
-mod error;
-mod rtmp;
-mod flv;
-mod logger;
-mod rtmp_url;
-use rtmp_url::Url;
-
+#[cfg(feature = "tui")]
+use waterfall::ui;
+use waterfall::rtmp_url::Url;
 
 const USAGE: &str = "
-    waterfall [FLAGS] [OPTIONS] --input <INPUT> <DEST_LIST_FILE>
-    waterfall [FLAGS] [OPTIONS] --input <INPUT> --concurrency <CONCURRENCY> --prefix <PREFIX>";
+    waterfall publish [FLAGS] [OPTIONS] --input <INPUT> <DEST_LIST_FILE>
+    waterfall publish [FLAGS] [OPTIONS] --input <INPUT> --concurrency <CONCURRENCY> --prefix <PREFIX>";
 
 const EXAMPLE: &str = "
 EXAMPLES:
 
     ## Auto-Generated destinations
 
-    > waterfall --input test.flv -c 100 -p rtmp://test.example.com/app/stream_prefix_
+    > waterfall publish --input test.flv -c 100 -p rtmp://test.example.com/app/stream_prefix_
 
     This command will read from test.flv, push RTMP stream to the following destinations concurrently:
 
@@ -53,7 +46,7 @@ EXAMPLES:
 
     ## From destinations list file
 
-    > waterfall --input test.flv target_list.txt
+    > waterfall publish --input test.flv target_list.txt
 
     This command will read from test.flv, push RTMP stream to the destinations read from `target_list.txt` concurrently:
 
@@ -66,132 +59,1775 @@ EXAMPLES:
         rtmp:://test.example.com/app/stream_Eie83lrF
 ";
 
-#[tokio::main]
-async fn main() -> Result<(), std::io::Error> {
-    let (root_logger, _guard) = logger::init();
-    #[allow(deprecated)]
-    let matches = App::new("RTMP Publish Bench Tool")
-        .version(crate_version!())
-        .author(crate_authors!("\n"))
-        .about("This tool read flv packages from a specified file and push it to destinations from list or generated path, concurrently.")
+/// Every client succeeded, and every assertion passed
+const EXIT_SUCCESS: i32 = 0;
+/// Some of the clients failed, an assertion failed, or the run was aborted
+const EXIT_PARTIAL_FAILURE: i32 = 1;
+/// The arguments, the input or an output could not be used, nothing was measured
+const EXIT_INPUT_ERROR: i32 = 2;
+/// Every client failed
+const EXIT_ALL_FAILED: i32 = 3;
+
+fn main() {
+    panic_hook::install();
+    // The options are checked with `expect` and `assert`, the panic message is already out
+    let matches = match std::panic::catch_unwind(args) {
+        Ok(Ok(matches)) => matches,
+        Ok(Err(code)) => std::process::exit(code),
+        Err(_) => std::process::exit(EXIT_INPUT_ERROR),
+    };
+    let mut runtime = match runtime(&matches) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(EXIT_INPUT_ERROR);
+        }
+    };
+    let code = runtime.block_on(async {
+        match std::panic::AssertUnwindSafe(run(&matches)).catch_unwind().await {
+            Ok(Ok(code)) => code,
+            Ok(Err(e)) => {
+                eprintln!("Error: {}", e);
+                EXIT_INPUT_ERROR
+            }
+            Err(_) => EXIT_INPUT_ERROR,
+        }
+    });
+    // A task which panicked is no success, even if the run went on without it
+    if code == EXIT_SUCCESS && panic_hook::panicked() {
+        std::process::exit(EXIT_PARTIAL_FAILURE);
+    }
+    std::process::exit(code);
+}
+
+/// The multi-threaded runtime, with `--worker-threads` of them, or a single thread for everything
+/// with `--single-thread`
+fn runtime(matches: &ArgMatches<'_>) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new();
+    if matches.is_present("single-thread") {
+        builder.basic_scheduler();
+    } else {
+        builder.threaded_scheduler();
+        if let Some(threads) = matches.value_of("WORKER_THREADS") {
+            let threads = threads.parse::<usize>().ok().filter(|n| *n > 0)
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Cannot parse `WORKER_THREADS`"))?;
+            builder.core_threads(threads);
+        }
+    }
+    builder.enable_all().build()
+}
+
+/// The exit code of a run where `failed` of the `clients` failed
+fn exit_code(clients: usize, failed: usize, other_failures: bool) -> i32 {
+    if clients > 0 && failed == clients {
+        EXIT_ALL_FAILED
+    } else if failed > 0 || other_failures {
+        EXIT_PARTIAL_FAILURE
+    } else {
+        EXIT_SUCCESS
+    }
+}
+
+/// The arguments, from the command line and `--config`, or the exit code if they are not usable
+fn args() -> Result<ArgMatches<'static>, i32> {
+    let publish_cmd = destination_args(SubCommand::with_name("publish")
+        .about("Read FLV tags from the input and publish them to every destination, concurrently")
         .usage(USAGE)
         .after_help(EXAMPLE)
         .arg(Arg::with_name("INPUT")
             .short("i")
             .long("input")
-            .help("Input FLV file path")
-            .required(true)
+            .help("Input FLV file path, `-` for stdin, an `http(s)://` url, or a `.m3u` playlist of them, \
+                   an `rtmp://` source or `synthetic:bitrate=3M,fps=30`. \
+                   Given more than once, the files are played back-to-back")
+            .required_unless("RENDITIONS")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("round-robin-inputs")
+            .long("round-robin-inputs")
+            .help("Assign the `--input` files to the destinations in turn, each read on its own, instead of playing them back-to-back"))
+        .arg(Arg::with_name("RENDITIONS")
+            .long("renditions")
+            .help("Publish a ladder to every destination, e.g. `1080p=a.flv,720p=b.flv`: one publisher per rendition, \
+                   its stream key suffixed with `_<name>` and fed from its own input")
+            .conflicts_with_all(&["round-robin-inputs", "adaptive", "STEPS", "PROFILE"])
             .takes_value(true))
 
+        .arg(Arg::with_name("no-audio")
+            .long("no-audio")
+            .help("Leave the audio of the input out, and out of the metadata, to publish video only")
+            .conflicts_with("no-video"))
+        .arg(Arg::with_name("no-video")
+            .long("no-video")
+            .help("Leave the video of the input out, and out of the metadata, to publish audio only"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Take every tag header of the input as it is, instead of skipping corrupt bytes up to the next plausible tag"))
+        .arg(Arg::with_name("preload")
+            .long("preload")
+            .help("Read the whole input into memory before publishing, and play every loop and client from there. \
+                   Lets stdin be repeated"))
+        .arg(Arg::with_name("SPEED")
+            .long("speed")
+            .help("Send the input at this multiple of real time, e.g. `0.5` or `2` [default: 1]")
+            .takes_value(true))
+        .arg(Arg::with_name("BURST")
+            .long("burst")
+            .help("Send the first this much of the input as fast as possible, e.g. `5s`, then in real time, \
+                   like an encoder dumping its buffer on connect")
+            .takes_value(true))
         .arg(Arg::with_name("repeat")
             .short("r")
-            .long("repeat"))
+            .long("repeat")
+            .help("Read the input file in a loop, publish sessions are kept across loops. `--repeat=10` plays it \
+                   10 times in all, timestamps keep increasing from one loop to the next")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1)
+            .require_equals(true))
+        .arg(Arg::with_name("START_OFFSET")
+            .long("start-offset")
+            .help("Publish the input from this far in, e.g. `1h30m`, starting at the keyframe at or before it. \
+                   Every loop plays the same range")
+            .conflicts_with("desync")
+            .takes_value(true))
+        .arg(Arg::with_name("END_OFFSET")
+            .long("end-offset")
+            .help("Publish the input up to this far in, e.g. `1h35m`, the end of every loop")
+            .conflicts_with("desync")
+            .takes_value(true))
+        .arg(Arg::with_name("DURATION")
+            .long("duration")
+            .help("Stop broadcasting and unpublish after this long, e.g. `8h`, whatever the input length and `--repeat`")
+            .takes_value(true))
+        .arg(Arg::with_name("republish-each-loop")
+            .long("republish-each-loop")
+            .help("Reconnect and publish again every time the input starts over")
+            .requires("repeat"))
+        .arg(Arg::with_name("REPUBLISH_STREAM")
+            .long("republish-stream")
+            .help("Publish again on the same connection instead of reconnecting: `reuse` publishes on the deleted \
+                   stream id again, `new` creates a new stream each time")
+            .possible_values(&["reuse", "new"])
+            .requires("republish-each-loop")
+            .takes_value(true))
+
+        .arg(Arg::with_name("TIMESTAMP_JITTER")
+            .long("timestamp-jitter")
+            .help("Randomly shift each timestamp sent on the wire by up to this much, e.g. `±20ms`. Pacing is not affected")
+            .takes_value(true))
 
-        .arg(Arg::with_name("CONCURRENCY")
-            .short("c")
-            .long("concurrency")
+        .arg(Arg::with_name("BYTES_PER_CLIENT")
+            .long("bytes-per-client")
+            .help("Stop a client once it has published this much, e.g. `500MB`")
+            .takes_value(true))
+        .arg(Arg::with_name("QUEUE_SIZE")
+            .long("queue-size")
+            .help("Broadcast messages each client queues before `--drop-policy` applies [default: 1024]")
+            .takes_value(true))
+        .arg(Arg::with_name("DROP_POLICY")
+            .long("drop-policy")
+            .help("What a full client queue gives up for the next message [default: drop-oldest]")
+            .possible_values(&["drop-oldest", "drop-non-keyframe", "disconnect"])
+            .takes_value(true))
+        .arg(Arg::with_name("MAX_BITRATE")
+            .long("max-bitrate")
+            .help("Shape the socket writes of each client to this many bits per second with a token bucket, e.g. `2M`, like a constrained uplink")
+            .takes_value(true))
+        .arg(Arg::with_name("drop-on-backpressure")
+            .long("drop-on-backpressure")
+            .help("Drop video frames up to the next keyframe while the socket is too slow, like an encoder, \
+                   instead of holding the client back. Audio and keyframes are always sent"))
+        .arg(Arg::with_name("measure-latency")
+            .long("measure-latency")
+            .help("Stamp the streams with the wall clock and play the first destination alongside, \
+                   reporting the publish to play latency percentiles"))
+        .arg(Arg::with_name("LATENCY_URL")
+            .long("latency-url")
+            .help("Play this url for `--measure-latency` rather than the first destination, e.g. an edge or \
+                   a play url differing from the publish one")
+            .requires("measure-latency")
+            .takes_value(true))
+        .arg(Arg::with_name("LATENCY_INTERVAL")
+            .long("latency-interval")
+            .help("Report the latency percentiles this often [default: 10s]")
+            .requires("measure-latency")
+            .takes_value(true))
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .help("Hash every audio and video message published to the first destination and play it alongside, \
+                   reporting the messages missing, corrupted or reordered on the way and the gaps and overlaps of \
+                   its AAC frames")
+            .conflicts_with_all(&["adaptive", "STEPS", "PROFILE"]))
+        .arg(Arg::with_name("VERIFY_URL")
+            .long("verify-url")
+            .help("Play this url for `--verify` rather than the first destination, the same stream from an \
+                   edge or a play url")
+            .requires("verify")
+            .takes_value(true))
+        .arg(Arg::with_name("NET_DELAY")
+            .long("net-delay")
+            .help("Hold what each client writes this long before it goes out, e.g. `200ms`")
+            .takes_value(true))
+        .arg(Arg::with_name("NET_STALL")
+            .long("net-stall")
+            .help("Send nothing for a while at every period of each session, `<length>@<every>` e.g. `2s@30s`")
+            .takes_value(true))
+        .arg(Arg::with_name("replace-exhausted")
+            .long("replace-exhausted")
+            .help("Publish again with a fresh budget instead of exiting, see `--bytes-per-client`")
+            .requires("BYTES_PER_CLIENT"))
+
+        .arg(Arg::with_name("desync")
+            .long("desync")
+            .visible_alias("randomize-offset")
+            .help("Start each client at a random offset into the input, at the keyframe after it, with its own reader"))
+
+        .arg(Arg::with_name("adaptive")
+            .long("adaptive")
+            .help("Add publishers one by one until the error rate or connect time crosses its limit, \
+                   then report the sustained maximum. `--concurrency` or the list caps the publishers")
+            .conflicts_with("desync"))
+        .arg(Arg::with_name("RAMP_RATE")
+            .long("ramp-rate")
+            .help("Publishers added per second in adaptive mode [default: 1]")
+            .requires("adaptive")
+            .takes_value(true))
+        .arg(Arg::with_name("MAX_ERROR_RATE")
+            .long("max-error-rate")
+            .help("Failed share of the publishers which stops the adaptive mode, e.g. `1%` [default: 1%]")
+            .requires("adaptive")
+            .takes_value(true))
+        .arg(Arg::with_name("MAX_CONNECT_TIME")
+            .long("max-connect-time")
+            .help("p90 time to publish which stops the adaptive mode, e.g. `2s` [default: 5s]")
+            .requires("adaptive")
+            .takes_value(true))
+
+        .arg(Arg::with_name("RAMP_UP")
+            .long("ramp-up")
+            .help("Spread the connects of the publishers evenly over this long, e.g. `5m`, \
+                   the broadcast starts once all of them are publishing")
+            .conflicts_with_all(&["adaptive", "STEPS", "PROFILE"])
+            .takes_value(true))
+        .arg(Arg::with_name("RAMP_STEPS")
+            .long("ramp-steps")
+            .help("Connect the publishers of `--ramp-up` in this many equal batches instead of one by one")
+            .requires("RAMP_UP")
+            .takes_value(true))
+
+        .arg(Arg::with_name("MIN_SUCCESS")
+            .long("min-success")
+            .help("Start the broadcast once this share of the publishers is publishing, e.g. `90%`, \
+                   instead of waiting for all of them. Fewer once all connects are done aborts the run")
+            .conflicts_with_all(&["adaptive", "STEPS", "PROFILE"])
+            .takes_value(true))
+        .arg(Arg::with_name("start-immediately")
+            .long("start-immediately")
+            .help("Start the broadcast once the first publisher is publishing, the others join it as soon as \
+                   their publish succeeds instead of waiting for all")
+            .conflicts_with_all(&["MIN_SUCCESS", "adaptive", "STEPS", "PROFILE"]))
+        .arg(Arg::with_name("ignore-connect-errors")
+            .long("ignore-connect-errors")
+            .help("Broadcast to the publishers which connected even below `--min-success`, the others are \
+                   reported as failed")
+            .requires("MIN_SUCCESS"))
+        .arg(Arg::with_name("fail-fast")
+            .long("fail-fast")
+            .help("Stop the whole run as soon as any client fails, the exit code is then non-zero"))
+
+        .arg(Arg::with_name("STEPS")
+            .long("steps")
+            .help("Hold plateaus of concurrency, e.g. `500:5m,1000:5m,2000:5m`, with a report block per step. \
+                   `--concurrency` or the list caps the publishers")
+            .conflicts_with_all(&["desync", "adaptive"])
+            .takes_value(true))
+        .arg(Arg::with_name("PROFILE")
+            .long("profile")
+            .help("Drive the concurrency over the run, e.g. `0s:100,5m:1000,15m:5000,20m:1000` or a file with a \
+                   point per line: publishers are started or unpublished at each time. `--concurrency` or the \
+                   list caps the publishers")
+            .conflicts_with_all(&["desync", "adaptive", "STEPS"])
+            .takes_value(true))
+
+        .arg(Arg::with_name("watch")
+            .long("watch")
+            .help("Keep reading the destination list and publish to the lines appended during the run, \
+                   they join the broadcast where it is. Their options after the url are ignored")
+            .requires("DEST_LIST_FILE")
+            .conflicts_with_all(&["desync", "adaptive", "STEPS", "PROFILE"]))
+
+        .arg(Arg::with_name("CONTROL_LISTEN")
+            .long("control-listen")
+            .help("Serve an HTTP API on this address, e.g. `127.0.0.1:8080`, to add and remove destinations, \
+                   get the stats, pause and resume the broadcast or shut the run down")
+            .conflicts_with_all(&["desync", "adaptive", "STEPS", "PROFILE"])
+            .takes_value(true))
+
+        .arg(Arg::with_name("WORKERS")
+            .long("workers")
+            .help("Coordinate a run over `waterfall worker` processes instead of publishing from here, e.g. \
+                   `10.0.0.1:7000,10.0.0.2:7000`. Each worker publishes a contiguous shard of the destinations \
+                   with the other options, and reads the inputs itself. The reports, `--assert` and `--junit` \
+                   are of the merged clients")
+            .conflicts_with_all(&["watch", "adaptive", "STEPS", "PROFILE", "CONTROL_LISTEN"])
+            .takes_value(true))
+
+        .arg(Arg::with_name("ASSERT")
+            .long("assert")
+            .help("Check the final report, e.g. `p99_connect_ms<500` or `error_rate<1%`, and exit with 1 if any fails. \
+                   May be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+
+        .arg(Arg::with_name("BASELINE")
+            .long("baseline")
+            .help("Compare the final report against this JSON report, and exit with 1 on regressions")
+            .takes_value(true))
+        .arg(Arg::with_name("TOLERANCE")
+            .long("tolerance")
+            .help("How much worse than the baseline each metric may get, e.g. `10%` [default: 10%]")
+            .requires("BASELINE")
+            .takes_value(true))
+
+        .arg(Arg::with_name("METRICS_TEXTFILE")
+            .long("metrics-textfile")
+            .help("Periodically write the current metrics in OpenMetrics text format to this file, \
+                   e.g. for the node_exporter textfile collector")
+            .takes_value(true))
+        .arg(Arg::with_name("METRICS_INTERVAL")
+            .long("metrics-interval")
+            .help("Seconds between two writes of the metrics textfile, or two pushes [default: 15]")
+            .takes_value(true))
+        .arg(Arg::with_name("STATSD")
+            .long("statsd")
+            .help("Push the metrics to this StatsD `host:port` over UDP, with the tags Telegraf reads, \
+                   e.g. `localhost:8125`")
+            .takes_value(true))
+        .arg(Arg::with_name("METRICS_TAG")
+            .long("metrics-tag")
+            .help("Tag the pushed metrics with this `key=value`, e.g. `generator=lg-3`. May be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+
+        .arg(Arg::with_name("HTML")
+            .long("html")
+            .help("Write a self-contained HTML report with charts to this file at the end of the run")
+            .takes_value(true))
+        .arg(Arg::with_name("JUNIT")
+            .long("junit")
+            .help("Write the final results as JUnit XML to this file, one test case per destination and assertion")
+            .takes_value(true))
+
+        .arg(Arg::with_name("EVENTS")
+            .long("events")
+            .help("Print lifecycle events on stdout, one JSON object per line. Logs go to stderr")
+            .possible_values(&["ndjson"])
+            .takes_value(true))
+        .arg(Arg::with_name("EVENTS_INTERVAL")
+            .long("events-interval")
+            .help("Seconds between two stats events [default: 10]")
+            .requires("EVENTS")
+            .takes_value(true))
+        .arg(Arg::with_name("TCP_KEEPALIVE")
+            .long("tcp-keepalive")
+            .help("Send TCP keepalive probes after this much idle time, e.g. `30s`, so NAT and firewall timeouts \
+                   surface as errors")
+            .takes_value(true))
+        .arg(Arg::with_name("TCP_KEEPALIVE_INTERVAL")
+            .long("tcp-keepalive-interval")
+            .help("Between two TCP keepalive probes, e.g. `5s` [default: the system's]")
+            .requires("TCP_KEEPALIVE")
+            .takes_value(true))
+        .arg(Arg::with_name("tcp-nodelay")
+            .long("tcp-nodelay")
+            .help("Set `TCP_NODELAY` on publish connections, sending the RTMP chunks as they are written \
+                   instead of coalescing small ones"))
+        .arg(Arg::with_name("SEND_BUFFER")
+            .long("send-buffer")
+            .help("`SO_SNDBUF` of publish connections, e.g. `256KB` [default: the system's]")
             .takes_value(true))
-        .arg(Arg::with_name("PREFIX")
-            .short("p")
-            .long("prefix")
-            .help("RTMP destinations prefix, e.g. `rtmp://example.com/app/stream_`")
+        .arg(Arg::with_name("PUBLISH_TYPE")
+            .long("publish-type")
+            .help("Type of the publish command, `record` and `append` make the server record the streams [default: live]")
+            .possible_values(&["live", "record", "append"])
             .takes_value(true))
+        .arg(Arg::with_name("PRE_PUBLISH")
+            .long("pre-publish")
+            .help("Commands sent before `createStream`, in this order, e.g. `FCPublish,releaseStream`, or `none` \
+                   [default: releaseStream,FCPublish]")
+            .takes_value(true))
+        .arg(Arg::with_name("AUTH")
+            .long("auth")
+            .help("`user:password` for servers with `authmod=adobe`, those of a destination url take precedence")
+            .takes_value(true))
+        .arg(Arg::with_name("CONNECT_APP")
+            .long("connect-app")
+            .help("App of the connect command in place of the one of the destination, e.g. `live?token=abc`")
+            .takes_value(true))
+        .arg(Arg::with_name("FLASH_VER")
+            .long("flash-ver")
+            .help("flashVer of the connect command, e.g. `FMLE/3.0 (compatible; FMSc/1.0)`")
+            .takes_value(true))
+        .arg(Arg::with_name("SWF_URL")
+            .long("swf-url")
+            .help("swfUrl of the connect command")
+            .takes_value(true))
+        .arg(Arg::with_name("PAGE_URL")
+            .long("page-url")
+            .help("pageUrl of the connect command")
+            .takes_value(true))
+        .arg(Arg::with_name("METADATA")
+            .long("metadata")
+            .help("Set `name=value` in the `onMetaData` sent, in place of the value of the input, a number, `true` or \
+                   `false`, or a string, e.g. `width=1920` or `encoder=obs`. May be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("METADATA_JSON")
+            .long("metadata-json")
+            .help("Set the properties of this JSON object in the `onMetaData` sent, `--metadata` goes over them")
+            .takes_value(true))
+        .arg(Arg::with_name("METADATA_INTERVAL")
+            .long("metadata-interval")
+            .help("Send the `onMetaData` again this often during the broadcast, e.g. `10s`, at the timestamp of the media")
+            .takes_value(true))
+        .arg(Arg::with_name("CONNECT_PARAM")
+            .long("connect-param")
+            .help("Add `name=value` to the connect command object, a number, `true` or `false`, or a string. \
+                   May be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("CHUNK_SIZE")
+            .long("chunk-size")
+            .help("Chunk size the publishers announce and send with, e.g. `4096` or `64K`")
+            .takes_value(true))
+        .arg(Arg::with_name("WINDOW_ACK_SIZE")
+            .long("window-ack-size")
+            .help("Window acknowledgement size the publishers announce, e.g. `2.5MB`")
+            .takes_value(true))
+        .arg(Arg::with_name("UNACKED_ALARM")
+            .long("unacked-alarm")
+            .help("Warn when the server leaves more than this many bytes of a publisher unacknowledged, e.g. \
+                   `16MB`, set it above the window acknowledgement size of the server")
+            .takes_value(true))
+        .arg(Arg::with_name("BIND")
+            .long("bind")
+            .help("Connect from this local address, given more than once the publishers take them in turn, \
+                   to spread the connections over several source IPs")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("CONNECT_RATE")
+            .long("connect-rate")
+            .help("Start at most this many connects of publishers, reconnects included, e.g. `50/s` or `600/m`")
+            .takes_value(true))
+        .arg(Arg::with_name("RUNTIMES")
+            .long("runtimes")
+            .help("Spread the publishers over this many single threaded runtimes, a thread each, \
+                   apart from the one reading the input")
+            .takes_value(true))
+        .arg(Arg::with_name("SRT_TRANSMIT")
+            .long("srt-transmit")
+            .help("The `srt-live-transmit` sending the MPEG-TS of `srt://` destinations, one per publisher. It comes \
+                   with the SRT tools, https://github.com/Haivision/srt [default: the one of the PATH]")
+            .takes_value(true))
+        .arg(Arg::with_name("GST_LAUNCH")
+            .long("gst-launch")
+            .help("The `gst-launch-1.0` running the WHIP pipeline of `whip://` and `whips://` destinations, one per \
+                   publisher. It needs the `whipsink` of gst-plugins-rs [default: the one of the PATH]")
+            .takes_value(true))
+        .arg(Arg::with_name("PROXY")
+            .long("proxy")
+            .help("Connect to the destinations through this proxy, `socks5://[user:password@]host:port` \
+                   or `http://[user:password@]host:port` for HTTP CONNECT")
+            .takes_value(true))
+
+        .arg(Arg::with_name("IDLE_SESSIONS")
+            .long("idle-sessions")
+            .help("Also open this many sessions which connect and create a stream but never publish, \
+                   spread over the destinations")
+            .takes_value(true))
+        .arg(Arg::with_name("idle-only")
+            .long("idle-only")
+            .help("Only open the idle sessions, nothing is published")
+            .requires("IDLE_SESSIONS"))
+        .arg(Arg::with_name("RECONNECT_ON_CLOSE")
+            .long("reconnect-on-close")
+            .help("Reconnect when the server closes the connection, after a random delay up to this window, \
+                   e.g. `30s`, so thousands of clients do not all come back at once")
+            .takes_value(true))
+        .arg(Arg::with_name("RECONNECT_RETRIES")
+            .long("reconnect-retries")
+            .help("Reconnect a publisher whose connection dropped after it published, up to this many times in a row \
+                   [default: 0]")
+            .takes_value(true))
+        .arg(Arg::with_name("RECONNECT_BACKOFF")
+            .long("reconnect-backoff")
+            .help("Delay before the first of `--reconnect-retries`, doubled for each further one up to 60s [default: 1s]")
+            .requires("RECONNECT_RETRIES")
+            .takes_value(true))
+        .arg(Arg::with_name("CHURN")
+            .long("churn")
+            .help("Fraction of the publishers which close their session and publish again in every `--churn-interval`, e.g. `5%`")
+            .takes_value(true))
+        .arg(Arg::with_name("CHURN_INTERVAL")
+            .long("churn-interval")
+            .help("Period of `--churn` [default: 10s]")
+            .requires("CHURN")
+            .takes_value(true))
+        .arg(Arg::with_name("CONNECT_TIMEOUT")
+            .long("connect-timeout")
+            .help("Fail a publisher whose TCP connect takes longer, `0` waits as long as the system does [default: 10s]")
+            .takes_value(true))
+        .arg(Arg::with_name("HANDSHAKE_TIMEOUT")
+            .long("handshake-timeout")
+            .help("Fail a publisher whose RTMP handshake, with TLS or the RTMPT tunnel, takes longer, `0` never [default: 10s]")
+            .takes_value(true))
+        .arg(Arg::with_name("PUBLISH_TIMEOUT")
+            .long("publish-timeout")
+            .help("Fail a publisher not publishing this long after its connect command, `0` never [default: 30s]")
+            .takes_value(true))
+        .arg(Arg::with_name("SERVER_SILENCE_TIMEOUT")
+            .long("server-silence-timeout")
+            .help("Fail a publishing client which received nothing from the server for this long, e.g. `60s`. \
+                   Servers only acknowledge every window, keep it above the window at the input bitrate")
+            .takes_value(true))
+
+        .arg(Arg::with_name("STATS_INTERVAL")
+            .long("stats-interval")
+            .help("Seconds between two log lines of each publisher with its bitrate, queued messages and bytes sent, \
+                   and of the totals")
+            .takes_value(true))
+        .arg(Arg::with_name("REPORT")
+            .long("report")
+            .help("Write the final report to this file when the run ends, one line per destination for a `.csv` \
+                   file, JSON with the aggregates otherwise. May be repeated")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true))
+        .arg(Arg::with_name("CHECKPOINT")
+            .long("checkpoint")
+            .help("Periodically write the partial results as JSON to this file")
+            .takes_value(true))
+        .arg(Arg::with_name("CHECKPOINT_INTERVAL")
+            .long("checkpoint-interval")
+            .help("Seconds between two checkpoints [default: 30]")
+            .requires("CHECKPOINT")
+            .takes_value(true)));
+
+    #[cfg(feature = "tui")]
+    let publish_cmd = publish_cmd.arg(Arg::with_name("tui")
+        .long("tui")
+        .help("Show a live table of the publishers with totals instead of the logs, \
+               which are discarded unless written to `--log-file`")
+        .conflicts_with_all(&["pretty", "EVENTS"]));
+
+    #[cfg(feature = "flame")]
+    let publish_cmd = publish_cmd.arg(Arg::with_name("FLAME")
+        .long("flame")
+        .help("Record tracing spans as folded stacks into this file, for flamegraphs")
+        .takes_value(true));
+
+    #[cfg(feature = "http")]
+    let publish_cmd = publish_cmd.arg(Arg::with_name("INFLUX_URL")
+        .long("influx-url")
+        .help("Push the metrics as InfluxDB line protocol to this write url, \
+               e.g. `http://influx:8086/write?db=load` or a Telegraf `influxdb_listener`")
+        .takes_value(true));
+
+    #[cfg(feature = "http")]
+    let publish_cmd = publish_cmd.arg(Arg::with_name("WEBHOOK")
+        .long("webhook")
+        .help("Post the start of the run, every client failure with its reason and the end of the run \
+               with the summary to this url, one JSON object each")
+        .takes_value(true));
+
+    #[cfg(feature = "otlp")]
+    let publish_cmd = publish_cmd.arg(Arg::with_name("OTLP_ENDPOINT")
+        .long("otlp-endpoint")
+        .help("Export a trace per publisher, with the spans of its connect and publish phases, to this \
+               OTLP/HTTP collector, e.g. `http://localhost:4318`. Not with `--flame`")
+        .takes_value(true));
+
+    let play_cmd = destination_args(SubCommand::with_name("play")
+        .about("Play every destination concurrently and report the received bitrate, first frame time and stalls")
+        .arg(Arg::with_name("DURATION")
+            .long("duration")
+            .help("Stop playing after this long, e.g. `10m`, instead of when the server ends the streams")
+            .takes_value(true))
+        .arg(Arg::with_name("STALL_THRESHOLD")
+            .long("stall-threshold")
+            .help("Count a gap between two media messages at least this long as a stall [default: 1s]")
+            .takes_value(true))
+        .arg(Arg::with_name("RECORD_DIR")
+            .long("record-dir")
+            .help("Mux the stream each client receives into an FLV file in this directory, named after its \
+                   app and stream")
+            .takes_value(true))
+        .arg(Arg::with_name("PAUSE_EVERY")
+            .long("pause-every")
+            .help("Pause each play this often once it started, e.g. `30s`, and resume it as often")
+            .takes_value(true))
+        .arg(Arg::with_name("SEEK_EVERY")
+            .long("seek-every")
+            .help("Seek each play to a random position of what it received so far this often, e.g. `1m`")
+            .takes_value(true))
+        .arg(Arg::with_name("JSON")
+            .long("json")
+            .help("Also write the results, with every client, as JSON to this file")
+            .takes_value(true)));
+
+    let probe_cmd = SubCommand::with_name("probe")
+        .about("Print the codecs, resolution, frame rate, duration, bitrates and keyframe interval of an input, then exit")
+        .arg(Arg::with_name("INPUT")
+            .help("Input FLV file path")
+            .required(true)
+            .index(1));
+
+    #[allow(deprecated)]
+    let app = App::new("RTMP Publish Bench Tool")
+        .version(crate_version!())
+        .author(crate_authors!("\n"))
+        .about("This tool read flv packages from a specified file and push it to destinations from list or generated path, concurrently.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("CONFIG")
+            .long("config")
+            .help("Read options from this TOML or YAML file, options given here take precedence")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("pretty")
+            .long("pretty")
+            .help("Colored console logs with aligned clients and shortened urls, for interactive use")
+            .conflicts_with("LOG_FILE")
+            .global(true))
+        .arg(Arg::with_name("LOG_FILE")
+            .long("log-file")
+            .help("Write the logs to this file instead of the terminal")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("LOG_ROTATE")
+            .long("log-rotate")
+            .help("Start a new log file once it reaches a size or age, keeping that many compressed ones, \
+                   e.g. `100MB,7` or `1d,7`")
+            .requires("LOG_FILE")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("LOG_FORMAT")
+            .long("log-format")
+            .help("Write the logs as text, or as one JSON object per line for log pipelines [default: text]")
+            .possible_values(&["text", "json"])
+            .conflicts_with("pretty")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("LOG_LEVEL")
+            .long("log-level")
+            .help("Least level logged, then per module, e.g. `info` or `warn,flv=error,rtmp::client=debug` [default: info]")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .help("Log debug records, `-vv` trace records too")
+            .multiple(true)
+            .conflicts_with("LOG_LEVEL")
+            .global(true))
+        .arg(Arg::with_name("WORKER_THREADS")
+            .long("worker-threads")
+            .help("Threads of the runtime running the clients [default: one per CPU]")
+            .takes_value(true)
+            .global(true))
+        .arg(Arg::with_name("single-thread")
+            .long("single-thread")
+            .help("Run everything on the main thread, with no worker threads")
+            .conflicts_with("WORKER_THREADS")
+            .global(true))
+        .subcommand(publish_cmd)
+        .subcommand(play_cmd)
+        .subcommand(probe_cmd)
+        .subcommand(SubCommand::with_name("compare")
+            .about("Print the deltas between two JSON reports, see `--checkpoint`")
+            .arg(Arg::with_name("REPORT_A").required(true).index(1))
+            .arg(Arg::with_name("REPORT_B").required(true).index(2)))
+        .subcommand(SubCommand::with_name("sink")
+            .about("Accept RTMP publishers, discard their media and report what every stream sent")
+            .arg(Arg::with_name("LISTEN")
+                .long("listen")
+                .help("Address to accept the publishers on, e.g. `0.0.0.0:1935`")
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("DURATION")
+                .long("duration")
+                .help("Stop after this long, e.g. `10m`, instead of on a signal")
+                .takes_value(true))
+            .arg(Arg::with_name("STATS_INTERVAL")
+                .long("stats-interval")
+                .help("Seconds between two log lines of the totals [default: 10]")
+                .takes_value(true))
+            .arg(Arg::with_name("JSON")
+                .long("json")
+                .help("Also write the results, with every stream, as JSON to this file")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("worker")
+            .about("Wait for a coordinator, see `publish --workers`, and publish its shard of the destinations, \
+                    one run at a time")
+            .arg(Arg::with_name("LISTEN")
+                .long("listen")
+                .help("Address to accept the coordinator on, e.g. `0.0.0.0:7000`")
+                .required(true)
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("connect-bench")
+            .about("Repeat TCP connect, handshake and connect command against the destinations, without media, \
+                    and print the latency percentiles of each stage")
+            .arg(Arg::with_name("DESTINATIONS")
+                .help("Urls as for publishing, used in turn. The stream key is not used")
+                .required(true)
+                .multiple(true)
+                .index(1))
+            .arg(Arg::with_name("RATE")
+                .long("rate")
+                .help("Setups started per second [default: 10]")
+                .takes_value(true))
+            .arg(Arg::with_name("COUNT")
+                .short("n")
+                .long("count")
+                .help("Setups in total [default: 100]")
+                .takes_value(true))
+            .arg(Arg::with_name("TIMEOUT")
+                .long("timeout")
+                .help("Fail a setup which is not done after this long [default: 10s]")
+                .takes_value(true))
+            .arg(Arg::with_name("insecure-tls")
+                .long("insecure-tls")
+                .help("Accept any certificate from `rtmps://` destinations"))
+            .arg(Arg::with_name("JSON")
+                .long("json")
+                .help("Also write the results as JSON to this file")
+                .takes_value(true)));
+
+    let args = config::merge(with_default_subcommand(std::env::args_os())).expect("Cannot read `CONFIG`");
+    match app.get_matches_from_safe(args) {
+        Ok(matches) => Ok(matches),
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e.message);
+            Err(EXIT_INPUT_ERROR)
+        }
+        // `--help` and `--version`
+        Err(e) => e.exit(),
+    }
+}
+
+async fn run(matches: &ArgMatches<'static>) -> Result<i32, std::io::Error> {
+    if let ("compare", Some(sub)) = matches.subcommand() {
+        let a = PathBuf::from(sub.value_of("REPORT_A").unwrap());
+        let b = PathBuf::from(sub.value_of("REPORT_B").unwrap());
+        return compare::run(&a, &b).map(|_| EXIT_SUCCESS);
+    }
+    if let ("probe", Some(sub)) = matches.subcommand() {
+        let path = sub.value_of("INPUT").unwrap().to_owned();
+        let probe = tokio::task::spawn_blocking(move || flv::probe(&path)).await.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::Other, "probe input file error")
+        })??;
+        println!("{}", probe);
+        return Ok(EXIT_SUCCESS);
+    }
+    if let ("worker", Some(sub)) = matches.subcommand() {
+        let addr = sub.value_of("LISTEN").unwrap().parse::<SocketAddr>().expect("Cannot parse `LISTEN`");
+        let (logger, _guard) = init_logger(sub)?;
+        distributed::serve(addr, logger).await?;
+        return Ok(EXIT_SUCCESS);
+    }
+    if let ("connect-bench", Some(sub)) = matches.subcommand() {
+        let urls = sub.values_of("DESTINATIONS").unwrap().map(|u| {
+            rtmp_url::parse_rtmp_url(u, None).unwrap_or_else(|e| panic!("RTMP url error: {}", e))
+        }).collect();
+        let rate = sub.value_of("RATE").map(|r| r.parse::<f64>().expect("Cannot parse `RATE`")).unwrap_or(10.0);
+        let count = sub.value_of("COUNT").map(|c| c.parse::<usize>().expect("Cannot parse `COUNT`")).unwrap_or(100);
+        let timeout = sub.value_of("TIMEOUT").map(|t| {
+            units::parse_duration(t).expect("Cannot parse `TIMEOUT`")
+        }).unwrap_or_else(|| Duration::from_secs(10));
+        let report = connect_bench::run(urls, rate, count, timeout, sub.is_present("insecure-tls")).await;
+        connect_bench::print(&report);
+        if let Some(path) = sub.value_of("JSON") {
+            connect_bench::write_json(&report, &PathBuf::from(path))?;
+        }
+        return Ok(EXIT_SUCCESS);
+    }
+
+    match matches.subcommand() {
+        ("publish", Some(sub)) => publish(sub).await,
+        ("play", Some(sub)) => play(sub).await,
+        ("sink", Some(sub)) => sink(sub).await,
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+/// `waterfall -i input.flv ...` from before the subcommands still publishes.
+fn with_default_subcommand<I: Iterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    const NAMES: &[&str] = &["publish", "play", "probe", "compare", "connect-bench", "worker", "sink", "help", "-h", "--help", "-V", "--version"];
+    let mut args = args.collect::<Vec<_>>();
+    let named = args.iter().skip(1).any(|a| a.to_str().map_or(false, |a| NAMES.contains(&a)));
+    if args.len() > 1 && !named {
+        args.insert(1, "publish".into());
+    }
+    args
+}
+
+/// The url a companion player of the publishers plays, the first destination by default
+fn companion_url(matches: &ArgMatches<'_>, name: &str, urls: &[(Url, LineOptions)]) -> Option<Url> {
+    match matches.value_of(name) {
+        Some(u) => Some(rtmp_url::parse_rtmp_url(u, None).unwrap_or_else(|e| panic!("Cannot parse `{}`: {}", name, e))),
+        None => urls.first().map(|(url, _)| url.clone()),
+    }
+}
+
+fn companion_config(matches: &ArgMatches<'_>) -> rtmp::player::Config {
+    rtmp::player::Config {
+        duration: None,
+        stall_threshold: Duration::from_secs(1),
+        insecure_tls: matches.is_present("insecure-tls"),
+        family: family(matches),
+        latency: None,
+        record_dir: None,
+        verify: None,
+        pause_every: None,
+        seek_every: None,
+    }
+}
+
+/// Set up the logger from the global logging options.
+fn init_logger(matches: &ArgMatches<'_>) -> Result<(Logger, logger::LogGuard), std::io::Error> {
+    let log_output = if let Some(path) = matches.value_of("LOG_FILE") {
+        let rotate = matches.value_of("LOG_ROTATE").map(|r| {
+            logger::parse_rotate(r).expect("Cannot parse `LOG_ROTATE`")
+        });
+        logger::Output::File(PathBuf::from(path), rotate)
+    } else if matches.is_present("tui") {
+        logger::Output::Discard
+    } else if matches.is_present("pretty") {
+        logger::Output::Pretty
+    } else {
+        logger::Output::Terminal
+    };
+    let format = matches.value_of("LOG_FORMAT").map(|f| {
+        f.parse::<logger::Format>().expect("Cannot parse `LOG_FORMAT`")
+    }).unwrap_or(logger::Format::Text);
+    let levels = match (matches.value_of("LOG_LEVEL"), matches.occurrences_of("verbose")) {
+        (Some(l), _) => logger::parse_levels(l).expect("Cannot parse `LOG_LEVEL`"),
+        (None, 0) => logger::Levels::new(slog::Level::Info),
+        (None, 1) => logger::Levels::new(slog::Level::Debug),
+        (None, _) => logger::Levels::new(slog::Level::Trace),
+    };
+    logger::init(log_output, format, levels)
+}
 
-        .arg(Arg::with_name("DEST_LIST_FILE")
-             .help("Sets the input file to use")
-             .index(1))
+/// Play every destination, then report. Returns the exit code.
+async fn play(matches: &ArgMatches<'_>) -> Result<i32, std::io::Error> {
+    let (root_logger, _guard) = init_logger(matches)?;
+    let urls = destinations(matches, &root_logger).await?;
+    let config = rtmp::player::Config {
+        duration: matches.value_of("DURATION").map(|d| {
+            units::parse_duration(d).expect("Cannot parse `DURATION`")
+        }),
+        stall_threshold: matches.value_of("STALL_THRESHOLD").map(|t| {
+            units::parse_duration(t).expect("Cannot parse `STALL_THRESHOLD`")
+        }).unwrap_or_else(|| Duration::from_secs(1)),
+        insecure_tls: matches.is_present("insecure-tls"),
+        family: family(matches),
+        latency: None,
+        record_dir: matches.value_of("RECORD_DIR").map(PathBuf::from),
+        verify: None,
+        pause_every: matches.value_of("PAUSE_EVERY").map(|p| {
+            units::parse_duration(p).ok().filter(|p| *p > Duration::from_secs(0)).expect("Cannot parse `PAUSE_EVERY`")
+        }),
+        seek_every: matches.value_of("SEEK_EVERY").map(|s| {
+            units::parse_duration(s).ok().filter(|s| *s > Duration::from_secs(0)).expect("Cannot parse `SEEK_EVERY`")
+        }),
+    };
+    if let Some(ref dir) = config.record_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    info!(root_logger, "Start players"; "clients" => urls.len());
+    let report = play::run(urls, config, &root_logger).await;
+    play::print(&report);
+    if let Some(path) = matches.value_of("JSON") {
+        play::write_json(&report, &PathBuf::from(path))?;
+    }
+    Ok(exit_code(report.clients, report.failed, false))
+}
 
-        .group(ArgGroup::with_name("prefix group")
-            .args(&["PREFIX"])
-            .conflicts_with("DEST_LIST_FILE")
-            .requires("CONCURRENCY"))
-        .group(ArgGroup::with_name("list group")
-            .arg("DEST_LIST_FILE")
-            .conflicts_with_all(&["prefix group", "CONCURRENCY"]))
-        .get_matches();
+/// Receive publishers until the duration or a signal, then report.
+async fn sink(matches: &ArgMatches<'_>) -> Result<i32, std::io::Error> {
+    let (root_logger, _guard) = init_logger(matches)?;
+    let addr = matches.value_of("LISTEN").unwrap().parse::<SocketAddr>().expect("Cannot parse `LISTEN`");
+    let interval = matches.value_of("STATS_INTERVAL").map(|i| {
+        Duration::from_secs(i.parse::<u64>().expect("Cannot parse `STATS_INTERVAL`"))
+    }).unwrap_or_else(|| Duration::from_secs(10));
+    let shutdown = shutdown::Shutdown::new();
+    tokio::spawn(shutdown::on_signals(shutdown.clone(), root_logger.clone()));
+    if let Some(duration) = matches.value_of("DURATION").map(|d| {
+        units::parse_duration(d).expect("Cannot parse `DURATION`")
+    }) {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(duration).await;
+            shutdown.trigger();
+        });
+    }
+    let report = sink::run(addr, interval, shutdown, &root_logger).await?;
+    sink::print(&report);
+    if let Some(path) = matches.value_of("JSON") {
+        sink::write_json(&report, &PathBuf::from(path))?;
+    }
+    Ok(EXIT_SUCCESS)
+}
 
-    let urls: Box<dyn Iterator<Item = String>> = if matches.is_present("PREFIX") {
+/// Publish the input to every destination, then report. Returns the exit code.
+async fn publish(matches: &ArgMatches<'_>) -> Result<i32, std::io::Error> {
+    let (root_logger, _guard) = init_logger(matches)?;
+    // The broadcasts stop at the deadline
+    let shutdown = match matches.value_of("DURATION") {
+        Some(d) => shutdown::Shutdown::with_duration(units::parse_duration(d).expect("Cannot parse `DURATION`")),
+        None => shutdown::Shutdown::new(),
+    };
+    let _panics = panic_hook::attach(root_logger.clone(), shutdown.clone());
+    tokio::spawn(shutdown::on_signals(shutdown.clone(), root_logger.clone()));
+
+    if let Some(workers) = matches.value_of("WORKERS") {
+        // Without a broadcast here, the workers stop at the deadline of their own `--duration`
+        let (shutdown, logger) = (shutdown.clone(), root_logger.clone());
+        tokio::spawn(async move {
+            shutdown.deadline().await;
+            info!(logger, "Run duration reached, stop the workers");
+            shutdown.trigger();
+        });
+        return coordinate(matches, workers, shutdown, root_logger).await;
+    }
+
+    #[cfg(feature = "flame")]
+    let _flame_guard = matches.value_of("FLAME").map(|path| {
+        logger::init_flame(path).expect("Cannot initialize flame recording")
+    });
+    #[cfg(feature = "otlp")]
+    let _otlp_guard = matches.value_of("OTLP_ENDPOINT").map(|endpoint| {
+        waterfall::otlp::init(endpoint, root_logger.clone()).expect("Cannot initialize the OTLP export")
+    });
+
+    let urls = assigned_destinations(matches, &root_logger).await?;
+    let mut repeat = matches.is_present("repeat");
+
+    // The idle sessions and the companions get the first rendition without `--input`
+    let inputs = match matches.values_of("INPUT") {
+        Some(inputs) => flv::inputs(inputs),
+        None => flv::inputs(renditions(matches).unwrap().into_iter().take(1).map(|(_, input)| input)),
+    }.expect("Cannot read `INPUT`");
+    let preload = matches.is_present("preload");
+    // A pipe is played once, by a single reader, unless it is preloaded
+    if !preload && inputs.iter().any(|p| p == flv::STDIN) {
+        assert!(inputs.iter().filter(|p| *p == flv::STDIN).count() == 1, "stdin can only be read once");
+        assert!(!matches.is_present("desync") && !matches.is_present("round-robin-inputs"),
+            "`--desync` and `--round-robin-inputs` need more than one reader of the input");
+        if repeat {
+            warn!(root_logger, "Input from stdin cannot be repeated, playing it once");
+            repeat = false;
+        }
+    }
+    let speed = matches.value_of("SPEED").map(|s| {
+        s.trim_end_matches('x').parse::<f64>().expect("Cannot parse `SPEED`")
+    }).unwrap_or(1.0);
+    assert!(speed > 0.0, "`SPEED` must be positive");
+    let repeat_count = matches.value_of("repeat").map(|n| {
+        n.parse::<u32>().ok().filter(|n| *n > 0).expect("Cannot parse `repeat` count")
+    });
+    let strict = matches.is_present("strict");
+    let offset_ms = |name: &str| matches.value_of(name).map(|o| {
+        let offset = units::parse_duration(o).unwrap_or_else(|_| panic!("Cannot parse `{}`", name));
+        u32::try_from(offset.as_millis()).unwrap_or_else(|_| panic!("`{}` is too far into the input", name))
+    });
+    let trim_start_ms = offset_ms("START_OFFSET").unwrap_or(0);
+    let trim_end_ms = offset_ms("END_OFFSET");
+    assert!(trim_end_ms.map_or(true, |end| end > trim_start_ms), "`END_OFFSET` must be after `START_OFFSET`");
+    let read_options = flv::ReadOptions {
+        repeat, repeat_count, speed, strict, trim_start_ms, trim_end_ms, preload,
+        burst: matches.value_of("BURST").map(|b| units::parse_duration(b).expect("Cannot parse `BURST`")).unwrap_or_default(),
+        audio: !matches.is_present("no-audio"),
+        video: !matches.is_present("no-video"),
+        ..Default::default()
+    };
+    let desync = matches.is_present("desync");
+
+    let assertions = assertions(matches)?;
+
+    let events = if matches.is_present("EVENTS") { Some(events::Events::new().stdout()) } else { None };
+    #[cfg(feature = "http")]
+    let events = match matches.value_of("WEBHOOK") {
+        Some(url) => Some(events.unwrap_or_default().webhook(url.to_owned(), root_logger.clone())),
+        None => events,
+    };
+    let events = events.map(Arc::new);
+    let stats = match events {
+        Some(ref events) => stats::Stats::with_events(events.clone()),
+        None => stats::Stats::new(),
+    };
+    let stats = Arc::new(if matches.is_present("fail-fast") { stats.with_fail_fast(shutdown.clone()) } else { stats });
+    if let Some(events) = events.as_ref().filter(|events| events.on_stdout()) {
+        let interval = matches.value_of("EVENTS_INTERVAL").map(|c| {
+            c.parse::<u64>().expect("Cannot parse `EVENTS_INTERVAL`")
+        }).unwrap_or(10);
+        tokio::spawn(events::periodic_stats(stats.clone(), events.clone(), Duration::from_secs(interval)));
+    }
+    // The logs are discarded while the dashboard has the terminal, the panics go to stderr once
+    // it is given back. Declared first, so dropped after the dashboard
+    #[cfg(feature = "tui")]
+    let _held = if matches.is_present("tui") { Some(panic_hook::hold()) } else { None };
+    #[cfg(feature = "tui")]
+    let dashboard = if matches.is_present("tui") {
+        Some(ui::Dashboard::start(stats.clone(), shutdown.clone())?)
+    } else {
+        None
+    };
+    let checkpoint_path = matches.value_of("CHECKPOINT").map(PathBuf::from);
+    if let Some(ref path) = checkpoint_path {
+        let interval = matches.value_of("CHECKPOINT_INTERVAL").map(|c| {
+            c.parse::<u64>().expect("Cannot parse `CHECKPOINT_INTERVAL`")
+        }).unwrap_or(30);
+        tokio::spawn(report::checkpoint(stats.clone(), path.clone(), Duration::from_secs(interval), root_logger.clone()));
+    }
+    let metrics_interval = Duration::from_secs(matches.value_of("METRICS_INTERVAL").map(|c| {
+        c.parse::<u64>().expect("Cannot parse `METRICS_INTERVAL`")
+    }).unwrap_or(15));
+    if let Some(path) = matches.value_of("METRICS_TEXTFILE") {
+        tokio::spawn(metrics::textfile(stats.clone(), PathBuf::from(path), metrics_interval, root_logger.clone()));
+    }
+    let mut pushes = Vec::new();
+    if let Some(addr) = matches.value_of("STATSD") {
+        pushes.push(metrics::parse_statsd(addr).expect("Cannot parse `STATSD`"));
+    }
+    #[cfg(feature = "http")]
+    pushes.extend(matches.value_of("INFLUX_URL").map(|url| metrics::Push::Influx(url.to_owned())));
+    let metrics_tags = matches.values_of("METRICS_TAG").map_or_else(Vec::new, |tags| {
+        tags.map(|t| metrics::parse_tag(t).expect("Cannot parse `METRICS_TAG`")).collect()
+    });
+    for target in pushes {
+        tokio::spawn(metrics::push(stats.clone(), target, metrics_tags.clone(), metrics_interval, root_logger.clone()));
+    }
+    if matches.is_present("HTML") {
+        tokio::spawn(report::sample(stats.clone(), Duration::from_secs(1)));
+    }
+
+    let stats_interval = matches.value_of("STATS_INTERVAL").map(|s| {
+        Duration::from_secs(s.parse::<u64>().ok().filter(|s| *s > 0).expect("Cannot parse `STATS_INTERVAL`"))
+    });
+    if let Some(interval) = stats_interval {
+        tokio::spawn(report::log_totals(stats.clone(), interval, root_logger.clone()));
+    }
+    let client_config = rtmp::client::Config {
+        republish_each_loop: matches.is_present("republish-each-loop"),
+        republish_stream: matches.value_of("REPUBLISH_STREAM").map(|s| {
+            s.parse().expect("Cannot parse `REPUBLISH_STREAM`")
+        }),
+        timestamp_jitter_ms: matches.value_of("TIMESTAMP_JITTER").map(|j| {
+            let j = j.trim_start_matches('±').trim_start_matches("+-");
+            units::parse_duration(j).expect("Cannot parse `TIMESTAMP_JITTER`").as_millis() as u32
+        }).unwrap_or(0),
+        bytes_per_client: matches.value_of("BYTES_PER_CLIENT").map(|b| {
+            units::parse_size(b).expect("Cannot parse `BYTES_PER_CLIENT`")
+        }),
+        max_bitrate_bps: matches.value_of("MAX_BITRATE").map(|b| {
+            units::parse_size(b).expect("Cannot parse `MAX_BITRATE`")
+        }),
+        drop_on_backpressure: matches.is_present("drop-on-backpressure"),
+        measure_latency: matches.is_present("measure-latency"),
+        metadata_interval: matches.value_of("METADATA_INTERVAL").map(|s| {
+            units::parse_duration(s).ok().filter(|d| *d > Duration::from_secs(0)).expect("Cannot parse `METADATA_INTERVAL`")
+        }),
+        unacked_alarm: matches.value_of("UNACKED_ALARM").map(|s| {
+            units::parse_size(s).expect("Cannot parse `UNACKED_ALARM`")
+        }),
+        net_delay: matches.value_of("NET_DELAY").map(|d| {
+            units::parse_duration(d).expect("Cannot parse `NET_DELAY`")
+        }).filter(|d| *d > Duration::from_secs(0)),
+        net_stall: matches.value_of("NET_STALL").map(|s| {
+            rtmp::impair::parse_stall(s).expect("Cannot parse `NET_STALL`")
+        }),
+        replace_exhausted: matches.is_present("replace-exhausted"),
+        tcp_keepalive: matches.value_of("TCP_KEEPALIVE").map(|k| {
+            units::parse_duration(k).expect("Cannot parse `TCP_KEEPALIVE`")
+        }),
+        tcp_keepalive_interval: matches.value_of("TCP_KEEPALIVE_INTERVAL").map(|k| {
+            units::parse_duration(k).expect("Cannot parse `TCP_KEEPALIVE_INTERVAL`")
+        }),
+        tcp_nodelay: matches.is_present("tcp-nodelay"),
+        send_buffer: matches.value_of("SEND_BUFFER").map(|s| {
+            units::parse_size(s).ok().filter(|s| *s > 0).expect("Cannot parse `SEND_BUFFER`") as usize
+        }),
+        server_silence_timeout: matches.value_of("SERVER_SILENCE_TIMEOUT").map(|t| {
+            units::parse_duration(t).expect("Cannot parse `SERVER_SILENCE_TIMEOUT`")
+        }),
+        connect_timeout: stage_timeout(matches, "CONNECT_TIMEOUT", Duration::from_secs(10)),
+        handshake_timeout: stage_timeout(matches, "HANDSHAKE_TIMEOUT", Duration::from_secs(10)),
+        publish_timeout: stage_timeout(matches, "PUBLISH_TIMEOUT", Duration::from_secs(30)),
+        insecure_tls: matches.is_present("insecure-tls"),
+        reconnect_on_close: matches.value_of("RECONNECT_ON_CLOSE").map(|w| {
+            units::parse_duration(w).expect("Cannot parse `RECONNECT_ON_CLOSE`")
+        }),
+        stats_interval,
+        reconnect_retries: matches.value_of("RECONNECT_RETRIES").map(|r| {
+            r.parse::<u32>().expect("Cannot parse `RECONNECT_RETRIES`")
+        }).unwrap_or(0),
+        reconnect_backoff: matches.value_of("RECONNECT_BACKOFF").map(|b| {
+            units::parse_duration(b).expect("Cannot parse `RECONNECT_BACKOFF`")
+        }).unwrap_or_else(|| Duration::from_secs(1)),
+        churn: matches.value_of("CHURN").map(|c| {
+            let fraction = units::parse_ratio(c).ok().filter(|f| *f > 0.0 && *f <= 1.0).expect("Cannot parse `CHURN`");
+            let interval = matches.value_of("CHURN_INTERVAL").map(|i| {
+                units::parse_duration(i).ok().filter(|i| *i > Duration::from_secs(0)).expect("Cannot parse `CHURN_INTERVAL`")
+            }).unwrap_or_else(|| Duration::from_secs(10));
+            rtmp::client::Churn { fraction, interval }
+        }),
+        idle: false,
+        bitrate_cap_bps: None,
+        proxy: matches.value_of("PROXY").map(|p| {
+            rtmp::proxy::parse(p).expect("Cannot parse `PROXY`")
+        }),
+        bind: matches.values_of("BIND").map(|b| {
+            Arc::new(rtmp::bind::parse(b).expect("Cannot parse `BIND`"))
+        }),
+        connect_rate: matches.value_of("CONNECT_RATE").map(|r| {
+            Arc::new(rtmp::connect_rate::parse(r).expect("Cannot parse `CONNECT_RATE`"))
+        }),
+        srt_transmit: matches.value_of("SRT_TRANSMIT").map(str::to_owned),
+        gst_launch: matches.value_of("GST_LAUNCH").map(str::to_owned),
+        shards: matches.value_of("RUNTIMES").map(|n| {
+            let count = n.parse::<usize>().ok().filter(|n| *n > 0).expect("Cannot parse `RUNTIMES`");
+            Arc::new(rtmp::shards::Shards::start(count).expect("Cannot start the runtimes of `RUNTIMES`"))
+        }),
+        family: family(matches),
+        chunk_size: matches.value_of("CHUNK_SIZE").map(|s| {
+            units::parse_size(s).ok()
+                .filter(|s| (1..=0x7fff_ffff).contains(s))
+                .expect("Cannot parse `CHUNK_SIZE`") as u32
+        }),
+        window_ack_size: matches.value_of("WINDOW_ACK_SIZE").map(|s| {
+            units::parse_size(s).ok()
+                .filter(|s| (1..=u64::from(u32::MAX)).contains(s))
+                .expect("Cannot parse `WINDOW_ACK_SIZE`") as u32
+        }),
+        connect: connect_params(matches).map(Arc::new),
+        metadata: metadata_overrides(matches).map(Arc::new),
+        publish_type: matches.value_of("PUBLISH_TYPE").map(|t| {
+            t.parse().expect("Cannot parse `PUBLISH_TYPE`")
+        }).unwrap_or_default(),
+        pre_publish: matches.value_of("PRE_PUBLISH").map(|s| {
+            rtmp::client::parse_pre_publish(s).expect("Cannot parse `PRE_PUBLISH`")
+        }),
+        auth: matches.value_of("AUTH").map(|a| {
+            let mut parts = a.splitn(2, ':');
+            let user = parts.next().filter(|u| !u.is_empty()).expect("Cannot parse `AUTH`");
+            (user.to_owned(), parts.next().unwrap_or("").to_owned())
+        }),
+    };
+
+    let mut run = waterfall::PublishRun::builder()
+        .read_options(read_options)
+        .desync(desync)
+        .round_robin_inputs(matches.is_present("round-robin-inputs"))
+        .start_immediately(matches.is_present("start-immediately"))
+        .logger(root_logger.clone());
+    for input in &inputs {
+        run = run.input(input.as_str());
+    }
+
+    let queue_size = matches.value_of("QUEUE_SIZE").map(|s| {
+        s.parse::<usize>().ok().filter(|s| *s > 0).expect("Cannot parse `QUEUE_SIZE`")
+    }).unwrap_or(fanout::DEFAULT_QUEUE_SIZE);
+    let drop_policy = matches.value_of("DROP_POLICY").map(|p| {
+        p.parse::<fanout::DropPolicy>().expect("Cannot parse `DROP_POLICY`")
+    }).unwrap_or_default();
+    run = run.queue(queue_size, drop_policy);
+
+    let idle_sessions = matches.value_of("IDLE_SESSIONS").map(|n| {
+        n.parse::<usize>().expect("Cannot parse `IDLE_SESSIONS`")
+    }).unwrap_or(0);
+    let idle_only = matches.is_present("idle-only");
+    run = run.idle_sessions(idle_sessions, idle_only);
+    if matches.is_present("measure-latency") {
+        let url = companion_url(matches, "LATENCY_URL", &urls);
+        let interval = matches.value_of("LATENCY_INTERVAL").map(|i| {
+            units::parse_duration(i).ok().filter(|i| *i > Duration::from_secs(0)).expect("Cannot parse `LATENCY_INTERVAL`")
+        }).unwrap_or(latency::DEFAULT_INTERVAL);
+        match url {
+            Some(url) => {
+                let config = rtmp::player::Config { latency: Some(stats.latency()), ..companion_config(matches) };
+                let logger = root_logger.new(o!("latency" => url.to_string()));
+                info!(logger, "Measure the latency");
+                let session = format!("latency {}", url);
+                tokio::spawn(panic_hook::scope(session, rtmp::player::companion(url, config, shutdown.clone(), logger.clone())));
+                tokio::spawn(latency::log_intervals(stats.latency(), interval, logger));
+            }
+            None => warn!(root_logger, "No destination to measure the latency on"),
+        }
+    }
+    if matches.is_present("verify") {
+        let ledger = Arc::new(verify::Ledger::default());
+        stats.set_verify(ledger.clone());
+        match companion_url(matches, "VERIFY_URL", &urls) {
+            Some(url) => {
+                let config = rtmp::player::Config { verify: Some(ledger.clone()), ..companion_config(matches) };
+                let logger = root_logger.new(o!("verify" => url.to_string()));
+                info!(logger, "Verify the media played back");
+                let session = format!("verify {}", url);
+                tokio::spawn(panic_hook::scope(session, rtmp::player::companion(url, config, shutdown.clone(), logger)));
+            }
+            None => warn!(root_logger, "No destination to verify"),
+        }
+        run = run.verify(ledger);
+    }
+
+    let profile = if matches.is_present("adaptive") {
+        Some(load::Profile::Adaptive(load::Adaptive {
+            rate: matches.value_of("RAMP_RATE").map(|r| {
+                r.parse::<f64>().ok().filter(|r| *r > 0.0).expect("Cannot parse `RAMP_RATE`")
+            }).unwrap_or(1.0),
+            max_error_rate: matches.value_of("MAX_ERROR_RATE").map(|r| {
+                units::parse_ratio(r).expect("Cannot parse `MAX_ERROR_RATE`")
+            }).unwrap_or(0.01),
+            max_connect_ms: matches.value_of("MAX_CONNECT_TIME").map(|t| {
+                units::parse_duration(t).expect("Cannot parse `MAX_CONNECT_TIME`").as_millis() as u64
+            }).unwrap_or(5_000),
+        }))
+    } else if let Some(p) = matches.value_of("PROFILE") {
+        // A file of points, or the points themselves
+        let spec = if Path::new(p).is_file() { std::fs::read_to_string(p)? } else { p.to_owned() };
+        Some(load::Profile::Schedule(load::parse_schedule(&spec).expect("Cannot parse `PROFILE`")))
+    } else {
+        matches.value_of("STEPS").map(|s| {
+            load::Profile::Steps(load::parse_steps(s).expect("Cannot parse `STEPS`"))
+        })
+    };
+    let clients = if idle_only { 0 } else { urls.len() };
+    if let Some(profile) = profile {
+        run = run.profile(profile);
+    } else {
+        if let Some(w) = matches.value_of("RAMP_UP") {
+            run = run.ramp_up(load::RampUp {
+                window: units::parse_duration(w).expect("Cannot parse `RAMP_UP`"),
+                steps: matches.value_of("RAMP_STEPS").map(|s| {
+                    s.parse::<usize>().ok().filter(|s| *s > 0).expect("Cannot parse `RAMP_STEPS`")
+                }),
+            });
+        }
+        if let Some(s) = matches.value_of("MIN_SUCCESS") {
+            run = run.min_success(units::parse_ratio(s).expect("Cannot parse `MIN_SUCCESS`"), matches.is_present("ignore-connect-errors"));
+        }
+        if matches.is_present("watch") {
+            let path = matches.value_of("DEST_LIST_FILE").unwrap();
+            assert!(path != "-", "`--watch` needs a destination list file, not stdin");
+            run = run.watch(path.into(), Arc::new(line_parser(matches, root_logger.clone())));
+        }
+        if let Some(addr) = matches.value_of("CONTROL_LISTEN") {
+            let addr = addr.parse::<SocketAddr>().expect("Cannot parse `CONTROL_LISTEN`");
+            run = run.control(addr, Arc::new(line_parser(matches, root_logger.clone())));
+        }
+        if let Some(ref events) = events {
+            events.emit(&events::Event::Started { clients });
+        }
+    }
+    for (url, options) in urls {
+        let inputs = match options.input {
+            Some(ref input) => flv::inputs(std::iter::once(input.as_str())).expect("Cannot read the input of a destination"),
+            None => Vec::new(),
+        };
+        run = run.destination(publish::Destination {
+            config: options.config(&client_config).map(Arc::new),
+            delay: options.delay.unwrap_or_default(),
+            url,
+            inputs,
+        });
+    }
+    let mut aborted = false;
+    match run.client_config(client_config).run(&stats, &shutdown, &root_logger).await {
+        // Logged already, still report the clients which did connect
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotConnected => aborted = true,
+        other => other?,
+    }
+
+    if stats.failed_fast() {
+        warn!(root_logger, "A client failed, the run was aborted with `--fail-fast`");
+    }
+    if stats.input_errors() > 0 {
+        warn!(root_logger, "An input failed to read, the run was aborted"; "inputs" => stats.input_errors());
+    }
+    let mut final_report = stats.report(true);
+    final_report.assertions = assertions.iter().map(|a| a.evaluate(&final_report)).collect();
+    if final_report.summary.dropped_messages > 0 {
+        warn!(root_logger, "Broadcast messages were dropped, the clients did not get the whole input";
+              "lagged_clients" => final_report.summary.lagged, "dropped" => final_report.summary.dropped_messages);
+    }
+    for (code, count) in &final_report.summary.statuses {
+        info!(root_logger, "Status from the server"; "code" => code, "clients" => count);
+    }
+    if let Some(integrity) = final_report.integrity {
+        if integrity.missing + integrity.corrupted + integrity.reordered + integrity.audio_gaps + integrity.audio_overlaps > 0 {
+            warn!(root_logger, "The media played back differs from the media published";
+                  "missing" => integrity.missing, "corrupted" => integrity.corrupted, "reordered" => integrity.reordered,
+                  "audio_gaps" => integrity.audio_gaps, "audio_overlaps" => integrity.audio_overlaps,
+                  "matched" => integrity.matched);
+        } else {
+            info!(root_logger, "The media played back is intact"; "matched" => integrity.matched, "sent" => integrity.sent);
+        }
+    }
+    if let Some(ref path) = checkpoint_path {
+        if let Err(e) = report::write_json(&final_report, path) {
+            warn!(root_logger, "Write final checkpoint error"; "path" => %path.display(), "error" => %e);
+        }
+    }
+
+    write_reports(matches, &final_report, &root_logger);
+
+    if let Some(path) = matches.value_of("HTML") {
+        stats.sample();
+        let mut report = stats.report(true);
+        report.assertions = final_report.assertions.clone();
+        if let Err(e) = html::write(&report, path.as_ref()) {
+            warn!(root_logger, "Write HTML report error"; "path" => path, "error" => %e);
+        }
+    }
+    if let Some(path) = matches.value_of("JUNIT") {
+        if let Err(e) = junit::write(&final_report, path.as_ref()) {
+            warn!(root_logger, "Write JUnit report error"; "path" => path, "error" => %e);
+        }
+    }
+
+    #[cfg(feature = "tui")]
+    if let Some(dashboard) = dashboard {
+        dashboard.stop();
+    }
+    info!(root_logger, "End");
+    let failed = match events {
+        // Keep stdout to the events
+        Some(ref events) if events.on_stdout() => {
+            events.emit(&events::Event::Stats { duration_secs: final_report.duration_secs, summary: &final_report.summary });
+            for assertion in &final_report.assertions {
+                events.emit(&events::Event::Assertion(assertion));
+            }
+            final_report.assertions.iter().filter(|a| !a.passed).count()
+        }
+        _ => slo::print(&final_report.assertions),
+    };
+    if failed > 0 {
+        warn!(root_logger, "Assertions failed"; "failed" => failed, "total" => final_report.assertions.len());
+    }
+    let summary = &final_report.summary;
+    let code = exit_code(summary.clients, summary.failed, failed > 0 || aborted || stats.failed_fast() || stats.input_errors() > 0 || panic_hook::panicked());
+    if let Some(ref events) = events {
+        events.emit(&events::Event::Finished { duration_secs: final_report.duration_secs, summary, exit_code: code });
+        events.close();
+    }
+    Ok(code)
+}
+
+/// `--assert` and those of `--baseline`
+fn assertions(matches: &ArgMatches<'_>) -> Result<Vec<slo::Assertion>, std::io::Error> {
+    let mut assertions = matches.values_of("ASSERT").map(|values| {
+        values.map(|a| slo::parse(a).expect("Cannot parse `ASSERT`")).collect::<Vec<_>>()
+    }).unwrap_or_default();
+    if let Some(path) = matches.value_of("BASELINE") {
+        let baseline = compare::read_report(path.as_ref())?;
+        let tolerance = matches.value_of("TOLERANCE").map(|t| {
+            units::parse_ratio(t).expect("Cannot parse `TOLERANCE`")
+        }).unwrap_or(0.1);
+        assertions.extend(slo::from_baseline(&baseline, tolerance));
+    }
+    Ok(assertions)
+}
+
+/// Every `--report`, CSV or JSON by the extension
+fn write_reports(matches: &ArgMatches<'_>, final_report: &report::Report, logger: &Logger) {
+    for path in matches.values_of("REPORT").into_iter().flatten() {
+        let written = if path.ends_with(".csv") || path.ends_with(".CSV") {
+            report::write_csv(final_report, path.as_ref())
+        } else {
+            report::write_json(final_report, path.as_ref())
+        };
+        if let Err(e) = written {
+            warn!(logger, "Write report error"; "path" => path, "error" => %e);
+        }
+    }
+}
+
+/// Options the coordinator of `--workers` handles itself, and which take a value
+const COORDINATOR_OPTIONS: &[&str] = &[
+    "--workers", "--config", "-p", "--prefix", "-c", "--concurrency", "--report", "--checkpoint", "--checkpoint-interval",
+    "--html", "--junit", "--assert", "--baseline", "--tolerance", "--control-listen", "--events", "--events-interval",
+    "--metrics-textfile",
+];
+
+/// The arguments of this run for the workers: the options of `--config` merged in, without the
+/// destinations and what the coordinator does itself
+fn worker_args(matches: &ArgMatches<'_>) -> Vec<String> {
+    let args = config::merge(with_default_subcommand(std::env::args_os())).expect("Cannot read `CONFIG`");
+    let mut args = args.into_iter().skip(1).map(|a| {
+        a.into_string().expect("`--workers` needs UTF-8 arguments")
+    }).collect::<Vec<_>>();
+    if let Some(list) = matches.value_of("DEST_LIST_FILE") {
+        if let Some(i) = args.iter().rposition(|a| a == list) {
+            args.remove(i);
+        }
+    }
+    let mut kept = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let name = arg.split('=').next().unwrap_or_default();
+        if COORDINATOR_OPTIONS.contains(&name) {
+            if !arg.contains('=') {
+                args.next();
+            }
+        } else if !(arg.len() > 2 && (arg.starts_with("-p") || arg.starts_with("-c"))) {
+            kept.push(arg);
+        }
+    }
+    kept
+}
+
+/// `--workers`: the run published by the workers, their reports merged here
+async fn coordinate(matches: &ArgMatches<'_>, workers: &str, shutdown: shutdown::Shutdown, root_logger: Logger) -> Result<i32, std::io::Error> {
+    let workers = workers.split(',').map(|w| w.trim().to_owned()).filter(|w| !w.is_empty()).collect::<Vec<_>>();
+    assert!(!workers.is_empty(), "`--workers` needs at least one worker");
+    let destinations = destination_lines(matches)?;
+    let assertions = assertions(matches)?;
+    info!(root_logger, "Coordinating the workers"; "workers" => workers.len(), "destinations" => destinations.len());
+    // The live view of the whole run, the progress of the workers merged
+    let checkpoint_path = matches.value_of("CHECKPOINT").map(PathBuf::from);
+    let metrics_path = matches.value_of("METRICS_TEXTFILE").map(PathBuf::from);
+    let logger = root_logger.clone();
+    let live = move |report: &report::Report| {
+        if let Some(ref path) = checkpoint_path {
+            if let Err(e) = report::write_json(report, path) {
+                warn!(logger, "Write checkpoint error"; "path" => %path.display(), "error" => %e);
+            }
+        }
+        if let Some(ref path) = metrics_path {
+            if let Err(e) = metrics::write(report, path) {
+                warn!(logger, "Write metrics textfile error"; "path" => %path.display(), "error" => %e);
+            }
+        }
+    };
+    let outcomes = distributed::coordinate(workers, worker_args(matches), destinations, distributed::PROGRESS_INTERVAL,
+                                           live, shutdown, root_logger.clone()).await;
+    let failed_workers = outcomes.iter().filter(|o| o.report.is_none()).count();
+    let mut final_report = match distributed::merge(outcomes.into_iter().filter_map(|o| o.report).collect()) {
+        Some(report) => report,
+        None => {
+            error!(root_logger, "No worker sent a report");
+            return Ok(EXIT_ALL_FAILED);
+        }
+    };
+    final_report.assertions = assertions.iter().map(|a| a.evaluate(&final_report)).collect();
+    write_reports(matches, &final_report, &root_logger);
+    if let Some(path) = matches.value_of("HTML") {
+        if let Err(e) = html::write(&final_report, path.as_ref()) {
+            warn!(root_logger, "Write HTML report error"; "path" => path, "error" => %e);
+        }
+    }
+    if let Some(path) = matches.value_of("JUNIT") {
+        if let Err(e) = junit::write(&final_report, path.as_ref()) {
+            warn!(root_logger, "Write JUnit report error"; "path" => path, "error" => %e);
+        }
+    }
+    let failed = slo::print(&final_report.assertions);
+    if failed > 0 {
+        warn!(root_logger, "Assertions failed"; "failed" => failed, "total" => final_report.assertions.len());
+    }
+    let summary = &final_report.summary;
+    info!(root_logger, "End"; "clients" => summary.clients, "publishing" => summary.publishing, "failed" => summary.failed,
+          "failed_workers" => failed_workers);
+    Ok(exit_code(summary.clients, summary.failed, failed > 0 || failed_workers > 0))
+}
+
+/// Destination options shared by the modes which open a session per destination.
+fn destination_args<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(Arg::with_name("CONCURRENCY")
+        .short("c")
+        .long("concurrency")
+        .takes_value(true))
+    .arg(Arg::with_name("PREFIX")
+        .short("p")
+        .long("prefix")
+        .help("RTMP destinations prefix, e.g. `rtmp://example.com/app/stream_`, or a pattern like \
+               `rtmp://example.com/app/stream_{index:04}_{rand:8}` with `{index}`, `{rand:N}` and `{uuid}`")
+        .takes_value(true))
+    .arg(Arg::with_name("PORT")
+        .long("port")
+        .help("Port of destinations without an explicit one, instead of 1935 (80 for rtmpt)")
+        .takes_value(true))
+    .arg(Arg::with_name("insecure-tls")
+        .long("insecure-tls")
+        .help("Accept any certificate from `rtmps://` destinations, for lab servers"))
+    .arg(Arg::with_name("ipv4")
+        .short("4")
+        .long("ipv4")
+        .help("Only connect to the IPv4 addresses of the destinations")
+        .conflicts_with("ipv6"))
+    .arg(Arg::with_name("ipv6")
+        .short("6")
+        .long("ipv6")
+        .help("Only connect to the IPv6 addresses of the destinations"))
+    .arg(Arg::with_name("expand-dns")
+        .long("expand-dns")
+        .help("Create one client per resolved address of each destination host"))
+    .arg(Arg::with_name("TC_URL")
+        .long("tc-url")
+        .help("Sends this tcUrl in the connect command instead of the one derived from the destination, \
+               e.g. `rtmp://vhost.example.com/live`. A `tcurl` query parameter of a destination takes precedence")
+        .takes_value(true))
+    .arg(Arg::with_name("DEST_LIST_FILE")
+         .help("File with one destination url per line, `-` reads them from stdin")
+         .index(1))
+    .group(ArgGroup::with_name("prefix group")
+        .args(&["PREFIX"])
+        .conflicts_with("DEST_LIST_FILE")
+        .requires("CONCURRENCY"))
+    .group(ArgGroup::with_name("list group")
+        .arg("DEST_LIST_FILE")
+        .conflicts_with_all(&["prefix group", "CONCURRENCY"]))
+}
+
+/// A timeout of `--connect-timeout` and the like, `0` is none
+fn stage_timeout(matches: &ArgMatches<'_>, name: &str, default: Duration) -> Option<Duration> {
+    let timeout = matches.value_of(name).map(|t| {
+        units::parse_duration(t).unwrap_or_else(|_| panic!("Cannot parse `{}`", name))
+    }).unwrap_or(default);
+    Some(timeout).filter(|t| *t > Duration::from_secs(0))
+}
+
+/// The overrides of the connect command, `None` without any
+fn connect_params(matches: &ArgMatches<'_>) -> Option<rtmp::client::ConnectParams> {
+    let params = rtmp::client::ConnectParams {
+        app: matches.value_of("CONNECT_APP").map(str::to_owned),
+        flash_ver: matches.value_of("FLASH_VER").map(str::to_owned),
+        swf_url: matches.value_of("SWF_URL").map(str::to_owned),
+        page_url: matches.value_of("PAGE_URL").map(str::to_owned),
+        extra: matches.values_of("CONNECT_PARAM").into_iter().flatten().map(|p| {
+            rtmp::client::ConnectParams::parse_extra(p).expect("Cannot parse `CONNECT_PARAM`")
+        }).collect(),
+    };
+    Some(params).filter(|p| !p.is_empty())
+}
+
+/// `--metadata-json`, then `--metadata` over it, `None` without any
+fn metadata_overrides(matches: &ArgMatches<'_>) -> Option<HashMap<String, rml_amf0::Amf0Value>> {
+    let mut properties = matches.value_of("METADATA_JSON").map(|path| {
+        let json = std::fs::read_to_string(path).expect("Cannot read `METADATA_JSON`");
+        rtmp::client::parse_metadata_json(&json).expect("Cannot parse `METADATA_JSON`")
+    }).unwrap_or_default();
+    properties.extend(matches.values_of("METADATA").into_iter().flatten().map(|p| {
+        rtmp::client::ConnectParams::parse_extra(p).expect("Cannot parse `METADATA`")
+    }));
+    Some(properties).filter(|p| !p.is_empty())
+}
+
+/// The address family of `-4` / `-6`, any without
+fn family(matches: &ArgMatches<'_>) -> Option<rtmp::bind::Family> {
+    if matches.is_present("ipv4") {
+        Some(rtmp::bind::Family::V4)
+    } else if matches.is_present("ipv6") {
+        Some(rtmp::bind::Family::V6)
+    } else {
+        None
+    }
+}
+
+/// Build the destinations from `--prefix` or the list file, with the options of `destination_args`.
+async fn destinations(matches: &ArgMatches<'_>, root_logger: &Logger) -> Result<Vec<Url>, std::io::Error> {
+    let urls = assigned_destinations(matches, root_logger).await?;
+    Ok(urls.into_iter().map(|(url, _)| url).collect())
+}
+
+/// Options after the url on a line of the destination list: `key=value` pairs, or just the input
+#[derive(Clone, Debug, Default)]
+struct LineOptions {
+    input: Option<String>,
+    /// Connect this much later
+    delay: Option<Duration>,
+    bitrate_cap_bps: Option<u64>,
+    max_bitrate_bps: Option<u64>,
+    bytes_per_client: Option<u64>,
+    reconnect_retries: Option<u32>,
+}
+
+impl LineOptions {
+    fn parse<'a>(fields: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut options = Self::default();
+        for field in fields {
+            let (key, value) = match field.find('=') {
+                Some(i) => (&field[..i], &field[i + 1..]),
+                // A path alone is the input
+                None => ("input", field),
+            };
+            match key {
+                "input" => options.input = Some(value.to_owned()),
+                "delay" => options.delay = Some(units::parse_duration(value)?),
+                "bitrate_cap" => options.bitrate_cap_bps = Some(units::parse_size(value)?),
+                "max_bitrate" => options.max_bitrate_bps = Some(units::parse_size(value)?),
+                "bytes_per_client" => options.bytes_per_client = Some(units::parse_size(value)?),
+                "reconnect_retries" => options.reconnect_retries = Some(value.parse().map_err(|_| {
+                    format!("invalid reconnect_retries `{}`", value)
+                })?),
+                _ => return Err(format!("unknown option `{}`", key)),
+            }
+        }
+        Ok(options)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.input.is_none() && self.delay.is_none() && !self.overrides_config()
+    }
+
+    fn overrides_config(&self) -> bool {
+        self.bitrate_cap_bps.is_some() || self.max_bitrate_bps.is_some() || self.bytes_per_client.is_some() || self.reconnect_retries.is_some()
+    }
+
+    /// The config of the run with the overrides of the line, `None` without any
+    fn config(&self, base: &rtmp::client::Config) -> Option<rtmp::client::Config> {
+        if !self.overrides_config() {
+            return None;
+        }
+        Some(rtmp::client::Config {
+            bitrate_cap_bps: self.bitrate_cap_bps.or(base.bitrate_cap_bps),
+            max_bitrate_bps: self.max_bitrate_bps.or(base.max_bitrate_bps),
+            bytes_per_client: self.bytes_per_client.or(base.bytes_per_client),
+            reconnect_retries: self.reconnect_retries.unwrap_or(base.reconnect_retries),
+            ..base.clone()
+        })
+    }
+}
+
+/// `--renditions`: the name and the input of each rendition, in order
+fn renditions(matches: &ArgMatches<'_>) -> Option<Vec<(String, String)>> {
+    let renditions = matches.value_of("RENDITIONS")?.split(',').map(|r| {
+        let mut parts = r.trim().splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(input)) if !name.is_empty() && !input.is_empty() => (name.to_owned(), input.to_owned()),
+            _ => panic!("Cannot parse `RENDITIONS`: expected `<name>=<input>`, got `{}`", r),
+        }
+    }).collect::<Vec<_>>();
+    let mut names = renditions.iter().map(|(name, _)| name).collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    assert!(names.len() == renditions.len(), "The names of `RENDITIONS` must differ");
+    Some(renditions)
+}
+
+/// Parse the destinations added during the run, with the port and tcUrl of the run.
+/// They share the config of the run, their options are ignored.
+fn line_parser(matches: &ArgMatches<'_>, logger: Logger) -> impl Fn(&str) -> Result<Url, String> + Send + 'static {
+    let default_port = matches.value_of("PORT").map(|p| {
+        p.parse::<u16>().expect("Cannot parse `PORT`")
+    });
+    let tc_url = matches.value_of("TC_URL").map(str::to_owned);
+    move |line: &str| {
+        let (mut url, options) = parse_line(line, default_port)?;
+        if !options.is_empty() {
+            warn!(logger, "Options of an added destination are ignored"; "url" => %url);
+        }
+        if url.tc_url.is_none() {
+            url.tc_url = tc_url.clone();
+        }
+        Ok(url)
+    }
+}
+
+/// A line of the destination list: the url and its backups after `|`, then its options
+fn parse_line(line: &str, default_port: Option<u16>) -> Result<(Url, LineOptions), String> {
+    let mut fields = line.split_whitespace().peekable();
+    let mut url = rtmp_url::parse_rtmp_url(fields.next().unwrap_or_default(), default_port)?;
+    while fields.peek() == Some(&"|") {
+        fields.next();
+        let backup = fields.next().ok_or_else(|| format!("{}: no backup url after `|`", url))?;
+        url.backups.push(rtmp_url::parse_rtmp_url(backup, default_port)?);
+    }
+    let options = LineOptions::parse(fields).map_err(|e| format!("{}: {}", url, e))?;
+    Ok((url, options))
+}
+
+/// The destinations with the options given after the url on its line of the list file, if any.
+/// The destination lines, from `--prefix` or the list, before they are parsed
+fn destination_lines(matches: &ArgMatches<'_>) -> Result<Vec<String>, std::io::Error> {
+    let lines: Box<dyn Iterator<Item = String>> = if matches.is_present("PREFIX") {
         let concurrency = matches.value_of("CONCURRENCY").map(|c| {
             c.parse::<usize>().expect("Cannot parse `CONCURRENCY`")
         }).unwrap_or(1);
         let prefix = matches.value_of("PREFIX").unwrap();
-        let urls = (0..concurrency).map(move |c| format!("{}{}", prefix, c));
+        let urls = (0..concurrency).map(move |c| {
+            rtmp_url::expand_template(prefix, c).expect("Cannot expand `PREFIX`")
+        });
         Box::new(urls)
     } else {
-        // Read from list file
+        // Read from list file, or stdin for `-`
         let dest_file_path = matches.value_of("DEST_LIST_FILE").unwrap();
-        let list_file = File::open(dest_file_path)?;
-        let reader = BufReader::new(list_file);
-        let urls = reader.lines().map(|r| r.unwrap());
+        let reader: Box<dyn BufRead> = if dest_file_path == "-" {
+            assert!(matches.values_of("INPUT").map_or(true, |mut i| !i.any(|i| i == flv::STDIN)),
+                "stdin cannot be both the input and the destination list");
+            Box::new(BufReader::new(std::io::stdin()))
+        } else {
+            Box::new(BufReader::new(File::open(dest_file_path)?))
+        };
+        let urls = reader.lines().map(|r| r.unwrap()).filter(|line| !line.trim().is_empty());
         Box::new(urls)
     };
-    let urls = urls.map(|u| rtmp_url::parse_rtmp_url(u.as_str())).collect::<Vec<Result<Url, _>>>();
-    let repeat = matches.is_present("repeat");
+    Ok(lines.collect())
+}
+
+async fn assigned_destinations(matches: &ArgMatches<'_>, root_logger: &Logger) -> Result<Vec<(Url, LineOptions)>, std::io::Error> {
+    let lines = destination_lines(matches)?;
+    let default_port = matches.value_of("PORT").map(|p| {
+        p.parse::<u16>().expect("Cannot parse `PORT`")
+    });
+    let urls = lines.iter().map(|line| parse_line(&line, default_port)).collect::<Vec<Result<_, String>>>();
 
     if let Some(Err(e)) = urls.iter().find(|u| u.is_err()) {
         panic!("RTMP url error: {}", e);
     }
 
-    let urls = urls.into_iter().map(|r| r.unwrap()).collect::<Vec<Url>>();
-
-    let input_file_path = matches.value_of("INPUT").unwrap();
-    assert!(input_file_path.ends_with(".flv") || input_file_path.ends_with(".FLV"),
-        "Only FLV files are supported");
-    let msgs = flv::read_flv_tag(input_file_path, repeat, root_logger.clone()).await?;
-
-    let (tx, _rx) = tokio::sync::broadcast::channel(1024);
-
-    let clients = futures::stream::futures_unordered::FuturesUnordered::new();
-    for url in urls {
-        let rx = tx.subscribe();
-        let client_fut = rtmp::client::Client::new(url, rx, &root_logger);
-        clients.push(client_fut);
+    let mut urls = urls.into_iter().map(|r| r.unwrap()).collect::<Vec<(Url, LineOptions)>>();
+    if let Some(tc_url) = matches.value_of("TC_URL") {
+        for (url, _) in urls.iter_mut() {
+            let backups = url.backups.iter_mut();
+            for url in backups.filter(|u| u.tc_url.is_none()) {
+                url.tc_url = Some(tc_url.to_owned());
+            }
+            if url.tc_url.is_none() {
+                url.tc_url = Some(tc_url.to_owned());
+            }
+        }
     }
 
-    pin_mut!(msgs);
-    let mut msgs: Pin<&mut _> = msgs;
-
-    // await for all publish client ready
-    let _ = clients.collect::<Vec<_>>().await;
-    info!(root_logger, "All publish clients are ready");
+    if let Some(renditions) = renditions(matches) {
+        urls = urls.into_iter().flat_map(|(url, options)| {
+            renditions.iter().map(move |(name, input)| {
+                let suffixed = |url: &Url| Url { stream: format!("{}_{}", url.stream, name), ..url.clone() };
+                let url = Url { backups: url.backups.iter().map(suffixed).collect(), ..suffixed(&url) };
+                (url, LineOptions { input: Some(input.clone()), ..options.clone() })
+            }).collect::<Vec<_>>()
+        }).collect();
+    }
 
-    // broadcast
-    while let Some(Ok(msg)) = msgs.next().await {
-        if tx.receiver_count() <= 0 {
-            warn!(root_logger, "No publish client exists, quit");
-            break;
-        }
-        match tx.send(msg) {
-            Ok(_num) => { }
-            Err(_) => {
-                warn!(root_logger, "No publish client exists, quit");
-                break;
+    if matches.is_present("expand-dns") {
+        let mut expanded = Vec::with_capacity(urls.len());
+        for (url, options) in urls {
+            let host = url.host.clone();
+            let mut addrs = rtmp_url::expand_dns(url).await?;
+            if let Some(family) = family(matches) {
+                addrs.retain(|u| u.addr.map_or(true, |addr| family.matches(&addr)));
             }
+            info!(root_logger, "Expanded destination host"; "host" => host, "addresses" => addrs.len());
+            expanded.extend(addrs.into_iter().map(|addr| (addr, options.clone())));
         }
+        urls = expanded;
     }
-
-    info!(root_logger, "End");
-    Ok(())
-}
-
-#[derive(Clone, Debug)]
-pub enum PacketType {
-    Metadata(Arc<StreamMetadata>),
-    Video {
-        data: Bytes,
-        ts: RtmpTimestamp,
-    },
-    Audio {
-        data: Bytes,
-        ts: RtmpTimestamp,
-    },
-}
-
-#[derive(Debug)]
-pub enum ReceivedType {
-    FromClient {
-        message: rml_rtmp::messages::MessagePayload,
-        bytes_read: usize,
-    },
-    Broadcast(Arc<PacketType>),
+    Ok(urls)
 }
@@ -0,0 +1,253 @@
+//! Current metrics in the OpenMetrics text format, for the node_exporter textfile collector, or
+//! pushed to StatsD or InfluxDB, for load generators too short-lived to be scraped.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "http")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use slog::{warn, Logger};
+
+use crate::report::Report;
+use crate::stats::Stats;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// A metric and its values, by label if any
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    values: Vec<(Option<(&'static str, String)>, f64)>,
+}
+
+impl Metric {
+    fn single(name: &'static str, help: &'static str, kind: Kind, value: f64) -> Self {
+        Self { name, help, kind, values: vec![(None, value)] }
+    }
+}
+
+fn metrics(report: &Report) -> Vec<Metric> {
+    let summary = &report.summary;
+    let mut metrics = Vec::new();
+
+    metrics.push(Metric {
+        name: "waterfall_clients",
+        help: "Publish clients by state.",
+        kind: Kind::Gauge,
+        values: [
+            ("connecting", summary.connecting),
+            ("publishing", summary.publishing),
+            ("finished", summary.finished),
+            ("failed", summary.failed),
+            ("idle", summary.idle),
+        ].iter().map(|(state, count)| (Some(("state", state.to_string())), *count as f64)).collect(),
+    });
+
+    let counters = [
+        ("waterfall_sent_bytes", "Bytes sent to the servers.", summary.bytes_sent),
+        ("waterfall_reconnects", "Sessions published again.", report.clients.iter().map(|c| c.reconnects).sum()),
+        ("waterfall_codec_changes", "Sequence header changes on the input.", report.clients.iter().map(|c| c.codec_changes).sum()),
+        ("waterfall_dropped_messages", "Broadcast messages dropped from full client queues.", summary.dropped_messages),
+    ];
+    for (name, help, value) in &counters {
+        metrics.push(Metric::single(*name, *help, Kind::Counter, *value as f64));
+    }
+
+    let gauges = [
+        ("waterfall_never_acked_clients", "Clients without any acknowledgement from the server.", summary.never_acked),
+        ("waterfall_erratic_acks_clients", "Clients which received inconsistent acknowledgements.", summary.erratic_acks),
+        ("waterfall_unacked_alarm_clients", "Clients with more unacknowledged bytes than the alarm threshold.", summary.unacked_alarms),
+        ("waterfall_write_stalled_clients", "Clients with a write blocked by the socket for a second or more.", summary.write_stalled),
+        ("waterfall_lagged_clients", "Clients which had broadcast messages dropped.", summary.lagged),
+    ];
+    for (name, help, value) in &gauges {
+        metrics.push(Metric::single(*name, *help, Kind::Gauge, *value as f64));
+    }
+
+    let max_unacked = report.clients.iter().map(|c| c.acks.unacked_bytes).max().unwrap_or(0);
+    metrics.push(Metric::single("waterfall_max_unacked_bytes", "Most bytes a client sent beyond the last acknowledgement.",
+                                Kind::Gauge, max_unacked as f64));
+
+    let blocked_ms = report.clients.iter().map(|c| c.writes.blocked_ms).sum::<u64>();
+    metrics.push(Metric::single("waterfall_write_blocked_seconds", "Time writes waited for the sockets to take more data.",
+                                Kind::Counter, blocked_ms as f64 / 1000.0));
+
+    if let Some(p) = summary.connect_ms {
+        metrics.push(Metric {
+            name: "waterfall_connect_seconds",
+            help: "Time from start to publishing, by quantile.",
+            kind: Kind::Gauge,
+            values: [("0", p.min), ("0.5", p.p50), ("0.9", p.p90), ("0.99", p.p99), ("1", p.max)].iter()
+                .map(|(quantile, ms)| (Some(("quantile", quantile.to_string())), *ms as f64 / 1000.0))
+                .collect(),
+        });
+    }
+
+    metrics.push(Metric::single("waterfall_duration_seconds", "Time since the run started.", Kind::Gauge, report.duration_secs));
+    metrics
+}
+
+fn render(report: &Report) -> String {
+    let mut out = String::new();
+    for metric in metrics(report) {
+        let kind = match metric.kind { Kind::Counter => "counter", Kind::Gauge => "gauge" };
+        let _ = writeln!(out, "# TYPE {} {}\n# HELP {} {}", metric.name, kind, metric.name, metric.help);
+        let suffix = if metric.kind == Kind::Counter { "_total" } else { "" };
+        for (label, value) in &metric.values {
+            let label = label.as_ref().map(|(key, label)| format!("{{{}=\"{}\"}}", key, label)).unwrap_or_default();
+            let _ = writeln!(out, "{}{}{} {}", metric.name, suffix, label, value);
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// The metrics of `report` to `path`, e.g. of the workers merged by `--workers`. The collector
+/// may read at any time, so write through a temporary file as well
+pub fn write(report: &Report, path: &Path) -> io::Result<()> {
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(render(report).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Write the current metrics to `path` every `interval`, never returns.
+pub async fn textfile(stats: Arc<Stats>, path: PathBuf, interval: Duration, logger: Logger) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = write(&stats.report(false), &path) {
+            warn!(logger, "Write metrics textfile error"; "path" => %path.display(), "error" => %e);
+        }
+    }
+}
+
+/// Where `push` sends the metrics
+#[derive(Clone, Debug)]
+pub enum Push {
+    /// Gauges, and the increments of the counters since the last push, as UDP datagrams with the
+    /// tags in the name the way Telegraf reads them, e.g. `waterfall_clients,state=failed:2|g`
+    Statsd(SocketAddr),
+    /// Every value as InfluxDB line protocol, posted to a `/write` or `/api/v2/write` url
+    #[cfg(feature = "http")]
+    Influx(String),
+}
+
+/// Datagrams are kept below the usual MTU
+const STATSD_PACKET_SIZE: usize = 1400;
+
+/// Escape a tag key or value, or a measurement name, of the line protocol
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// `,key=value` of the label of a value and `tags`
+fn tag_set(label: &Option<(&'static str, String)>, tags: &[(String, String)]) -> String {
+    label.iter().map(|(key, value)| (key.to_string(), value.clone()))
+        .chain(tags.iter().cloned())
+        .map(|(key, value)| format!(",{}={}", escape(&key), escape(&value)))
+        .collect()
+}
+
+fn statsd_lines(metrics: &[Metric], tags: &[(String, String)], last: &mut HashMap<String, f64>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for metric in metrics {
+        for (label, value) in &metric.values {
+            let bucket = format!("{}{}", metric.name, tag_set(label, tags));
+            match metric.kind {
+                Kind::Gauge => lines.push(format!("{}:{}|g", bucket, value)),
+                Kind::Counter => {
+                    let delta = value - last.insert(bucket.clone(), *value).unwrap_or(0.0);
+                    lines.push(format!("{}:{}|c", bucket, delta.max(0.0)));
+                }
+            }
+        }
+    }
+    lines
+}
+
+#[cfg(feature = "http")]
+fn influx_lines(metrics: &[Metric], tags: &[(String, String)]) -> String {
+    let ns = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let mut out = String::new();
+    for metric in metrics {
+        for (label, value) in &metric.values {
+            let _ = writeln!(out, "{}{} value={} {}", escape(metric.name), tag_set(label, tags), value, ns);
+        }
+    }
+    out
+}
+
+/// Send the current metrics to `target` every `interval`, tagged with `tags`, never returns.
+pub async fn push(stats: Arc<Stats>, target: Push, tags: Vec<(String, String)>, interval: Duration, logger: Logger) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut last_counters = HashMap::new();
+    #[cfg(feature = "http")]
+    let client = reqwest::Client::new();
+    loop {
+        ticks.tick().await;
+        let metrics = metrics(&stats.report(false));
+        let pushed = match target {
+            Push::Statsd(addr) => send_statsd(addr, statsd_lines(&metrics, &tags, &mut last_counters)).await,
+            #[cfg(feature = "http")]
+            Push::Influx(ref url) => {
+                client.post(url).body(influx_lines(&metrics, &tags)).send().await
+                    .and_then(|r| r.error_for_status())
+                    .map(|_| ())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            }
+        };
+        if let Err(e) = pushed {
+            warn!(logger, "Push metrics error"; "target" => ?target, "error" => %e);
+        }
+    }
+}
+
+async fn send_statsd(addr: SocketAddr, lines: Vec<String>) -> io::Result<()> {
+    let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { (std::net::Ipv6Addr::UNSPECIFIED, 0).into() };
+    let mut socket = tokio::net::UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > STATSD_PACKET_SIZE {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Parse `host:port` of `--statsd`
+pub fn parse_statsd(s: &str) -> Result<Push, String> {
+    use std::net::ToSocketAddrs;
+    s.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()).map(Push::Statsd)
+        .ok_or_else(|| format!("invalid statsd address `{}`, expected `host:port`", s))
+}
+
+/// Parse the `key=value` of `--metrics-tag`
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.trim().is_empty() => Ok((key.trim().to_owned(), value.trim().to_owned())),
+        _ => Err(format!("invalid metrics tag `{}`, expected `key=value`", s)),
+    }
+}
@@ -0,0 +1,186 @@
+//! `--otlp-endpoint`: the `tracing` spans of the sessions exported as OTLP/HTTP JSON traces.
+//!
+//! Every publisher is a trace of its own, with the spans of its phases: TCP connect, handshake,
+//! the connect command, `createStream`, `publish` and the wait for the first media sent. Spans are
+//! batched and posted to `<endpoint>/v1/traces` from a thread of their own, so a slow collector
+//! never holds a session back. Trace level spans, one per message, are not recorded.
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde_json::{json, Value};
+use slog::{warn, Logger};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+/// How often the finished spans are posted
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const SPAN_KIND_INTERNAL: u32 = 1;
+
+/// A span being recorded, in the extensions of the registry
+struct Recorded {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_owned(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name().to_owned(), format!("{:?}", value)));
+    }
+}
+
+struct Exporter {
+    finished: Arc<Mutex<Vec<Value>>>,
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0).to_string()
+}
+
+impl<S> Layer<S> for Exporter
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        *metadata.level() <= Level::DEBUG
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<Recorded>().map(|r| (r.trace_id, r.span_id))
+        });
+        let mut rng = rand::thread_rng();
+        let mut attributes = Fields(Vec::new());
+        attrs.record(&mut attributes);
+        span.extensions_mut().insert(Recorded {
+            trace_id: parent.map_or_else(|| rng.gen(), |(trace_id, _)| trace_id),
+            span_id: rng.gen(),
+            parent_id: parent.map(|(_, span_id)| span_id),
+            start: SystemTime::now(),
+            attributes: attributes.0,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(recorded) = span.extensions_mut().get_mut::<Recorded>() {
+                let mut attributes = Fields(Vec::new());
+                values.record(&mut attributes);
+                recorded.attributes.extend(attributes.0);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let recorded = match span.extensions_mut().remove::<Recorded>() {
+            Some(recorded) => recorded,
+            None => return,
+        };
+        let attributes = recorded.attributes.iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect::<Vec<_>>();
+        let exported = json!({
+            "traceId": format!("{:032x}", recorded.trace_id),
+            "spanId": format!("{:016x}", recorded.span_id),
+            "parentSpanId": recorded.parent_id.map(|id| format!("{:016x}", id)).unwrap_or_default(),
+            "name": span.name(),
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": nanos(recorded.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": attributes,
+        });
+        if let Ok(mut finished) = self.finished.lock() {
+            finished.push(exported);
+        }
+    }
+}
+
+/// Posts what is left when dropped
+pub struct OtlpGuard {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Record the spans and post them to the OTLP/HTTP collector at `endpoint`, e.g.
+/// `http://localhost:4318`
+pub fn init(endpoint: &str, logger: Logger) -> Result<OtlpGuard, String> {
+    let url = if endpoint.ends_with("/v1/traces") {
+        endpoint.to_owned()
+    } else {
+        format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+    };
+    let finished = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry::Registry::default().with(Exporter { finished: finished.clone() });
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| e.to_string())?;
+
+    let (stop, stopped) = mpsc::channel::<()>();
+    let thread = thread::Builder::new()
+        .name("otlp-export".to_owned())
+        .spawn(move || {
+            let mut runtime = match tokio::runtime::Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    warn!(logger, "Cannot start the OTLP export"; "error" => %e);
+                    return;
+                }
+            };
+            let client = reqwest::Client::new();
+            loop {
+                let last = !matches!(stopped.recv_timeout(EXPORT_INTERVAL), Err(mpsc::RecvTimeoutError::Timeout));
+                let spans = finished.lock().map(|mut f| std::mem::take(&mut *f)).unwrap_or_default();
+                if !spans.is_empty() {
+                    let count = spans.len();
+                    let body = json!({
+                        "resourceSpans": [{
+                            "resource": { "attributes": [{ "key": "service.name", "value": { "stringValue": "waterfall" } }] },
+                            "scopeSpans": [{ "scope": { "name": "waterfall" }, "spans": spans }],
+                        }],
+                    });
+                    let posted = runtime.block_on(client.post(&url)
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .body(body.to_string())
+                        .send());
+                    match posted.and_then(|r| r.error_for_status()) {
+                        Ok(_) => (),
+                        Err(e) => warn!(logger, "OTLP export failed, spans dropped"; "spans" => count, "error" => %e),
+                    }
+                }
+                if last {
+                    return;
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+    Ok(OtlpGuard { stop: Some(stop), thread: Some(thread) })
+}
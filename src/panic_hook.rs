@@ -0,0 +1,177 @@
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::future::Future;
+use std::panic;
+use std::sync::{
+    Arc, Mutex, MutexGuard,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread;
+
+use futures::future::poll_fn;
+use slog::{crit, Logger};
+
+use crate::shutdown::Shutdown;
+
+/// Whether anything panicked, on any thread, since `install`
+static PANICKED: AtomicBool = AtomicBool::new(false);
+/// The run the panics are logged to and stop, see `attach`
+static RUN: Mutex<Option<(Logger, Shutdown)>> = Mutex::new(None);
+/// The panics kept from stderr while the terminal is taken, see `hold`
+static HELD: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+thread_local! {
+    /// The sessions being polled on this thread, innermost last, see `scope`
+    static SESSIONS: RefCell<Vec<Arc<str>>> = RefCell::new(Vec::new());
+}
+
+/// Count the panics of the process, once at its start, and report them on stderr until a run is
+/// attached.
+///
+/// Tokio catches panics of spawned tasks, so without this a panicking client just vanishes,
+/// the rest of the run keeps going in a half-broken state, and it may even exit with success.
+pub fn install() {
+    panic::set_hook(Box::new(|info| {
+        PANICKED.store(true, Ordering::SeqCst);
+        let current = thread::current();
+        let thread_name = current.name().unwrap_or("<unnamed>");
+        let location = info.location()
+            .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+            .unwrap_or_else(|| "<unknown>".to_owned());
+        let message = message(info.payload());
+        let session = SESSIONS.with(|s| s.try_borrow().ok().and_then(|s| s.last().cloned()));
+        let session = session.as_deref().unwrap_or("<none>");
+        // Only recorded with a subscriber, `--flame` or `--otlp`
+        let span = tracing::Span::current().metadata().map(|m| m.name()).unwrap_or("<none>");
+        // Not logged under the lock, the drain may panic itself
+        let run = lock(&RUN).clone();
+        if let Some((ref logger, _)) = run {
+            let backtrace = Backtrace::force_capture();
+            crit!(logger, "Panicked: {}", message;
+                "thread" => thread_name,
+                "session" => session,
+                "span" => span,
+                "location" => location.as_str(),
+                "backtrace" => %backtrace);
+        }
+        let line = format!("thread '{}' panicked at '{}', {}, session {}", thread_name, message, location, session);
+        match *lock(&HELD) {
+            Some(ref mut held) => held.push(line),
+            None if run.is_none() => eprintln!("{}", line),
+            // In the log already
+            None => (),
+        }
+        if let Some((_, shutdown)) = run {
+            shutdown.trigger();
+        }
+    }));
+}
+
+/// Whether anything panicked, the exit code must not be a success then
+pub fn panicked() -> bool {
+    PANICKED.load(Ordering::SeqCst)
+}
+
+/// Route the panics through `logger` instead of stderr and stop the run, until the guard is dropped.
+pub fn attach(logger: Logger, shutdown: Shutdown) -> Attached {
+    *lock(&RUN) = Some((logger, shutdown));
+    Attached(())
+}
+
+/// See `attach`
+pub struct Attached(());
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        lock(&RUN).take();
+    }
+}
+
+/// Keep the panics from stderr, e.g. while `--tui` has the terminal and the logs are discarded,
+/// and write them once the guard is dropped. Drop it after the terminal is given back.
+pub fn hold() -> Held {
+    lock(&HELD).get_or_insert_with(Vec::new);
+    Held(())
+}
+
+/// See `hold`
+pub struct Held(());
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        for line in lock(&HELD).take().unwrap_or_default() {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// Name the panics raised while `future` is polled after `session`, e.g. `client rtmp://host/app/stream`.
+///
+/// The tracing spans are empty without a subscriber, and a task may be polled on any thread, so
+/// the session is set around every poll.
+pub fn scope<F: Future>(session: String, future: F) -> impl Future<Output = F::Output> {
+    let session: Arc<str> = session.into();
+    let mut future = Box::pin(future);
+    poll_fn(move |cx| {
+        SESSIONS.with(|s| s.borrow_mut().push(session.clone()));
+        let _entered = Entered;
+        future.as_mut().poll(cx)
+    })
+}
+
+/// Leaves the session of `scope` again, also when its poll unwinds
+struct Entered;
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        SESSIONS.with(|s| s.borrow_mut().pop());
+    }
+}
+
+/// A poisoned lock still holds usable state, and the hook must not panic
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::AssertUnwindSafe;
+
+    use super::*;
+
+    fn current() -> Option<String> {
+        SESSIONS.with(|s| s.borrow().last().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn sessions_are_set_while_polled() {
+        let inner = scope("player rtmp://host/live/b".to_owned(), async { current() });
+        let outer = scope("client rtmp://host/live/a".to_owned(), async move { (current(), inner.await, current()) });
+        let (before, nested, after) = futures::executor::block_on(outer);
+        assert_eq!(before.as_deref(), Some("client rtmp://host/live/a"));
+        assert_eq!(nested.as_deref(), Some("player rtmp://host/live/b"));
+        assert_eq!(after.as_deref(), Some("client rtmp://host/live/a"));
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn sessions_are_left_on_unwind() {
+        let panicking = scope("sink 127.0.0.1:1935".to_owned(), async { panic!("in the session") });
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| futures::executor::block_on(panicking))).is_err());
+        assert_eq!(current(), None);
+    }
+}
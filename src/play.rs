@@ -0,0 +1,87 @@
+//! `waterfall play`: concurrent subscribers of the destinations.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Serialize;
+use slog::{o, Logger};
+
+use crate::panic_hook;
+use crate::report::Percentiles;
+use crate::rtmp::player::{self, PlayReport};
+use crate::rtmp_url::Url;
+
+#[derive(Debug, Serialize)]
+pub struct PlayRunReport {
+    pub clients: usize,
+    /// Clients which received at least one media message
+    pub played: usize,
+    pub failed: usize,
+    pub first_frame_ms: Option<Percentiles>,
+    /// From the play command, of the clients which received any
+    pub first_audio_ms: Option<Percentiles>,
+    pub first_video_ms: Option<Percentiles>,
+    pub first_keyframe_ms: Option<Percentiles>,
+    pub bitrate_bps: Option<Percentiles>,
+    pub stalls: u64,
+    pub per_client: Vec<PlayReport>,
+}
+
+/// Play every destination at once and wait for all of them to end.
+pub async fn run(urls: Vec<Url>, config: player::Config, logger: &Logger) -> PlayRunReport {
+    let config = Arc::new(config);
+    let players = urls.into_iter().map(|url| {
+        let config = config.clone();
+        let logger = logger.new(o!("app" => url.app.clone(), "stream" => url.stream.clone()));
+        let session = format!("player {}", url);
+        tokio::spawn(panic_hook::scope(session, async move { player::play(url, &config, &logger).await }))
+    }).collect::<FuturesUnordered<_>>();
+    let reports = players.filter_map(|r| async move { r.ok() }).collect::<Vec<_>>().await;
+    summarize(reports)
+}
+
+fn summarize(mut reports: Vec<PlayReport>) -> PlayRunReport {
+    reports.sort_by(|a, b| a.url.cmp(&b.url));
+    let played = reports.iter().filter(|r| r.first_frame_ms.is_some()).collect::<Vec<_>>();
+    PlayRunReport {
+        clients: reports.len(),
+        played: played.len(),
+        failed: reports.iter().filter(|r| r.error.is_some()).count(),
+        first_frame_ms: Percentiles::from_values(played.iter().filter_map(|r| r.first_frame_ms).collect()),
+        first_audio_ms: Percentiles::from_values(played.iter().filter_map(|r| r.first_audio_ms).collect()),
+        first_video_ms: Percentiles::from_values(played.iter().filter_map(|r| r.first_video_ms).collect()),
+        first_keyframe_ms: Percentiles::from_values(played.iter().filter_map(|r| r.first_keyframe_ms).collect()),
+        bitrate_bps: Percentiles::from_values(played.iter().map(|r| r.bitrate_bps).collect()),
+        stalls: reports.iter().map(|r| r.stalls).sum(),
+        per_client: reports,
+    }
+}
+
+pub fn print(report: &PlayRunReport) {
+    println!("clients {}, played {}, failed {}, stalls {}", report.clients, report.played, report.failed, report.stalls);
+    println!("{:<18} {:>10} {:>10} {:>10} {:>10} {:>10}", "", "min", "p50", "p90", "p99", "max");
+    let rows = [
+        ("first_frame_ms", report.first_frame_ms),
+        ("first_audio_ms", report.first_audio_ms),
+        ("first_video_ms", report.first_video_ms),
+        ("first_keyframe_ms", report.first_keyframe_ms),
+        ("bitrate_bps", report.bitrate_bps),
+    ];
+    for (name, p) in &rows {
+        match p {
+            Some(p) => println!("{:<18} {:>10} {:>10} {:>10} {:>10} {:>10}", name, p.min, p.p50, p.p90, p.p99, p.max),
+            None => println!("{:<18} {:>10}", name, "-"),
+        }
+    }
+    for r in report.per_client.iter().filter(|r| r.error.is_some()) {
+        println!("{}  {}", r.url, r.error.as_ref().unwrap());
+    }
+}
+
+pub fn write_json(report: &PlayRunReport, path: &Path) -> io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, report)?;
+    Ok(())
+}
@@ -0,0 +1,329 @@
+//! Publishing an input to every destination: the readers of the inputs and their broadcasts.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{
+    future::{self, Either, FutureExt},
+    stream::{
+        LocalBoxStream,
+        Stream,
+        StreamExt,
+    },
+};
+use pin_utils::pin_mut;
+use rand::Rng;
+use slog::{debug, error, info, warn, Logger};
+
+use crate::{fanout, flv, generator, load, rtmp, rtmp_url, shutdown, stats, transmit};
+use crate::rtmp_url::Url;
+use crate::PacketType;
+
+/// A destination of `publish_all` and what it publishes
+#[derive(Clone, Debug)]
+pub struct Destination {
+    pub url: Url,
+    /// Played back-to-back, see `flv::read_flv_tag`
+    pub inputs: Vec<String>,
+    /// Connect this much later, on top of the ramp up
+    pub delay: Duration,
+    /// In place of the config of the run
+    pub config: Option<Arc<rtmp::client::Config>>,
+}
+
+impl Destination {
+    pub fn new(url: Url, inputs: Vec<String>) -> Self {
+        Self { url, inputs, delay: Duration::default(), config: None }
+    }
+}
+
+/// How long the clients get to unpublish at the end of the run
+pub const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Start a publisher for every destination, and broadcast once they are all publishing, or with
+/// `start_immediately` once the first one is.
+///
+/// Each destination comes with the input files it publishes. Destinations with the same ones share
+/// a reader, `inputs` is read in any case for the idle sessions subscribed to `tx`. Fails with
+/// `NotConnected` when fewer than `min_success` of them publish.
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_all(destinations: Vec<Destination>,
+                         tx: fanout::Sender,
+                         desync: bool,
+                         ramp_up: Option<load::RampUp>,
+                         min_success: Option<f64>,
+                         start_immediately: bool,
+                         ignore_connect_errors: bool,
+                         inputs: &[String],
+                         read_options: flv::ReadOptions,
+                         cache: &flv::Cache,
+                         client_config: Arc<rtmp::client::Config>,
+                         stats: &Arc<stats::Stats>,
+                         shutdown: &shutdown::Shutdown,
+                         root_logger: &Logger) -> Result<(), std::io::Error>
+{
+    // One reader per distinct input shared by its clients, or one for each client with `--desync`
+    let mut sources = Vec::new();
+    let mut shared = HashMap::<Vec<String>, fanout::Sender>::new();
+    let mut loop_lengths = HashMap::<Vec<String>, u32>::new();
+    // Idle sessions are subscribed already
+    let mut default_used = tx.receiver_count() > 0;
+    let mut clients = futures::stream::futures_unordered::FuturesUnordered::new();
+    let (started, total) = (tokio::time::Instant::now(), destinations.len());
+    for (i, destination) in destinations.into_iter().enumerate() {
+        let Destination { url, inputs: paths, delay, config } = destination;
+        let mut desync_offset = None;
+        let rx = if desync {
+            let loop_ms = match loop_lengths.get(&paths) {
+                Some(&ms) => ms,
+                None => {
+                    let ms = loop_duration(&paths).await?;
+                    loop_lengths.insert(paths.clone(), ms);
+                    ms
+                }
+            };
+            let start_offset_ms = if loop_ms > 0 { rand::thread_rng().gen_range(0, loop_ms) } else { 0 };
+            debug!(root_logger, "Desynchronized start"; "url" => %url, "start_offset_ms" => start_offset_ms);
+            desync_offset = Some(start_offset_ms);
+            let options = flv::ReadOptions { start_offset_ms, ..read_options };
+            let msgs = open_input(&paths, options, cache, client_config.insecure_tls, root_logger.clone()).await?;
+            let tx = tx.like();
+            let rx = tx.subscribe();
+            sources.push((msgs, tx));
+            rx
+        } else if paths == inputs {
+            default_used = true;
+            tx.subscribe()
+        } else {
+            match shared.get(&paths) {
+                Some(tx) => tx.subscribe(),
+                None => {
+                    debug!(root_logger, "Separate input"; "url" => %url, "input" => paths.join(","));
+                    let msgs = open_input(&paths, read_options, cache, client_config.insecure_tls, root_logger.clone()).await?;
+                    let tx = tx.like();
+                    let rx = tx.subscribe();
+                    shared.insert(paths, tx.clone());
+                    sources.push((msgs, tx));
+                    rx
+                }
+            }
+        };
+        let client_stats = stats.register(url.to_string());
+        if let Some(offset_ms) = desync_offset {
+            client_stats.set_start_offset(offset_ms);
+        }
+        let config = config.unwrap_or_else(|| client_config.clone());
+        let client_fut = if transmit::is_transmitted(url.scheme) {
+            Either::Left(transmit::publish(url, rx, Vec::new(), config, client_stats.clone(), root_logger))
+        } else {
+            Either::Right(rtmp::client::Client::new(url, rx, Vec::new(), config, client_stats.clone(), root_logger).map(drop))
+        };
+        let start_at = started + ramp_up.map(|r| r.offset(i, total)).unwrap_or_default() + delay;
+        clients.push(async move {
+            tokio::time::delay_until(start_at).await;
+            client_fut.await;
+            client_stats
+        });
+    }
+
+    if !desync && default_used {
+        let msgs = open_input(inputs, read_options, cache, client_config.insecure_tls, root_logger.clone()).await?;
+        sources.push((msgs, tx));
+    }
+
+    // await for all publish clients to be ready, or with `--min-success` for that share to publish
+    let needed = if start_immediately {
+        total.min(1)
+    } else {
+        min_success.map_or(total, |share| (share * total as f64).ceil() as usize)
+    };
+    let mut publishing = 0;
+    let stopped = future::select(shutdown.wait().boxed(), shutdown.deadline().boxed());
+    pin_mut!(stopped);
+    while publishing < needed {
+        match future::select(clients.next(), stopped.as_mut()).await {
+            Either::Left((Some(client_stats), _)) => {
+                if client_stats.state() == stats::ClientState::Publishing {
+                    publishing += 1;
+                }
+            }
+            Either::Left((None, _)) => break,
+            Either::Right(_) => {
+                warn!(root_logger, "Shutdown before all publish clients are ready");
+                // At the deadline, the clients still connecting give up as well
+                shutdown.trigger();
+                for (_, tx) in &sources {
+                    let _ = tx.send(Arc::new(PacketType::End));
+                }
+                return Ok(());
+            }
+        }
+    }
+    if min_success.is_some() && publishing < needed {
+        if !ignore_connect_errors {
+            let message = format!("only {} of {} publish clients connected, {} needed", publishing, total, needed);
+            return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, message));
+        }
+        warn!(root_logger, "Not enough publish clients connected, broadcast anyway"; "publishing" => publishing, "needed" => needed);
+    } else if clients.is_empty() {
+        info!(root_logger, "All publish clients are ready");
+    } else if start_immediately {
+        info!(root_logger, "First publish client is ready, start broadcasting"; "pending" => clients.len());
+    } else {
+        info!(root_logger, "Enough publish clients are ready"; "publishing" => publishing, "pending" => clients.len());
+    }
+
+    // broadcast, the pending clients join as they get ready
+    let broadcasts = sources.into_iter().map(|(msgs, tx)| {
+        broadcast(msgs, tx, stats.clone(), shutdown.clone(), root_logger.clone())
+    });
+    future::join(future::join_all(broadcasts), clients.for_each(|_| async {})).await;
+    Ok(())
+}
+
+/// The tags of the input files, or the media of a live RTMP source or a synthetic stream given as
+/// the only input. Without the audio or the video if `options` leaves them out.
+pub async fn open_input(paths: &[String],
+                        options: flv::ReadOptions,
+                        cache: &flv::Cache,
+                        insecure_tls: bool,
+                        logger: Logger) -> Result<LocalBoxStream<'static, std::io::Result<Arc<PacketType>>>, std::io::Error>
+{
+    let msgs = match paths {
+        [path] if rtmp_url::is_rtmp_url(path) => {
+            let url = rtmp_url::parse_rtmp_url(path, None).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+            })?;
+            rtmp::player::pull(url, insecure_tls, logger).boxed_local()
+        }
+        [path] if generator::is_synthetic(path) => {
+            let synthetic = generator::parse(path).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+            })?;
+            generator::generate(synthetic, options.speed, options.burst).boxed_local()
+        }
+        _ if paths.iter().any(|p| rtmp_url::is_rtmp_url(p) || generator::is_synthetic(p)) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "an RTMP source or a synthetic stream must be the only input"));
+        }
+        _ => flv::read_flv_tag(paths, options, cache, logger).await?.boxed_local(),
+    };
+    if options.audio && options.video {
+        return Ok(msgs);
+    }
+    let (audio, video) = (options.audio, options.video);
+    Ok(msgs.filter_map(move |msg| future::ready(match msg {
+        Ok(msg) => tracks(msg, audio, video).map(Ok),
+        Err(e) => Some(Err(e)),
+    })).boxed_local())
+}
+
+/// `msg` if it is of a track kept, the metadata without the tracks left out
+fn tracks(msg: Arc<PacketType>, audio: bool, video: bool) -> Option<Arc<PacketType>> {
+    match *msg {
+        PacketType::Audio{ .. } if !audio => None,
+        PacketType::Video{ .. } if !video => None,
+        PacketType::Metadata(ref metadata) => {
+            let mut metadata = (**metadata).clone();
+            if !audio {
+                metadata.audio_codec = None;
+                metadata.audio_bitrate_kbps = None;
+                metadata.audio_sample_rate = None;
+                metadata.audio_channels = None;
+                metadata.audio_is_stereo = None;
+            }
+            if !video {
+                metadata.video_codec = None;
+                metadata.video_bitrate_kbps = None;
+                metadata.video_width = None;
+                metadata.video_height = None;
+                metadata.video_frame_rate = None;
+            }
+            Some(Arc::new(PacketType::Metadata(Arc::new(metadata))))
+        }
+        _ => Some(msg),
+    }
+}
+
+/// Length of one loop of the input files together, to spread the `--desync` start offsets over.
+async fn loop_duration(paths: &[String]) -> Result<u32, std::io::Error> {
+    let paths = paths.to_vec();
+    let duration = tokio::task::spawn_blocking(move || {
+        paths.iter().map(|path| flv::duration(path)).sum::<std::io::Result<Duration>>()
+    }).await.map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::Other, "scan input file error")
+    })??;
+    Ok(duration.as_millis() as u32)
+}
+
+/// Send the tags of one input to its subscribed clients, until the input ends, the run is shut
+/// down or its duration is over. An input which fails to read shuts the run down, counted in `stats`.
+pub async fn broadcast<S>(mut msgs: S, tx: fanout::Sender, stats: Arc<stats::Stats>, shutdown: shutdown::Shutdown, logger: Logger)
+    where S: Stream<Item = std::io::Result<Arc<PacketType>>> + Unpin,
+{
+    // While paused the input goes on without being sent, like a cut live feed. The video resumes
+    // at a keyframe, so the players can decode it right away
+    let (mut skipping, mut has_video) = (false, false);
+    // Also while the input blocks, e.g. a live encoder on stdin which went quiet
+    let (stopped, deadline) = (shutdown.wait(), shutdown.deadline());
+    pin_mut!(stopped, deadline);
+    loop {
+        let msg = tokio::select! {
+            msg = msgs.next() => msg,
+            _ = &mut stopped => {
+                warn!(logger, "Shutdown triggered, stop broadcasting");
+                break;
+            }
+            _ = &mut deadline => {
+                info!(logger, "Run duration reached, stop broadcasting");
+                shutdown.trigger();
+                break;
+            }
+        };
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            Some(Err(e)) => {
+                error!(logger, "Read input error, stop the run"; "error" => %e);
+                stats.add_input_error();
+                shutdown.trigger();
+                break;
+            }
+            None => break,
+        };
+        if let PacketType::Video{ .. } = *msg {
+            has_video = true;
+        }
+        if shutdown.is_paused() {
+            if !skipping {
+                info!(logger, "Broadcast paused");
+                skipping = true;
+            }
+            continue;
+        }
+        if skipping {
+            match *msg {
+                PacketType::Video{ ref data, .. } if flv::is_video_keyframe_or_header(data) => (),
+                PacketType::Video{ .. } | PacketType::Audio{ .. } if has_video => continue,
+                _ => (),
+            }
+            info!(logger, "Broadcast resumed");
+            skipping = false;
+        }
+        if tx.send(msg) == 0 {
+            warn!(logger, "No publish client exists, quit");
+            break;
+        }
+    }
+    let _ = tx.send(Arc::new(PacketType::End));
+}
+
+
+/// The clients unpublish and close once the broadcast ended, give them a moment
+pub async fn wait_teardown(stats: &stats::Stats, timeout: Duration) {
+    let deadline = std::time::Instant::now() + timeout;
+    while std::time::Instant::now() < deadline && stats.clients().iter().any(|c| {
+        matches!(c.state(), stats::ClientState::Publishing | stats::ClientState::Idle) && !c.socket_closed()
+    }) {
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+    }
+}
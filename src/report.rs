@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+
+use crate::stats::{ClientState, Stats, SUSTAINED_WINDOWS};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Report {
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    pub duration_secs: f64,
+    /// `false` for checkpoints written while the run is still going
+    pub finished: bool,
+    pub summary: Summary,
+    /// Result of the adaptive mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<CapacityReport>,
+    /// One block per completed step of `--steps`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepReport>,
+    /// Results of `--assert`, in the final report only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assertions: Vec<AssertionReport>,
+    /// Counts sampled over the run, only recorded for the HTML report
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub timeline: Vec<TimelineSample>,
+    /// Publish to play latency per interval, with `--measure-latency`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub latency: Vec<LatencyInterval>,
+    /// The media received against the media sent, with `--verify`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<IntegrityReport>,
+    pub clients: Vec<ClientReport>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    pub clients: usize,
+    pub connecting: usize,
+    pub publishing: usize,
+    pub finished: usize,
+    pub failed: usize,
+    /// Sessions kept open without publishing, see `--idle-sessions`
+    #[serde(default)]
+    pub idle: usize,
+    pub bytes_sent: u64,
+    pub connect_ms: Option<Percentiles>,
+    /// Clients which sent more than `NEVER_ACKED_BYTES` without any acknowledgement
+    #[serde(default)]
+    pub never_acked: usize,
+    /// Clients which received inconsistent acknowledgements
+    #[serde(default)]
+    pub erratic_acks: usize,
+    /// Clients whose unacknowledged bytes went over `--unacked-alarm`
+    #[serde(default)]
+    pub unacked_alarms: usize,
+    /// Clients which sent off the input frame rate for `SUSTAINED_WINDOWS` seconds in a row
+    #[serde(default)]
+    pub cadence_deviations: usize,
+    /// Clients with at least one write waiting `WRITE_STALL` or longer for the socket
+    #[serde(default)]
+    pub write_stalled: usize,
+    /// Clients whose full queue dropped broadcast messages, the reader outran them
+    #[serde(default)]
+    pub lagged: usize,
+    #[serde(default)]
+    pub dropped_messages: u64,
+    /// Publish to play latency over the whole run, with `--measure-latency`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<Percentiles>,
+    /// `onStatus` codes of the server over all clients, with their counts
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub statuses: BTreeMap<String, u64>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientReport {
+    pub url: String,
+    pub peer_addr: Option<String>,
+    /// The random offset into the input of `--desync`, the client started at the keyframe after it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_offset_ms: Option<u64>,
+    pub state: ClientState,
+    pub connect_ms: Option<u64>,
+    pub bytes_sent: u64,
+    pub messages_sent: u64,
+    #[serde(default)]
+    pub reconnects: u64,
+    /// Connect redirects followed, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redirects: Vec<String>,
+    /// The urls of the destination line failed over to, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failovers: Vec<String>,
+    /// `onStatus` codes of the server, with their counts
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub statuses: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub codec_changes: u64,
+    #[serde(default)]
+    pub acks: AckReport,
+    #[serde(default)]
+    pub cadence: CadenceReport,
+    #[serde(default)]
+    pub writes: WriteReport,
+    #[serde(default)]
+    pub queue: QueueReport,
+    /// Values the server reported with `onBWDone`
+    #[serde(default)]
+    pub bw_check: Option<BwCheckReport>,
+    pub duration_secs: f64,
+    pub error: Option<String>,
+}
+
+/// Highest load the destinations sustained, see `--adaptive`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapacityReport {
+    pub sustained_publishers: usize,
+    pub started_publishers: usize,
+    /// Failed share of the started publishers when the ramp up stopped
+    pub error_rate: f64,
+    pub connect_p90_ms: Option<u64>,
+    /// Why the ramp up stopped
+    pub limit: String,
+}
+
+/// One plateau of a stepwise load, the counters cover the step only
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepReport {
+    pub concurrency: usize,
+    pub duration_secs: f64,
+    /// Publishers at the end of the step
+    pub publishing: usize,
+    pub failed: usize,
+    pub bytes_sent: u64,
+    pub throughput_bps: u64,
+    /// Of the publishers started for this step
+    pub connect_ms: Option<Percentiles>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct TimelineSample {
+    pub elapsed_secs: f64,
+    pub clients: usize,
+    pub publishing: usize,
+    pub failed: usize,
+    pub bytes_sent: u64,
+}
+
+/// Audio and video messages of the verified destination, see `verify`
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    pub sent: u64,
+    pub received: u64,
+    pub matched: u64,
+    /// Sent while the player was playing, never received
+    pub missing: u64,
+    /// Received, matching nothing sent
+    pub corrupted: u64,
+    /// Received after a message sent later
+    pub reordered: u64,
+    /// AAC frames received later or earlier than the end of the one before
+    #[serde(default)]
+    pub audio_gaps: u64,
+    #[serde(default)]
+    pub audio_overlaps: u64,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct LatencyInterval {
+    pub elapsed_secs: f64,
+    /// Stamps received in the interval
+    pub samples: usize,
+    pub latency_ms: Percentiles,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AssertionReport {
+    pub assertion: String,
+    /// `None` if the metric had no samples
+    pub actual: Option<f64>,
+    pub passed: bool,
+}
+
+/// Acknowledgements sent back by the server. The windows are the byte counts between two acks,
+/// i.e. the effective window acknowledgement size of the server.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AckReport {
+    pub acks: u64,
+    pub window_min: Option<u64>,
+    pub window_max: Option<u64>,
+    pub window_avg: Option<u64>,
+    pub anomalies: u64,
+    /// Bytes sent on the current connection beyond the last acknowledgement
+    pub unacked_bytes: u64,
+    /// Times the unacknowledged bytes went over `--unacked-alarm`
+    #[serde(default)]
+    pub alarms: u64,
+}
+
+/// Video frames sent per second, measured in one second windows
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CadenceReport {
+    /// From the input metadata, otherwise each window compares with its timestamps
+    pub nominal_fps: Option<f64>,
+    pub windows: u64,
+    /// Windows more than 10% off the nominal frame rate
+    pub deviating_windows: u64,
+    pub longest_deviation_windows: u64,
+    pub min_fps: Option<f64>,
+    pub max_fps: Option<f64>,
+}
+
+/// Backpressure of the socket, a destination or network path which cannot keep up blocks writes
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteReport {
+    /// Total time writes and flushes waited for the socket
+    pub blocked_ms: u64,
+    /// Writes which waited `WRITE_STALL` or longer
+    pub stalls: u64,
+    pub longest_stall_ms: u64,
+    /// Video frames dropped for the backpressure, see `--drop-on-backpressure`
+    #[serde(default)]
+    pub dropped_frames: u64,
+}
+
+/// The queue of broadcast messages a client has not taken yet, see `fanout`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueReport {
+    pub depth: usize,
+    pub max_depth: usize,
+    /// Messages dropped by the drop policy once the queue was full
+    pub dropped: u64,
+}
+
+/// Result of a server initiated bandwidth check
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BwCheckReport {
+    /// `onBWCheck` calls answered
+    pub checks: u32,
+    pub kbps: Option<f64>,
+    pub latency_ms: Option<f64>,
+}
+
+/// No server should let this much go by without an acknowledgement, the usual windows are 2.5MB or less
+pub const NEVER_ACKED_BYTES: u64 = 16_000_000;
+
+impl Summary {
+    pub fn from_clients(clients: &[ClientReport]) -> Self {
+        let count = |state: ClientState| clients.iter().filter(|c| c.state == state).count();
+        Self {
+            clients: clients.len(),
+            connecting: count(ClientState::Connecting),
+            publishing: count(ClientState::Publishing),
+            finished: count(ClientState::Finished),
+            failed: count(ClientState::Failed),
+            idle: count(ClientState::Idle),
+            bytes_sent: clients.iter().map(|c| c.bytes_sent).sum(),
+            connect_ms: Percentiles::from_values(clients.iter().filter_map(|c| c.connect_ms).collect()),
+            never_acked: clients.iter().filter(|c| c.acks.acks == 0 && c.acks.unacked_bytes > NEVER_ACKED_BYTES).count(),
+            erratic_acks: clients.iter().filter(|c| c.acks.anomalies > 0).count(),
+            unacked_alarms: clients.iter().filter(|c| c.acks.alarms > 0).count(),
+            cadence_deviations: clients.iter().filter(|c| c.cadence.longest_deviation_windows >= SUSTAINED_WINDOWS).count(),
+            write_stalled: clients.iter().filter(|c| c.writes.stalls > 0).count(),
+            lagged: clients.iter().filter(|c| c.queue.dropped > 0).count(),
+            dropped_messages: clients.iter().map(|c| c.queue.dropped).sum(),
+            latency_ms: None,
+            statuses: clients.iter().flat_map(|c| c.statuses.iter()).fold(BTreeMap::new(), |mut statuses, (code, n)| {
+                *statuses.entry(code.clone()).or_default() += n;
+                statuses
+            }),
+        }
+    }
+}
+
+impl Percentiles {
+    /// Nearest-rank percentiles, `None` if there are no samples
+    pub fn from_values(mut values: Vec<u64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        let rank = |p: f64| {
+            let idx = ((p / 100.0) * values.len() as f64).ceil() as usize;
+            values[idx.saturating_sub(1).min(values.len() - 1)]
+        };
+        Some(Self {
+            min: values[0],
+            p50: rank(50.0),
+            p90: rank(90.0),
+            p99: rank(99.0),
+            max: values[values.len() - 1],
+        })
+    }
+}
+
+/// Write the report through a temporary file, so a crash mid-write never leaves a truncated report.
+pub fn write_json(report: &Report, path: &Path) -> io::Result<()> {
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    let mut file = File::create(&tmp)?;
+    serde_json::to_writer_pretty(&mut file, report)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// One line per destination: whether it published, connect time, bytes, duration and error.
+/// The aggregates are in the JSON report only.
+pub fn write_csv(report: &Report, path: &Path) -> io::Result<()> {
+    let mut out = String::from("url,state,success,connect_ms,bytes_sent,duration_secs,reconnects,error\n");
+    for client in &report.clients {
+        let success = client.connect_ms.is_some() && client.state != ClientState::Failed;
+        out.push_str(&format!("{},{},{},{},{},{:.3},{},{}\n",
+            csv_field(&client.url),
+            serde_json::to_value(client.state).ok().and_then(|s| s.as_str().map(str::to_owned)).unwrap_or_default(),
+            success,
+            client.connect_ms.map(|ms| ms.to_string()).unwrap_or_default(),
+            client.bytes_sent,
+            client.duration_secs,
+            client.reconnects,
+            csv_field(client.error.as_deref().unwrap_or(""))));
+    }
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp, out)?;
+    fs::rename(&tmp, path)
+}
+
+/// Quote a value which holds a separator, a quote or a line break
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Log the totals of all clients every `interval`, never returns.
+pub async fn log_totals(stats: Arc<Stats>, interval: Duration, logger: Logger) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    let mut last_bytes = 0;
+    loop {
+        ticks.tick().await;
+        let clients = stats.clients();
+        let publishing = clients.iter().filter(|c| c.state() == ClientState::Publishing).count();
+        let bytes = clients.iter().map(|c| c.bytes_sent()).sum::<u64>();
+        let queued = clients.iter().map(|c| c.queued()).sum::<u64>();
+        let kbps = bytes.saturating_sub(last_bytes) as f64 * 8.0 / interval.as_secs_f64() / 1000.0;
+        last_bytes = bytes;
+        info!(logger, "Run stats"; "clients" => clients.len(), "publishing" => publishing,
+              "bitrate_kbps" => format!("{:.0}", kbps), "queued" => queued, "bytes_sent" => bytes);
+    }
+}
+
+/// Sample the timeline every `interval`, never returns.
+pub async fn sample(stats: Arc<Stats>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        stats.sample();
+    }
+}
+
+/// Write the partial results to `path` every `interval`, never returns.
+pub async fn checkpoint(stats: Arc<Stats>, path: PathBuf, interval: Duration, logger: Logger) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = write_json(&stats.report(false), &path) {
+            warn!(logger, "Write checkpoint error"; "path" => %path.display(), "error" => %e);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! The `authmod=adobe` challenge of Adobe Media Server and Wowza publish ingests.
+//!
+//! The connect goes out with `?authmod=adobe&user=<user>` after the app and the tcUrl. The server
+//! rejects it with a salt and a challenge in the description of the error, and the next connect
+//! carries the response computed from them and the password.
+
+use md5::{Digest, Md5};
+
+/// The query of the first connect
+pub fn start_query(user: &str) -> String {
+    format!("authmod=adobe&user={}", user)
+}
+
+/// Whether the description of a rejected connect is about this authentication
+pub fn is_auth_rejection(description: &str) -> bool {
+    description.contains("authmod=adobe")
+}
+
+/// The query of the next connect, from the description of a rejected one
+pub fn next_query(description: &str, user: &str, password: &str) -> Result<String, String> {
+    if description.contains("reason=authfailed") {
+        return Err("authentication failed, check the user and password".to_owned());
+    }
+    if let Some(i) = description.find("?reason=needauth") {
+        let params = parse_params(&description[i + 1..]);
+        let param = |key: &str| params.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let salt = param("salt").ok_or("missing salt in the auth challenge")?;
+        let opaque = param("opaque");
+        let challenge = param("challenge");
+        let client_challenge = format!("{:08x}", rand::random::<u32>());
+        let response = response(user, password, salt, opaque.or(challenge).unwrap_or(""), &client_challenge);
+        let mut query = format!("authmod=adobe&user={}&challenge={}&response={}", user, client_challenge, response);
+        if let Some(opaque) = opaque {
+            query.push_str("&opaque=");
+            query.push_str(opaque);
+        }
+        return Ok(query);
+    }
+    if description.contains("code=403 need auth") {
+        return Ok(start_query(user));
+    }
+    Err(format!("unexpected auth rejection: {}", description))
+}
+
+/// `base64(md5(base64(md5(user + salt + password)) + opaque or challenge + client challenge))`
+fn response(user: &str, password: &str, salt: &str, server_challenge: &str, client_challenge: &str) -> String {
+    let hash = base64::encode(Md5::digest(format!("{}{}{}", user, salt, password).as_bytes()));
+    base64::encode(Md5::digest(format!("{}{}{}", hash, server_challenge, client_challenge).as_bytes()))
+}
+
+/// `key=value` pairs up to the end of the query, the description may go on after a space
+fn parse_params(query: &str) -> Vec<(&str, &str)> {
+    let query = query.split(|c: char| c.is_whitespace()).next().unwrap_or("");
+    query.trim_start_matches('?').split('&').filter_map(|pair| {
+        let mut parts = pair.splitn(2, '=');
+        Some((parts.next()?, parts.next().unwrap_or("")))
+    }).collect()
+}
@@ -0,0 +1,67 @@
+//! A single session setup without media, for `connect-bench`.
+
+use std::time::{Duration, Instant};
+
+use futures::{sink::SinkExt, stream::StreamExt};
+use rml_rtmp::sessions::{ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult};
+use tokio::net::TcpStream;
+use tokio_util::codec::Decoder;
+
+use crate::error::{Error, ErrorKind};
+use crate::rtmp_url::Url;
+use super::{codec::Codec, command};
+
+/// Time spent in each stage of the setup
+#[derive(Clone, Copy, Debug)]
+pub struct Timings {
+    pub tcp: Duration,
+    pub handshake: Duration,
+    /// From the connect command to its result
+    pub connect: Duration,
+}
+
+/// TCP connect, handshake (with TLS or a tunnel as the scheme asks) and connect command, then
+/// close the connection.
+pub async fn connect_once(url: &Url, insecure_tls: bool) -> Result<Timings, Error> {
+    let started = Instant::now();
+    let server = url.server();
+    let socket = TcpStream::connect(server.as_str()).await?;
+    let tcp = started.elapsed();
+
+    let io = super::open(socket, url.scheme, &url.host, url.port, insecure_tls).await?;
+    let handshake = started.elapsed() - tcp;
+
+    let mut transport = Codec::default().framed(io);
+    let (mut session, results) = ClientSession::new(ClientSessionConfig::new())?;
+    for result in results {
+        if let ClientSessionResult::OutboundResponse(packet) = result {
+            transport.feed(packet).await?;
+        }
+    }
+    transport.send(session.request_connection(url.app.clone(), Some(url.tc_url()))?).await?;
+
+    let mut bw_checks = 0;
+    while let Some(received) = transport.next().await {
+        let (message, bytes_read) = received?;
+        let (outbounds, event, _) = session.handle_input_message(message, bytes_read)?;
+        for packet in outbounds {
+            transport.feed(packet).await?;
+        }
+        match event {
+            Some(ClientSessionEvent::ConnectionRequestAccepted) => {
+                let connect = started.elapsed() - tcp - handshake;
+                return Ok(Timings { tcp, handshake, connect });
+            }
+            Some(ClientSessionEvent::ConnectionRequestRejected{ description }) => {
+                return Err(ErrorKind::Unknown(format!("connect request rejected by peer server: {}", description)).into());
+            }
+            Some(ClientSessionEvent::UnhandleableAmf0Command{ ref command_name, transaction_id, .. }) if command_name == "onBWCheck" => {
+                transport.feed(command::bw_check_result(transaction_id, bw_checks)?).await?;
+                bw_checks += 1;
+            }
+            _ => (),
+        }
+        transport.flush().await?;
+    }
+    Err(ErrorKind::Unknown("connection lost".into()).into())
+}
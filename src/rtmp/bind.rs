@@ -0,0 +1,104 @@
+//! The local end of outgoing connections: `--bind` addresses taken in turn, and the address
+//! family of `-4` / `-6`.
+//!
+//! Each local address has its own range of ephemeral ports, so spreading the connections over
+//! several raises the number of sessions to one server, and the server sees as many clients.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::net::{self, TcpStream, ToSocketAddrs};
+
+/// Only connect to addresses of this family
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn of(ip: IpAddr) -> Self {
+        if ip.is_ipv4() { Family::V4 } else { Family::V6 }
+    }
+
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        Family::of(addr.ip()) == self
+    }
+}
+
+impl std::fmt::Display for Family {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self { Family::V4 => "IPv4", Family::V6 => "IPv6" })
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalAddrs {
+    addrs: Vec<IpAddr>,
+    next: AtomicUsize,
+}
+
+impl LocalAddrs {
+    pub fn new(addrs: Vec<IpAddr>) -> Self {
+        Self { addrs, next: AtomicUsize::new(0) }
+    }
+
+    fn next(&self) -> IpAddr {
+        self.addrs[self.next.fetch_add(1, Ordering::Relaxed) % self.addrs.len()]
+    }
+}
+
+/// Connect to the first address of `addr` which answers, only to those of `family` if given.
+/// From the next of the local addresses of `bind`, the remote address is of its family then.
+pub async fn connect<A: ToSocketAddrs>(addr: A, family: Option<Family>, bind: Option<&LocalAddrs>) -> io::Result<TcpStream> {
+    let local = bind.map(LocalAddrs::next);
+    let family = match (family, local) {
+        (None, None) => return TcpStream::connect(addr).await,
+        (Some(family), Some(local)) if Family::of(local) != family => {
+            let message = format!("local address {} is not {}", local, family);
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, message));
+        }
+        (Some(family), _) => family,
+        (None, Some(local)) => Family::of(local),
+    };
+    let mut last_error = io::Error::new(io::ErrorKind::AddrNotAvailable, format!("no {} address", family));
+    for remote in net::lookup_host(addr).await?.filter(|remote| family.matches(remote)) {
+        let connected = match local {
+            // The connect of a bound socket blocks, it runs on the blocking pool
+            Some(local) => match tokio::task::spawn_blocking(move || connect_from(local, remote)).await {
+                Ok(Ok(stream)) => TcpStream::from_std(stream),
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+            },
+            None => TcpStream::connect(remote).await,
+        };
+        match connected {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+fn connect_from(local: IpAddr, remote: SocketAddr) -> io::Result<std::net::TcpStream> {
+    let socket = Socket::new(Domain::for_address(remote), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&SockAddr::from(SocketAddr::new(local, 0)))
+        .map_err(|e| io::Error::new(e.kind(), format!("bind {}: {}", local, e)))?;
+    socket.connect(&SockAddr::from(remote))?;
+    let stream = std::net::TcpStream::from(socket);
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+/// Parse the addresses of `--bind`
+pub fn parse<'a>(values: impl Iterator<Item = &'a str>) -> Result<LocalAddrs, String> {
+    let addrs = values
+        .map(|v| v.trim().parse::<IpAddr>().map_err(|_| format!("invalid local address `{}`", v)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if addrs.is_empty() {
+        return Err("no local address".to_owned());
+    }
+    Ok(LocalAddrs::new(addrs))
+}
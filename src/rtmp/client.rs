@@ -1,12 +1,12 @@
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::prelude::*;
-use tokio::sync::{
-    broadcast,
-    oneshot,
-};
-use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 use tokio_util::codec::{Decoder, Framed};
 use futures::{
+    future::{self, FutureExt},
     stream::{
         self,
         Stream,
@@ -26,19 +26,31 @@ use rml_rtmp::{
         PeerBandwidthLimitType,
     },
     sessions::{
-        ClientSession, ClientSessionConfig, ClientSessionError, ClientSessionEvent,
-        ClientSessionResult, PublishRequestType,
+        ClientSession, ClientSessionConfig, ClientSessionEvent,
+        ClientSessionResult, PublishRequestType, StreamMetadata,
     },
+    time::RtmpTimestamp,
 };
+use rml_amf0::Amf0Value;
+use rand::Rng;
+use pin_utils::pin_mut;
+use tracing::{debug_span, info_span, trace_span, Instrument, Span};
 use slog::{
     o,
-    trace, debug, info, warn, error,
+    trace, debug, info, warn, error, crit,
     Logger,
 };
 
+use super::{adobe_auth, command, Io};
 use crate::{
-    rtmp_url::Url,
+    fanout,
+    latency,
+    panic_hook,
+    report::BwCheckReport,
+    rtmp_url::{self, Scheme, Url},
     flv,
+    stats::{AckCheck, ClientState, ClientStats, SUSTAINED_WINDOWS},
+    verify::Ledger,
     error::{
         Error,
         ErrorKind,
@@ -47,6 +59,256 @@ use crate::{
     ReceivedType,
 };
 
+/// Publish behaviour shared by all clients of a run
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Tear down the session and publish again whenever the input restarts from the beginning
+    pub republish_each_loop: bool,
+    /// Publish again on the same connection instead of a new one, with these stream ids
+    pub republish_stream: Option<StreamIds>,
+    /// Maximum random shift of each audio/video timestamp, in either direction
+    pub timestamp_jitter_ms: u32,
+    /// Stop publishing once a session has sent this many bytes
+    pub bytes_per_client: Option<u64>,
+    /// Connect and publish again with a fresh budget instead of exiting once `bytes_per_client` is sent
+    pub replace_exhausted: bool,
+    /// Idle time before TCP keepalive probes are sent on publish connections
+    pub tcp_keepalive: Option<Duration>,
+    /// Between two keepalive probes, the system default without
+    pub tcp_keepalive_interval: Option<Duration>,
+    /// Send small writes right away instead of coalescing them, i.e. no Nagle
+    pub tcp_nodelay: bool,
+    /// `SO_SNDBUF` of publish connections, the system default without
+    pub send_buffer: Option<usize>,
+    /// Fail a publishing session which received nothing from the server for this long
+    pub server_silence_timeout: Option<Duration>,
+    /// Fail a TCP connect, through the proxy if any, not done after this long
+    pub connect_timeout: Option<Duration>,
+    /// Fail a handshake, and the TLS or tunnel setup before it, not done after this long
+    pub handshake_timeout: Option<Duration>,
+    /// Fail a session not publishing this long after its connect command
+    pub publish_timeout: Option<Duration>,
+    /// Reconnect after the server closed the connection, at a random delay within this window
+    pub reconnect_on_close: Option<Duration>,
+    /// Reconnect a session which published and then failed this many times in a row
+    pub reconnect_retries: u32,
+    /// Delay before the first reconnect, doubled for each further one up to `MAX_BACKOFF`
+    pub reconnect_backoff: Duration,
+    /// Close publishing sessions at random and publish again
+    pub churn: Option<Churn>,
+    /// Connect and create a stream, but never publish
+    pub idle: bool,
+    /// Accept any certificate from `rtmps` servers
+    pub insecure_tls: bool,
+    /// Log the bitrate, queue and bytes of each client this often
+    pub stats_interval: Option<Duration>,
+    /// Never write faster than this many bits per second, on average over the session
+    pub bitrate_cap_bps: Option<u64>,
+    /// Shape the socket writes to this many bits per second, in bursts
+    pub max_bitrate_bps: Option<u64>,
+    /// Drop video up to the next keyframe while the socket holds the writer back
+    pub drop_on_backpressure: bool,
+    /// Warn once more than this many bytes sent are not acknowledged
+    pub unacked_alarm: Option<u64>,
+    /// Stamp the stream with the wall clock for `--measure-latency`
+    pub measure_latency: bool,
+    /// Send the `onMetaData` of the input again this often
+    pub metadata_interval: Option<Duration>,
+    /// Hash the media sent for `--verify`
+    pub verify: Option<Arc<Ledger>>,
+    /// Hold the written bytes this long before they go out
+    pub net_delay: Option<Duration>,
+    /// Send nothing during these periodic stalls
+    pub net_stall: Option<super::impair::Stall>,
+    /// Open the connections through this proxy
+    pub proxy: Option<super::proxy::Proxy>,
+    /// Connect from these local addresses in turn
+    pub bind: Option<Arc<super::bind::LocalAddrs>>,
+    /// Space the connects of all the sessions
+    pub connect_rate: Option<Arc<super::connect_rate::ConnectRate>>,
+    /// `srt-live-transmit` of `srt://` destinations, the one of the `PATH` without
+    pub srt_transmit: Option<String>,
+    /// `gst-launch-1.0` of WHIP destinations, the one of the `PATH` without
+    pub gst_launch: Option<String>,
+    /// Run the sessions on these runtimes in turn, on the current one without
+    pub shards: Option<Arc<super::shards::Shards>>,
+    /// Only connect to addresses of this family
+    pub family: Option<super::bind::Family>,
+    /// Chunk size announced after the handshake, the library default without
+    pub chunk_size: Option<u32>,
+    /// Bytes the server may receive before it acknowledges, announced with the connect
+    pub window_ack_size: Option<u32>,
+    /// Fields of the connect command in place of or besides those of `ClientSession`
+    pub connect: Option<Arc<ConnectParams>>,
+    /// User and password for `authmod=adobe`, those of the url take precedence
+    pub auth: Option<(String, String)>,
+    pub publish_type: PublishType,
+    /// `releaseStream` and `FCPublish` to send before `createStream`, in this order, in place of
+    /// those of `ClientSession`
+    pub pre_publish: Option<Vec<PrePublish>>,
+    /// Properties of `onMetaData` in place of or besides those of the input
+    pub metadata: Option<Arc<HashMap<String, Amf0Value>>>,
+}
+
+/// The type of the publish command, `record` and `append` make the server write the stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PublishType {
+    Live,
+    Record,
+    Append,
+}
+
+impl Default for PublishType {
+    fn default() -> Self {
+        PublishType::Live
+    }
+}
+
+impl PublishType {
+    /// As the publish command names it
+    fn as_str(self) -> &'static str {
+        match self {
+            PublishType::Live => "live",
+            PublishType::Record => "record",
+            PublishType::Append => "append",
+        }
+    }
+}
+
+impl std::str::FromStr for PublishType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "live" => Ok(PublishType::Live),
+            "record" => Ok(PublishType::Record),
+            "append" => Ok(PublishType::Append),
+            _ => Err(format!("unknown publish type `{}`, use live, record or append", s)),
+        }
+    }
+}
+
+/// A command sent before `createStream`, see `parse_pre_publish`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrePublish {
+    ReleaseStream,
+    FcPublish,
+}
+
+impl PrePublish {
+    /// As the command names it
+    fn as_str(self) -> &'static str {
+        match self {
+            PrePublish::ReleaseStream => "releaseStream",
+            PrePublish::FcPublish => "FCPublish",
+        }
+    }
+}
+
+/// `releaseStream,FCPublish`, the other way round, only one of them, or `none`
+pub fn parse_pre_publish(s: &str) -> Result<Vec<PrePublish>, String> {
+    if s == "none" {
+        return Ok(Vec::new());
+    }
+    let mut commands = Vec::new();
+    for name in s.split(',').map(str::trim) {
+        let command = match name {
+            "releaseStream" => PrePublish::ReleaseStream,
+            "FCPublish" => PrePublish::FcPublish,
+            _ => return Err(format!("unknown command `{}`, use releaseStream, FCPublish or none", name)),
+        };
+        if commands.contains(&command) {
+            return Err(format!("`{}` is listed twice", name));
+        }
+        commands.push(command);
+    }
+    Ok(commands)
+}
+
+/// The stream a re-publish on the same connection goes out on, after `closeStream` and
+/// `deleteStream` of the previous one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamIds {
+    /// The deleted stream id again, without a `createStream`
+    Reuse,
+    /// A new stream of a `createStream` each time
+    New,
+}
+
+impl std::str::FromStr for StreamIds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reuse" => Ok(StreamIds::Reuse),
+            "new" => Ok(StreamIds::New),
+            _ => Err(format!("unknown stream ids `{}`, use reuse or new", s)),
+        }
+    }
+}
+
+/// The connect command object as some servers want it: the vhost or auth modules of SRS and
+/// Wowza look at these.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectParams {
+    /// In place of the app of the url, in the command object only
+    pub app: Option<String>,
+    pub flash_ver: Option<String>,
+    pub swf_url: Option<String>,
+    pub page_url: Option<String>,
+    /// Further properties, they replace those of the same name
+    pub extra: Vec<(String, Amf0Value)>,
+}
+
+impl ConnectParams {
+    /// `key=value` of `--connect-param`: a number, `true` or `false`, a string otherwise
+    pub fn parse_extra(s: &str) -> Result<(String, Amf0Value), String> {
+        let mut parts = s.splitn(2, '=');
+        let key = parts.next().filter(|k| !k.is_empty()).ok_or_else(|| format!("missing name in `{}`", s))?;
+        let value = parts.next().ok_or_else(|| format!("missing value in `{}`", s))?;
+        let value = match value {
+            "true" => Amf0Value::Boolean(true),
+            "false" => Amf0Value::Boolean(false),
+            _ => match value.parse::<f64>() {
+                Ok(n) => Amf0Value::Number(n),
+                Err(_) => Amf0Value::Utf8String(value.to_owned()),
+            },
+        };
+        Ok((key.to_owned(), value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flash_ver.is_none() && !self.needs_command()
+    }
+
+    /// Only `flashVer` is supported by `ClientSession`, the command is built here for the others
+    fn needs_command(&self) -> bool {
+        self.app.is_some() || self.swf_url.is_some() || self.page_url.is_some() || !self.extra.is_empty()
+    }
+}
+
+/// The properties of a `--metadata-json` object: numbers, strings, booleans, `null` and objects
+/// of them
+pub fn parse_metadata_json(json: &str) -> Result<HashMap<String, Amf0Value>, String> {
+    fn amf0(value: serde_json::Value) -> Result<Amf0Value, String> {
+        Ok(match value {
+            serde_json::Value::Null => Amf0Value::Null,
+            serde_json::Value::Bool(b) => Amf0Value::Boolean(b),
+            serde_json::Value::Number(n) => Amf0Value::Number(n.as_f64().unwrap_or_default()),
+            serde_json::Value::String(s) => Amf0Value::Utf8String(s),
+            serde_json::Value::Object(o) => Amf0Value::Object(properties(o)?),
+            serde_json::Value::Array(_) => return Err("arrays are not supported".into()),
+        })
+    }
+    fn properties(object: serde_json::Map<String, serde_json::Value>) -> Result<HashMap<String, Amf0Value>, String> {
+        object.into_iter().map(|(k, v)| Ok((k, amf0(v)?))).collect()
+    }
+    match serde_json::from_str(json).map_err(|e| e.to_string())? {
+        serde_json::Value::Object(object) => properties(object),
+        _ => Err("expected an object".into()),
+    }
+}
+
 #[derive(Debug)]
 pub struct Client {
 }
@@ -56,42 +318,415 @@ struct Session {
     stream: String,
     inner: ClientSession,
     ready: bool,
+    /// `onBWCheck` calls answered so far
+    bw_checks: u32,
+    /// From a rejected connect, reconnect there
+    redirect: Option<String>,
+    /// The description of a rejected connect
+    rejection: Option<String>,
+    /// The code and description of a fatal `onStatus`, the session fails
+    failure: Option<String>,
+    idle: bool,
+    /// The stream of an idle session was created
+    stream_created: bool,
+    /// From the `createStream` result, to delete it at the end
+    stream_id: Option<u32>,
+    /// Publishing again on the connection, see `StreamIds`
+    republishing: bool,
+    /// The stream is one of a `createStream` of our own, which `ClientSession` does not know of:
+    /// the media and metadata are built here
+    own_stream: bool,
+    timestamp_jitter_ms: u32,
+    publish_type: PublishType,
+    pre_publish: Option<Vec<PrePublish>>,
+    connect: Option<Arc<ConnectParams>>,
+    metadata_overrides: Option<Arc<HashMap<String, Amf0Value>>>,
+    /// Of the `ClientSessionConfig`, for a connect command built here
+    flash_version: String,
+    chunk_size: u32,
+    /// The span of the publish, and of the step of the setup the session is at, closed with the
+    /// next one: `connect_command`, `create_stream`, `publish_command` and `first_media`
+    span: Span,
+    phase: Span,
+    stats: Arc<ClientStats>,
     logger: Logger,
 }
 
+/// Longest GOP cached, in messages, a longer one is dropped and the session waits for a keyframe
+const MAX_GOP: usize = 4096;
+
+/// Metadata and sequence headers last seen on the broadcast. They are sent only once by the
+/// reader, so every new session (reconnect, re-publish) needs them replayed before any media.
+///
+/// The media since the latest video keyframe is kept as well, a session joining the broadcast
+/// mid-stream starts with it rather than with a frame which cannot be decoded.
+#[derive(Default)]
+pub struct HeaderCache {
+    metadata: Option<Arc<PacketType>>,
+    video: Option<Arc<PacketType>>,
+    audio: Option<Arc<PacketType>>,
+    /// The multichannel configuration of enhanced audio, which comes with its sequence header
+    audio_config: Option<Arc<PacketType>>,
+    gop: Vec<Arc<PacketType>>,
+}
+
+impl HeaderCache {
+    /// Returns `true` if the packet replaces a different sequence header, i.e. codec parameters changed
+    pub fn observe(&mut self, packet: &Arc<PacketType>) -> bool {
+        let (cached, data) = match **packet {
+            PacketType::Metadata(_) => {
+                self.metadata = Some(packet.clone());
+                return false;
+            }
+            PacketType::Video{ ref data, .. } if flv::is_video_sequence_header(data) => (&mut self.video, data),
+            PacketType::Audio{ ref data, .. } if flv::is_audio_multichannel_config(data) => (&mut self.audio_config, data),
+            PacketType::Audio{ ref data, .. } if flv::is_audio_sequence_header(data) => (&mut self.audio, data),
+            PacketType::Video{ ref data, .. } if flv::is_video_keyframe(data) => {
+                self.gop.clear();
+                self.gop.push(packet.clone());
+                return false;
+            }
+            PacketType::Video{ .. } | PacketType::Audio{ .. } if !self.gop.is_empty() => {
+                if self.gop.len() == MAX_GOP {
+                    self.gop = Vec::new();
+                } else {
+                    self.gop.push(packet.clone());
+                }
+                return false;
+            }
+            _ => return false,
+        };
+        let changed = match cached.as_deref() {
+            Some(PacketType::Video{ data: last, .. }) | Some(PacketType::Audio{ data: last, .. }) => last != data,
+            _ => false,
+        };
+        *cached = Some(packet.clone());
+        changed
+    }
+
+    pub fn metadata(&self) -> Option<&StreamMetadata> {
+        match self.metadata.as_deref() {
+            Some(PacketType::Metadata(metadata)) => Some(metadata),
+            _ => None,
+        }
+    }
+
+    /// The headers, then the GOP
+    pub fn packets(&self) -> impl Iterator<Item = Arc<PacketType>> + '_ {
+        self.metadata.iter().chain(self.video.iter()).chain(self.audio_config.iter()).chain(self.audio.iter())
+            .chain(self.gop.iter()).cloned()
+    }
+}
+
+/// Of the publishing sessions, `fraction` are closed and published again within every `interval`.
+/// Each session ends after a random time, exponentially distributed, so the closes are spread
+/// evenly over the run rather than in waves.
+#[derive(Clone, Copy, Debug)]
+pub struct Churn {
+    pub fraction: f64,
+    pub interval: Duration,
+}
+
+impl Churn {
+    /// How long the next session publishes
+    fn lifetime(&self) -> Duration {
+        let mean = self.interval.as_secs_f64() / self.fraction;
+        Duration::from_secs_f64(-(1.0 - rand::random::<f64>()).ln() * mean)
+    }
+}
+
+/// The url of the destination and its backups, failed over in turn
+struct Endpoints {
+    urls: Vec<Url>,
+    current: usize,
+    /// Since the last session which reached publishing, each url is tried once before giving up
+    failovers: usize,
+}
+
+impl Endpoints {
+    fn new(url: &Url) -> Self {
+        let primary = Url { backups: Vec::new(), ..url.clone() };
+        Self { urls: std::iter::once(primary).chain(url.backups.iter().cloned()).collect(), current: 0, failovers: 0 }
+    }
+
+    fn has_next(&self) -> bool {
+        self.failovers + 1 < self.urls.len()
+    }
+
+    fn next(&mut self) -> &Url {
+        self.current = (self.current + 1) % self.urls.len();
+        self.failovers += 1;
+        &self.urls[self.current]
+    }
+}
+
+/// Why a publish session ended without error
+enum SessionEnd {
+    Republish,
+    /// Closed by `Churn`, to publish again
+    Churned,
+    /// The server rejected the connect and redirected to this url
+    Redirect(String),
+    ServerClosed,
+    BudgetExhausted,
+    Closed,
+    /// The broadcast ended and the session was torn down
+    Ended,
+    /// Removed from the run, see `ClientStats::stop`
+    Stopped,
+    /// The connect was rejected for `authmod=adobe`, with this description
+    AuthRejected(String),
+}
+
 impl Client {
-    pub async fn new(url: Url, broadcast_rx: broadcast::Receiver<Arc<PacketType>>, logger: &Logger) -> Self {
-        let Url { app, stream, host, port, vhost } = url;
+    pub async fn new(url: Url,
+                     mut broadcast_rx: fanout::Receiver,
+                     seed_headers: Vec<Arc<PacketType>>,
+                     config: Arc<Config>,
+                     stats: Arc<ClientStats>,
+                     logger: &Logger) -> Self
+    {
+        let wire_stream = url.wire_stream();
+        let (server, tc_url) = (url.server(), url.tc_url());
+        let mut endpoints = Endpoints::new(&url);
+        let Url { scheme, app, stream, host, port, auth, .. } = url;
         let logger = logger.new(o!("app" => app.clone(), "stream" => stream.clone()));
+        let (tc_url_session, stream_session) = (tc_url.clone(), stream.clone());
         let (notify_tx, notify_rx) = oneshot::channel();
-        let (buffer_tx, buffer_rx) = futures::channel::mpsc::channel(8);
+        stats.set_queue(broadcast_rx.gauge());
 
-        tokio::spawn(async move {
-            broadcast_rx.into_stream()
-                .map(|r| async { r })
-                .buffered(8)
-                .map_err(|_e| format!("Receive broadcast error") )
-                .forward(buffer_tx.sink_map_err(|_e| format!("Buffer sender error"))).await
-        });
+        if let Some(interval) = config.stats_interval {
+            tokio::spawn(log_stats(stats.clone(), interval, logger.clone()));
+        }
+        if let Some(threshold) = config.unacked_alarm {
+            tokio::spawn(watch_acks(stats.clone(), threshold, logger.clone()));
+        }
 
         let logger_inner = logger.clone();
-        tokio::spawn(async move {
-            let server = format!("{}:{}", host, port);
-            match Self::connect(&server).await {
-                Ok(transport) => {
-                    let tc_url = if let Some(vhost) = vhost {
-                        format!("rtmp://{}:{}/{}", vhost, port, app)
-                    } else {
-                        format!("rtmp://{}:{}/{}", host, port, app)
-                    };
-                    info!(logger_inner, "starting to push RTMP server: {}, with tc_url: {}/{}", server, tc_url, stream);
-                    Self::start_push(transport, buffer_rx, notify_tx, app, stream, tc_url, logger_inner.clone()).await;
+        let logger_panic = logger.clone();
+        let shards = config.shards.clone();
+        let span = info_span!("client", app = %app, stream = %stream);
+        let push = async move {
+            let (mut scheme, mut app) = (scheme, app);
+            let (mut server, mut tc_url) = (server, tc_url);
+            let (mut host, mut port) = (host, port);
+            let mut wire_stream = wire_stream;
+            let mut redirects = 0;
+            // Reconnects since the last session which reached publishing
+            let mut retries = 0;
+            // The error of the session to fail over from, before the next connect
+            let mut failover: Option<String> = None;
+            let mut auth = auth.or_else(|| config.auth.clone());
+            // After the app and the tcUrl of the connect, and how often it was answered
+            let mut auth_query = auth.as_ref().map(|(user, _)| adobe_auth::start_query(user));
+            let mut auth_steps = 0;
+            let mut notify_tx = Some(notify_tx);
+            // Headers broadcast before this client subscribed
+            let mut headers = HeaderCache::default();
+            for packet in &seed_headers {
+                headers.observe(packet);
+            }
+            loop {
+                if stats.stop_requested() {
+                    info!(logger_inner, "Removed from the run before publishing again");
+                    stats.set_state(ClientState::Finished);
+                    break;
                 }
-                Err(e) => {
-                    error!(logger_inner, "connect to server error: {}", e);
+                if let Some(error) = failover.take() {
+                    let next = endpoints.next();
+                    warn!(logger_inner, "Fail over"; "to" => %next, "error" => &error);
+                    stats.add_failover(next.to_string(), &error);
+                    stats.set_state(ClientState::Connecting);
+                    scheme = next.scheme;
+                    app = next.app.clone();
+                    server = next.server();
+                    tc_url = next.tc_url();
+                    host = next.host.clone();
+                    port = next.port;
+                    wire_stream = next.wire_stream();
+                    auth = next.auth.clone().or_else(|| config.auth.clone());
+                    auth_query = auth.as_ref().map(|(user, _)| adobe_auth::start_query(user));
+                    auth_steps = 0;
+                    redirects = 0;
+                }
+                let transport = match Self::connect(&server, scheme, &host, port, &config, &stats, &logger_inner).instrument(debug_span!("connect", server = %server)).await {
+                    Ok(transport) => transport,
+                    Err(e) if endpoints.has_next() => {
+                        failover = Some(e.to_string());
+                        continue;
+                    }
+                    // Only a session which published before is retried, i.e. once notified
+                    Err(e) if notify_tx.is_none() && retries < config.reconnect_retries => {
+                        retries += 1;
+                        let delay = backoff(config.reconnect_backoff, retries);
+                        warn!(logger_inner, "Reconnect error, retry"; "error" => %e, "retry" => retries, "delay_ms" => delay.as_millis() as u64);
+                        tokio::time::delay_for(delay).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(logger_inner, "connect to server error: {}", e);
+                        stats.fail(e);
+                        break;
+                    }
+                };
+                info!(logger_inner, "starting to push RTMP server: {}, with tc_url: {}/{}", server, tc_url, stream);
+                let (connect_app, connect_tc_url) = match auth_query {
+                    Some(ref query) => (format!("{}?{}", app, query), format!("{}?{}", tc_url, query)),
+                    None => (app.clone(), tc_url.clone()),
+                };
+                let session = Self::start_push(transport, &mut broadcast_rx, &mut notify_tx, &mut headers, &connect_app, &wire_stream, &connect_tc_url, &config, &stats, &logger_inner);
+                let end = session.await;
+                if stats.state() == ClientState::Publishing {
+                    retries = 0;
+                    auth_steps = 0;
+                    endpoints.failovers = 0;
+                }
+                match end {
+                    Ok(SessionEnd::ServerClosed) => match config.reconnect_on_close {
+                        Some(window) => {
+                            let delay = Duration::from_millis(rand::thread_rng().gen_range(0, window.as_millis() as u64 + 1));
+                            warn!(logger_inner, "Connection closed by the server, reconnect"; "delay_ms" => delay.as_millis() as u64);
+                            stats.add_reconnect();
+                            stats.set_state(ClientState::Connecting);
+                            tokio::time::delay_for(delay).await;
+                        }
+                        None if endpoints.has_next() => {
+                            if notify_tx.is_none() {
+                                stats.add_reconnect();
+                            }
+                            failover = Some("connection closed by the server".to_owned());
+                        }
+                        None if notify_tx.is_none() && retries < config.reconnect_retries => {
+                            retries += 1;
+                            let delay = backoff(config.reconnect_backoff, retries);
+                            warn!(logger_inner, "Connection closed by the server, reconnect"; "retry" => retries, "delay_ms" => delay.as_millis() as u64);
+                            stats.add_reconnect();
+                            stats.set_state(ClientState::Connecting);
+                            tokio::time::delay_for(delay).await;
+                        }
+                        None => {
+                            error!(logger_inner, "Connection closed by the server");
+                            stats.fail("connection lost");
+                            break;
+                        }
+                    },
+                    Ok(SessionEnd::Republish) => {
+                        info!(logger_inner, "Input restarted, publish again");
+                        stats.add_reconnect();
+                        stats.set_state(ClientState::Connecting);
+                    }
+                    Ok(SessionEnd::Churned) => {
+                        info!(logger_inner, "Churn, session closed, publish again");
+                        stats.add_reconnect();
+                        stats.set_state(ClientState::Connecting);
+                    }
+                    Ok(SessionEnd::AuthRejected(description)) => {
+                        let next = match auth {
+                            None => Err("the server asks for authmod=adobe, give a user and password".to_owned()),
+                            Some(_) if auth_steps == MAX_AUTH_STEPS => Err("authentication did not complete".to_owned()),
+                            Some((ref user, ref password)) => adobe_auth::next_query(&description, user, password),
+                        };
+                        match next {
+                            Ok(query) => {
+                                debug!(logger_inner, "Auth challenge, connect again"; "description" => &description);
+                                auth_steps += 1;
+                                auth_query = Some(query);
+                                stats.set_state(ClientState::Connecting);
+                            }
+                            Err(e) => {
+                                error!(logger_inner, "Publish authentication error"; "error" => &e, "description" => &description);
+                                stats.fail(e);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(SessionEnd::Redirect(_)) if redirects == MAX_REDIRECTS => {
+                        error!(logger_inner, "Too many connect redirects"; "redirects" => redirects);
+                        stats.fail(format!("more than {} connect redirects", MAX_REDIRECTS));
+                        break;
+                    }
+                    Ok(SessionEnd::Redirect(url)) => {
+                        let redirect = match rtmp_url::parse_redirect(&url) {
+                            Ok(redirect) => redirect,
+                            Err(e) => {
+                                error!(logger_inner, "Invalid connect redirect"; "url" => &url, "error" => &e);
+                                stats.fail(format!("invalid connect redirect {}: {}", url, e));
+                                break;
+                            }
+                        };
+                        info!(logger_inner, "Connect redirected"; "url" => &url);
+                        redirects += 1;
+                        stats.add_redirect(url);
+                        stats.set_state(ClientState::Connecting);
+                        scheme = redirect.scheme;
+                        if let Some(redirect_app) = redirect.app {
+                            app = redirect_app;
+                        }
+                        server = rtmp_url::authority(&redirect.host, redirect.port);
+                        host = redirect.host;
+                        port = redirect.port;
+                        tc_url = format!("{}://{}/{}", scheme, rtmp_url::authority(&redirect.host, redirect.port), app);
+                    }
+                    Ok(SessionEnd::BudgetExhausted) if config.replace_exhausted => {
+                        info!(logger_inner, "Byte budget exhausted, publish again"; "bytes_sent" => stats.bytes_sent());
+                        stats.add_reconnect();
+                        stats.set_state(ClientState::Connecting);
+                    }
+                    Ok(SessionEnd::BudgetExhausted) => {
+                        info!(logger_inner, "Byte budget exhausted, stop publishing"; "bytes_sent" => stats.bytes_sent());
+                        stats.set_state(ClientState::Finished);
+                        break;
+                    }
+                    Ok(SessionEnd::Ended) => {
+                        // The state stays as it was at the end of the broadcast
+                        info!(logger_inner, "Broadcast ended, session torn down");
+                        break;
+                    }
+                    Ok(SessionEnd::Stopped) => {
+                        info!(logger_inner, "Removed from the run, session torn down");
+                        stats.set_state(ClientState::Finished);
+                        break;
+                    }
+                    Ok(SessionEnd::Closed) => {
+                        info!(logger_inner, "Reading broadcast done");
+                        stats.set_state(ClientState::Finished);
+                        break;
+                    }
+                    Err(e) if endpoints.has_next() => {
+                        if notify_tx.is_none() {
+                            stats.add_reconnect();
+                        }
+                        failover = Some(e.to_string());
+                    }
+                    Err(e) if notify_tx.is_none() && retries < config.reconnect_retries => {
+                        retries += 1;
+                        let delay = backoff(config.reconnect_backoff, retries);
+                        warn!(logger_inner, "Session error, reconnect"; "error" => %e, "retry" => retries, "delay_ms" => delay.as_millis() as u64);
+                        stats.add_reconnect();
+                        stats.set_state(ClientState::Connecting);
+                        tokio::time::delay_for(delay).await;
+                    }
+                    Err(e) => {
+                        error!(logger_inner, "Reading broadcast error"; "error" => %e);
+                        stats.fail(e);
+                        break;
+                    }
                 }
             }
-        });
+        };
+        let push = panic_hook::scope(format!("client {}/{}", tc_url_session, stream_session), push.instrument(span));
+        let task = async move {
+            // The panic hook has already logged the backtrace, add the client context here
+            if AssertUnwindSafe(push).catch_unwind().await.is_err() {
+                crit!(logger_panic, "Publish client task panicked");
+            }
+        };
+        match shards {
+            Some(shards) => shards.spawn(task),
+            None => {
+                tokio::spawn(task);
+            }
+        }
 
         if let Err(e) = notify_rx.await {
             error!(logger, "notify_rx error"; "error" => %e);
@@ -99,37 +734,109 @@ impl Client {
         Self {}
     }
 
-    async fn connect<A: tokio::net::ToSocketAddrs>(addr: A) -> Result<Framed<TcpStream, super::codec::Codec>, Error> {
-        let socket = TcpStream::connect(addr).await?;
-        let io = super::handshake(socket).await?;
+    /// Connect and handshake over the transport of `scheme`, `host` is the name the transport
+    /// layers (RTMPT, TLS) present to the server.
+    async fn connect<A: tokio::net::ToSocketAddrs>(addr: A,
+                                                   scheme: Scheme,
+                                                   host: &str,
+                                                   port: u16,
+                                                   config: &Config,
+                                                   stats: &ClientStats,
+                                                   logger: &Logger) -> Result<Framed<Box<dyn Io>, super::codec::Codec>, Error>
+    {
+        if let Some(ref rate) = config.connect_rate {
+            rate.wait().await;
+        }
+        let socket = match config.proxy {
+            Some(ref proxy) => {
+                let connecting = proxy.connect(host, port, config.family, config.bind.as_deref());
+                let socket = within(config.connect_timeout, "TCP connect", connecting).instrument(debug_span!("tcp_connect")).await?;
+                // The peer is the proxy, the address of the server is not known
+                debug!(logger, "TCP connected through proxy"; "proxy" => &proxy.server);
+                socket
+            }
+            None => {
+                let connecting = super::bind::connect(addr, config.family, config.bind.as_deref());
+                let socket = within(config.connect_timeout, "TCP connect", connecting).instrument(debug_span!("tcp_connect")).await?;
+                if let Ok(peer_addr) = socket.peer_addr() {
+                    debug!(logger, "TCP connected"; "peer_addr" => %peer_addr);
+                    stats.set_peer_addr(peer_addr);
+                }
+                socket
+            }
+        };
+        if let Some(idle) = config.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+            let keepalive = match config.tcp_keepalive_interval {
+                Some(interval) => keepalive.with_interval(interval),
+                None => keepalive,
+            };
+            socket2::SockRef::from(&socket).set_tcp_keepalive(&keepalive)?;
+        }
+        if config.tcp_nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(size) = config.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        let opening = super::open(socket, scheme, host, port, config.insecure_tls);
+        let io = within(config.handshake_timeout, "handshake", opening).await?;
+        let io: Box<dyn super::Io> = match config.max_bitrate_bps.filter(|bps| *bps > 0) {
+            Some(bps) => Box::new(super::shaper::Shaped::new(io, bps)),
+            None => io,
+        };
+        let io: Box<dyn super::Io> = if config.net_delay.is_some() || config.net_stall.is_some() {
+            Box::new(super::impair::Impaired::new(io, config.net_delay, config.net_stall))
+        } else {
+            io
+        };
         Ok(super::codec::Codec::default().framed(io))
     }
 
-    async fn start_push<T, S1, S2>(transport: Framed<T, super::codec::Codec>,
-                                   buffer_rx: futures::channel::mpsc::Receiver<Arc<PacketType>>,
-                                   notify_tx: oneshot::Sender<()>,
-                                   app: S1, stream: S2, tc_url: String,
-                                   logger: Logger)
-        where S1: Into<String>,
-              S2: Into<String>,
-              T: AsyncRead + AsyncWrite + Send + 'static,
+    #[allow(clippy::too_many_arguments)]
+    async fn start_push<T>(transport: Framed<T, super::codec::Codec>,
+                           broadcast_rx: &mut fanout::Receiver,
+                           notify_tx: &mut Option<oneshot::Sender<()>>,
+                           headers: &mut HeaderCache,
+                           app: &str, stream: &str, tc_url: &str,
+                           config: &Config,
+                           stats: &Arc<ClientStats>,
+                           logger: &Logger) -> Result<SessionEnd, Error>
+        where T: AsyncRead + AsyncWrite + Send + 'static,
     {
         let (to_server, from_server) = transport.split();
-        let (tx, rx) = futures::channel::mpsc::channel(8); // response to socket channel
+        let (tx, rx) = futures::channel::mpsc::channel(WRITER_QUEUE); // response to socket channel
 
         // write back to connection asynchronously
         let logger_inner = logger.clone();
+        let stats_inner = stats.clone();
+        let bitrate_cap_bps = config.bitrate_cap_bps;
         tokio::spawn(async move {
-            let rs = rx.map(|r| Ok(r)).forward(to_server).await;
+            let rs = write_to_server(rx, to_server, bitrate_cap_bps, &stats_inner).await;
+            stats_inner.set_socket_closed();
             match rs {
                 Ok(_) => warn!(logger_inner, "Publisher write end finished"),
                 Err(e) => error!(logger_inner, "Publisher write error"; "error" => %e),
             };
         });
 
-        let broadcast_rx = buffer_rx
-            .map(|m| Ok::<_, Error>(ReceivedType::Broadcast(m)))
-            .map_err(|e| {
+        // Dropped messages are logged, at most every `LAG_WARNING_INTERVAL`, the session goes on
+        let (lag_logger, mut lagged, mut warned) = (logger.clone(), 0, None::<Instant>);
+        let broadcast_rx = broadcast_rx
+            .filter_map(move |received| future::ready(match received {
+                Err(fanout::RecvError::Lagged(n)) => {
+                    lagged += n;
+                    if warned.map_or(true, |at| at.elapsed() >= LAG_WARNING_INTERVAL) {
+                        warn!(lag_logger, "Broadcast lagged, messages dropped"; "dropped" => lagged);
+                        warned = Some(Instant::now());
+                        lagged = 0;
+                    }
+                    None
+                }
+                received => Some(received),
+            }))
+            .map_ok(ReceivedType::Broadcast)
+            .map_err(|e| -> Error {
                 let err_msg = format!("Receive source broadcast error: {}", e);
                 ErrorKind::Unknown(err_msg).into()
             })
@@ -137,45 +844,224 @@ impl Client {
 
         let from_server = from_server
             .map_ok(|(message, bytes_read)| ReceivedType::FromClient{ message, bytes_read })
-            .chain(stream::once(async { Err(ErrorKind::Unknown("connection lost".into()).into()) }));
+            .chain(stream::once(async { Ok(ReceivedType::ServerClosed) }));
 
         let reading_rx = stream::select(from_server, broadcast_rx);
 
-        start_reading(tx, reading_rx, notify_tx, app.into(), stream.into(), tc_url, logger).await;
+        start_reading(tx, reading_rx, notify_tx, headers, app.into(), stream.into(), tc_url.into(), config, stats, logger)
+            .instrument(debug_span!("publish"))
+            .await
     }
 }
 
 impl Session {
-    fn new(app: String, stream: String, inner: ClientSession, logger: &Logger) -> Self {
-        Self { app, stream, inner, ready: false, logger: logger.clone() }
+    fn new(app: String,
+           stream: String,
+           inner: ClientSession,
+           (flash_version, chunk_size): (String, u32),
+           config: &Config,
+           stats: Arc<ClientStats>,
+           logger: &Logger) -> Self
+    {
+        Self {
+            app,
+            stream,
+            inner,
+            ready: false,
+            bw_checks: 0,
+            redirect: None,
+            rejection: None,
+            failure: None,
+            idle: config.idle,
+            stream_created: false,
+            stream_id: None,
+            republishing: false,
+            own_stream: false,
+            timestamp_jitter_ms: config.timestamp_jitter_ms,
+            publish_type: config.publish_type,
+            pre_publish: config.pre_publish.clone(),
+            connect: config.connect.clone(),
+            metadata_overrides: config.metadata.clone(),
+            flash_version,
+            chunk_size,
+            span: Span::current(),
+            phase: Span::none(),
+            stats,
+            logger: logger.clone(),
+        }
+    }
+
+    /// End the current step of the setup, and start `next`
+    fn enter_phase(&mut self, next: fn(&Span) -> Span) {
+        self.phase = next(&self.span);
+    }
+
+    /// `FCUnpublish`, `closeStream` and `deleteStream`, as far as the session got
+    fn teardown(&self) -> Vec<Result<Packet, Error>> {
+        let mut packets = Vec::new();
+        if self.ready {
+            packets.push(command::fc_unpublish(&self.stream));
+        }
+        if let Some(stream_id) = self.stream_id {
+            if self.ready {
+                packets.push(command::close_stream(stream_id));
+            }
+            packets.push(command::delete_stream(stream_id));
+        }
+        packets
+    }
+
+    /// Tear the stream down and publish again on the connection, on the stream `ids` says. The
+    /// media waits for the `NetStream.Publish.Start` of the new publish.
+    fn republish(&mut self, ids: StreamIds) -> Vec<Result<Packet, Error>> {
+        let mut packets = self.teardown();
+        self.ready = false;
+        self.republishing = true;
+        match ids {
+            StreamIds::Reuse => {
+                if let Some(stream_id) = self.stream_id {
+                    packets.push(command::publish(stream_id, &self.stream, self.publish_type.as_str()));
+                }
+                self.enter_phase(|parent| debug_span!(parent: parent, "publish_command"));
+            }
+            StreamIds::New => {
+                self.stream_id = None;
+                self.own_stream = true;
+                packets.push(command::create_stream(REPUBLISH_TRANSACTION_ID));
+                self.enter_phase(|parent| debug_span!(parent: parent, "create_stream"));
+            }
+        }
+        packets
+    }
+
+    /// The id of a stream `ClientSession` does not know of, `None` for its own
+    fn own_stream_id(&self) -> Option<u32> {
+        self.stream_id.filter(|_| self.own_stream)
     }
 
     fn request_connect(&mut self, tc_url: String) -> Result<Packet, Error> {
-        self.inner.request_connection(self.app.clone(), Some(tc_url)).map_err(From::from)
+        self.enter_phase(|parent| debug_span!(parent: parent, "connect_command"));
+        let packet = self.inner.request_connection(self.app.clone(), Some(tc_url.clone()))?;
+        let params = match self.connect {
+            Some(ref params) if params.needs_command() => params,
+            _ => return Ok(packet),
+        };
+        // The session still waits for the result of its own connect, under the same transaction
+        let mut properties = HashMap::new();
+        let text = |s: &str| Amf0Value::Utf8String(s.to_owned());
+        properties.insert("app".to_owned(), text(params.app.as_deref().unwrap_or(&self.app)));
+        properties.insert("type".to_owned(), text("nonprivate"));
+        properties.insert("flashVer".to_owned(), text(&self.flash_version));
+        properties.insert("tcUrl".to_owned(), text(&tc_url));
+        properties.insert("objectEncoding".to_owned(), Amf0Value::Number(0.0));
+        if let Some(ref swf_url) = params.swf_url {
+            properties.insert("swfUrl".to_owned(), text(swf_url));
+        }
+        if let Some(ref page_url) = params.page_url {
+            properties.insert("pageUrl".to_owned(), text(page_url));
+        }
+        for (key, value) in &params.extra {
+            properties.insert(key.clone(), value.clone());
+        }
+        command::connect(CONNECT_TRANSACTION_ID, properties, self.chunk_size as usize)
     }
 
-    fn handle_broadcast(&mut self, send_type: Arc<PacketType>) -> Result<Packet, ClientSessionError> {
+    fn handle_broadcast(&mut self, send_type: Arc<PacketType>) -> Result<Packet, Error> {
+        let _span = trace_span!("handle_broadcast").entered();
         match *send_type {
             PacketType::Audio{ ref data, ref ts, .. } => {
                 if flv::is_audio_sequence_header(data) {
                     debug!(self.logger, "Send audio sequence header")
                 }
-                self.inner.publish_audio_data(data.clone(), ts.clone(), false)
+                let ts = self.jitter(ts);
+                match self.own_stream_id() {
+                    Some(stream_id) => Ok(command::audio(stream_id, ts.value, data, self.chunk_size as usize)),
+                    None => Ok(self.inner.publish_audio_data(data.clone(), ts, false)?),
+                }
             }
             PacketType::Video{ ref data, ref ts, .. } => {
                 if flv::is_video_sequence_header(data) {
                     debug!(self.logger, "Send video sequence header");
                 }
-                self.inner.publish_video_data(data.clone(), ts.clone(), false)
+                let ts = self.jitter(ts);
+                match self.own_stream_id() {
+                    Some(stream_id) => Ok(command::video(stream_id, ts.value, data, self.chunk_size as usize)),
+                    None => Ok(self.inner.publish_video_data(data.clone(), ts, false)?),
+                }
             }
             PacketType::Metadata( ref metadata ) => {
                 debug!(self.logger, "Send metadata");
-                self.inner.publish_metadata(&metadata)
+                self.metadata(metadata, 0)
             }
+            PacketType::Loop | PacketType::End => unreachable!("markers are not published"),
         }
     }
 
+    /// `onMetaData` with the overrides, at `timestamp`
+    fn metadata(&mut self, metadata: &StreamMetadata, timestamp: u32) -> Result<Packet, Error> {
+        match self.stream_id {
+            // `ClientSession` only sends the fields it knows, at timestamp 0, on its own stream
+            Some(stream_id) if self.metadata_overrides.is_some() || timestamp > 0 || self.own_stream => {
+                let mut properties = command::metadata_properties(metadata);
+                if let Some(ref overrides) = self.metadata_overrides {
+                    properties.extend(overrides.iter().map(|(k, v)| (k.clone(), v.clone())));
+                }
+                command::set_data_frame(stream_id, timestamp, properties, self.chunk_size as usize)
+            }
+            _ => Ok(self.inner.publish_metadata(metadata)?),
+        }
+    }
+
+    /// Emulate an encoder with an imperfect clock, timestamps may go backwards
+    fn jitter(&self, ts: &RtmpTimestamp) -> RtmpTimestamp {
+        if self.timestamp_jitter_ms == 0 {
+            return RtmpTimestamp::new(ts.value);
+        }
+        let max = self.timestamp_jitter_ms as i64;
+        let offset = rand::thread_rng().gen_range(-max, max + 1);
+        // Past 32 bits the cast wraps like the timestamps do, only the start is kept from going negative
+        RtmpTimestamp::new((ts.value as i64 + offset).max(0) as u32)
+    }
+
     fn handle_from_peer_server(&mut self, message: MessagePayload, bytes_read: usize) -> Vec<Result<Packet, Error>> {
+        let _span = trace_span!("handle_from_peer_server").entered();
+        // The publish of a re-publish, on the stream just created
+        let mut republish = None;
+        if message.type_id == AMF0_COMMAND {
+            if let Ok(RtmpMessage::Amf0Command{ ref command_name, transaction_id, ref additional_arguments, .. }) = message.to_rtmp_message() {
+                if !self.ready && command_name == "_error" {
+                    self.redirect = additional_arguments.iter().find_map(redirect_url);
+                    self.rejection = additional_arguments.iter().find_map(rejection_description);
+                }
+                if !self.ready && self.idle && command_name == "_result" && transaction_id == IDLE_TRANSACTION_ID {
+                    self.stream_created = true;
+                }
+                // The connect result carries an object, only `createStream` returns a number, some
+                // servers answer `releaseStream` with one too
+                if !self.ready && command_name == "_result" && !is_pre_publish(transaction_id) {
+                    if let Some(Amf0Value::Number(id)) = additional_arguments.get(0) {
+                        self.stream_id = Some(*id as u32);
+                        if !self.idle {
+                            self.enter_phase(|parent| debug_span!(parent: parent, "publish_command"));
+                        }
+                        if self.republishing && transaction_id == REPUBLISH_TRANSACTION_ID {
+                            republish = Some(command::publish(*id as u32, &self.stream, self.publish_type.as_str()));
+                        }
+                    }
+                }
+                // `ClientSession` only looks for the start of the publish
+                if command_name == "onStatus" {
+                    if let Some(status) = additional_arguments.iter().find_map(Status::parse) {
+                        // `ClientSession` is publishing all along, the re-publish is tracked here
+                        if self.republishing && status.code == "NetStream.Publish.Start" {
+                            self.republishing = false;
+                            self.handle_push_publish_accepted_event();
+                        }
+                        self.handle_status(status);
+                    }
+                }
+            }
+        }
         let (outbounds, event, unknown) = match self.inner.handle_input_message(message, bytes_read) {
             Ok(v) => v,
             Err(e) => {
@@ -203,7 +1089,7 @@ impl Session {
         }
 
         // handle raised event
-        let mut outbounds = outbounds.into_iter().map(Ok).collect::<Vec<_>>();
+        let mut outbounds = outbounds.into_iter().map(Ok).chain(republish).collect::<Vec<_>>();
         match self.handle_raised_event(event) {
             Ok(None) => (),
             Ok(Some(v)) => {
@@ -225,12 +1111,20 @@ impl Session {
             None => (),
             Some(event) => {
                 match event {
+                    ConnectionRequestAccepted if self.idle => {
+                        debug!(self.logger, "Connect request accepted, create a stream");
+                        return command::create_stream(IDLE_TRANSACTION_ID).map(|p| Some(vec![p]));
+                    }
                     ConnectionRequestAccepted => {
                         debug!(self.logger, "Connect request accepted");
+                        self.enter_phase(|parent| debug_span!(parent: parent, "create_stream"));
                         return self.handle_push_connection_accepted_event().map(Some);
                     }
                     ConnectionRequestRejected{ description } => {
-                        debug!(self.logger, "Connect request rejected");
+                        debug!(self.logger, "Connect request rejected"; "redirect" => ?self.redirect);
+                        if self.redirect.is_some() {
+                            return Ok(None);
+                        }
                         return Err(ErrorKind::Unknown(format!("connect request rejected by peer server: {}", description)).into());
                     }
                     PublishRequestAccepted => {
@@ -239,12 +1133,35 @@ impl Session {
                     }
                     AcknowledgementReceived{ bytes_received } => {
                         trace!(self.logger, "Ack received: {:?}", bytes_received);
+                        match self.stats.add_ack(bytes_received) {
+                            AckCheck::Ok => (),
+                            AckCheck::Overacked{ acked, sent } => {
+                                warn!(self.logger, "Server acknowledged more bytes than sent"; "acked" => acked, "sent" => sent);
+                            }
+                            AckCheck::Backwards{ acked, last } => {
+                                warn!(self.logger, "Server acknowledgement went backwards"; "acked" => acked, "last" => last);
+                            }
+                        }
                     }
                     UnhandleableAmf0Command{ command_name, .. } if command_name == "onFCPublish" => {
                         debug!(self.logger, "Received onFCPublish");
                     }
-                    UnhandleableAmf0Command{ command_name, .. } if command_name == "onBWDone" => {
-                        debug!(self.logger, "Received onBWDone");
+                    UnhandleableAmf0Command{ command_name, transaction_id, .. } if command_name == "onBWCheck" => {
+                        // The server waits for every check to be answered before it goes on with the connect
+                        trace!(self.logger, "Received onBWCheck"; "transaction_id" => transaction_id);
+                        let result = command::bw_check_result(transaction_id, self.bw_checks)?;
+                        self.bw_checks += 1;
+                        return Ok(Some(vec![result]));
+                    }
+                    UnhandleableAmf0Command{ command_name, additional_values, .. } if command_name == "onBWDone" => {
+                        // kbitDown, deltaDown, deltaTime, latency, the values are absent without a check
+                        let number = |i: usize| match additional_values.get(i) {
+                            Some(Amf0Value::Number(n)) => Some(*n),
+                            _ => None,
+                        };
+                        let (kbps, latency_ms) = (number(0), number(3));
+                        debug!(self.logger, "Received onBWDone"; "kbps" => ?kbps, "latency_ms" => ?latency_ms, "checks" => self.bw_checks);
+                        self.stats.set_bw_check(BwCheckReport { checks: self.bw_checks, kbps, latency_ms });
                     }
                     x => {
                         warn!(self.logger, "Unknown event raised by peer server: {:?}", x);
@@ -256,33 +1173,89 @@ impl Session {
         Ok(None)
     }
 
+    fn handle_status(&mut self, status: Status) {
+        self.stats.add_status(&status.code);
+        if status.is_fatal() {
+            warn!(self.logger, "Publish failed"; "code" => &status.code, "description" => &status.description);
+            self.failure = Some(format!("{} {}", status.code, status.description));
+        } else if status.level == "warning" {
+            warn!(self.logger, "Status warning"; "code" => &status.code, "description" => &status.description);
+        } else {
+            debug!(self.logger, "Status"; "code" => &status.code, "description" => &status.description);
+        }
+    }
+
     fn handle_push_connection_accepted_event(&mut self) -> Result<Vec<Packet>, Error> {
-        let outbounds = self.inner.request_publishing(self.stream.clone(), PublishRequestType::Live)?;
-        Ok(outbounds)
+        let request_type = match self.publish_type {
+            PublishType::Live => PublishRequestType::Live,
+            PublishType::Record => PublishRequestType::Record,
+            PublishType::Append => PublishRequestType::Append,
+        };
+        let outbounds = self.inner.request_publishing(self.stream.clone(), request_type)?;
+        let sequence = match self.pre_publish {
+            Some(ref sequence) => sequence,
+            None => return Ok(outbounds),
+        };
+        debug!(self.logger, "Pre-publish commands"; "sequence" => ?sequence.iter().map(|c| c.as_str()).collect::<Vec<_>>());
+        let mut packets = Vec::with_capacity(outbounds.len() + sequence.len());
+        for pre in sequence {
+            packets.push(match pre {
+                PrePublish::ReleaseStream => command::release_stream(RELEASE_STREAM_TRANSACTION_ID, &self.stream)?,
+                PrePublish::FcPublish => command::fc_publish(FC_PUBLISH_TRANSACTION_ID, &self.stream)?,
+            });
+        }
+        for packet in outbounds {
+            match command::parse_command(&packet) {
+                Some((ref name, _)) if name == "releaseStream" || name == "FCPublish" => (),
+                // With a full header, its own may be compressed against the commands left out
+                Some((ref name, transaction_id)) if name == "createStream" => packets.push(command::create_stream(transaction_id)?),
+                _ => packets.push(packet),
+            }
+        }
+        Ok(packets)
     }
 
     fn handle_push_publish_accepted_event(&mut self) {
         if !self.ready {
             self.ready = true;
+            self.enter_phase(|parent| debug_span!(parent: parent, "first_media"));
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn start_reading<T>(tx: futures::channel::mpsc::Sender<Packet>,
                           rx: T,
-                          notify_tx: oneshot::Sender<()>,
+                          notify_tx: &mut Option<oneshot::Sender<()>>,
+                          headers: &mut HeaderCache,
                           app: String,
                           stream: String,
                           tc_url: String,
-                          logger: Logger)
+                          config: &Config,
+                          stats: &Arc<ClientStats>,
+                          logger: &Logger) -> Result<SessionEnd, Error>
     where
-        T: Stream<Item = Result<ReceivedType, Error>> + Send + 'static,
+        T: Stream<Item = Result<ReceivedType, Error>> + Send,
 {
+    let queue_stats = stats.clone();
     let mut tx = tx.sink_map_err(|_| {
         ErrorKind::Unknown("send response error".into()).into()
+    }).with(move |packet: Packet| {
+        queue_stats.add_queued();
+        future::ready(Ok::<_, Error>(packet))
     });
-    // maybe create push cilent config from configs
-    let (session, session_results) = ClientSession::new(ClientSessionConfig::new()).unwrap();
+    let mut session_config = ClientSessionConfig::new();
+    if let Some(size) = config.chunk_size {
+        session_config.chunk_size = size;
+    }
+    if let Some(size) = config.window_ack_size {
+        session_config.window_ack_size = size;
+    }
+    if let Some(flash_ver) = config.connect.as_ref().and_then(|c| c.flash_ver.clone()) {
+        session_config.flash_version = flash_ver;
+    }
+    let command_defaults = (session_config.flash_version.clone(), session_config.chunk_size);
+    let (session, session_results) = ClientSession::new(session_config)?;
     let mut requests = session_results.into_iter().filter_map(|result| {
         match result {
             ClientSessionResult::OutboundResponse(packet) => {
@@ -292,50 +1265,434 @@ async fn start_reading<T>(tx: futures::channel::mpsc::Sender<Packet>,
         }
     }).collect::<Vec<_>>();
 
-    let mut session = Session::new(app, stream, session, &logger);
+    let mut session = Session::new(app, stream, session, command_defaults, config, stats.clone(), logger);
+    stats.begin_connection();
 
-    let packet = session.request_connect(tc_url).unwrap();
+    let packet = session.request_connect(tc_url)?;
     requests.push(Ok(packet));
     if let Err(e) = tx.send_all(&mut stream::iter(requests)).await {
         error!(logger, "Send request to server error"; "error" => %e);
     }
-    let result = rx.try_fold((tx, session, Some(notify_tx), logger.clone()), |(mut tx, mut session, notify_tx, logger), received| async move {
-        let to_send = match received {
+
+    pin_mut!(rx);
+    let mut published = false;
+    // A session joining a running broadcast starts its media at a keyframe
+    let mut awaiting_keyframe = false;
+    // Dropping video for the backpressure, up to the next keyframe
+    let mut dropping_video = false;
+    let session_start_bytes = stats.bytes_sent();
+    let mut last_from_server = Instant::now();
+    // The publish timeout counts from a re-publish again
+    let mut connect_sent = Instant::now();
+    // Set once publishing
+    let mut churn_at = None;
+    let mut last_stamp: Option<Instant> = None;
+    let mut last_metadata = Instant::now();
+    loop {
+        let deadline = if published {
+            config.server_silence_timeout.map(|limit| (last_from_server + limit, limit))
+        } else {
+            config.publish_timeout.map(|limit| (connect_sent + limit, limit))
+        };
+        let received = match deadline {
+            Some((deadline, limit)) => {
+                match tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), rx.next()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        let message = if published {
+                            format!("nothing received from the server for {}s", limit.as_secs())
+                        } else {
+                            format!("not publishing {}s after connect", limit.as_secs())
+                        };
+                        return Err(ErrorKind::Unknown(message).into());
+                    }
+                }
+            }
+            None => rx.next().await,
+        };
+        let received = match received {
+            Some(received) => received,
+            None => break,
+        };
+        let to_send = match received? {
             ReceivedType::FromClient{ message, bytes_read } => {
-                session.handle_from_peer_server(message, bytes_read)
+                last_from_server = Instant::now();
+                let to_send = session.handle_from_peer_server(message, bytes_read);
+                if let Some(redirect) = session.redirect.take() {
+                    return Ok(SessionEnd::Redirect(redirect));
+                }
+                if let Some(failure) = session.failure.take() {
+                    return Err(ErrorKind::Unknown(format!("publish rejected by peer server: {}", failure)).into());
+                }
+                if let Some(description) = session.rejection.take() {
+                    if adobe_auth::is_auth_rejection(&description) {
+                        return Ok(SessionEnd::AuthRejected(description));
+                    }
+                }
+                to_send
             }
+            ReceivedType::ServerClosed => return Ok(SessionEnd::ServerClosed),
             ReceivedType::Broadcast(received) => {
-                let result = session.handle_broadcast(received).map_err(Error::from);
-                vec![result]
+                let stopped = stats.stop_requested();
+                let churned = churn_at.map_or(false, |at| Instant::now() >= at);
+                if stopped || churned || matches!(*received, PacketType::End) {
+                    let teardown = session.teardown();
+                    debug!(logger, "Tear the session down"; "commands" => teardown.len());
+                    if let Err(e) = tx.send_all(&mut stream::iter(teardown)).await {
+                        error!(logger, "Send teardown error"; "error" => %e);
+                    }
+                    // Dropping the sender lets the writer flush and close the socket
+                    return Ok(if stopped {
+                        SessionEnd::Stopped
+                    } else if let PacketType::End = *received {
+                        SessionEnd::Ended
+                    } else {
+                        SessionEnd::Churned
+                    });
+                }
+                if let PacketType::Loop = *received {
+                    if config.republish_each_loop && session.ready {
+                        let ids = match config.republish_stream {
+                            Some(ids) => ids,
+                            None => return Ok(SessionEnd::Republish),
+                        };
+                        info!(logger, "Input restarted, publish again on the connection"; "stream_ids" => ?ids);
+                        stats.add_reconnect();
+                        stats.set_state(ClientState::Connecting);
+                        published = false;
+                        connect_sent = Instant::now();
+                        let republish = session.republish(ids);
+                        if let Err(e) = tx.send_all(&mut stream::iter(republish)).await {
+                            error!(logger, "Send republish error"; "error" => %e);
+                        }
+                    }
+                    continue;
+                }
+                if headers.observe(&received) {
+                    info!(logger, "Codec parameters changed");
+                    stats.add_codec_change();
+                }
+                observe_frame_rate(&received, stats);
+                if !session.ready {
+                    // Not publishing yet, the headers are replayed once accepted
+                    continue;
+                }
+                if awaiting_keyframe {
+                    match *received {
+                        PacketType::Video{ ref data, .. } if flv::is_video_sequence_header(data) => (),
+                        PacketType::Video{ ref data, .. } if flv::is_video_keyframe(data) => awaiting_keyframe = false,
+                        PacketType::Audio{ ref data, .. } if flv::is_audio_sequence_header(data) => (),
+                        PacketType::Video{ .. } | PacketType::Audio{ .. } => continue,
+                        _ => (),
+                    }
+                }
+                if config.drop_on_backpressure {
+                    if let PacketType::Video{ ref data, .. } = *received {
+                        if flv::is_video_keyframe(data) {
+                            dropping_video = false;
+                        } else if !flv::is_video_sequence_header(data) && (dropping_video || stats.queued() >= WRITER_QUEUE as u64) {
+                            if !dropping_video {
+                                debug!(logger, "Socket backpressure, drop video up to the next keyframe"; "queued" => stats.queued());
+                                dropping_video = true;
+                            }
+                            stats.add_dropped_frame();
+                            continue;
+                        }
+                    }
+                }
+                if let PacketType::Video{ ref data, ref ts } = *received {
+                    if !flv::is_video_sequence_header(data) {
+                        if let Some(d) = stats.add_video_frame(ts.value) {
+                            if d.run == SUSTAINED_WINDOWS {
+                                warn!(logger, "Sustained frame rate deviation";
+                                      "achieved_fps" => d.achieved_fps, "nominal_fps" => d.nominal_fps, "seconds" => d.run);
+                            }
+                        }
+                    }
+                }
+                let stamp = match *received {
+                    PacketType::Video{ ref ts, .. } if config.measure_latency
+                        && last_stamp.map_or(true, |at| at.elapsed() >= latency::STAMP_INTERVAL) => {
+                        session.stream_id.map(|id| (id, ts.value))
+                    }
+                    _ => None,
+                };
+                if let Some(ref ledger) = config.verify {
+                    ledger.sent(&received);
+                }
+                let repeat_metadata = match *received {
+                    PacketType::Metadata(_) => {
+                        last_metadata = Instant::now();
+                        None
+                    }
+                    PacketType::Video{ ref ts, .. } | PacketType::Audio{ ref ts, .. } if config.metadata_interval
+                        .map_or(false, |interval| last_metadata.elapsed() >= interval) => headers.metadata().map(|m| (m.clone(), ts.value)),
+                    _ => None,
+                };
+                if matches!(*received, PacketType::Video{ .. } | PacketType::Audio{ .. }) {
+                    // Queued for the socket, the first media is on its way
+                    session.phase = Span::none();
+                }
+                let mut to_send = vec![session.handle_broadcast(received)];
+                if let Some((stream_id, ts)) = stamp {
+                    last_stamp = Some(Instant::now());
+                    to_send.push(command::on_fi(stream_id, ts, latency::epoch_ms()));
+                }
+                if let Some((metadata, ts)) = repeat_metadata {
+                    last_metadata = Instant::now();
+                    to_send.push(session.metadata(&metadata, ts));
+                }
+                to_send
             }
         };
 
         if let Err(e) = tx.send_all(&mut stream::iter(to_send)).await {
             error!(logger, "Send to server response error"; "error" => %e);
         }
-        let notify_tx = match (notify_tx, session.ready) {
-            (Some(notify_tx), true) => {
-                info!(logger, "Publish accepted for push stream");
-                if let Err(_) = notify_tx.send(()) {
+        if let Some(budget) = config.bytes_per_client {
+            if stats.bytes_sent() - session_start_bytes >= budget {
+                return Ok(SessionEnd::BudgetExhausted);
+            }
+        }
+        if session.stream_created && !published {
+            published = true;
+            info!(logger, "Idle session established");
+            stats.set_idle();
+            if let Some(notify_tx) = notify_tx.take() {
+                if notify_tx.send(()).is_err() {
+                    error!(logger, "Notify error");
+                }
+            }
+        }
+        if session.ready && !published {
+            published = true;
+            info!(logger, "Publish accepted for push stream");
+            stats.set_publishing();
+            churn_at = config.churn.map(|churn| Instant::now() + churn.lifetime());
+            if let Some(notify_tx) = notify_tx.take() {
+                if notify_tx.send(()).is_err() {
                     error!(logger, "Notify error");
                 }
-                None
             }
-            (Some(notify_tx), false) => {
-                Some(notify_tx)
+
+            // The cached GOP starts at a keyframe, without one the live media has to
+            awaiting_keyframe = headers.video.is_some() && headers.gop.is_empty();
+            let replay = headers.packets().map(|p| {
+                // Seeded headers never went through the broadcast
+                observe_frame_rate(&p, stats);
+                session.handle_broadcast(p)
+            }).collect::<Vec<_>>();
+            if !replay.is_empty() {
+                debug!(logger, "Replay cached headers"; "count" => replay.len(), "gop" => headers.gop.len());
+                if let Err(e) = tx.send_all(&mut stream::iter(replay)).await {
+                    error!(logger, "Send cached headers error"; "error" => %e);
+                }
+            }
+        }
+    }
+    Ok(SessionEnd::Closed)
+}
+
+/// Like `forward`, feed whatever is ready and flush once nothing is, timing the waits for the socket.
+/// With a bitrate cap, a packet waits until the bytes written so far are due.
+async fn write_to_server<S>(mut rx: futures::channel::mpsc::Receiver<Packet>,
+                            mut to_server: S,
+                            bitrate_cap_bps: Option<u64>,
+                            stats: &ClientStats) -> Result<(), Error>
+    where S: futures::Sink<Packet, Error = Error> + Unpin
+{
+    let (session_started, mut written) = (tokio::time::Instant::now(), 0u64);
+    loop {
+        let packet = match rx.next().now_or_never() {
+            Some(Some(packet)) => packet,
+            Some(None) => break,
+            None => {
+                let started = Instant::now();
+                to_server.flush().await?;
+                stats.add_write_wait(started.elapsed());
+                match rx.next().await {
+                    Some(packet) => packet,
+                    None => break,
+                }
             }
-            (None, _) => {
-                None
+        };
+        if let Some(cap) = bitrate_cap_bps.filter(|cap| *cap > 0) {
+            let due = session_started + Duration::from_secs_f64(written as f64 * 8.0 / cap as f64);
+            if due > tokio::time::Instant::now() {
+                to_server.flush().await?;
+                tokio::time::delay_until(due).await;
             }
+        }
+        written += packet.bytes.len() as u64;
+        stats.add_sent(packet.bytes.len());
+        stats.take_queued();
+        let started = Instant::now();
+        to_server.feed(packet).await?;
+        stats.add_write_wait(started.elapsed());
+    }
+    to_server.close().await
+}
+
+/// Log the bitrate since the previous line, the queued messages and the bytes sent every
+/// `interval`, until the client is done.
+async fn log_stats(stats: Arc<ClientStats>, interval: Duration, logger: Logger) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.tick().await;
+    let mut last_bytes = stats.bytes_sent();
+    loop {
+        ticks.tick().await;
+        if let ClientState::Finished | ClientState::Failed = stats.state() {
+            break;
+        }
+        let bytes = stats.bytes_sent();
+        let kbps = bytes.saturating_sub(last_bytes) as f64 * 8.0 / interval.as_secs_f64() / 1000.0;
+        last_bytes = bytes;
+        let queue = stats.queue_report();
+        info!(logger, "Client stats"; "bitrate_kbps" => format!("{:.0}", kbps), "queued" => stats.queued(),
+              "broadcast_queued" => queue.depth, "dropped" => queue.dropped, "unacked" => stats.unacked_bytes(),
+              "bytes_sent" => bytes);
+    }
+}
+
+/// Warn once the server leaves more than `threshold` bytes unacknowledged, and once it catches up
+async fn watch_acks(stats: Arc<ClientStats>, threshold: u64, logger: Logger) {
+    let mut ticks = tokio::time::interval(ACK_CHECK_INTERVAL);
+    let mut alarmed = false;
+    loop {
+        ticks.tick().await;
+        if let ClientState::Finished | ClientState::Failed = stats.state() {
+            break;
+        }
+        let unacked = stats.unacked_bytes();
+        if unacked > threshold && !alarmed {
+            warn!(logger, "Server stopped acknowledging"; "unacked" => unacked, "threshold" => threshold);
+            stats.add_unacked_alarm();
+            alarmed = true;
+        } else if unacked <= threshold && alarmed {
+            info!(logger, "Server acknowledges again"; "unacked" => unacked);
+            alarmed = false;
+        }
+    }
+}
+
+const AMF0_COMMAND: u8 = 20;
+/// Out of the range `ClientSession` numbers its own requests with
+const IDLE_TRANSACTION_ID: f64 = 1000.0;
+/// The commands of `--pre-publish`, out of that range as well
+const RELEASE_STREAM_TRANSACTION_ID: f64 = 1002.0;
+const FC_PUBLISH_TRANSACTION_ID: f64 = 1003.0;
+
+fn is_pre_publish(transaction_id: f64) -> bool {
+    transaction_id == RELEASE_STREAM_TRANSACTION_ID || transaction_id == FC_PUBLISH_TRANSACTION_ID
+}
+
+/// The `createStream` of a re-publish with `StreamIds::New`, out of that range as well
+const REPUBLISH_TRANSACTION_ID: f64 = 1001.0;
+/// `ClientSession` numbers its requests from 1, the connect comes first
+const CONNECT_TRANSACTION_ID: f64 = 1.0;
+/// Stop following redirects after this many, the servers may point at each other
+const MAX_REDIRECTS: u32 = 5;
+/// Connects answered with a challenge before giving up, the flow takes two
+const MAX_AUTH_STEPS: u32 = 3;
+/// Messages the writer takes ahead of the socket, once full the socket holds the session back
+const WRITER_QUEUE: usize = 8;
+/// Lag warnings of a client are at least this far apart, the dropped messages are summed up
+const LAG_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the unacknowledged bytes are checked against `--unacked-alarm`
+const ACK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Upper bound of the reconnect delay
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Delay before the `retry`th reconnect in a row, from 1
+fn backoff(base: Duration, retry: u32) -> Duration {
+    base.checked_mul(1 << retry.saturating_sub(1).min(16)).map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF))
+}
+
+/// `onStatus` codes which end the publish, whatever their level
+const FATAL_STATUS_CODES: &[&str] = &[
+    "NetStream.Publish.BadName",
+    "NetStream.Publish.Rejected",
+    "NetStream.Publish.Denied",
+    "NetStream.Publish.Failed",
+    "NetStream.Failed",
+];
+
+/// The info object of an `onStatus`
+struct Status {
+    level: String,
+    code: String,
+    description: String,
+}
+
+impl Status {
+    fn parse(info: &Amf0Value) -> Option<Status> {
+        let info = match info {
+            Amf0Value::Object(properties) => properties,
+            _ => return None,
+        };
+        let string = |key: &str| match info.get(key) {
+            Some(Amf0Value::Utf8String(s)) => Some(s.clone()),
+            _ => None,
         };
-        Ok((tx, session, notify_tx, logger))
-    }).await;
-    match result {
-        Ok((_tx, _session, _, _logger)) => {
-            info!(logger, "Reading broadcast done");
-        }
-        Err(e) => {
-            error!(logger, "Reading broadcast error"; "error" => %e);
+        Some(Status {
+            code: string("code")?,
+            level: string("level").unwrap_or_default(),
+            description: string("description").unwrap_or_default(),
+        })
+    }
+
+    fn is_fatal(&self) -> bool {
+        self.level == "error" || FATAL_STATUS_CODES.contains(&self.code.as_str())
+    }
+}
+
+/// `ex.redirect` of a `NetConnection.Connect.Rejected` status, as sent by some CDNs
+fn redirect_url(info: &Amf0Value) -> Option<String> {
+    let info = match info {
+        Amf0Value::Object(properties) => properties,
+        _ => return None,
+    };
+    match info.get("code") {
+        Some(Amf0Value::Utf8String(code)) if code == "NetConnection.Connect.Rejected" => (),
+        _ => return None,
+    }
+    match info.get("ex") {
+        Some(Amf0Value::Object(ex)) => match ex.get("redirect") {
+            Some(Amf0Value::Utf8String(url)) => Some(url.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `stage timed out after ...` once `limit` is over
+async fn within<T, E, F>(limit: Option<Duration>, stage: &str, f: F) -> Result<T, Error>
+    where F: std::future::Future<Output = Result<T, E>>, E: Into<Error>,
+{
+    match limit {
+        Some(limit) => match tokio::time::timeout(limit, f).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(ErrorKind::Unknown(format!("{} timed out after {}ms", stage, limit.as_millis())).into()),
+        },
+        None => f.await.map_err(Into::into),
+    }
+}
+
+fn rejection_description(info: &Amf0Value) -> Option<String> {
+    match info {
+        Amf0Value::Object(properties) => match properties.get("description") {
+            Some(Amf0Value::Utf8String(description)) => Some(description.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The frame rate the input metadata declares is what the send cadence is held against
+fn observe_frame_rate(packet: &PacketType, stats: &ClientStats) {
+    if let PacketType::Metadata(ref metadata) = *packet {
+        if let Some(fps) = metadata.video_frame_rate {
+            stats.set_nominal_fps(fps as f64);
         }
     }
 }
@@ -0,0 +1,369 @@
+//! AMF0 commands and data messages `ClientSession` has no API for, and the replies of `waterfall sink`.
+//!
+//! They go out on a chunk stream of their own with full (type 0) headers, so they never
+//! disturb the header compression state of the chunk streams the session serializes.
+
+use std::collections::HashMap;
+
+use rml_amf0::Amf0Value;
+use rml_rtmp::chunk_io::Packet;
+use rml_rtmp::sessions::StreamMetadata;
+
+use crate::error::{Error, ErrorKind};
+
+/// Not used by `ClientSession`
+const CHUNK_STREAM_ID: u8 = 8;
+/// Protocol control messages, always on this one
+const CONTROL_CHUNK_STREAM_ID: u8 = 2;
+/// The commands of a sink
+const SERVER_CHUNK_STREAM_ID: u8 = 3;
+/// The chunk size the server assumes until told otherwise
+const DEFAULT_CHUNK_SIZE: usize = 128;
+const ACKNOWLEDGEMENT: u8 = 3;
+const WINDOW_ACK_SIZE: u8 = 5;
+const SET_PEER_BANDWIDTH: u8 = 6;
+const AUDIO: u8 = 8;
+const VIDEO: u8 = 9;
+const AMF0_COMMAND: u8 = 20;
+const AMF0_DATA: u8 = 18;
+/// Timestamps from here on go in the extended timestamp field
+const EXTENDED_TIMESTAMP: u32 = 0xff_ffff;
+
+/// A connect command with a command object of our own, in chunks of `chunk_size`: the size
+/// announced right before it.
+pub fn connect(transaction_id: f64, properties: HashMap<String, Amf0Value>, chunk_size: usize) -> Result<Packet, Error> {
+    let payload = serialize(&[
+        Amf0Value::Utf8String("connect".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Object(properties),
+    ])?;
+    Ok(chunked(CHUNK_STREAM_ID, 0, AMF0_COMMAND, 0, &payload, chunk_size))
+}
+
+/// Reply to a server side `onBWCheck` call
+pub fn bw_check_result(transaction_id: f64, counter: u32) -> Result<Packet, Error> {
+    command(&[
+        Amf0Value::Utf8String("_result".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Null,
+        Amf0Value::Number(counter as f64),
+    ])
+}
+
+/// `ClientSession` only creates a stream on the way to publishing
+pub fn create_stream(transaction_id: f64) -> Result<Packet, Error> {
+    command(&[
+        Amf0Value::Utf8String("createStream".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Null,
+    ])
+}
+
+/// Before the publish, in the order of `--pre-publish`: the server drops a stale publisher of `stream`
+pub fn release_stream(transaction_id: f64, stream: &str) -> Result<Packet, Error> {
+    command(&[
+        Amf0Value::Utf8String("releaseStream".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Null,
+        Amf0Value::Utf8String(stream.into()),
+    ])
+}
+
+/// Before the publish, like `release_stream`, the server answers with `onFCPublish`
+pub fn fc_publish(transaction_id: f64, stream: &str) -> Result<Packet, Error> {
+    command(&[
+        Amf0Value::Utf8String("FCPublish".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Null,
+        Amf0Value::Utf8String(stream.into()),
+    ])
+}
+
+/// Sent before closing, on the connection like `FCPublish`
+pub fn fc_unpublish(stream: &str) -> Result<Packet, Error> {
+    command(&[
+        Amf0Value::Utf8String("FCUnpublish".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+        Amf0Value::Utf8String(stream.into()),
+    ])
+}
+
+/// Stop publishing, on the stream itself
+pub fn close_stream(stream_id: u32) -> Result<Packet, Error> {
+    command_on(stream_id, &[
+        Amf0Value::Utf8String("closeStream".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+    ])
+}
+
+/// Pause or resume a play at `ms`, where the player is, on the stream
+pub fn pause(stream_id: u32, paused: bool, ms: u32) -> Result<Packet, Error> {
+    command_on(stream_id, &[
+        Amf0Value::Utf8String("pause".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+        Amf0Value::Boolean(paused),
+        Amf0Value::Number(f64::from(ms)),
+    ])
+}
+
+/// Play from `ms` on, on the stream
+pub fn seek(stream_id: u32, ms: u32) -> Result<Packet, Error> {
+    command_on(stream_id, &[
+        Amf0Value::Utf8String("seek".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+        Amf0Value::Number(f64::from(ms)),
+    ])
+}
+
+/// Publish again, on a stream `ClientSession` did not create or already deleted
+pub fn publish(stream_id: u32, stream: &str, publish_type: &str) -> Result<Packet, Error> {
+    command_on(stream_id, &[
+        Amf0Value::Utf8String("publish".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+        Amf0Value::Utf8String(stream.into()),
+        Amf0Value::Utf8String(publish_type.into()),
+    ])
+}
+
+pub fn delete_stream(stream_id: u32) -> Result<Packet, Error> {
+    command(&[
+        Amf0Value::Utf8String("deleteStream".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+        Amf0Value::Number(stream_id as f64),
+    ])
+}
+
+/// An `onFI` data message at `timestamp` on the publish stream, with the wall clock of the
+/// publisher: `sd` and `st` as encoders send them, in UTC, and `wf_ms` in milliseconds since the
+/// Unix epoch for `--measure-latency`.
+pub fn on_fi(stream_id: u32, timestamp: u32, epoch_ms: u64) -> Result<Packet, Error> {
+    let (date, time) = crate::latency::date_time(epoch_ms);
+    let mut properties = HashMap::new();
+    properties.insert("sd".to_owned(), Amf0Value::Utf8String(date));
+    properties.insert("st".to_owned(), Amf0Value::Utf8String(time));
+    properties.insert(crate::latency::STAMP_KEY.to_owned(), Amf0Value::Number(epoch_ms as f64));
+    let payload = serialize(&[Amf0Value::Utf8String("onFI".into()), Amf0Value::Object(properties)])?;
+    if payload.len() > DEFAULT_CHUNK_SIZE {
+        return Err(ErrorKind::Unknown("data message does not fit in a chunk".into()).into());
+    }
+    Ok(chunked(CHUNK_STREAM_ID, stream_id, AMF0_DATA, timestamp, &payload, DEFAULT_CHUNK_SIZE))
+}
+
+/// `@setDataFrame` with `onMetaData` at `timestamp` on the publish stream, any properties and in
+/// chunks of `chunk_size`, the size in use
+pub fn set_data_frame(stream_id: u32, timestamp: u32, properties: HashMap<String, Amf0Value>, chunk_size: usize) -> Result<Packet, Error> {
+    let payload = serialize(&[
+        Amf0Value::Utf8String("@setDataFrame".into()),
+        Amf0Value::Utf8String("onMetaData".into()),
+        Amf0Value::Object(properties),
+    ])?;
+    Ok(chunked(CHUNK_STREAM_ID, stream_id, AMF0_DATA, timestamp, &payload, chunk_size))
+}
+
+/// An audio message at `timestamp` on a stream `ClientSession` does not know of, in chunks of
+/// `chunk_size`
+pub fn audio(stream_id: u32, timestamp: u32, data: &[u8], chunk_size: usize) -> Packet {
+    chunked(CHUNK_STREAM_ID, stream_id, AUDIO, timestamp, data, chunk_size)
+}
+
+/// A video message, like `audio`
+pub fn video(stream_id: u32, timestamp: u32, data: &[u8], chunk_size: usize) -> Packet {
+    chunked(CHUNK_STREAM_ID, stream_id, VIDEO, timestamp, data, chunk_size)
+}
+
+/// The `onMetaData` properties `ClientSession` would send for `metadata`
+pub fn metadata_properties(metadata: &StreamMetadata) -> HashMap<String, Amf0Value> {
+    let mut properties = HashMap::new();
+    let mut number = |key: &str, value: Option<f64>| {
+        if let Some(value) = value {
+            properties.insert(key.to_owned(), Amf0Value::Number(value));
+        }
+    };
+    number("width", metadata.video_width.map(f64::from));
+    number("height", metadata.video_height.map(f64::from));
+    number("framerate", metadata.video_frame_rate.map(f64::from));
+    number("videodatarate", metadata.video_bitrate_kbps.map(f64::from));
+    number("audiodatarate", metadata.audio_bitrate_kbps.map(f64::from));
+    number("audiosamplerate", metadata.audio_sample_rate.map(f64::from));
+    number("audiochannels", metadata.audio_channels.map(f64::from));
+    let strings = [("videocodecid", &metadata.video_codec), ("audiocodecid", &metadata.audio_codec), ("encoder", &metadata.encoder)];
+    for (key, value) in strings.iter() {
+        if let Some(value) = value {
+            properties.insert((*key).to_owned(), Amf0Value::Utf8String(value.clone()));
+        }
+    }
+    if let Some(stereo) = metadata.audio_is_stereo {
+        properties.insert("stereo".to_owned(), Amf0Value::Boolean(stereo));
+    }
+    properties
+}
+
+/// The window acknowledgement size a sink announces
+pub fn window_ack_size(size: u32) -> Packet {
+    control(WINDOW_ACK_SIZE, &size.to_be_bytes())
+}
+
+/// Dynamic limit, the publisher may keep its own window
+pub fn set_peer_bandwidth(size: u32) -> Packet {
+    let mut payload = size.to_be_bytes().to_vec();
+    payload.push(2);
+    control(SET_PEER_BANDWIDTH, &payload)
+}
+
+/// The bytes received so far, once a window
+pub fn acknowledgement(sequence_number: u32) -> Packet {
+    control(ACKNOWLEDGEMENT, &sequence_number.to_be_bytes())
+}
+
+/// A sink accepting a connect command
+pub fn connect_result(transaction_id: f64) -> Result<Packet, Error> {
+    let mut properties = HashMap::new();
+    properties.insert("fmsVer".to_owned(), Amf0Value::Utf8String("FMS/3,0,1,123".into()));
+    properties.insert("capabilities".to_owned(), Amf0Value::Number(31.0));
+    let mut information = HashMap::new();
+    information.insert("level".to_owned(), Amf0Value::Utf8String("status".into()));
+    information.insert("code".to_owned(), Amf0Value::Utf8String("NetConnection.Connect.Success".into()));
+    information.insert("description".to_owned(), Amf0Value::Utf8String("Connection succeeded.".into()));
+    information.insert("objectEncoding".to_owned(), Amf0Value::Number(0.0));
+    reply(0, &[
+        Amf0Value::Utf8String("_result".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Object(properties),
+        Amf0Value::Object(information),
+    ])
+}
+
+/// The `_result` of any other command, e.g. the id of the stream of `createStream`
+pub fn result(transaction_id: f64, value: Amf0Value) -> Result<Packet, Error> {
+    reply(0, &[
+        Amf0Value::Utf8String("_result".into()),
+        Amf0Value::Number(transaction_id),
+        Amf0Value::Null,
+        value,
+    ])
+}
+
+/// A status of the stream, e.g. `NetStream.Publish.Start`
+pub fn on_status(stream_id: u32, level: &str, code: &str, description: &str) -> Result<Packet, Error> {
+    let mut information = HashMap::new();
+    information.insert("level".to_owned(), Amf0Value::Utf8String(level.into()));
+    information.insert("code".to_owned(), Amf0Value::Utf8String(code.into()));
+    information.insert("description".to_owned(), Amf0Value::Utf8String(description.into()));
+    reply(stream_id, &[
+        Amf0Value::Utf8String("onStatus".into()),
+        Amf0Value::Number(0.0),
+        Amf0Value::Null,
+        Amf0Value::Object(information),
+    ])
+}
+
+/// The name and the transaction id of the AMF0 command `packet` holds, e.g. one `ClientSession`
+/// serialized: a whole message, whose payload starts in its first chunk
+pub fn parse_command(packet: &Packet) -> Option<(String, f64)> {
+    let bytes = &packet.bytes;
+    let first = *bytes.get(0)?;
+    let fmt = first >> 6;
+    let basic_header = match first & 0x3f {
+        0 => 2,
+        1 => 3,
+        _ => 1,
+    };
+    // Only types 0 and 1 carry the message type, the other ones continue a message like the last one
+    if fmt < 2 && *bytes.get(basic_header + 6)? != AMF0_COMMAND {
+        return None;
+    }
+    let mut offset = basic_header + [11, 7, 3, 0][fmt as usize];
+    if fmt < 3 && bytes.get(basic_header..basic_header + 3)?.iter().all(|b| *b == 0xff) {
+        offset += 4;
+    }
+    let mut payload = bytes.get(offset..)?;
+    let values = rml_amf0::deserialize(&mut payload).ok()?;
+    match (values.get(0), values.get(1)) {
+        (Some(Amf0Value::Utf8String(name)), Some(Amf0Value::Number(transaction_id))) => Some((name.clone(), *transaction_id)),
+        _ => None,
+    }
+}
+
+/// From a sink, which never changes its chunk size, so replies of any length are chunked as the
+/// publisher expects
+fn reply(stream_id: u32, values: &[Amf0Value]) -> Result<Packet, Error> {
+    Ok(chunked(SERVER_CHUNK_STREAM_ID, stream_id, AMF0_COMMAND, 0, &serialize(values)?, DEFAULT_CHUNK_SIZE))
+}
+
+fn control(type_id: u8, payload: &[u8]) -> Packet {
+    chunked(CONTROL_CHUNK_STREAM_ID, 0, type_id, 0, payload, DEFAULT_CHUNK_SIZE)
+}
+
+/// On the connection, message stream 0
+fn command(values: &[Amf0Value]) -> Result<Packet, Error> {
+    command_on(0, values)
+}
+
+fn command_on(stream_id: u32, values: &[Amf0Value]) -> Result<Packet, Error> {
+    let payload = serialize(values)?;
+    // Always smaller than any chunk size in use, so a single chunk suffices
+    if payload.len() > DEFAULT_CHUNK_SIZE {
+        return Err(ErrorKind::Unknown("command does not fit in a chunk".into()).into());
+    }
+    Ok(chunked(CHUNK_STREAM_ID, stream_id, AMF0_COMMAND, 0, &payload, DEFAULT_CHUNK_SIZE))
+}
+
+fn serialize(values: &[Amf0Value]) -> Result<Vec<u8>, Error> {
+    rml_amf0::serialize(&values.to_vec())
+        .map_err(|e| ErrorKind::Unknown(format!("serialize command error: {:?}", e)).into())
+}
+
+fn chunked(chunk_stream_id: u8, stream_id: u32, type_id: u8, timestamp: u32, payload: &[u8], chunk_size: usize) -> Packet {
+    let extended = timestamp >= EXTENDED_TIMESTAMP;
+    let mut bytes = Vec::with_capacity(16 + payload.len() + payload.len() / chunk_size * 5);
+    // fmt 0, chunk stream id
+    bytes.push(chunk_stream_id);
+    bytes.extend_from_slice(&timestamp.min(EXTENDED_TIMESTAMP).to_be_bytes()[1..]);
+    let len = payload.len() as u32;
+    bytes.extend_from_slice(&len.to_be_bytes()[1..]);
+    bytes.push(type_id);
+    // message stream id, little endian
+    bytes.extend_from_slice(&stream_id.to_le_bytes());
+    for (i, chunk) in payload.chunks(chunk_size).enumerate() {
+        if i > 0 {
+            // fmt 3, the rest of the same message
+            bytes.push(0xc0 | chunk_stream_id);
+        }
+        if extended {
+            bytes.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        bytes.extend_from_slice(chunk);
+    }
+    Packet { bytes, can_be_dropped: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_commands_built_here() {
+        let parsed = parse_command(&create_stream(1001.0).unwrap());
+        assert_eq!(parsed, Some(("createStream".to_owned(), 1001.0)));
+        let parsed = parse_command(&release_stream(7.0, "stream").unwrap());
+        assert_eq!(parsed, Some(("releaseStream".to_owned(), 7.0)));
+        // Type 3, a continuation of the previous message on the chunk stream
+        let mut packet = fc_publish(8.0, "stream").unwrap();
+        packet.bytes.drain(1..12);
+        packet.bytes[0] |= 0xc0;
+        assert_eq!(parse_command(&packet), Some(("FCPublish".to_owned(), 8.0)));
+    }
+
+    #[test]
+    fn media_and_data_are_no_commands() {
+        assert_eq!(parse_command(&video(1, 0, &[0x17, 1, 0, 0, 0], DEFAULT_CHUNK_SIZE)), None);
+        assert_eq!(parse_command(&on_fi(1, 0, 0).unwrap()), None);
+        assert_eq!(parse_command(&acknowledgement(10)), None);
+    }
+}
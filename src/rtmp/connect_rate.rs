@@ -0,0 +1,47 @@
+//! `--connect-rate`: new connections of all the sessions of the run spaced evenly, whatever
+//! starts them, the ramp-up, reconnects or churn.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+#[derive(Debug)]
+pub struct ConnectRate {
+    /// Between two connects
+    spacing: Duration,
+    /// The earliest the next connect may start
+    next: Mutex<Instant>,
+}
+
+impl ConnectRate {
+    pub fn new(per_second: f64) -> Self {
+        Self { spacing: Duration::from_secs_f64(1.0 / per_second), next: Mutex::new(Instant::now()) }
+    }
+
+    /// Wait for the slot of a new connect, the slots are taken in turn
+    pub async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.spacing;
+            slot
+        };
+        tokio::time::delay_until(slot).await;
+    }
+}
+
+/// Parse `<N>`, `<N>/s` or `<N>/m`, connects per second or minute
+pub fn parse(s: &str) -> Result<ConnectRate, String> {
+    let (count, per) = match s.find('/') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, "s"),
+    };
+    let count = count.trim().parse::<f64>().ok().filter(|c| *c > 0.0).ok_or_else(|| format!("invalid connect rate `{}`", s))?;
+    let per_second = match per.trim() {
+        "s" => count,
+        "m" | "min" => count / 60.0,
+        _ => return Err(format!("unknown unit of connect rate `{}`, use /s or /m", s)),
+    };
+    Ok(ConnectRate::new(per_second))
+}
@@ -23,3 +23,26 @@ pub async fn handshake<T>(mut io: T) -> Result<T, Error>
     io.flush().await?;
     Ok(io)
 }
+
+/// The server side, for `waterfall sink`
+pub async fn accept<T>(mut io: T) -> Result<T, Error>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let mut handshake = RtmpHandshake::new(PeerType::Server);
+    let mut c0_and_c1 = [0; super::C0C1_SIZE];
+    io.read_exact(&mut c0_and_c1).await?;
+    let response_bytes = match handshake.process_bytes(&c0_and_c1)? {
+        HandshakeProcessResult::InProgress{ response_bytes } => response_bytes,
+        HandshakeProcessResult::Completed{ .. } => {
+            return Err(ErrorKind::Unknown("handshake completed before C2".into()).into());
+        }
+    };
+    io.write_all(response_bytes.as_slice()).await?;
+    io.flush().await?;
+    let mut c2 = [0; super::C2_SIZE];
+    io.read_exact(&mut c2).await?;
+    match handshake.process_bytes(&c2)? {
+        HandshakeProcessResult::Completed{ .. } => Ok(io),
+        HandshakeProcessResult::InProgress{ .. } => Err(ErrorKind::Unknown("handshake incomplete after C2".into()).into()),
+    }
+}
@@ -0,0 +1,161 @@
+//! `--net-delay` and `--net-stall`: a flaky network between a session and the server, without
+//! tc / netem on the load box.
+//!
+//! The written bytes go through a delay line and leave `delay` after they were written, so the
+//! throughput stays while the latency grows. During a stall nothing leaves at all, the bytes
+//! written meanwhile pile up and go out at once when it ends. Reads pass through.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{ready, Future};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Delay, Instant};
+
+/// Writes wait once this much is in the delay line
+const MAX_IN_FLIGHT: usize = 1 << 20;
+
+/// Nothing leaves for `length`, every `every` from the start of the session
+#[derive(Clone, Copy, Debug)]
+pub struct Stall {
+    pub length: Duration,
+    pub every: Duration,
+}
+
+/// Parse `<length>@<every>`, e.g. `2s@30s`
+pub fn parse_stall(s: &str) -> Result<Stall, String> {
+    let mut parts = s.splitn(2, '@');
+    let length = crate::units::parse_duration(parts.next().unwrap_or(""))?;
+    let every = crate::units::parse_duration(parts.next().ok_or_else(|| format!("expected `<length>@<every>`, got `{}`", s))?)?;
+    if every <= length {
+        return Err(format!("a stall of `{}` must be shorter than its period", s));
+    }
+    Ok(Stall { length, every })
+}
+
+pub struct Impaired<T> {
+    inner: T,
+    delay: Duration,
+    stall: Option<Stall>,
+    started: Instant,
+    /// The written bytes and when they are due
+    in_flight: VecDeque<(Instant, Bytes)>,
+    in_flight_bytes: usize,
+    timer: Option<Delay>,
+}
+
+impl<T> Impaired<T> {
+    pub fn new(inner: T, delay: Option<Duration>, stall: Option<Stall>) -> Self {
+        Self {
+            inner,
+            delay: delay.unwrap_or_default(),
+            stall,
+            started: Instant::now(),
+            in_flight: VecDeque::new(),
+            in_flight_bytes: 0,
+            timer: None,
+        }
+    }
+
+    /// The end of the stall `now` is in, if any
+    fn stall_end(&self, now: Instant) -> Option<Instant> {
+        let stall = self.stall?;
+        let elapsed = now - self.started;
+        if elapsed < stall.every {
+            return None;
+        }
+        let phase = Duration::from_nanos((elapsed.as_nanos() % stall.every.as_nanos()) as u64);
+        if phase < stall.length {
+            Some(now + (stall.length - phase))
+        } else {
+            None
+        }
+    }
+
+    /// Ready once `deadline` is over, the task is woken then otherwise
+    fn poll_until(&mut self, cx: &mut Context<'_>, deadline: Instant) -> Poll<()> {
+        match self.timer {
+            Some(ref timer) if timer.deadline() == deadline => (),
+            _ => self.timer = Some(tokio::time::delay_until(deadline)),
+        }
+        let timer = self.timer.as_mut().expect("timer is set");
+        ready!(Pin::new(timer).poll(cx));
+        self.timer = None;
+        Poll::Ready(())
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Impaired<T> {
+    /// Write out the bytes which are due, ready once the delay line is empty
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let now = Instant::now();
+            if let Some(end) = self.stall_end(now) {
+                ready!(self.poll_until(cx, end));
+                continue;
+            }
+            let due = match self.in_flight.front() {
+                Some((due, _)) => *due,
+                None => return Poll::Ready(Ok(())),
+            };
+            if due > now {
+                ready!(self.poll_until(cx, due));
+                continue;
+            }
+            let (_, bytes) = self.in_flight.front_mut().expect("front is there");
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &bytes[..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.in_flight_bytes -= n;
+            if n == bytes.len() {
+                self.in_flight.pop_front();
+            } else {
+                *bytes = bytes.slice(n..);
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Impaired<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Impaired<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if this.in_flight_bytes >= MAX_IN_FLIGHT {
+            // Woken by the drain, once the next bytes are due or written
+            return Poll::Pending;
+        }
+        this.in_flight.push_back((Instant::now() + this.delay, Bytes::copy_from_slice(buf)));
+        this.in_flight_bytes += buf.len();
+        // Without a delay the bytes may go out right away
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,12 +1,60 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tracing::{debug_span, Instrument};
+
+use crate::error::Error;
+use crate::rtmp_url::Scheme;
+
+mod adobe_auth;
 mod handshake;
+pub mod bench;
+pub mod bind;
 pub mod client;
 mod codec;
+mod command;
+pub mod connect_rate;
+pub mod impair;
+pub mod player;
+pub mod proxy;
+pub mod server;
+mod shaper;
+pub mod shards;
+#[cfg(feature = "rtmpe")]
+mod rtmpe;
+mod rtmpt;
+mod tls;
 
 pub use self::handshake::handshake;
 
+/// Any byte stream a session can run over: plain TCP or a tunnel
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Io for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+/// Layer the transport of `scheme` over a connected socket and handshake. `host` is the name
+/// RTMPT and TLS present to the server.
+pub async fn open(socket: TcpStream, scheme: Scheme, host: &str, port: u16, insecure_tls: bool) -> Result<Box<dyn Io>, Error> {
+    let io: Box<dyn Io> = match scheme {
+        Scheme::Rtmpt => Box::new(rtmpt::open(socket, crate::rtmp_url::authority(host, port)).instrument(debug_span!("rtmpt_open")).await?),
+        Scheme::Rtmps => Box::new(tls::connect(socket, host, insecure_tls).instrument(debug_span!("tls_connect")).await?),
+        Scheme::Rtmp | Scheme::Rtmpe => Box::new(socket),
+        Scheme::Srt | Scheme::Whip | Scheme::Whips => {
+            return Err(crate::error::ErrorKind::Unknown(format!("{} destinations are not played or connected over RTMP", scheme)).into());
+        }
+    };
+    // Urls are only parsed as `rtmpe` with the feature
+    #[cfg(feature = "rtmpe")]
+    let io: Box<dyn Io> = if scheme == Scheme::Rtmpe {
+        Box::new(rtmpe::handshake(io).instrument(debug_span!("rtmpe_handshake")).await?)
+    } else {
+        handshake(io).instrument(debug_span!("handshake")).await?
+    };
+    #[cfg(not(feature = "rtmpe"))]
+    let io = handshake(io).instrument(debug_span!("handshake")).await?;
+    Ok(io)
+}
+
 const HANDSHAKE_PACKET_SIZE: usize = 1536;
-#[allow(dead_code)]
 const C0C1_SIZE: usize = HANDSHAKE_PACKET_SIZE + 1;
-#[allow(dead_code)]
 const C2_SIZE: usize = HANDSHAKE_PACKET_SIZE;
 const S0S1S2_SIZE: usize = HANDSHAKE_PACKET_SIZE * 2 + 1;
@@ -0,0 +1,558 @@
+//! A subscriber session for `waterfall play`: connect, play a stream and consume its media.
+//! `pull` plays one the same way as the input of the publishers.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_stream::try_stream;
+use futures::{future::{self, Either}, sink::SinkExt, stream::{Stream, StreamExt, TryStreamExt}};
+use pin_utils::pin_mut;
+use rand::Rng;
+use rml_rtmp::sessions::{ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult};
+use serde::Serialize;
+use slog::{debug, info, warn, Logger};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::error::{Error, ErrorKind};
+use crate::{flv, latency};
+use crate::rtmp_url::Url;
+use crate::shutdown::Shutdown;
+use crate::verify::Ledger;
+use crate::PacketType;
+use super::{bind::{self, Family}, codec::Codec, command, Io};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Stop playing after this long, otherwise play until the server ends the stream
+    pub duration: Option<Duration>,
+    /// A gap between two media messages at least this long is a stall
+    pub stall_threshold: Duration,
+    pub insecure_tls: bool,
+    /// Only connect to addresses of this family
+    pub family: Option<Family>,
+    /// Record the latency of the `onFI` stamps of `--measure-latency` here
+    pub latency: Option<Arc<latency::Recorder>>,
+    /// Mux the received stream into an FLV file in this directory
+    pub record_dir: Option<PathBuf>,
+    /// Check the received media against the media sent for `--verify`
+    pub verify: Option<Arc<Ledger>>,
+    /// Pause the play this often once it started, and resume it as often
+    pub pause_every: Option<Duration>,
+    /// Seek to a random position of the media received so far this often
+    pub seek_every: Option<Duration>,
+}
+
+const AMF0_DATA: u8 = 18;
+const AUDIO: u8 = 8;
+const VIDEO: u8 = 9;
+/// Prefix of the metadata as the publisher sent it, some servers relay it as is
+const SET_DATA_FRAME: &[u8] = b"\x02\x00\x0d@setDataFrame";
+
+/// A companion player waits this long before playing again
+const COMPANION_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+type Recording = flv::Writer<tokio::io::BufWriter<tokio::fs::File>>;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PlayReport {
+    pub url: String,
+    /// From the TCP connect to the connect result
+    pub connect_ms: Option<u64>,
+    /// From the TCP connect to the first audio or video message
+    pub first_frame_ms: Option<u64>,
+    /// From the play command to the first audio message
+    pub first_audio_ms: Option<u64>,
+    /// From the play command to the first video message, sequence headers included
+    pub first_video_ms: Option<u64>,
+    /// From the play command to the first keyframe, when the picture shows
+    pub first_keyframe_ms: Option<u64>,
+    pub bytes_received: u64,
+    pub video_frames: u64,
+    pub audio_frames: u64,
+    /// Media bytes received over the time from the first to the last media message
+    pub bitrate_bps: u64,
+    pub stalls: u64,
+    pub longest_stall_ms: u64,
+    /// AAC frames later than the end of the one before, dropped on the way, see `AudioContinuity`
+    pub audio_gaps: u64,
+    /// Or earlier, duplicated
+    pub audio_overlaps: u64,
+    /// Pause and resume commands sent, with `--pause-every`
+    pub pauses: u64,
+    /// With `--seek-every`
+    pub seeks: u64,
+    /// The FLV file of the received stream, with `--record-dir`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recording: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Media arrival of one session
+#[derive(Default)]
+struct Reception {
+    first: Option<Instant>,
+    last: Option<Instant>,
+    /// No media is expected while paused, the gap to the next media counts from the resume
+    paused: bool,
+    resumed: Option<Instant>,
+}
+
+impl Reception {
+    fn add(&mut self, report: &mut PlayReport, started: Instant, bytes: usize, stall_threshold: Duration) {
+        let now = Instant::now();
+        if self.first.is_none() {
+            self.first = Some(now);
+            report.first_frame_ms = Some((now - started).as_millis() as u64);
+        }
+        if let Some(last) = self.last.max(self.resumed).filter(|_| !self.paused) {
+            let gap = now - last;
+            if gap >= stall_threshold {
+                report.stalls += 1;
+                report.longest_stall_ms = report.longest_stall_ms.max(gap.as_millis() as u64);
+            }
+        }
+        self.last = Some(now);
+        report.bytes_received += bytes as u64;
+    }
+
+    fn pause(&mut self, paused: bool) {
+        self.paused = paused;
+        if !paused {
+            self.resumed = Some(Instant::now());
+        }
+    }
+
+    fn finish(&self, report: &mut PlayReport) {
+        if let (Some(first), Some(last)) = (self.first, self.last) {
+            let secs = (last - first).as_secs_f64();
+            if secs > 0.0 {
+                report.bitrate_bps = (report.bytes_received as f64 * 8.0 / secs) as u64;
+            }
+        }
+    }
+}
+
+/// AAC frames against their timestamps: each lasts 1024 samples at the rate of the last sequence
+/// header, a frame more than half a frame off the end of the one before is a gap or an overlap
+#[derive(Default)]
+struct AudioContinuity {
+    frame_ms: Option<f64>,
+    last: Option<u32>,
+    /// Of the timestamps against the frame durations since the last discontinuity, they are rounded
+    drift_ms: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Discontinuity {
+    Gap,
+    Overlap,
+}
+
+impl AudioContinuity {
+    fn add(&mut self, data: &[u8], timestamp: u32) -> Option<Discontinuity> {
+        if let Some(frame_ms) = flv::aac_frame_ms(data) {
+            self.frame_ms = Some(frame_ms);
+            self.reset();
+            return None;
+        }
+        let frame_ms = self.frame_ms.filter(|_| flv::is_aac_frame(data))?;
+        let last = self.last.replace(timestamp)?;
+        // Through the 32 bit wrap
+        self.drift_ms += f64::from(timestamp.wrapping_sub(last) as i32) - frame_ms;
+        if self.drift_ms.abs() <= frame_ms / 2.0 {
+            return None;
+        }
+        let found = if self.drift_ms > 0.0 { Discontinuity::Gap } else { Discontinuity::Overlap };
+        self.drift_ms = 0.0;
+        Some(found)
+    }
+
+    /// After a pause or a seek the next frame starts anywhere
+    fn reset(&mut self) {
+        self.last = None;
+        self.drift_ms = 0.0;
+    }
+}
+
+/// Play the stream of `url` until the configured duration passes or the server ends it.
+pub async fn play(url: Url, config: &Config, logger: &Logger) -> PlayReport {
+    let mut report = PlayReport { url: url.to_string(), ..Default::default() };
+    let mut reception = Reception::default();
+    let started = Instant::now();
+    let mut recording = match config.record_dir {
+        Some(ref dir) => match create_recording(dir, &url).await {
+            Ok((path, writer)) => {
+                report.recording = Some(path.display().to_string());
+                Some(writer)
+            }
+            Err(e) => {
+                warn!(logger, "Create recording error"; "error" => %e);
+                report.error = Some(format!("create recording error: {}", e));
+                return report;
+            }
+        },
+        None => None,
+    };
+    let deadline = config.duration.map(|d| tokio::time::Instant::from_std(started + d));
+    let playing = receive(&url, config, started, &mut report, &mut reception, &mut recording, logger);
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, playing).await.unwrap_or(Ok(())),
+        None => playing.await,
+    };
+    // Also at the deadline, the buffered tags go to the file
+    let result = match recording {
+        Some(recording) => result.and(recording.finish().await.map_err(Error::from)),
+        None => result,
+    };
+    // A session still waiting for media at the end has stalled as well
+    if let (Some(last), Ok(()), false) = (reception.last.max(reception.resumed), &result, reception.paused) {
+        let gap = last.elapsed();
+        if gap >= config.stall_threshold {
+            report.stalls += 1;
+            report.longest_stall_ms = report.longest_stall_ms.max(gap.as_millis() as u64);
+        }
+    }
+    reception.finish(&mut report);
+    match result {
+        Ok(()) => info!(logger, "Play ended"; "bytes_received" => report.bytes_received, "stalls" => report.stalls),
+        Err(e) => {
+            warn!(logger, "Play error"; "error" => %e);
+            report.error = Some(e.to_string());
+        }
+    }
+    report
+}
+
+async fn receive(url: &Url,
+                 config: &Config,
+                 started: Instant,
+                 report: &mut PlayReport,
+                 reception: &mut Reception,
+                 recording: &mut Option<Recording>,
+                 logger: &Logger) -> Result<(), Error>
+{
+    let (mut transport, mut session) = connect(url, config.insecure_tls, config.family).await?;
+    let mut bw_checks = 0;
+    let mut exercise = Exercise::new(config);
+    let mut continuity = AudioContinuity::default();
+    // Of the play, known from its first media message, and where it is
+    let (mut stream_id, mut position) = (None, 0);
+    let mut play_sent = started;
+    let since_play = |play_sent: Instant| Some(play_sent.elapsed().as_millis() as u64);
+    loop {
+        let received = tokio::select! {
+            received = transport.next() => match received {
+                Some(received) => received,
+                None => break,
+            },
+            exercised = exercise.next(), if stream_id.is_some() => {
+                let stream_id = stream_id.unwrap_or_default();
+                let packet = match exercised {
+                    Exercised::Pause(paused) => {
+                        debug!(logger, "Pause"; "paused" => paused, "position_ms" => position);
+                        report.pauses += 1;
+                        reception.pause(paused);
+                        continuity.reset();
+                        command::pause(stream_id, paused, position)?
+                    }
+                    Exercised::Seek => {
+                        let to = rand::thread_rng().gen_range(0, position.saturating_add(1));
+                        debug!(logger, "Seek"; "position_ms" => position, "to_ms" => to);
+                        report.seeks += 1;
+                        continuity.reset();
+                        command::seek(stream_id, to)?
+                    }
+                };
+                transport.send(packet).await?;
+                continue;
+            }
+        };
+        let (message, bytes_read) = received?;
+        if let AUDIO | VIDEO = message.type_id {
+            if stream_id.is_none() {
+                exercise.start();
+            }
+            stream_id = Some(message.message_stream_id);
+            position = message.timestamp.value;
+        }
+        // `ClientSession` drops any data message but the metadata
+        if let (Some(recorder), AMF0_DATA) = (&config.latency, message.type_id) {
+            if let Some(sent_ms) = latency::parse_stamp(&message.data) {
+                recorder.add(latency::epoch_ms().saturating_sub(sent_ms));
+            }
+        }
+        if let (Some(recording), AMF0_DATA) = (recording.as_mut(), message.type_id) {
+            let data = message.data.strip_prefix(SET_DATA_FRAME).unwrap_or(&message.data[..]);
+            recording.write_tag(flv::TAG_SCRIPT_DATA, message.timestamp.value, data).await?;
+        }
+        let (outbounds, event, _) = session.handle_input_message(message, bytes_read)?;
+        for packet in outbounds {
+            transport.feed(packet).await?;
+        }
+        match event {
+            Some(ClientSessionEvent::ConnectionRequestAccepted) => {
+                report.connect_ms = Some(started.elapsed().as_millis() as u64);
+                debug!(logger, "Connect request accepted, request playback");
+                for packet in session.request_playback(url.wire_stream())? {
+                    transport.feed(packet).await?;
+                }
+                play_sent = Instant::now();
+            }
+            Some(ClientSessionEvent::ConnectionRequestRejected{ description }) => {
+                return Err(ErrorKind::Unknown(format!("connect request rejected by peer server: {}", description)).into());
+            }
+            Some(ClientSessionEvent::PlaybackRequestAccepted) => {
+                debug!(logger, "Playback request accepted");
+            }
+            Some(ClientSessionEvent::VideoDataReceived{ data, timestamp, .. }) => {
+                report.video_frames += 1;
+                reception.add(report, started, data.len(), config.stall_threshold);
+                if report.first_video_ms.is_none() {
+                    report.first_video_ms = since_play(play_sent);
+                }
+                if report.first_keyframe_ms.is_none() && flv::is_video_keyframe(&data) {
+                    report.first_keyframe_ms = since_play(play_sent);
+                }
+                if let Some(recording) = recording {
+                    recording.write_tag(flv::TAG_VIDEO, timestamp.value, &data).await?;
+                }
+                if let Some(ref ledger) = config.verify {
+                    ledger.received(&PacketType::Video { data, ts: timestamp });
+                }
+            }
+            Some(ClientSessionEvent::AudioDataReceived{ data, timestamp, .. }) => {
+                report.audio_frames += 1;
+                reception.add(report, started, data.len(), config.stall_threshold);
+                if report.first_audio_ms.is_none() {
+                    report.first_audio_ms = since_play(play_sent);
+                }
+                if let Some(found) = continuity.add(&data, timestamp.value) {
+                    debug!(logger, "Audio discontinuity"; "kind" => ?found, "timestamp" => timestamp.value);
+                    let gap = found == Discontinuity::Gap;
+                    if gap {
+                        report.audio_gaps += 1;
+                    } else {
+                        report.audio_overlaps += 1;
+                    }
+                    if let Some(ref ledger) = config.verify {
+                        ledger.audio_discontinuity(gap);
+                    }
+                }
+                if let Some(recording) = recording {
+                    recording.write_tag(flv::TAG_AUDIO, timestamp.value, &data).await?;
+                }
+                if let Some(ref ledger) = config.verify {
+                    ledger.received(&PacketType::Audio { data, ts: timestamp });
+                }
+            }
+            Some(ClientSessionEvent::StreamMetadataReceived{ .. }) => {
+                debug!(logger, "Stream metadata received");
+            }
+            Some(ClientSessionEvent::UnhandleableAmf0Command{ ref command_name, transaction_id, .. }) if command_name == "onBWCheck" => {
+                transport.feed(command::bw_check_result(transaction_id, bw_checks)?).await?;
+                bw_checks += 1;
+            }
+            _ => (),
+        }
+        transport.flush().await?;
+    }
+    Ok(())
+}
+
+/// The pause and seek commands of `--pause-every` and `--seek-every`
+struct Exercise {
+    pause_every: Option<Duration>,
+    seek_every: Option<Duration>,
+    next_pause: Option<tokio::time::Instant>,
+    next_seek: Option<tokio::time::Instant>,
+    paused: bool,
+}
+
+enum Exercised {
+    /// Paused or resumed
+    Pause(bool),
+    Seek,
+}
+
+impl Exercise {
+    fn new(config: &Config) -> Self {
+        Self { pause_every: config.pause_every, seek_every: config.seek_every, next_pause: None, next_seek: None, paused: false }
+    }
+
+    /// Once the play started
+    fn start(&mut self) {
+        let now = tokio::time::Instant::now();
+        self.next_pause = self.pause_every.map(|every| now + every);
+        self.next_seek = self.seek_every.map(|every| now + every);
+    }
+
+    /// The next command when it is due, never without any
+    async fn next(&mut self) -> Exercised {
+        let seek = match (self.next_pause, self.next_seek) {
+            (None, None) => return future::pending().await,
+            (Some(pause), Some(seek)) => seek < pause,
+            (pause, _) => pause.is_none(),
+        };
+        // Only changed once due, a message arriving first drops the wait
+        if seek {
+            let at = self.next_seek.unwrap_or_else(tokio::time::Instant::now);
+            tokio::time::delay_until(at).await;
+            self.next_seek = self.seek_every.map(|every| at + every);
+            Exercised::Seek
+        } else {
+            let at = self.next_pause.unwrap_or_else(tokio::time::Instant::now);
+            tokio::time::delay_until(at).await;
+            self.next_pause = self.pause_every.map(|every| at + every);
+            self.paused = !self.paused;
+            Exercised::Pause(self.paused)
+        }
+    }
+}
+
+/// Play `url` alongside a publish run until its shutdown, playing again whenever the play ends,
+/// for `--measure-latency` and `--verify`
+pub async fn companion(url: Url, config: Config, shutdown: Shutdown, logger: Logger) {
+    let stopped = shutdown.wait();
+    pin_mut!(stopped);
+    loop {
+        let playing = play(url.clone(), &config, &logger);
+        pin_mut!(playing);
+        match future::select(playing, stopped.as_mut()).await {
+            Either::Left((report, _)) => {
+                if report.error.is_none() {
+                    warn!(logger, "The companion play session ended, play again");
+                }
+            }
+            Either::Right(_) => return,
+        }
+        tokio::time::delay_for(COMPANION_RETRY_DELAY).await;
+    }
+}
+
+/// A new file in `dir` named after the app and stream of `url`, numbered if there is one already
+async fn create_recording(dir: &Path, url: &Url) -> std::io::Result<(PathBuf, Recording)> {
+    let name = format!("{}_{}", url.app, url.stream).chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect::<String>();
+    let mut n = 0;
+    loop {
+        let path = match n {
+            0 => dir.join(format!("{}.flv", name)),
+            n => dir.join(format!("{}-{}.flv", name, n)),
+        };
+        match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(file) => return Ok((path, flv::Writer::new(tokio::io::BufWriter::new(file)).await?)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Open the session and send the connect command
+async fn connect(url: &Url, insecure_tls: bool, family: Option<Family>) -> Result<(Framed<Box<dyn Io>, Codec>, ClientSession), Error> {
+    let socket = bind::connect(url.server().as_str(), family, None).await?;
+    let io = super::open(socket, url.scheme, &url.host, url.port, insecure_tls).await?;
+
+    let mut transport = Codec::default().framed(io);
+    let (mut session, results) = ClientSession::new(ClientSessionConfig::new())?;
+    for result in results {
+        if let ClientSessionResult::OutboundResponse(packet) = result {
+            transport.feed(packet).await?;
+        }
+    }
+    transport.send(session.request_connection(url.app.clone(), Some(url.tc_url()))?).await?;
+    Ok((transport, session))
+}
+
+/// The metadata, audio and video of a live stream as they arrive, with their source timestamps.
+/// Ends when the source closes the connection.
+pub fn pull(url: Url, insecure_tls: bool, logger: Logger) -> impl Stream<Item = std::io::Result<Arc<PacketType>>> {
+    let messages: async_stream::AsyncStream<Result<Arc<PacketType>, Error>, _> = try_stream! {
+        let (mut transport, mut session) = connect(&url, insecure_tls, None).await?;
+        let mut bw_checks = 0;
+        while let Some(received) = transport.next().await {
+            let (message, bytes_read) = received?;
+            let (outbounds, event, _) = session.handle_input_message(message, bytes_read)?;
+            for packet in outbounds {
+                transport.feed(packet).await?;
+            }
+            let packet = match event {
+                Some(ClientSessionEvent::ConnectionRequestAccepted) => {
+                    for packet in session.request_playback(url.wire_stream())? {
+                        transport.feed(packet).await?;
+                    }
+                    None
+                }
+                Some(ClientSessionEvent::ConnectionRequestRejected{ description }) => {
+                    Err::<(), _>(ErrorKind::Unknown(format!("connect request rejected by source server: {}", description)))?;
+                    None
+                }
+                Some(ClientSessionEvent::PlaybackRequestAccepted) => {
+                    info!(logger, "Pulling the input"; "url" => %url);
+                    None
+                }
+                Some(ClientSessionEvent::VideoDataReceived{ data, timestamp, .. }) => Some(PacketType::Video { data, ts: timestamp }),
+                Some(ClientSessionEvent::AudioDataReceived{ data, timestamp, .. }) => Some(PacketType::Audio { data, ts: timestamp }),
+                Some(ClientSessionEvent::StreamMetadataReceived{ metadata, .. }) => Some(PacketType::Metadata(Arc::new(metadata))),
+                Some(ClientSessionEvent::UnhandleableAmf0Command{ ref command_name, transaction_id, .. }) if command_name == "onBWCheck" => {
+                    transport.feed(command::bw_check_result(transaction_id, bw_checks)?).await?;
+                    bw_checks += 1;
+                    None
+                }
+                _ => None,
+            };
+            transport.flush().await?;
+            if let Some(packet) = packet {
+                yield Arc::new(packet);
+            }
+        }
+        info!(logger, "Input source closed the connection"; "url" => %url);
+    };
+    messages.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// AAC LC at 48 kHz, stereo: frames of 21.33 ms
+    const SEQUENCE_HEADER: &[u8] = &[0xaf, 0, 0x11, 0x90];
+    const FRAME: &[u8] = &[0xaf, 1, 0x21, 0x00];
+
+    fn check(timestamps: &[u32]) -> Vec<(usize, Discontinuity)> {
+        let mut continuity = AudioContinuity::default();
+        assert_eq!(continuity.add(SEQUENCE_HEADER, 0), None);
+        timestamps.iter().enumerate().filter_map(|(i, &ts)| continuity.add(FRAME, ts).map(|d| (i, d))).collect()
+    }
+
+    /// The rounded timestamps of `n` contiguous frames at 48 kHz from `from`
+    fn contiguous(from: u32, n: u32) -> Vec<u32> {
+        (0..n).map(|i| from.wrapping_add((f64::from(i) * 1024_f64 * 1000.0 / 48000.0).round() as u32)).collect()
+    }
+
+    #[test]
+    fn contiguous_frames_have_no_discontinuity() {
+        assert_eq!(check(&contiguous(0, 500)), vec![]);
+        // Through the 32 bit wrap
+        assert_eq!(check(&contiguous(u32::MAX - 100, 20)), vec![]);
+    }
+
+    #[test]
+    fn dropped_and_duplicated_frames() {
+        let mut timestamps = contiguous(0, 10);
+        // The 6th frame dropped
+        timestamps.remove(5);
+        assert_eq!(check(&timestamps), vec![(5, Discontinuity::Gap)]);
+        let mut timestamps = contiguous(0, 10);
+        timestamps.insert(4, timestamps[3]);
+        assert_eq!(check(&timestamps), vec![(4, Discontinuity::Overlap)]);
+    }
+
+    #[test]
+    fn frames_without_a_sequence_header_are_not_checked() {
+        let mut continuity = AudioContinuity::default();
+        assert_eq!(continuity.add(FRAME, 0), None);
+        assert_eq!(continuity.add(FRAME, 500), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! `--proxy`: the TCP connection of a session opened through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! The proxy only carries the bytes, the RTMP handshake and TLS of `rtmps` run over the tunnel
+//! to the destination as they would over a direct connection.
+
+use std::io;
+use std::net::IpAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::bind::{self, Family, LocalAddrs};
+
+/// Longest response head accepted from an HTTP proxy
+const MAX_HEAD: usize = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Socks5,
+    Http,
+}
+
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    pub kind: Kind,
+    /// `host:port` of the proxy
+    pub server: String,
+    /// User name and password
+    pub auth: Option<(String, String)>,
+}
+
+/// Parse `socks5://[user:password@]host:port` or `http://[user:password@]host:port`
+pub fn parse(s: &str) -> Result<Proxy, String> {
+    let url = url::Url::parse(s).map_err(|e| format!("invalid proxy `{}`: {}", s, e))?;
+    let (kind, default_port) = match url.scheme() {
+        "socks5" | "socks5h" => (Kind::Socks5, 1080),
+        "http" => (Kind::Http, 8080),
+        scheme => return Err(format!("unsupported proxy scheme `{}`, use socks5:// or http://", scheme)),
+    };
+    let host = url.host_str().ok_or_else(|| format!("missing host in proxy `{}`", s))?;
+    let auth = if url.username().is_empty() {
+        None
+    } else {
+        let decode = |s: &str| percent_encoding::percent_decode_str(s).decode_utf8_lossy().into_owned();
+        Some((decode(url.username()), decode(url.password().unwrap_or(""))))
+    };
+    Ok(Proxy {
+        kind,
+        server: format!("{}:{}", host, url.port().unwrap_or(default_port)),
+        auth,
+    })
+}
+
+impl Proxy {
+    /// A socket connected to `host:port` through the proxy, the name is resolved by the proxy.
+    /// The connection to the proxy is made as `bind::connect` does.
+    pub async fn connect(&self, host: &str, port: u16, family: Option<Family>, bind: Option<&LocalAddrs>) -> io::Result<TcpStream> {
+        let mut socket = bind::connect(self.server.as_str(), family, bind).await?;
+        match self.kind {
+            Kind::Socks5 => self.socks5(&mut socket, host, port).await?,
+            Kind::Http => self.http_connect(&mut socket, host, port).await?,
+        }
+        Ok(socket)
+    }
+
+    async fn socks5(&self, socket: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        // No authentication, or user name and password
+        let methods: &[u8] = if self.auth.is_some() { &[0x00, 0x02] } else { &[0x00] };
+        let mut greeting = vec![0x05, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        socket.write_all(&greeting).await?;
+        let mut choice = [0; 2];
+        socket.read_exact(&mut choice).await?;
+        match (choice[1], &self.auth) {
+            (0x00, _) => (),
+            (0x02, Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(proxy_error("SOCKS5 user name or password longer than 255 bytes"));
+                }
+                let mut request = vec![0x01, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                socket.write_all(&request).await?;
+                let mut status = [0; 2];
+                socket.read_exact(&mut status).await?;
+                if status[1] != 0x00 {
+                    return Err(proxy_error("SOCKS5 authentication failed"));
+                }
+            }
+            (0xff, _) => return Err(proxy_error("SOCKS5 proxy accepts none of the authentication methods")),
+            (method, _) => return Err(proxy_error(&format!("SOCKS5 proxy chose unsupported method {}", method))),
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(proxy_error("host name longer than 255 bytes"));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        socket.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        socket.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(proxy_error(&format!("SOCKS5 connect to {}:{} failed: {}", host, port, socks5_reply(reply[1]))));
+        }
+        // The bound address, unused
+        let len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0; 1];
+                socket.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            atyp => return Err(proxy_error(&format!("SOCKS5 reply with unknown address type {}", atyp))),
+        };
+        let mut bound = vec![0; len + 2];
+        socket.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_connect(&self, socket: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+        let target = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some((ref user, ref password)) = self.auth {
+            let credentials = base64::encode(format!("{}:{}", user, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+        }
+        request.push_str("\r\n");
+        socket.write_all(request.as_bytes()).await?;
+
+        // Byte by byte, what follows the head belongs to the tunnel
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > MAX_HEAD {
+                return Err(proxy_error("HTTP proxy response head too long"));
+            }
+            let mut byte = [0; 1];
+            if socket.read(&mut byte).await? == 0 {
+                return Err(proxy_error("HTTP proxy closed the connection"));
+            }
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status = head.lines().next().unwrap_or("");
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(proxy_error(&format!("HTTP proxy CONNECT to {} refused: {}", target, status))),
+        }
+    }
+}
+
+fn socks5_reply(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by the ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn proxy_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message.to_owned())
+}
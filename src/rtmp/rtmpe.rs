@@ -0,0 +1,269 @@
+//! RTMPE: the Diffie-Hellman handshake of Flash Player 9, then RC4 over everything after it.
+//!
+//! The digest and public key positions follow librtmp, which the legacy servers asking for
+//! RTMPE interoperate with. The server may use either of the two position schemes.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use hmac::{Hmac, Mac};
+use num_bigint::BigUint;
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{Error, ErrorKind};
+use super::HANDSHAKE_PACKET_SIZE as SIG_SIZE;
+
+const ENCRYPTED: u8 = 0x06;
+const DIGEST_SIZE: usize = 32;
+const KEY_SIZE: usize = 128;
+/// Flash Player 9.0.115 or later, the first version with RTMPE
+const CLIENT_VERSION: [u8; 4] = [128, 0, 3, 2];
+
+/// "Genuine Adobe Flash Player 001" followed by 32 bytes, only the text signs C1
+const GENUINE_FP_KEY: [u8; 62] = [
+    0x47, 0x65, 0x6e, 0x75, 0x69, 0x6e, 0x65, 0x20, 0x41, 0x64, 0x6f, 0x62, 0x65, 0x20, 0x46, 0x6c,
+    0x61, 0x73, 0x68, 0x20, 0x50, 0x6c, 0x61, 0x79, 0x65, 0x72, 0x20, 0x30, 0x30, 0x31,
+    0xf0, 0xee, 0xc2, 0x4a, 0x80, 0x68, 0xbe, 0xe8, 0x2e, 0x00, 0xd0, 0xd1, 0x02, 0x9e, 0x7e, 0x57,
+    0x6e, 0xec, 0x5d, 0x2d, 0x29, 0x80, 0x6f, 0xab, 0x93, 0xb8, 0xe6, 0x36, 0xcf, 0xeb, 0x31, 0xae,
+];
+const FP_KEY_TEXT: usize = 30;
+
+/// "Genuine Adobe Flash Media Server 001" followed by the same 32 bytes
+const GENUINE_FMS_KEY: [u8; 68] = [
+    0x47, 0x65, 0x6e, 0x75, 0x69, 0x6e, 0x65, 0x20, 0x41, 0x64, 0x6f, 0x62, 0x65, 0x20, 0x46, 0x6c,
+    0x61, 0x73, 0x68, 0x20, 0x4d, 0x65, 0x64, 0x69, 0x61, 0x20, 0x53, 0x65, 0x72, 0x76, 0x65, 0x72,
+    0x20, 0x30, 0x30, 0x31,
+    0xf0, 0xee, 0xc2, 0x4a, 0x80, 0x68, 0xbe, 0xe8, 0x2e, 0x00, 0xd0, 0xd1, 0x02, 0x9e, 0x7e, 0x57,
+    0x6e, 0xec, 0x5d, 0x2d, 0x29, 0x80, 0x6f, 0xab, 0x93, 0xb8, 0xe6, 0x36, 0xcf, 0xeb, 0x31, 0xae,
+];
+const FMS_KEY_TEXT: usize = 36;
+
+/// The 1024 bit MODP group of RFC 2409, with generator 2
+const P1024: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                       020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                       4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+                       EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE65381FFFFFFFFFFFFFFFF";
+
+/// Handshake with the encrypted client type, the returned stream encrypts and decrypts the session.
+pub async fn handshake<T>(mut io: T) -> Result<Rc4Stream<T>, Error>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let p = BigUint::parse_bytes(P1024, 16).expect("Invalid DH prime");
+    let mut rng = rand::thread_rng();
+    let mut private = [0; KEY_SIZE];
+    rng.fill_bytes(&mut private);
+    let private = BigUint::from_bytes_be(&private);
+    let public = to_key(&BigUint::from(2u32).modpow(&private, &p));
+
+    // Flash Player starts with the second scheme for encrypted handshakes
+    let mut scheme = Scheme::Second;
+    let mut c1 = vec![0; SIG_SIZE];
+    rng.fill_bytes(&mut c1[8..]);
+    c1[4..8].copy_from_slice(&CLIENT_VERSION);
+    let dh_client = scheme.dh_offset(&c1);
+    c1[dh_client..dh_client + KEY_SIZE].copy_from_slice(&public);
+    let digest_client = scheme.digest_offset(&c1);
+    let digest = digest(&c1, digest_client, &GENUINE_FP_KEY[..FP_KEY_TEXT]);
+    c1[digest_client..digest_client + DIGEST_SIZE].copy_from_slice(&digest);
+    io.write_all(&[ENCRYPTED]).await?;
+    io.write_all(&c1).await?;
+    io.flush().await?;
+
+    let mut s0 = [0; 1];
+    io.read_exact(&mut s0).await?;
+    if s0[0] != ENCRYPTED {
+        return Err(encryption_error(format!("server answered with handshake type {}", s0[0])));
+    }
+    let mut s1 = vec![0; SIG_SIZE];
+    io.read_exact(&mut s1).await?;
+    let mut digest_server = scheme.digest_offset(&s1);
+    if digest(&s1, digest_server, &GENUINE_FMS_KEY[..FMS_KEY_TEXT]) != s1[digest_server..digest_server + DIGEST_SIZE] {
+        scheme = scheme.other();
+        digest_server = scheme.digest_offset(&s1);
+        if digest(&s1, digest_server, &GENUINE_FMS_KEY[..FMS_KEY_TEXT]) != s1[digest_server..digest_server + DIGEST_SIZE] {
+            return Err(encryption_error("no valid digest in S1".into()));
+        }
+    }
+
+    let dh_server = scheme.dh_offset(&s1);
+    let server_public = &s1[dh_server..dh_server + KEY_SIZE];
+    let y = BigUint::from_bytes_be(server_public);
+    if y <= BigUint::from(1u32) || y >= &p - 1u32 {
+        return Err(encryption_error("invalid server public key".into()));
+    }
+    let secret = to_key(&y.modpow(&private, &p));
+    let mut encrypt = Rc4::new(&hmac(&secret, &[server_public])[..16]);
+    let mut decrypt = Rc4::new(&hmac(&secret, &[&c1[dh_client..dh_client + KEY_SIZE]])[..16]);
+
+    let mut c2 = vec![0; SIG_SIZE];
+    rng.fill_bytes(&mut c2);
+    let key = hmac(&GENUINE_FP_KEY, &[&s1[digest_server..digest_server + DIGEST_SIZE]]);
+    let signature = hmac(&key, &[&c2[..SIG_SIZE - DIGEST_SIZE]]);
+    c2[SIG_SIZE - DIGEST_SIZE..].copy_from_slice(&signature);
+    io.write_all(&c2).await?;
+    io.flush().await?;
+
+    let mut s2 = vec![0; SIG_SIZE];
+    io.read_exact(&mut s2).await?;
+    let key = hmac(&GENUINE_FMS_KEY, &[&c1[digest_client..digest_client + DIGEST_SIZE]]);
+    if hmac(&key, &[&s2[..SIG_SIZE - DIGEST_SIZE]]) != s2[SIG_SIZE - DIGEST_SIZE..] {
+        return Err(encryption_error("S2 is not signed with our digest".into()));
+    }
+
+    // Both key streams start after the length of a handshake packet
+    encrypt.apply(&mut [0; SIG_SIZE]);
+    decrypt.apply(&mut [0; SIG_SIZE]);
+    Ok(Rc4Stream { inner: io, encrypt, decrypt, pending: Vec::new(), written: 0 })
+}
+
+#[derive(Clone, Copy)]
+enum Scheme {
+    First,
+    Second,
+}
+
+impl Scheme {
+    fn other(self) -> Self {
+        match self {
+            Scheme::First => Scheme::Second,
+            Scheme::Second => Scheme::First,
+        }
+    }
+
+    fn digest_offset(self, sig: &[u8]) -> usize {
+        match self {
+            Scheme::First => offset_sum(&sig[8..12]) % 728 + 12,
+            Scheme::Second => offset_sum(&sig[772..776]) % 728 + 776,
+        }
+    }
+
+    fn dh_offset(self, sig: &[u8]) -> usize {
+        match self {
+            Scheme::First => offset_sum(&sig[1532..1536]) % 632 + 772,
+            Scheme::Second => offset_sum(&sig[768..772]) % 632 + 8,
+        }
+    }
+}
+
+fn offset_sum(bytes: &[u8]) -> usize {
+    bytes.iter().map(|&b| b as usize).sum()
+}
+
+/// Big endian, left padded to the size of the group
+fn to_key(n: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = n.to_bytes_be();
+    let mut key = [0; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hmac(key: &[u8], message: &[&[u8]]) -> [u8; DIGEST_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in message {
+        mac.update(part);
+    }
+    let mut out = [0; DIGEST_SIZE];
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+/// Digest of a handshake packet without the digest itself
+fn digest(sig: &[u8], offset: usize, key: &[u8]) -> [u8; DIGEST_SIZE] {
+    hmac(key, &[&sig[..offset], &sig[offset + DIGEST_SIZE..]])
+}
+
+fn encryption_error(message: String) -> Error {
+    ErrorKind::Encryption(message).into()
+}
+
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for b in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            *b ^= self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+        }
+    }
+}
+
+/// An encrypted session. Writes are encrypted as a whole and queued, so a partial write of the
+/// inner stream never desynchronizes the key stream.
+pub struct Rc4Stream<T> {
+    inner: T,
+    encrypt: Rc4,
+    decrypt: Rc4,
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<T: AsyncWrite + Unpin> Rc4Stream<T> {
+    fn poll_drain(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rc4Stream<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.decrypt.apply(&mut buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rc4Stream<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        this.pending.extend_from_slice(buf);
+        this.encrypt.apply(&mut this.pending);
+        // Accepted either way, the rest goes out with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,237 @@
+//! RTMPT: RTMP tunneled through HTTP POST requests.
+//!
+//! The client opens a session with `/open/1`, then pushes its bytes with `/send/<id>/<seq>`
+//! and polls with `/idle/<id>/<seq>` when it has nothing to send. Every response starts with
+//! a polling interval byte, followed by the bytes the server has for us.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{
+    channel::mpsc,
+    sink::SinkExt,
+    stream::StreamExt,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::error::{Error, ErrorKind};
+
+const MIN_IDLE_DELAY: Duration = Duration::from_millis(10);
+const MAX_IDLE_DELAY: Duration = Duration::from_millis(500);
+const CHANNEL_SIZE: usize = 64;
+
+/// The client side of a tunnel, reads and writes are carried by a background task.
+pub struct RtmptStream {
+    to_tunnel: mpsc::Sender<Bytes>,
+    from_tunnel: mpsc::Receiver<Bytes>,
+    pending: Bytes,
+}
+
+/// Open a tunnel session over an established HTTP connection.
+///
+/// `host` is sent as the `Host` header.
+pub async fn open(socket: TcpStream, host: String) -> Result<RtmptStream, Error> {
+    let mut conn = HttpConnection { stream: BufReader::new(socket), host };
+    let body = conn.post("/open/1", &[0]).await?;
+    let session_id = String::from_utf8_lossy(&body).trim().to_owned();
+    if session_id.is_empty() {
+        return Err(tunnel_error("empty session id from /open"));
+    }
+
+    let (to_tunnel, outbound) = mpsc::channel(CHANNEL_SIZE);
+    let (inbound, from_tunnel) = mpsc::channel(CHANNEL_SIZE);
+    tokio::spawn(async move {
+        // Errors surface to the session as a closed stream
+        let _ = poll_tunnel(conn, session_id, outbound, inbound).await;
+    });
+
+    Ok(RtmptStream { to_tunnel, from_tunnel, pending: Bytes::new() })
+}
+
+async fn poll_tunnel(mut conn: HttpConnection,
+                     session_id: String,
+                     mut outbound: mpsc::Receiver<Bytes>,
+                     mut inbound: mpsc::Sender<Bytes>) -> Result<(), Error>
+{
+    let mut seq = 1u64;
+    let mut idle_delay = MIN_IDLE_DELAY;
+    loop {
+        let mut body = Vec::new();
+        match tokio::time::timeout(idle_delay, outbound.next()).await {
+            Ok(Some(chunk)) => {
+                body.extend_from_slice(&chunk);
+                // Batch everything already written into one request
+                while let Ok(Some(chunk)) = outbound.try_next() {
+                    body.extend_from_slice(&chunk);
+                }
+            }
+            Ok(None) => {
+                let _ = conn.post(&format!("/close/{}/{}", session_id, seq), &[0]).await;
+                return Ok(());
+            }
+            Err(_elapsed) => (),
+        }
+
+        let sending = !body.is_empty();
+        let path = if sending {
+            format!("/send/{}/{}", session_id, seq)
+        } else {
+            body.push(0);
+            format!("/idle/{}/{}", session_id, seq)
+        };
+        let response = conn.post(&path, &body).await?;
+        seq += 1;
+
+        if response.is_empty() {
+            return Err(tunnel_error("response without polling interval"));
+        }
+        let data = response.slice(1..);
+        if data.is_empty() {
+            if !sending {
+                idle_delay = (idle_delay * 2).min(MAX_IDLE_DELAY);
+            }
+        } else {
+            idle_delay = MIN_IDLE_DELAY;
+            inbound.send(data).await.map_err(|_| tunnel_error("session closed"))?;
+        }
+    }
+}
+
+struct HttpConnection {
+    stream: BufReader<TcpStream>,
+    host: String,
+}
+
+impl HttpConnection {
+    /// POST `body` on the kept-alive connection and return the response body
+    async fn post(&mut self, path: &str, body: &[u8]) -> Result<Bytes, Error> {
+        let head = format!("POST {} HTTP/1.1\r\n\
+                            Host: {}\r\n\
+                            Content-Type: application/x-fcs\r\n\
+                            Content-Length: {}\r\n\
+                            Connection: Keep-Alive\r\n\
+                            Cache-Control: no-cache\r\n\
+                            User-Agent: Shockwave Flash\r\n\r\n", path, self.host, body.len());
+        let socket = self.stream.get_mut();
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(body).await?;
+        socket.flush().await?;
+
+        let mut line = String::new();
+        self.stream.read_line(&mut line).await?;
+        let status = line.split_whitespace().nth(1).unwrap_or("");
+        if status != "200" {
+            return Err(tunnel_error(&format!("{} responded: {}", path, line.trim())));
+        }
+
+        let mut content_length = None;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(tunnel_error("connection closed in response headers"));
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut kv = header.splitn(2, ':');
+            let name = kv.next().unwrap_or("").trim().to_ascii_lowercase();
+            let value = kv.next().unwrap_or("").trim();
+            if name == "content-length" {
+                content_length = value.parse::<usize>().ok();
+            } else if name == "transfer-encoding" && value.eq_ignore_ascii_case("chunked") {
+                chunked = true;
+            }
+        }
+
+        if chunked {
+            return self.read_chunked().await;
+        }
+        let content_length = content_length.ok_or_else(|| tunnel_error("response without Content-Length"))?;
+        let mut body = vec![0; content_length];
+        self.stream.read_exact(&mut body).await?;
+        Ok(Bytes::from(body))
+    }
+
+    /// A `Transfer-Encoding: chunked` body, as some servers send their responses
+    async fn read_chunked(&mut self) -> Result<Bytes, Error> {
+        let mut body = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(tunnel_error("connection closed in a chunked response"));
+            }
+            // Chunk extensions after `;` are allowed and meaningless here
+            let size = line.trim().split(';').next().unwrap_or("");
+            let size = usize::from_str_radix(size, 16).map_err(|_| tunnel_error(&format!("bad chunk size `{}`", line.trim())))?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            self.stream.read_exact(&mut body[start..]).await?;
+            line.clear();
+            self.stream.read_line(&mut line).await?;
+        }
+        // Trailers, up to the empty line
+        loop {
+            line.clear();
+            if self.stream.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+                break;
+            }
+        }
+        Ok(Bytes::from(body))
+    }
+}
+
+impl AsyncRead for RtmptStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.pending.is_empty() {
+            match self.from_tunnel.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => self.pending = data,
+                // Tunnel closed
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        let rest = self.pending.slice(n..);
+        self.pending = rest;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for RtmptStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.to_tunnel.poll_ready(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+        match self.to_tunnel.start_send(Bytes::copy_from_slice(buf)) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Written bytes go out with the next poll of the tunnel
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.to_tunnel.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn tunnel_error(msg: &str) -> Error {
+    ErrorKind::Tunnel(msg.to_owned()).into()
+}
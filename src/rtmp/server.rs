@@ -0,0 +1,180 @@
+//! The server side of a publish session for `waterfall sink`: accept the connect, `createStream`
+//! and `publish` commands of any publisher, acknowledge what it sends and count the media.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use futures::{sink::SinkExt, stream::StreamExt};
+use rml_amf0::Amf0Value;
+use rml_rtmp::messages::RtmpMessage;
+use serde::Serialize;
+use slog::{debug, info, warn, Logger};
+use tokio::net::TcpStream;
+use tokio_util::codec::Decoder;
+
+use crate::error::{Error, ErrorKind};
+use crate::flv;
+use crate::shutdown::Shutdown;
+use super::{codec::Codec, command, handshake};
+
+/// The window acknowledgement size announced to the publishers
+pub const WINDOW: u32 = 2_500_000;
+const AMF0_DATA: u8 = 18;
+const AMF0_COMMAND: u8 = 20;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IngestReport {
+    pub peer: String,
+    pub app: String,
+    pub stream: String,
+    /// From the TCP accept to the publish command
+    pub publish_ms: Option<u64>,
+    pub bytes_received: u64,
+    pub video_frames: u64,
+    pub keyframes: u64,
+    pub audio_frames: u64,
+    pub data_messages: u64,
+    /// Media bytes received over the time from the first to the last media message
+    pub bitrate_bps: u64,
+    /// `FCUnpublish`, `closeStream` or `deleteStream` was received before the end
+    pub unpublished: bool,
+    pub error: Option<String>,
+}
+
+/// Totals over the sessions still open
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub sessions: AtomicUsize,
+    pub publishing: AtomicUsize,
+    pub bytes_received: AtomicU64,
+}
+
+#[derive(Default)]
+struct Media {
+    first: Option<Instant>,
+    last: Option<Instant>,
+    bytes: u64,
+}
+
+/// Serve one publisher until it closes the connection or `shutdown` triggers.
+pub async fn session(socket: TcpStream, peer: SocketAddr, counters: Arc<Counters>, shutdown: Shutdown, logger: Logger) -> IngestReport {
+    let mut report = IngestReport { peer: peer.to_string(), ..Default::default() };
+    let mut media = Media::default();
+    counters.sessions.fetch_add(1, Ordering::Relaxed);
+    let result = ingest(socket, &counters, &shutdown, &mut report, &mut media, &logger).await;
+    counters.sessions.fetch_sub(1, Ordering::Relaxed);
+    if report.publish_ms.is_some() {
+        counters.publishing.fetch_sub(1, Ordering::Relaxed);
+    }
+    if let (Some(first), Some(last)) = (media.first, media.last) {
+        let secs = (last - first).as_secs_f64();
+        if secs > 0.0 {
+            report.bitrate_bps = (media.bytes as f64 * 8.0 / secs) as u64;
+        }
+    }
+    match result {
+        Ok(()) => info!(logger, "Publisher gone"; "bytes_received" => report.bytes_received, "video_frames" => report.video_frames),
+        Err(e) => {
+            warn!(logger, "Ingest error"; "error" => %e);
+            report.error = Some(e.to_string());
+        }
+    }
+    report
+}
+
+async fn ingest(socket: TcpStream,
+                counters: &Counters,
+                shutdown: &Shutdown,
+                report: &mut IngestReport,
+                media: &mut Media,
+                logger: &Logger) -> Result<(), Error>
+{
+    let accepted = Instant::now();
+    let io = handshake::accept(socket).await?;
+    let mut transport = Codec::default().framed(io);
+    let mut acked = 0u64;
+    let mut next_stream_id = 0u32;
+    loop {
+        let received = tokio::select! {
+            received = transport.next() => received,
+            _ = shutdown.wait() => return Ok(()),
+        };
+        let (message, bytes_read) = match received {
+            Some(received) => received?,
+            None => return Ok(()),
+        };
+        report.bytes_received += bytes_read as u64;
+        counters.bytes_received.fetch_add(bytes_read as u64, Ordering::Relaxed);
+        if report.bytes_received - acked >= u64::from(WINDOW) {
+            acked = report.bytes_received;
+            transport.feed(command::acknowledgement(acked as u32)).await?;
+        }
+        match message.type_id {
+            flv::TAG_AUDIO | flv::TAG_VIDEO => {
+                if message.type_id == flv::TAG_VIDEO {
+                    report.video_frames += 1;
+                    if flv::is_video_keyframe(&message.data) {
+                        report.keyframes += 1;
+                    }
+                } else {
+                    report.audio_frames += 1;
+                }
+                let now = Instant::now();
+                media.first.get_or_insert(now);
+                media.last = Some(now);
+                media.bytes += message.data.len() as u64;
+            }
+            AMF0_DATA => report.data_messages += 1,
+            AMF0_COMMAND => {
+                let (command_name, transaction_id, command_object, additional_arguments) = match message.to_rtmp_message()? {
+                    RtmpMessage::Amf0Command{ command_name, transaction_id, command_object, additional_arguments } => {
+                        (command_name, transaction_id, command_object, additional_arguments)
+                    }
+                    _ => continue,
+                };
+                debug!(logger, "Command"; "name" => &command_name);
+                match command_name.as_str() {
+                    "connect" => {
+                        if let Amf0Value::Object(ref properties) = command_object {
+                            if let Some(Amf0Value::Utf8String(app)) = properties.get("app") {
+                                report.app = app.clone();
+                            }
+                        }
+                        transport.feed(command::window_ack_size(WINDOW)).await?;
+                        transport.feed(command::set_peer_bandwidth(WINDOW)).await?;
+                        transport.feed(command::connect_result(transaction_id)?).await?;
+                    }
+                    "createStream" => {
+                        next_stream_id += 1;
+                        transport.feed(command::result(transaction_id, Amf0Value::Number(f64::from(next_stream_id)))?).await?;
+                    }
+                    "publish" => {
+                        let stream = match additional_arguments.first() {
+                            Some(Amf0Value::Utf8String(stream)) => stream.split('?').next().unwrap_or_default().to_owned(),
+                            _ => return Err(ErrorKind::Unknown("publish command without a stream".into()).into()),
+                        };
+                        let description = format!("{} is now published.", stream);
+                        transport.feed(command::on_status(message.message_stream_id, "status", "NetStream.Publish.Start", &description)?).await?;
+                        if report.publish_ms.is_none() {
+                            counters.publishing.fetch_add(1, Ordering::Relaxed);
+                        }
+                        report.publish_ms = Some(accepted.elapsed().as_millis() as u64);
+                        info!(logger, "Publishing"; "app" => &report.app, "stream" => &stream);
+                        report.stream = stream;
+                    }
+                    "FCUnpublish" | "closeStream" | "deleteStream" => report.unpublished = true,
+                    "play" => return Err(ErrorKind::Unknown("the sink does not serve players".into()).into()),
+                    // `releaseStream`, `FCPublish` and the like, which need no more than an answer
+                    _ if transaction_id > 0.0 => {
+                        transport.feed(command::result(transaction_id, Amf0Value::Null)?).await?;
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        transport.flush().await?;
+    }
+}
@@ -0,0 +1,81 @@
+//! `--max-bitrate`: the writes of a session through a token bucket, like a constrained uplink.
+//!
+//! The bucket fills at the rate and holds up to `BURST` of it, so the sender goes quiet while it
+//! refills and then writes a burst at once, as a publisher behind a slow link does. Reads pass
+//! through unshaped.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{ready, Future};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Delay, Instant};
+
+/// The bucket holds this much of the rate
+const BURST: Duration = Duration::from_millis(100);
+/// Even at low rates, at least this many bytes go out at once
+const MIN_BURST: f64 = 4096.0;
+
+pub struct Shaped<T> {
+    inner: T,
+    /// Bytes per second
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+    delay: Option<Delay>,
+}
+
+impl<T> Shaped<T> {
+    pub fn new(inner: T, bitrate_bps: u64) -> Self {
+        let rate = bitrate_bps as f64 / 8.0;
+        let burst = (rate * BURST.as_secs_f64()).max(MIN_BURST);
+        Self { inner, rate, burst, tokens: burst, refilled: Instant::now(), delay: None }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.refilled).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Shaped<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Shaped<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // Wait for the whole write, or a full bucket, rather than trickling out what is there
+        let wanted = (buf.len() as f64).min(this.burst);
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                ready!(Pin::new(delay).poll(cx));
+                this.delay = None;
+            }
+            this.refill();
+            if this.tokens >= wanted {
+                break;
+            }
+            let wait = Duration::from_secs_f64((wanted - this.tokens) / this.rate);
+            this.delay = Some(tokio::time::delay_until(this.refilled + wait));
+        }
+        let n = buf.len().min(this.tokens as usize);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..n]))?;
+        this.tokens -= written as f64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,66 @@
+//! `--runtimes`: the publish clients spread over runtimes of their own, one thread each, apart
+//! from the runtime reading the inputs and feeding the broadcasts.
+//!
+//! Each runtime has its own scheduler, timers and sockets, so tens of thousands of sessions do not
+//! contend for one task queue, and a session stays on the thread it started on.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use futures::Future;
+use tokio::runtime::Handle;
+
+#[derive(Debug)]
+pub struct Shards {
+    handles: Vec<Handle>,
+    /// The shard of the next client, in turn
+    next: AtomicUsize,
+}
+
+impl Shards {
+    /// Start `count` single threaded runtimes, running until the process exits
+    pub fn start(count: usize) -> io::Result<Self> {
+        let mut handles = Vec::with_capacity(count);
+        for shard in 0..count {
+            let (tx, rx) = mpsc::channel();
+            thread::Builder::new()
+                .name(format!("shard-{}", shard))
+                .spawn(move || {
+                    let runtime = tokio::runtime::Builder::new()
+                        .basic_scheduler()
+                        .enable_all()
+                        .build();
+                    match runtime {
+                        Ok(mut runtime) => {
+                            let _ = tx.send(Ok(runtime.handle().clone()));
+                            runtime.block_on(futures::future::pending::<()>());
+                        }
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                        }
+                    }
+                })?;
+            let handle = rx.recv().map_err(|_| io::Error::new(io::ErrorKind::Other, "shard runtime thread exited"))??;
+            handles.push(handle);
+        }
+        Ok(Self { handles, next: AtomicUsize::new(0) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Run `task` on the next shard, the tasks it spawns stay there
+    pub fn spawn<F>(&self, task: F)
+        where F: Future<Output = ()> + Send + 'static,
+    {
+        let shard = self.next.fetch_add(1, Ordering::Relaxed) % self.handles.len();
+        self.handles[shard].spawn(task);
+    }
+}
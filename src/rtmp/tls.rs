@@ -0,0 +1,52 @@
+//! TLS for `rtmps://` destinations, with the Mozilla root certificates.
+
+use std::sync::{Arc, OnceLock};
+
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError},
+    webpki::DNSNameRef,
+    TlsConnector,
+};
+
+use crate::error::{Error, ErrorKind};
+
+/// Built once, every client shares the root store
+static VERIFYING: OnceLock<TlsConnector> = OnceLock::new();
+static INSECURE: OnceLock<TlsConnector> = OnceLock::new();
+
+/// TLS handshake with `host` as the server name, which an IP address cannot be.
+pub async fn connect(socket: TcpStream, host: &str, insecure: bool) -> Result<TlsStream<TcpStream>, Error> {
+    let name = DNSNameRef::try_from_ascii_str(host)
+        .map_err(|_| ErrorKind::Tls(format!("`{}` is not a valid DNS name for TLS", host)))?;
+    let connector = if insecure {
+        INSECURE.get_or_init(|| connector(true))
+    } else {
+        VERIFYING.get_or_init(|| connector(false))
+    };
+    connector.connect(name, socket).await.map_err(|e| ErrorKind::Tls(e.to_string()).into())
+}
+
+fn connector(insecure: bool) -> TlsConnector {
+    let mut config = ClientConfig::new();
+    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if insecure {
+        config.dangerous().set_certificate_verifier(Arc::new(AcceptAny));
+    }
+    TlsConnector::from(Arc::new(config))
+}
+
+/// For lab servers with self-signed certificates, see `--insecure-tls`
+struct AcceptAny;
+
+impl ServerCertVerifier for AcceptAny {
+    fn verify_server_cert(&self,
+                          _roots: &RootCertStore,
+                          _presented_certs: &[Certificate],
+                          _dns_name: DNSNameRef,
+                          _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError>
+    {
+        Ok(ServerCertVerified::assertion())
+    }
+}
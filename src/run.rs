@@ -0,0 +1,378 @@
+//! Embedding the bench in another program:
+//!
+//! ```no_run
+//! let run = waterfall::PublishRun::builder()
+//!     .input("input.flv")
+//!     .prefix("rtmp://localhost:1935/test/stream-")
+//!     .concurrency(100)
+//!     .spawn()?;
+//! std::thread::sleep(std::time::Duration::from_secs(60));
+//! run.shutdown();
+//! let report = run.join()?;
+//! println!("{} publishing", report.summary.publishing);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use pin_utils::pin_mut;
+use slog::{error, info, o, warn, Logger};
+
+use crate::load::{self, Profile, RampUp};
+use crate::report::Report;
+use crate::rtmp_url::{self, Url};
+use crate::shutdown::Shutdown;
+use crate::stats::Stats;
+use crate::verify::Ledger;
+use crate::{control, fanout, flv, publish, rtmp, transmit, watch, PacketType};
+
+/// The destination of a line added by `--watch` or the control API
+pub type LineParser = Arc<dyn Fn(&str) -> Result<Url, String> + Send + Sync>;
+
+/// A publish run like `waterfall publish`, see `PublishRun::builder`
+pub struct PublishRun {
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl PublishRun {
+    pub fn builder() -> PublishRunBuilder {
+        PublishRunBuilder::default()
+    }
+
+    /// The live counters of every client
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Stop broadcasting, the clients unpublish and the run ends
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Wait for the end of the input or a shutdown, then the final report
+    pub fn join(self) -> io::Result<Report> {
+        self.thread.join().map_err(|_| io::Error::new(io::ErrorKind::Other, "publish run panicked"))??;
+        Ok(self.stats.report(true))
+    }
+}
+
+/// The options of `waterfall publish` which make sense without a terminal, `waterfall publish`
+/// itself runs through `PublishRunBuilder::run`
+#[derive(Clone)]
+pub struct PublishRunBuilder {
+    inputs: Vec<String>,
+    destinations: Vec<String>,
+    targets: Vec<publish::Destination>,
+    prefix: Option<String>,
+    concurrency: usize,
+    round_robin_inputs: bool,
+    idle_sessions: usize,
+    idle_only: bool,
+    profile: Option<Profile>,
+    watch: Option<(PathBuf, LineParser)>,
+    control: Option<(SocketAddr, LineParser)>,
+    verify: Option<Arc<Ledger>>,
+    read_options: flv::ReadOptions,
+    client_config: rtmp::client::Config,
+    desync: bool,
+    ramp_up: Option<RampUp>,
+    min_success: Option<f64>,
+    start_immediately: bool,
+    ignore_connect_errors: bool,
+    queue_size: usize,
+    drop_policy: fanout::DropPolicy,
+    logger: Option<Logger>,
+}
+
+impl Default for PublishRunBuilder {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            destinations: Vec::new(),
+            targets: Vec::new(),
+            prefix: None,
+            concurrency: 1,
+            round_robin_inputs: false,
+            idle_sessions: 0,
+            idle_only: false,
+            profile: None,
+            watch: None,
+            control: None,
+            verify: None,
+            read_options: flv::ReadOptions::default(),
+            client_config: rtmp::client::Config { reconnect_backoff: Duration::from_secs(1), ..Default::default() },
+            desync: false,
+            ramp_up: None,
+            min_success: None,
+            start_immediately: false,
+            ignore_connect_errors: false,
+            queue_size: fanout::DEFAULT_QUEUE_SIZE,
+            drop_policy: fanout::DropPolicy::default(),
+            logger: None,
+        }
+    }
+}
+
+impl PublishRunBuilder {
+    /// Anything `--input` takes, given more than once the inputs are played back-to-back
+    pub fn input<S: Into<String>>(mut self, input: S) -> Self {
+        self.inputs.push(input.into());
+        self
+    }
+
+    /// Destination urls, in place of `prefix` and `concurrency`
+    pub fn destinations<I, S>(mut self, urls: I) -> Self
+        where I: IntoIterator<Item = S>, S: Into<String>,
+    {
+        self.destinations.extend(urls.into_iter().map(Into::into));
+        self
+    }
+
+    /// A destination with a config, a delay or inputs of its own, after those of `destinations`.
+    /// Without inputs it publishes those of the run
+    pub fn destination(mut self, destination: publish::Destination) -> Self {
+        self.targets.push(destination);
+        self
+    }
+
+    /// Publish to `concurrency` destinations, the prefix followed by `0`, `1`, ... or a pattern, see `rtmp_url::expand_template`
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    pub fn read_options(mut self, options: flv::ReadOptions) -> Self {
+        self.read_options = options;
+        self
+    }
+
+    pub fn client_config(mut self, config: rtmp::client::Config) -> Self {
+        self.client_config = config;
+        self
+    }
+
+    /// `--round-robin-inputs`
+    pub fn round_robin_inputs(mut self, round_robin: bool) -> Self {
+        self.round_robin_inputs = round_robin;
+        self
+    }
+
+    /// `--idle-sessions` and `--idle-only`
+    pub fn idle_sessions(mut self, sessions: usize, only: bool) -> Self {
+        self.idle_sessions = sessions;
+        self.idle_only = only;
+        self
+    }
+
+    /// `--adaptive`, `--profile` or `--steps`, in place of publishing to every destination at once
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// `--watch`: publish to the destinations added to the list file at `path`
+    pub fn watch(mut self, path: PathBuf, parse: LineParser) -> Self {
+        self.watch = Some((path, parse));
+        self
+    }
+
+    /// `--control-listen`
+    pub fn control(mut self, addr: SocketAddr, parse: LineParser) -> Self {
+        self.control = Some((addr, parse));
+        self
+    }
+
+    /// `--verify`: the publisher of the first destination records what it sends
+    pub fn verify(mut self, ledger: Arc<Ledger>) -> Self {
+        self.verify = Some(ledger);
+        self
+    }
+
+    /// `--desync`
+    pub fn desync(mut self, desync: bool) -> Self {
+        self.desync = desync;
+        self
+    }
+
+    pub fn ramp_up(mut self, ramp_up: RampUp) -> Self {
+        self.ramp_up = Some(ramp_up);
+        self
+    }
+
+    /// `--min-success` and `--ignore-connect-errors`
+    pub fn min_success(mut self, share: f64, ignore_connect_errors: bool) -> Self {
+        self.min_success = Some(share);
+        self.ignore_connect_errors = ignore_connect_errors;
+        self
+    }
+
+    /// `--start-immediately`
+    pub fn start_immediately(mut self, start_immediately: bool) -> Self {
+        self.start_immediately = start_immediately;
+        self
+    }
+
+    /// `--queue-size` and `--drop-policy`
+    pub fn queue(mut self, size: usize, policy: fanout::DropPolicy) -> Self {
+        self.queue_size = size;
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Discarded by default, `run` takes its own
+    pub fn logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Start the run on its own runtime thread.
+    pub fn spawn(self) -> io::Result<PublishRun> {
+        let logger = self.logger.clone().unwrap_or_else(|| Logger::root(slog::Discard, o!()));
+        let (inputs, destinations) = self.resolve(&logger)?;
+        let stats = Arc::new(Stats::new());
+        let shutdown = Shutdown::new();
+        let (run_stats, run_shutdown) = (stats.clone(), shutdown.clone());
+        let thread = thread::Builder::new()
+            .name("publish-run".to_owned())
+            .spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new()
+                    .threaded_scheduler()
+                    .enable_all()
+                    .build()?;
+                runtime.block_on(self.execute(inputs, destinations, &run_stats, &run_shutdown, &logger))
+            })?;
+        Ok(PublishRun { stats, shutdown, thread })
+    }
+
+    /// Run on the current runtime until the end of the input or `shutdown`, and the teardown of the
+    /// clients, with `stats` for the counters. Fails with `NotConnected` when fewer than
+    /// `min_success` of the destinations publish, after the teardown of those which do.
+    pub async fn run(self, stats: &Arc<Stats>, shutdown: &Shutdown, logger: &Logger) -> io::Result<()> {
+        let (inputs, destinations) = self.resolve(logger)?;
+        self.execute(inputs, destinations, stats, shutdown, logger).await
+    }
+
+    /// The inputs and the destinations, those without inputs of their own take the run's
+    fn resolve(&self, logger: &Logger) -> io::Result<(Vec<String>, Vec<publish::Destination>)> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let inputs = flv::inputs(self.inputs.iter().map(String::as_str))?;
+        let urls: Vec<String> = match self.prefix {
+            Some(ref prefix) => (0..self.concurrency)
+                .map(|c| rtmp_url::expand_template(prefix, c))
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?,
+            None => self.destinations.clone(),
+        };
+        let mut destinations = urls.iter()
+            .map(|u| rtmp_url::parse_rtmp_url(u, None).map(|url| publish::Destination::new(url, Vec::new())))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        destinations.extend(self.targets.iter().cloned());
+        transmit::check(destinations.iter().map(|d| &d.url), &self.client_config, logger)
+            .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+        Ok((inputs, destinations))
+    }
+
+    async fn execute(self,
+                     inputs: Vec<String>,
+                     mut destinations: Vec<publish::Destination>,
+                     stats: &Arc<Stats>,
+                     shutdown: &Shutdown,
+                     logger: &Logger) -> io::Result<()>
+    {
+        let client_config = Arc::new(self.client_config);
+        let tx = fanout::channel(self.queue_size, self.drop_policy);
+        if self.idle_sessions > 0 && !destinations.is_empty() {
+            let idle_config = Arc::new(rtmp::client::Config { idle: true, ..(*client_config).clone() });
+            info!(logger, "Open idle sessions"; "sessions" => self.idle_sessions);
+            for i in 0..self.idle_sessions {
+                let url = destinations[i % destinations.len()].url.clone();
+                let client_stats = stats.register(format!("{} (idle)", url));
+                let (rx, config, logger) = (tx.subscribe(), idle_config.clone(), logger.clone());
+                tokio::spawn(async move {
+                    rtmp::client::Client::new(url, rx, Vec::new(), config, client_stats, &logger).await;
+                });
+            }
+        }
+        if self.idle_only {
+            destinations.clear();
+        }
+
+        // Loaded and preloaded inputs of the run, released when it ends
+        let cache = flv::Cache::default();
+        let published = if let Some(profile) = self.profile {
+            // Publishers join mid-stream, they need the headers they have not seen. The cache also
+            // keeps the broadcast going before the first publisher subscribes
+            let headers = Arc::new(Mutex::new(rtmp::client::HeaderCache::default()));
+            tokio::spawn(load::cache_headers(tx.subscribe(), headers.clone()));
+            let msgs = publish::open_input(&inputs, self.read_options, &cache, client_config.insecure_tls, logger.clone()).await?;
+            if destinations.iter().any(|d| d.config.is_some() || !d.inputs.is_empty() || d.delay > Duration::default()) {
+                warn!(logger, "Options of the destination list are ignored with a load profile");
+            }
+            let urls = destinations.into_iter().map(|d| d.url).collect();
+            let spawner = load::Spawner::new(urls, tx.clone(), headers, client_config, stats.clone(), logger.clone());
+            let profile = load::run(profile, spawner, stats.clone(), shutdown.clone(), logger.clone());
+            let end_tx = tx.clone();
+            let broadcasting = publish::broadcast(msgs, tx, stats.clone(), shutdown.clone(), logger.clone());
+            pin_mut!(profile, broadcasting);
+            if let Either::Left(_) = future::select(profile, broadcasting).await {
+                let _ = end_tx.send(Arc::new(PacketType::End));
+            }
+            Ok(())
+        } else {
+            if let Some((path, parse)) = self.watch {
+                let headers = Arc::new(Mutex::new(rtmp::client::HeaderCache::default()));
+                let spawner = load::Spawner::new(Vec::new(), tx.clone(), headers.clone(), client_config.clone(), stats.clone(), logger.clone());
+                let parse = move |line: &str| parse(line);
+                tokio::spawn(watch::run(path, spawner, tx.subscribe(), headers, parse, shutdown.clone(), logger.clone()));
+            }
+            if let Some((addr, parse)) = self.control {
+                let headers = Arc::new(Mutex::new(rtmp::client::HeaderCache::default()));
+                tokio::spawn(load::cache_headers(tx.subscribe(), headers.clone()));
+                let spawner = load::Spawner::new(Vec::new(), tx.clone(), headers, client_config.clone(), stats.clone(), logger.clone());
+                let parse = move |line: &str| parse(line);
+                let (stats, shutdown, logger) = (stats.clone(), shutdown.clone(), logger.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = control::serve(addr, spawner, parse, stats, shutdown, logger.clone()).await {
+                        error!(logger, "Control API error"; "error" => %e);
+                    }
+                });
+            }
+            for (i, destination) in destinations.iter_mut().enumerate() {
+                if destination.inputs.is_empty() {
+                    destination.inputs = if self.round_robin_inputs { vec![inputs[i % inputs.len()].clone()] } else { inputs.clone() };
+                }
+            }
+            // Only the publisher of the destination the companion plays is verified
+            if let (Some(ledger), Some(first)) = (self.verify, destinations.first_mut()) {
+                let base = first.config.take().unwrap_or_else(|| client_config.clone());
+                first.config = Some(Arc::new(rtmp::client::Config { verify: Some(ledger), ..(*base).clone() }));
+            }
+            let published = publish::publish_all(destinations, tx, self.desync, self.ramp_up, self.min_success, self.start_immediately,
+                                                 self.ignore_connect_errors, &inputs, self.read_options, &cache, client_config, stats, shutdown, logger).await;
+            if let Err(ref e) = published {
+                if e.kind() == io::ErrorKind::NotConnected {
+                    // Still report the clients which did connect
+                    error!(logger, "Run aborted"; "error" => %e);
+                    shutdown.trigger();
+                }
+            }
+            published
+        };
+        publish::wait_teardown(stats, publish::TEARDOWN_TIMEOUT).await;
+        published
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::Duration;
+
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+};
+use slog::{warn, Logger};
+use tokio::{signal, time::Instant};
+
+/// A cloneable run-wide shutdown switch, which also holds the broadcast while paused.
+///
+/// Triggering is idempotent and may happen from any thread (including a panic hook).
+#[derive(Clone)]
+pub struct Shutdown {
+    triggered: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    trigger: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    signal: Shared<oneshot::Receiver<()>>,
+    /// The end of a run of `--duration`
+    deadline: Option<Instant>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, rx) = oneshot::channel();
+        Self {
+            triggered: Arc::new(AtomicBool::new(false)),
+            paused: Arc::new(AtomicBool::new(false)),
+            trigger: Arc::new(Mutex::new(Some(tx))),
+            signal: rx.shared(),
+            deadline: None,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// For a run of `duration` from now, those waiting for `deadline` trigger it
+    pub fn with_duration(duration: Duration) -> Self {
+        Self { deadline: Some(Instant::now() + duration), ..Self::default() }
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        // A poisoned lock still holds a usable sender
+        let mut trigger = match self.trigger.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(tx) = trigger.take() {
+            let _ = tx.send(());
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Stop sending the broadcast, the sessions stay open
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once `trigger` has been called.
+    pub async fn wait(&self) {
+        let _ = self.signal.clone().await;
+    }
+
+    /// Resolves at the end of the run of `with_duration`, never without one
+    pub async fn deadline(&self) {
+        match self.deadline {
+            Some(deadline) => tokio::time::delay_until(deadline).await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+/// Trigger on SIGINT or SIGTERM, so the clients unpublish before the summary. A second signal
+/// exits right away.
+pub async fn on_signals(shutdown: Shutdown, logger: Logger) {
+    #[cfg(unix)]
+    let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!(logger, "Cannot listen for SIGTERM"; "error" => %e);
+            return;
+        }
+    };
+    loop {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+        #[cfg(not(unix))]
+        let _ = signal::ctrl_c().await;
+
+        if shutdown.is_triggered() {
+            warn!(logger, "Signal received again, exit");
+            std::process::exit(130);
+        }
+        warn!(logger, "Signal received, stop broadcasting and close the sessions");
+        shutdown.trigger();
+    }
+}
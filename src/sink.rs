@@ -0,0 +1,121 @@
+//! `waterfall sink`: an RTMP server accepting any publisher, discarding the media and reporting
+//! what every stream sent, a known-good target to calibrate the publishers against.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Serialize;
+use slog::{info, o, warn, Logger};
+use tokio::net::TcpListener;
+
+use crate::panic_hook;
+use crate::report::Percentiles;
+use crate::rtmp::server::{self, Counters, IngestReport};
+use crate::shutdown::Shutdown;
+
+#[derive(Debug, Serialize)]
+pub struct SinkReport {
+    pub sessions: usize,
+    /// Sessions which got as far as the publish command
+    pub published: usize,
+    pub failed: usize,
+    pub bytes_received: u64,
+    pub publish_ms: Option<Percentiles>,
+    pub bitrate_bps: Option<Percentiles>,
+    pub per_stream: Vec<IngestReport>,
+}
+
+/// Accept publishers on `addr` until `shutdown` triggers, logging the totals every `interval`.
+pub async fn run(addr: SocketAddr, interval: Duration, shutdown: Shutdown, logger: &Logger) -> io::Result<SinkReport> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!(logger, "Sink listening"; "addr" => %addr);
+    let counters = Arc::new(Counters::default());
+    let (logging, logged) = {
+        let (counters, logger) = (counters.clone(), logger.clone());
+        futures::future::abortable(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            let mut last_bytes = 0;
+            loop {
+                ticks.tick().await;
+                let bytes = counters.bytes_received.load(Ordering::Relaxed);
+                info!(logger, "Sink totals"; "sessions" => counters.sessions.load(Ordering::Relaxed),
+                      "publishing" => counters.publishing.load(Ordering::Relaxed), "bytes_received" => bytes,
+                      "bitrate_bps" => (bytes - last_bytes) * 8 / interval.as_secs().max(1));
+                last_bytes = bytes;
+            }
+        })
+    };
+    tokio::spawn(logging);
+
+    let mut sessions = FuturesUnordered::new();
+    let mut reports = Vec::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    let _ = socket.set_nodelay(true);
+                    let logger = logger.new(o!("peer" => peer.to_string()));
+                    let session = server::session(socket, peer, counters.clone(), shutdown.clone(), logger);
+                    sessions.push(tokio::spawn(panic_hook::scope(format!("sink {}", peer), session)));
+                }
+                // Out of file descriptors and the like, the sessions open go on
+                Err(e) => warn!(logger, "Accept error"; "error" => %e),
+            },
+            Some(report) = sessions.next(), if !sessions.is_empty() => {
+                if let Ok(report) = report {
+                    reports.push(report);
+                }
+            }
+            _ = shutdown.wait() => break,
+        }
+    }
+    // The sessions see the shutdown as well
+    while let Some(report) = sessions.next().await {
+        if let Ok(report) = report {
+            reports.push(report);
+        }
+    }
+    logged.abort();
+    Ok(summarize(reports))
+}
+
+fn summarize(mut reports: Vec<IngestReport>) -> SinkReport {
+    reports.sort_by(|a, b| (&a.app, &a.stream).cmp(&(&b.app, &b.stream)));
+    let published = reports.iter().filter(|r| r.publish_ms.is_some()).collect::<Vec<_>>();
+    SinkReport {
+        sessions: reports.len(),
+        published: published.len(),
+        failed: reports.iter().filter(|r| r.error.is_some()).count(),
+        bytes_received: reports.iter().map(|r| r.bytes_received).sum(),
+        publish_ms: Percentiles::from_values(published.iter().filter_map(|r| r.publish_ms).collect()),
+        bitrate_bps: Percentiles::from_values(published.iter().map(|r| r.bitrate_bps).collect()),
+        per_stream: reports,
+    }
+}
+
+pub fn print(report: &SinkReport) {
+    println!("sessions {}, published {}, failed {}, bytes received {}",
+             report.sessions, report.published, report.failed, report.bytes_received);
+    println!("{:<16} {:>10} {:>10} {:>10} {:>10} {:>10}", "", "min", "p50", "p90", "p99", "max");
+    for (name, p) in &[("publish_ms", report.publish_ms), ("bitrate_bps", report.bitrate_bps)] {
+        match p {
+            Some(p) => println!("{:<16} {:>10} {:>10} {:>10} {:>10} {:>10}", name, p.min, p.p50, p.p90, p.p99, p.max),
+            None => println!("{:<16} {:>10}", name, "-"),
+        }
+    }
+    for r in report.per_stream.iter().filter(|r| r.error.is_some()) {
+        println!("{} {}/{}  {}", r.peer, r.app, r.stream, r.error.as_ref().unwrap());
+    }
+}
+
+pub fn write_json(report: &SinkReport, path: &Path) -> io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer_pretty(file, report)?;
+    Ok(())
+}
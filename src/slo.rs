@@ -0,0 +1,153 @@
+//! Assertions on the final report, like `p99_connect_ms<500` or `error_rate<1%`.
+
+use std::fmt;
+
+use crate::report::{AssertionReport, Report};
+use crate::units;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+pub struct Assertion {
+    metric: String,
+    op: Op,
+    threshold: f64,
+    /// Where the threshold comes from, if not from the command line
+    origin: Option<String>,
+}
+
+/// Names accepted on the left hand side of an assertion
+pub const METRICS: &[&str] = &[
+    "min_connect_ms", "p50_connect_ms", "p90_connect_ms", "p99_connect_ms", "max_connect_ms",
+    "error_rate", "failed", "publishing", "clients", "bytes_sent", "throughput_bps", "never_acked", "erratic_acks", "unacked_alarms",
+    "cadence_deviations", "write_stalled", "lagged", "dropped_messages", "p50_latency_ms", "p99_latency_ms",
+    "missing_frames", "corrupted_frames", "reordered_frames", "audio_gaps", "audio_overlaps",
+];
+
+/// Parse `<metric><op><threshold>`, ratios accept a percent sign.
+pub fn parse(s: &str) -> Result<Assertion, String> {
+    let s = s.trim();
+    let split = s.find(|c| c == '<' || c == '>').ok_or_else(|| format!("no comparison in `{}`", s))?;
+    let (metric, rest) = s.split_at(split);
+    let (op, threshold) = if let Some(t) = rest.strip_prefix("<=") {
+        (Op::Le, t)
+    } else if let Some(t) = rest.strip_prefix(">=") {
+        (Op::Ge, t)
+    } else if let Some(t) = rest.strip_prefix('<') {
+        (Op::Lt, t)
+    } else {
+        (Op::Gt, &rest[1..])
+    };
+    let metric = metric.trim();
+    if !METRICS.contains(&metric) {
+        return Err(format!("unknown metric `{}`, expected one of {}", metric, METRICS.join(", ")));
+    }
+    let threshold = units::parse_ratio(threshold)?;
+    Ok(Assertion { metric: metric.to_owned(), op, threshold, origin: None })
+}
+
+/// `Some(true)` if a higher value is better, `None` if the metric only describes the run
+fn higher_is_better(metric: &str) -> Option<bool> {
+    match metric {
+        "publishing" | "throughput_bps" => Some(true),
+        "clients" | "bytes_sent" => None,
+        _ => Some(false),
+    }
+}
+
+/// One assertion per metric of the baseline, allowing each to get worse by `tolerance`
+pub fn from_baseline(baseline: &Report, tolerance: f64) -> Vec<Assertion> {
+    METRICS.iter().filter_map(|&metric| {
+        let higher = higher_is_better(metric)?;
+        let value = self::metric(baseline, metric)?;
+        let (op, threshold) = if higher {
+            (Op::Ge, value * (1.0 - tolerance))
+        } else {
+            (Op::Le, value * (1.0 + tolerance))
+        };
+        let origin = format!("baseline {}", value);
+        Some(Assertion { metric: metric.to_owned(), op, threshold, origin: Some(origin) })
+    }).collect()
+}
+
+/// Value of a metric in the report, `None` without samples, e.g. no client ever connected
+pub fn metric(report: &Report, metric: &str) -> Option<f64> {
+    let summary = &report.summary;
+    let connect = summary.connect_ms;
+    let value = match metric {
+        "min_connect_ms" => connect?.min as f64,
+        "p50_connect_ms" => connect?.p50 as f64,
+        "p90_connect_ms" => connect?.p90 as f64,
+        "p99_connect_ms" => connect?.p99 as f64,
+        "max_connect_ms" => connect?.max as f64,
+        "error_rate" if summary.clients == 0 => return None,
+        "error_rate" => summary.failed as f64 / summary.clients as f64,
+        "failed" => summary.failed as f64,
+        "publishing" => summary.publishing as f64,
+        "clients" => summary.clients as f64,
+        "bytes_sent" => summary.bytes_sent as f64,
+        "throughput_bps" if report.duration_secs <= 0.0 => return None,
+        "throughput_bps" => summary.bytes_sent as f64 * 8.0 / report.duration_secs,
+        "never_acked" => summary.never_acked as f64,
+        "erratic_acks" => summary.erratic_acks as f64,
+        "unacked_alarms" => summary.unacked_alarms as f64,
+        "cadence_deviations" => summary.cadence_deviations as f64,
+        "write_stalled" => summary.write_stalled as f64,
+        "lagged" => summary.lagged as f64,
+        "dropped_messages" => summary.dropped_messages as f64,
+        "p50_latency_ms" => summary.latency_ms?.p50 as f64,
+        "p99_latency_ms" => summary.latency_ms?.p99 as f64,
+        "missing_frames" => report.integrity?.missing as f64,
+        "corrupted_frames" => report.integrity?.corrupted as f64,
+        "reordered_frames" => report.integrity?.reordered as f64,
+        "audio_gaps" => report.integrity?.audio_gaps as f64,
+        "audio_overlaps" => report.integrity?.audio_overlaps as f64,
+        _ => return None,
+    };
+    Some(value)
+}
+
+impl Assertion {
+    /// An assertion on a metric without samples fails
+    pub fn evaluate(&self, report: &Report) -> AssertionReport {
+        let actual = metric(report, &self.metric);
+        let passed = actual.map_or(false, |v| match self.op {
+            Op::Lt => v < self.threshold,
+            Op::Le => v <= self.threshold,
+            Op::Gt => v > self.threshold,
+            Op::Ge => v >= self.threshold,
+        });
+        AssertionReport { assertion: self.to_string(), actual, passed }
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        };
+        write!(f, "{}{}{}", self.metric, op, self.threshold)?;
+        if let Some(ref origin) = self.origin {
+            write!(f, " ({})", origin)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print every result, returns the number of failed assertions
+pub fn print(results: &[AssertionReport]) -> usize {
+    for r in results {
+        let actual = r.actual.map_or_else(|| "no samples".to_owned(), |v| v.to_string());
+        println!("{} {} (actual: {})", if r.passed { "PASS" } else { "FAIL" }, r.assertion, actual);
+    }
+    results.iter().filter(|r| !r.passed).count()
+}
@@ -0,0 +1,40 @@
+//! `srt://` destinations, sent over SRT in caller mode.
+//!
+//! Each publisher runs `srt-live-transmit` from the SRT tools, which reads the TS from its stdin
+//! and speaks SRT to the destination: the `streamid`, `passphrase`, `pbkeylen`, `latency` and the
+//! other options of the url go to it as they are.
+
+use tokio::process::Command;
+
+use crate::rtmp::client::Config;
+use crate::rtmp_url::Url;
+use crate::transmit;
+
+/// The `srt-live-transmit` of the `PATH` unless `--srt-transmit` names another
+pub const DEFAULT_TRANSMIT: &str = "srt-live-transmit";
+
+fn program(config: &Config) -> String {
+    config.srt_transmit.clone().unwrap_or_else(|| DEFAULT_TRANSMIT.to_owned())
+}
+
+/// Fails unless there is a `srt-live-transmit` to run
+pub fn check(config: &Config) -> Result<(), String> {
+    let program = program(config);
+    match transmit::find_program(&program) {
+        Some(_) => Ok(()),
+        None => Err(format!("`srt://` destinations need `{}` of the SRT tools (https://github.com/Haivision/srt), \
+                             it is not found: install them or give its path with `--srt-transmit`", program)),
+    }
+}
+
+/// The program and the command reading `chunk` bytes at a time from stdin and sending to `url`
+pub fn command(url: &Url, config: &Config, chunk: usize) -> (String, Command) {
+    let program = program(config);
+    let mut command = Command::new(&program);
+    command
+        .arg("-a:no")
+        .arg(format!("-chunk:{}", chunk))
+        .arg("file://con")
+        .arg(url.srt_target());
+    (program, command)
+}
@@ -0,0 +1,706 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering},
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::{Event, Events};
+use crate::fanout::Gauge;
+use crate::latency;
+use crate::report::{
+    AckReport, BwCheckReport, CadenceReport, CapacityReport, ClientReport, QueueReport, Report, StepReport, Summary,
+    TimelineSample, WriteReport,
+};
+use crate::shutdown::Shutdown;
+use crate::verify::Ledger;
+
+const UNSET: u64 = u64::MAX;
+
+/// `--fail-fast`: the first client error stops the whole run
+struct FailFast {
+    shutdown: Shutdown,
+    tripped: AtomicBool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientState {
+    Connecting,
+    Publishing,
+    Finished,
+    Failed,
+    /// Connected with a stream created, never publishing
+    Idle,
+}
+
+impl ClientState {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => ClientState::Connecting,
+            1 => ClientState::Publishing,
+            2 => ClientState::Finished,
+            4 => ClientState::Idle,
+            _ => ClientState::Failed,
+        }
+    }
+}
+
+/// C0C1 and C2
+const HANDSHAKE_BYTES: u64 = 1 + 1536 * 2;
+
+/// Acknowledgements of the current connection
+#[derive(Default)]
+struct Acks {
+    /// `bytes_sent` when the connection was established, acks count from there
+    base: u64,
+    count: u64,
+    last: Option<u64>,
+    window_min: Option<u64>,
+    window_max: Option<u64>,
+    window_total: u64,
+    windows: u64,
+    anomalies: u64,
+    /// Times the unacknowledged bytes went over `--unacked-alarm`
+    alarms: u64,
+}
+
+const CADENCE_WINDOW: Duration = Duration::from_secs(1);
+/// Deviation of the achieved frame rate from the nominal one which counts a window as off
+const CADENCE_TOLERANCE: f64 = 0.1;
+/// Consecutive windows off the nominal rate for a deviation to count as sustained
+pub const SUSTAINED_WINDOWS: u64 = 3;
+
+/// A single write waiting at least this long for the socket counts as a stall
+pub const WRITE_STALL: Duration = Duration::from_secs(1);
+
+/// Video frames sent per wall clock window, against the frame rate of the input
+#[derive(Default)]
+struct Cadence {
+    nominal_fps: Option<f64>,
+    window_start: Option<Instant>,
+    window_frames: u64,
+    window_first_ts: u32,
+    window_last_ts: u32,
+    windows: u64,
+    deviating_windows: u64,
+    current_run: u64,
+    longest_run: u64,
+    min_fps: Option<f64>,
+    max_fps: Option<f64>,
+}
+
+/// A window closed off the nominal frame rate
+pub struct CadenceDeviation {
+    pub achieved_fps: f64,
+    pub nominal_fps: f64,
+    /// Consecutive windows off so far
+    pub run: u64,
+}
+
+/// Outcome of checking an acknowledgement against what was sent
+pub enum AckCheck {
+    Ok,
+    /// More bytes acknowledged than sent on this connection
+    Overacked { acked: u64, sent: u64 },
+    /// Sequence number went backwards
+    Backwards { acked: u64, last: u64 },
+}
+
+/// Counters of a single publish client, updated lock-free from the client tasks.
+pub struct ClientStats {
+    url: String,
+    events: Option<Arc<Events>>,
+    fail_fast: Option<Arc<FailFast>>,
+    started: Instant,
+    state: AtomicU8,
+    connect_ms: AtomicU64,
+    ended_ms: AtomicU64,
+    bytes_sent: AtomicU64,
+    messages_sent: AtomicU64,
+    /// Messages handed to the writer and not taken by it yet
+    queued: AtomicU64,
+    /// The queue of broadcast messages of the client
+    queue: Mutex<Option<Arc<Gauge>>>,
+    /// The writer of the current connection closed the socket
+    socket_closed: AtomicBool,
+    /// Asked to unpublish and leave the run
+    stop: AtomicBool,
+    video_frames: AtomicU64,
+    reconnects: AtomicU64,
+    redirects: Mutex<Vec<String>>,
+    failovers: Mutex<Vec<String>>,
+    /// `onStatus` codes received, with their counts
+    statuses: Mutex<BTreeMap<String, u64>>,
+    codec_changes: AtomicU64,
+    /// Time spent waiting for the socket to take more data
+    write_blocked_us: AtomicU64,
+    write_stalls: AtomicU64,
+    longest_write_stall_ms: AtomicU64,
+    /// Video frames not sent while the socket held the writer back
+    dropped_frames: AtomicU64,
+    peer_addr: Mutex<Option<SocketAddr>>,
+    /// Into the input, with `--desync`
+    start_offset_ms: AtomicU64,
+    acks: Mutex<Acks>,
+    cadence: Mutex<Cadence>,
+    bw_check: Mutex<Option<BwCheckReport>>,
+    error: Mutex<Option<String>>,
+}
+
+impl ClientStats {
+    fn new(url: String, events: Option<Arc<Events>>, fail_fast: Option<Arc<FailFast>>) -> Self {
+        if let Some(ref events) = events {
+            events.emit(&Event::State { url: &url, state: ClientState::Connecting, error: None });
+        }
+        Self {
+            url,
+            events,
+            fail_fast,
+            started: Instant::now(),
+            state: AtomicU8::new(ClientState::Connecting as u8),
+            connect_ms: AtomicU64::new(UNSET),
+            ended_ms: AtomicU64::new(UNSET),
+            bytes_sent: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            queue: Mutex::new(None),
+            socket_closed: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            video_frames: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            redirects: Mutex::new(Vec::new()),
+            failovers: Mutex::new(Vec::new()),
+            statuses: Mutex::new(BTreeMap::new()),
+            codec_changes: AtomicU64::new(0),
+            write_blocked_us: AtomicU64::new(0),
+            write_stalls: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            longest_write_stall_ms: AtomicU64::new(0),
+            peer_addr: Mutex::new(None),
+            start_offset_ms: AtomicU64::new(UNSET),
+            acks: Mutex::new(Acks::default()),
+            cadence: Mutex::new(Cadence::default()),
+            bw_check: Mutex::new(None),
+            error: Mutex::new(None),
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        ClientState::from_u8(self.state.load(Ordering::SeqCst))
+    }
+
+    pub fn set_state(&self, state: ClientState) {
+        let last = ClientState::from_u8(self.state.swap(state as u8, Ordering::SeqCst));
+        if let Some(ref events) = self.events {
+            if last != state {
+                let error = if state == ClientState::Failed { self.error.lock().ok().and_then(|e| e.clone()) } else { None };
+                events.emit(&Event::State { url: &self.url, state, error: error.as_deref() });
+            }
+        }
+        if let ClientState::Finished | ClientState::Failed = state {
+            self.ended_ms.store(self.elapsed_ms(), Ordering::SeqCst);
+        }
+    }
+
+    /// Publish accepted by the server
+    pub fn set_publishing(&self) {
+        let _ = self.connect_ms.compare_exchange(UNSET, self.elapsed_ms(), Ordering::SeqCst, Ordering::SeqCst);
+        self.set_state(ClientState::Publishing);
+    }
+
+    /// An idle session is established, it never publishes
+    pub fn set_idle(&self) {
+        self.set_state(ClientState::Idle);
+    }
+
+    /// Only the first error is kept, it is usually the cause of the following ones
+    pub fn fail<E: ToString>(&self, error: E) {
+        if let Ok(mut e) = self.error.lock() {
+            if e.is_none() {
+                *e = Some(error.to_string());
+            }
+        }
+        self.set_state(ClientState::Failed);
+        if let Some(ref fail_fast) = self.fail_fast {
+            if !fail_fast.tripped.swap(true, Ordering::SeqCst) {
+                fail_fast.shutdown.trigger();
+            }
+        }
+    }
+
+    /// The address actually connected to, which may differ between clients behind round-robin DNS
+    pub fn set_peer_addr(&self, addr: SocketAddr) {
+        if let Ok(mut peer_addr) = self.peer_addr.lock() {
+            *peer_addr = Some(addr);
+        }
+    }
+
+    /// The client plays its own copy of the input from the first keyframe after this offset
+    pub fn set_start_offset(&self, offset_ms: u32) {
+        self.start_offset_ms.store(u64::from(offset_ms), Ordering::Relaxed);
+    }
+
+    /// Acknowledgements count bytes from the start of each connection, and the time spent
+    /// reconnecting is no frame rate deviation.
+    pub fn begin_connection(&self) {
+        self.socket_closed.store(false, Ordering::SeqCst);
+        if let Ok(mut acks) = self.acks.lock() {
+            acks.base = self.bytes_sent();
+            acks.last = None;
+        }
+        if let Ok(mut cadence) = self.cadence.lock() {
+            cadence.window_start = None;
+        }
+    }
+
+    /// Record an Acknowledgement, `bytes_received` is the server side 32 bit sequence number
+    pub fn add_ack(&self, bytes_received: u32) -> AckCheck {
+        let sent = self.bytes_sent();
+        let mut acks = match self.acks.lock() {
+            Ok(acks) => acks,
+            Err(_) => return AckCheck::Ok,
+        };
+        let sent = sent - acks.base;
+        acks.count += 1;
+        // Unwrap the sequence number, assuming less than 4GiB between two acks
+        let acked = match acks.last {
+            Some(last) => {
+                let low = last & 0xffff_ffff;
+                let high = last & !0xffff_ffff;
+                let candidate = high | bytes_received as u64;
+                if (bytes_received as u64) < low && low - bytes_received as u64 > 1 << 31 {
+                    candidate + (1 << 32)
+                } else {
+                    candidate
+                }
+            }
+            None => bytes_received as u64,
+        };
+
+        let check = match acks.last {
+            Some(last) if acked < last => AckCheck::Backwards { acked, last },
+            // Some servers count the handshake too
+            _ if acked > sent + HANDSHAKE_BYTES => AckCheck::Overacked { acked, sent },
+            _ => AckCheck::Ok,
+        };
+        match check {
+            AckCheck::Ok => {
+                if let Some(last) = acks.last {
+                    let window = acked - last;
+                    acks.window_min = Some(acks.window_min.map_or(window, |m| m.min(window)));
+                    acks.window_max = Some(acks.window_max.map_or(window, |m| m.max(window)));
+                    acks.window_total += window;
+                    acks.windows += 1;
+                }
+                acks.last = Some(acked);
+            }
+            AckCheck::Overacked { .. } => {
+                acks.anomalies += 1;
+                acks.last = Some(acked);
+            }
+            AckCheck::Backwards { .. } => {
+                acks.anomalies += 1;
+            }
+        }
+        check
+    }
+
+    /// Frame rate declared by the input metadata
+    pub fn set_nominal_fps(&self, fps: f64) {
+        if let Ok(mut cadence) = self.cadence.lock() {
+            cadence.nominal_fps = Some(fps).filter(|fps| *fps > 0.0);
+        }
+    }
+
+    /// A video frame with timestamp `ts` was sent, returns a deviation when this closes a window
+    /// off the nominal frame rate. Without metadata the nominal rate follows from the timestamps.
+    pub fn add_video_frame(&self, ts: u32) -> Option<CadenceDeviation> {
+        self.video_frames.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut c = self.cadence.lock().ok()?;
+        let start = match c.window_start {
+            Some(start) => start,
+            None => {
+                c.window_start = Some(now);
+                c.window_frames = 0;
+                c.window_first_ts = ts;
+                now
+            }
+        };
+        c.window_frames += 1;
+        c.window_last_ts = ts;
+        let elapsed = now.duration_since(start);
+        if elapsed < CADENCE_WINDOW {
+            return None;
+        }
+
+        c.window_start = None;
+        let achieved_fps = c.window_frames as f64 / elapsed.as_secs_f64();
+        let media_ms = c.window_last_ts.wrapping_sub(c.window_first_ts);
+        let nominal_fps = match c.nominal_fps {
+            Some(fps) => fps,
+            None if media_ms > 0 => (c.window_frames - 1) as f64 * 1000.0 / media_ms as f64,
+            None => return None,
+        };
+        c.windows += 1;
+        c.min_fps = Some(c.min_fps.map_or(achieved_fps, |m| m.min(achieved_fps)));
+        c.max_fps = Some(c.max_fps.map_or(achieved_fps, |m| m.max(achieved_fps)));
+        if (achieved_fps - nominal_fps).abs() / nominal_fps <= CADENCE_TOLERANCE {
+            c.current_run = 0;
+            return None;
+        }
+        c.deviating_windows += 1;
+        c.current_run += 1;
+        c.longest_run = c.longest_run.max(c.current_run);
+        Some(CadenceDeviation { achieved_fps, nominal_fps, run: c.current_run })
+    }
+
+    fn cadence_report(&self) -> CadenceReport {
+        match self.cadence.lock() {
+            Ok(c) => CadenceReport {
+                nominal_fps: c.nominal_fps,
+                windows: c.windows,
+                deviating_windows: c.deviating_windows,
+                longest_deviation_windows: c.longest_run,
+                min_fps: c.min_fps,
+                max_fps: c.max_fps,
+            },
+            Err(_) => CadenceReport::default(),
+        }
+    }
+
+    pub fn set_bw_check(&self, bw_check: BwCheckReport) {
+        if let Ok(mut last) = self.bw_check.lock() {
+            *last = Some(bw_check);
+        }
+    }
+
+    fn ack_report(&self) -> AckReport {
+        let sent = self.bytes_sent();
+        match self.acks.lock() {
+            Ok(acks) => AckReport {
+                acks: acks.count,
+                window_min: acks.window_min,
+                window_max: acks.window_max,
+                window_avg: if acks.windows > 0 { Some(acks.window_total / acks.windows) } else { None },
+                anomalies: acks.anomalies,
+                unacked_bytes: (sent - acks.base).saturating_sub(acks.last.unwrap_or(0)),
+                alarms: acks.alarms,
+            },
+            Err(_) => AckReport::default(),
+        }
+    }
+
+    /// Bytes sent on the current connection beyond the last acknowledgement
+    pub fn unacked_bytes(&self) -> u64 {
+        let sent = self.bytes_sent();
+        self.acks.lock().map(|acks| (sent - acks.base).saturating_sub(acks.last.unwrap_or(0))).unwrap_or(0)
+    }
+
+    pub fn add_unacked_alarm(&self) {
+        if let Ok(mut acks) = self.acks.lock() {
+            acks.alarms += 1;
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn video_frames(&self) -> u64 {
+        self.video_frames.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_socket_closed(&self) {
+        self.socket_closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn socket_closed(&self) -> bool {
+        self.socket_closed.load(Ordering::SeqCst)
+    }
+
+    /// The client tears its session down on the next broadcast packet and finishes
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    pub fn add_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The writer took a queued message
+    pub fn take_queued(&self) {
+        let _ = self.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |q| q.checked_sub(1));
+    }
+
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn set_queue(&self, gauge: Arc<Gauge>) {
+        if let Ok(mut queue) = self.queue.lock() {
+            *queue = Some(gauge);
+        }
+    }
+
+    pub fn queue_report(&self) -> QueueReport {
+        match self.queue.lock().ok().and_then(|q| q.clone()) {
+            Some(gauge) => QueueReport {
+                depth: gauge.depth(),
+                max_depth: gauge.max_depth(),
+                dropped: gauge.dropped(),
+            },
+            None => QueueReport::default(),
+        }
+    }
+
+    /// A write or flush to the socket took `waited`
+    pub fn add_write_wait(&self, waited: Duration) {
+        self.write_blocked_us.fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        if waited >= WRITE_STALL {
+            self.write_stalls.fetch_add(1, Ordering::Relaxed);
+            self.longest_write_stall_ms.fetch_max(waited.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// A video frame was dropped for the backpressure of the socket
+    pub fn add_dropped_frame(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The server redirected the connect to `url`
+    pub fn add_redirect(&self, url: String) {
+        if let Ok(mut redirects) = self.redirects.lock() {
+            redirects.push(url);
+        }
+    }
+
+    /// The session failed with `error` and the client moved on to the backup `to`
+    pub fn add_failover(&self, to: String, error: &str) {
+        if let Some(ref events) = self.events {
+            events.emit(&Event::Failover { url: &self.url, to: &to, error });
+        }
+        if let Ok(mut failovers) = self.failovers.lock() {
+            failovers.push(to);
+        }
+    }
+
+    /// The server sent an `onStatus` with `code`
+    pub fn add_status(&self, code: &str) {
+        if let Ok(mut statuses) = self.statuses.lock() {
+            *statuses.entry(code.to_owned()).or_default() += 1;
+        }
+    }
+
+    /// A new sequence header with different parameters was published
+    pub fn add_codec_change(&self) {
+        self.codec_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> ClientReport {
+        let ended_ms = self.ended_ms.load(Ordering::SeqCst);
+        let duration_ms = if ended_ms == UNSET { self.elapsed_ms() } else { ended_ms };
+        ClientReport {
+            url: self.url.clone(),
+            peer_addr: self.peer_addr.lock().ok().and_then(|a| a.map(|a| a.to_string())),
+            start_offset_ms: optional(self.start_offset_ms.load(Ordering::Relaxed)),
+            state: self.state(),
+            connect_ms: optional(self.connect_ms.load(Ordering::SeqCst)),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            redirects: self.redirects.lock().map(|r| r.clone()).unwrap_or_default(),
+            failovers: self.failovers.lock().map(|f| f.clone()).unwrap_or_default(),
+            statuses: self.statuses.lock().map(|s| s.clone()).unwrap_or_default(),
+            codec_changes: self.codec_changes.load(Ordering::Relaxed),
+            acks: self.ack_report(),
+            cadence: self.cadence_report(),
+            writes: WriteReport {
+                blocked_ms: self.write_blocked_us.load(Ordering::Relaxed) / 1000,
+                stalls: self.write_stalls.load(Ordering::Relaxed),
+                dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+                longest_stall_ms: self.longest_write_stall_ms.load(Ordering::Relaxed),
+            },
+            queue: self.queue_report(),
+            bw_check: self.bw_check.lock().ok().and_then(|b| b.clone()),
+            duration_secs: duration_ms as f64 / 1000.0,
+            error: self.error.lock().ok().and_then(|e| e.clone()),
+        }
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+/// Registry of all clients of a run
+pub struct Stats {
+    events: Option<Arc<Events>>,
+    fail_fast: Option<Arc<FailFast>>,
+    started: Instant,
+    started_at: u64,
+    clients: Mutex<Vec<Arc<ClientStats>>>,
+    capacity: Mutex<Option<CapacityReport>>,
+    steps: Mutex<Vec<StepReport>>,
+    timeline: Mutex<Vec<TimelineSample>>,
+    latency: Arc<latency::Recorder>,
+    verify: Mutex<Option<Arc<Ledger>>>,
+    /// Inputs which failed to read, each stopped the run
+    input_errors: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Self {
+            events: None,
+            fail_fast: None,
+            started: Instant::now(),
+            started_at,
+            clients: Mutex::new(Vec::new()),
+            capacity: Mutex::new(None),
+            steps: Mutex::new(Vec::new()),
+            timeline: Mutex::new(Vec::new()),
+            latency: Arc::new(latency::Recorder::default()),
+            verify: Mutex::new(None),
+            input_errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit the state changes of every client
+    pub fn with_events(events: Arc<Events>) -> Self {
+        Self { events: Some(events), ..Self::default() }
+    }
+
+    /// Trigger `shutdown` at the first client which fails
+    pub fn with_fail_fast(self, shutdown: Shutdown) -> Self {
+        Self { fail_fast: Some(Arc::new(FailFast { shutdown, tripped: AtomicBool::new(false) })), ..self }
+    }
+
+    /// Whether the run was stopped by `with_fail_fast`
+    pub fn failed_fast(&self) -> bool {
+        self.fail_fast.as_ref().map_or(false, |f| f.tripped.load(Ordering::SeqCst))
+    }
+
+    pub fn add_input_error(&self) {
+        self.input_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn input_errors(&self) -> u64 {
+        self.input_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn register(&self, url: String) -> Arc<ClientStats> {
+        let client = Arc::new(ClientStats::new(url, self.events.clone(), self.fail_fast.clone()));
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.push(client.clone());
+        }
+        client
+    }
+
+    /// The clients registered so far, in order
+    pub fn clients(&self) -> Vec<Arc<ClientStats>> {
+        self.clients.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    pub fn set_capacity(&self, capacity: CapacityReport) {
+        if let Ok(mut last) = self.capacity.lock() {
+            *last = Some(capacity);
+        }
+    }
+
+    pub fn add_step(&self, step: StepReport) {
+        if let Ok(mut steps) = self.steps.lock() {
+            steps.push(step);
+        }
+    }
+
+    /// The publish to play latencies of `--measure-latency`
+    pub fn latency(&self) -> Arc<latency::Recorder> {
+        self.latency.clone()
+    }
+
+    /// Report the integrity checks of `--verify`
+    pub fn set_verify(&self, ledger: Arc<Ledger>) {
+        if let Ok(mut verify) = self.verify.lock() {
+            *verify = Some(ledger);
+        }
+    }
+
+    /// Append the current counts to the timeline
+    pub fn sample(&self) {
+        let mut sample = TimelineSample {
+            elapsed_secs: self.started.elapsed().as_millis() as f64 / 1000.0,
+            ..Default::default()
+        };
+        if let Ok(clients) = self.clients.lock() {
+            for client in clients.iter() {
+                sample.clients += 1;
+                match client.state() {
+                    ClientState::Publishing => sample.publishing += 1,
+                    ClientState::Failed => sample.failed += 1,
+                    _ => (),
+                }
+                sample.bytes_sent += client.bytes_sent();
+            }
+        }
+        if let Ok(mut timeline) = self.timeline.lock() {
+            timeline.push(sample);
+        }
+    }
+
+    pub fn report(&self, finished: bool) -> Report {
+        let clients = match self.clients.lock() {
+            Ok(clients) => clients.iter().map(|c| c.report()).collect::<Vec<_>>(),
+            Err(_) => Vec::new(),
+        };
+        Report {
+            started_at: self.started_at,
+            duration_secs: self.started.elapsed().as_millis() as f64 / 1000.0,
+            finished,
+            summary: Summary { latency_ms: self.latency.percentiles(), ..Summary::from_clients(&clients) },
+            capacity: self.capacity.lock().ok().and_then(|c| c.clone()),
+            steps: self.steps.lock().map(|s| s.clone()).unwrap_or_default(),
+            assertions: Vec::new(),
+            timeline: self.timeline.lock().map(|t| t.clone()).unwrap_or_default(),
+            latency: self.latency.intervals(),
+            integrity: self.verify.lock().ok().and_then(|v| v.as_ref().map(|l| l.report())),
+            clients,
+        }
+    }
+}
+
+fn optional(v: u64) -> Option<u64> {
+    if v == UNSET { None } else { Some(v) }
+}
@@ -0,0 +1,245 @@
+//! Destinations published by a tool of their own: the broadcast remuxed to MPEG-TS and written to
+//! the stdin of `srt-live-transmit` for `srt://`, or of a GStreamer pipeline for WHIP.
+//!
+//! The TS goes in chunks of 7 packets, the payload of an SRT packet. A publisher counts as
+//! publishing once the tool takes the first chunk, and fails if it exits before the end.
+
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, FutureExt, StreamExt};
+use slog::{crit, debug, error, info, o, warn, Logger};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin};
+use tokio::sync::oneshot;
+
+use crate::fanout::{self, RecvError};
+use crate::panic_hook;
+use crate::rtmp::client::Config;
+use crate::rtmp_url::{Scheme, Url};
+use crate::stats::{ClientState, ClientStats};
+use crate::{srt, ts, whip, PacketType};
+
+/// The payload of one SRT live mode packet
+const CHUNK: usize = 7 * ts::PACKET_SIZE;
+/// How long the tool gets to send what is buffered once the broadcast ends
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether destinations of `scheme` are published by `publish` instead of an RTMP client
+pub fn is_transmitted(scheme: Scheme) -> bool {
+    matches!(scheme, Scheme::Srt | Scheme::Whip | Scheme::Whips)
+}
+
+/// Fails when the tool of one of `urls` or of their backups is missing, once up front rather than
+/// for every publisher
+pub fn check<'a>(urls: impl IntoIterator<Item = &'a Url>, config: &Config, logger: &Logger) -> Result<(), String> {
+    let (mut srt, mut whip) = (false, false);
+    for url in urls.into_iter().flat_map(|url| std::iter::once(url).chain(&url.backups)) {
+        srt |= url.scheme == Scheme::Srt;
+        whip |= matches!(url.scheme, Scheme::Whip | Scheme::Whips);
+    }
+    if srt {
+        srt::check(config)?;
+    }
+    if whip {
+        let missing = whip::check(config)?;
+        if !missing.is_empty() {
+            warn!(logger, "GStreamer cannot transcode AAC for WHIP, inputs with audio will fail"; "missing" => missing.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Where `program` is run from, as given if it is a path or the first of the `PATH`
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    let is_executable = |path: &Path| path.is_file() || (cfg!(windows) && path.with_extension("exe").is_file());
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return Some(path.to_owned()).filter(|path| is_executable(path));
+    }
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths).map(|dir| dir.join(program)).find(|path| is_executable(path))
+}
+
+/// Publish the broadcast to `url` with its tool, in a task of its own, `seed_headers` are those
+/// broadcast before `broadcast_rx` subscribed. Returns once the tool takes the stream or fails, as
+/// `rtmp::client::Client::new` does.
+pub async fn publish(url: Url,
+                     mut broadcast_rx: fanout::Receiver,
+                     seed_headers: Vec<Arc<PacketType>>,
+                     config: Arc<Config>,
+                     stats: Arc<ClientStats>,
+                     logger: &Logger)
+{
+    let logger = logger.new(o!("stream" => url.stream.clone()));
+    let url_session = url.to_string();
+    stats.set_queue(broadcast_rx.gauge());
+    let (notify_tx, notify_rx) = oneshot::channel();
+    let shards = config.shards.clone();
+    let logger_panic = logger.clone();
+    let push = async move {
+        let mut notify_tx = Some(notify_tx);
+        match send(&url, &mut broadcast_rx, &seed_headers, &config, &stats, &mut notify_tx, &logger).await {
+            Ok(()) => {
+                info!(logger, "Reading broadcast done");
+                stats.set_state(ClientState::Finished);
+            }
+            Err(e) => {
+                error!(logger, "Transmit error"; "error" => %e);
+                stats.fail(e);
+            }
+        }
+    };
+    let push = panic_hook::scope(format!("transmit {}", url_session), push);
+    let task = async move {
+        if AssertUnwindSafe(push).catch_unwind().await.is_err() {
+            crit!(logger_panic, "Publish client task panicked");
+        }
+    };
+    match shards {
+        Some(shards) => shards.spawn(task),
+        None => {
+            tokio::spawn(task);
+        }
+    }
+    // Dropped unsent once the task ends, publishing or not
+    let _ = notify_rx.await;
+}
+
+async fn send(url: &Url,
+              broadcast_rx: &mut fanout::Receiver,
+              seed_headers: &[Arc<PacketType>],
+              config: &Config,
+              stats: &ClientStats,
+              notify_tx: &mut Option<oneshot::Sender<()>>,
+              logger: &Logger) -> Result<(), String>
+{
+    if let Some(ref rate) = config.connect_rate {
+        rate.wait().await;
+    }
+    let mut muxer = ts::Muxer::default();
+    for packet in seed_headers {
+        muxer.push(packet);
+    }
+    // Started with the first TS out, the tracks of the input are known by then
+    let mut transmitter = None;
+    let mut pending = Vec::with_capacity(CHUNK * 2);
+    let (mut ended, mut warned) = (false, false);
+    while !ended {
+        if stats.stop_requested() {
+            info!(logger, "Removed from the run, stop sending");
+            break;
+        }
+        let received = tokio::select! {
+            received = broadcast_rx.recv() => received,
+            exited = exited(&mut transmitter) => return exited,
+        };
+        let packet = match received {
+            Ok(packet) => packet,
+            Err(RecvError::Closed) => break,
+            Err(RecvError::Lagged(n)) => {
+                warn!(logger, "Broadcast lagged"; "dropped" => n);
+                continue;
+            }
+            Err(e @ RecvError::Overflowed) => return Err(e.to_string()),
+        };
+        match *packet {
+            PacketType::End => ended = true,
+            PacketType::Video{ ref ts, .. } => {
+                stats.add_video_frame(ts.value);
+            }
+            _ => (),
+        }
+        pending.extend_from_slice(&muxer.push(&packet));
+        if muxer.skipped && !warned {
+            warn!(logger, "Only H.264 and AAC go into MPEG-TS, the other tracks are left out");
+            warned = true;
+        }
+        let whole = if ended { pending.len() } else { pending.len() - pending.len() % CHUNK };
+        if whole == 0 {
+            continue;
+        }
+        if transmitter.is_none() {
+            transmitter = Some(Transmitter::spawn(url, config, &muxer, logger)?);
+        }
+        let Transmitter { tool, stdin, .. } = transmitter.as_mut().ok_or("no transmitter")?;
+        stdin.write_all(&pending[..whole]).await.map_err(|e| format!("write to {} error: {}", tool, e))?;
+        stats.add_sent(whole);
+        pending.drain(..whole);
+        if let Some(notify_tx) = notify_tx.take() {
+            info!(logger, "Publishing"; "target" => %url, "tool" => tool.as_str());
+            stats.set_publishing();
+            let _ = notify_tx.send(());
+        }
+    }
+
+    let Transmitter { tool, mut child, stdin } = match transmitter {
+        Some(transmitter) => transmitter,
+        None => {
+            warn!(logger, "Nothing of the broadcast to transmit");
+            return Ok(());
+        }
+    };
+    // The end of its input ends the tool once it sent what it has
+    drop(stdin);
+    match tokio::time::timeout(DRAIN_TIMEOUT, &mut child).await {
+        Ok(Ok(status)) if !status.success() => Err(format!("{} exited with {}", tool, status)),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => {
+            warn!(logger, "Transmitter still running after the end, killed"; "tool" => &tool);
+            Ok(())
+        }
+    }
+}
+
+/// The tool publishing one destination
+struct Transmitter {
+    tool: String,
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl Transmitter {
+    /// For the tracks `muxer` has, a WHIP pipeline without a branch for a missing one
+    fn spawn(url: &Url, config: &Config, muxer: &ts::Muxer, logger: &Logger) -> Result<Self, String> {
+        let (tool, mut command) = if url.scheme == Scheme::Srt {
+            srt::command(url, config, CHUNK)
+        } else {
+            whip::command(url, config, muxer.has_video(), muxer.has_audio())
+        };
+        debug!(logger, "Start the transmitter"; "tool" => &tool, "video" => muxer.has_video(), "audio" => muxer.has_audio());
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("cannot run {}: {}", tool, e))?;
+        let stdin = child.stdin.take().ok_or("no stdin to the transmitter")?;
+        if let Some(stderr) = child.stderr.take() {
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Some(Ok(line)) = lines.next().await {
+                    debug!(logger, "Transmitter output"; "line" => line);
+                }
+            });
+        }
+        Ok(Self { tool, child, stdin })
+    }
+}
+
+/// Fails once the tool exits before the end, never before it runs
+async fn exited(transmitter: &mut Option<Transmitter>) -> Result<(), String> {
+    match transmitter {
+        Some(transmitter) => {
+            let status = (&mut transmitter.child).await.map_err(|e| e.to_string())?;
+            Err(format!("{} exited with {}", transmitter.tool, status))
+        }
+        None => future::pending().await,
+    }
+}
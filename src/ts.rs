@@ -0,0 +1,462 @@
+//! The FLV tags of the broadcast remuxed to MPEG-TS: H.264 as Annex B with the parameter sets
+//! before every keyframe, AAC with ADTS headers. Other codecs are left out.
+
+use crate::PacketType;
+
+pub const PACKET_SIZE: usize = 188;
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_AAC: u8 = 0x0f;
+const CODEC_AVC: u8 = 7;
+const SOUND_AAC: u8 = 10;
+/// The tables are repeated this often, in milliseconds of the stream
+const TABLE_INTERVAL_MS: u32 = 500;
+/// Presentation times start this much after the clock reference, in 90 kHz units
+const PTS_OFFSET: u64 = 90 * 100;
+
+#[derive(Default)]
+pub struct Muxer {
+    /// SPS and PPS in Annex B, from the AVC sequence header
+    parameter_sets: Option<Vec<u8>>,
+    nal_length_size: usize,
+    /// Profile, sampling frequency index and channel configuration of the AAC sequence header
+    aac: Option<(u8, u8, u8)>,
+    continuity: [u8; 4],
+    /// Stream time the tables were last written at
+    tables_at: Option<u32>,
+    /// A message of another codec was left out
+    pub skipped: bool,
+}
+
+impl Muxer {
+    /// Whether the H.264 sequence header came
+    pub fn has_video(&self) -> bool {
+        self.parameter_sets.is_some()
+    }
+
+    /// Whether the AAC sequence header came
+    pub fn has_audio(&self) -> bool {
+        self.aac.is_some()
+    }
+
+    /// The TS packets of one message of the broadcast, empty for those producing none
+    pub fn push(&mut self, packet: &PacketType) -> Vec<u8> {
+        let mut out = Vec::new();
+        match packet {
+            PacketType::Video{ data, ts } if data.len() > 5 => {
+                if data[0] & 0x0f != CODEC_AVC {
+                    self.skipped = true;
+                    return out;
+                }
+                match data[1] {
+                    0 => {
+                        self.avc_config(&data[5..]);
+                        return out;
+                    }
+                    // End of sequence
+                    2 => return out,
+                    _ => (),
+                }
+                let keyframe = data[0] >> 4 == 1;
+                let (dts, cts) = (ts.value, composition_time(&data[2..5]));
+                let mut es = Vec::with_capacity(data.len() + 64);
+                // Access unit delimiter
+                es.extend_from_slice(&[0, 0, 0, 1, 0x09, 0xf0]);
+                if keyframe {
+                    es.extend_from_slice(self.parameter_sets.as_deref().unwrap_or_default());
+                }
+                let mut nalus = &data[5..];
+                let size = self.nal_length_size.max(1);
+                while nalus.len() >= size {
+                    let len = nalus[..size].iter().fold(0usize, |len, b| len << 8 | *b as usize);
+                    let nalu = &nalus[size..(size + len).min(nalus.len())];
+                    es.extend_from_slice(&[0, 0, 0, 1]);
+                    es.extend_from_slice(nalu);
+                    nalus = &nalus[size + nalu.len()..];
+                }
+                self.tables(&mut out, dts, keyframe);
+                let dts = u64::from(dts) * 90 + PTS_OFFSET;
+                let pts = (dts as i64 + i64::from(cts) * 90).max(0) as u64;
+                self.pes(&mut out, VIDEO_PID, 0xe0, pts, Some(dts), &es, Some(keyframe));
+            }
+            PacketType::Audio{ data, ts } if data.len() > 2 => {
+                if data[0] >> 4 != SOUND_AAC {
+                    self.skipped = true;
+                    return out;
+                }
+                if data[1] == 0 {
+                    self.aac_config(&data[2..]);
+                    return out;
+                }
+                let (profile, frequency, channels) = match self.aac {
+                    Some(aac) => aac,
+                    None => return out,
+                };
+                let raw = &data[2..];
+                let len = raw.len() + 7;
+                let mut es = Vec::with_capacity(len);
+                es.extend_from_slice(&[
+                    0xff,
+                    0xf1,
+                    (profile << 6) | (frequency << 2) | (channels >> 2),
+                    ((channels & 3) << 6) | (len >> 11) as u8,
+                    (len >> 3) as u8,
+                    ((len & 7) << 5) as u8 | 0x1f,
+                    0xfc,
+                ]);
+                es.extend_from_slice(raw);
+                self.tables(&mut out, ts.value, false);
+                let pts = u64::from(ts.value) * 90 + PTS_OFFSET;
+                // Every AAC frame decodes on its own
+                let pcr = if self.parameter_sets.is_none() { Some(true) } else { None };
+                self.pes(&mut out, AUDIO_PID, 0xc0, pts, None, &es, pcr);
+            }
+            _ => (),
+        }
+        out
+    }
+
+    fn avc_config(&mut self, record: &[u8]) {
+        if record.len() < 7 {
+            return;
+        }
+        self.nal_length_size = usize::from(record[4] & 3) + 1;
+        let mut sets = Vec::new();
+        let mut rest = &record[5..];
+        // The SPS count in the low 5 bits, then one count of PPS
+        for mask in &[0x1f, 0xff] {
+            let count = match rest.first() {
+                Some(count) => count & mask,
+                None => break,
+            };
+            rest = &rest[1..];
+            for _ in 0..count {
+                if rest.len() < 2 {
+                    break;
+                }
+                let len = usize::from(rest[0]) << 8 | usize::from(rest[1]);
+                let set = &rest[2..(2 + len).min(rest.len())];
+                sets.extend_from_slice(&[0, 0, 0, 1]);
+                sets.extend_from_slice(set);
+                rest = &rest[2 + set.len()..];
+            }
+        }
+        self.parameter_sets = Some(sets);
+    }
+
+    fn aac_config(&mut self, config: &[u8]) {
+        if config.len() < 2 {
+            return;
+        }
+        let object_type = config[0] >> 3;
+        let frequency = ((config[0] & 7) << 1) | (config[1] >> 7);
+        let channels = (config[1] >> 3) & 0x0f;
+        self.aac = Some((object_type.saturating_sub(1) & 3, frequency, channels));
+    }
+
+    /// PAT and PMT at the start, before keyframes and every `TABLE_INTERVAL_MS`
+    fn tables(&mut self, out: &mut Vec<u8>, at: u32, keyframe: bool) {
+        let due = self.tables_at.map_or(true, |last| keyframe || at.wrapping_sub(last) >= TABLE_INTERVAL_MS);
+        if !due {
+            return;
+        }
+        self.tables_at = Some(at);
+        // Program 1
+        let pat = [0x00, 0x01, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8];
+        self.section(out, PAT_PID, 0x00, 1, &pat);
+        let pcr_pid = if self.parameter_sets.is_some() || self.aac.is_none() { VIDEO_PID } else { AUDIO_PID };
+        let mut pmt = vec![0xe0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xf0, 0x00];
+        let streams = [(self.parameter_sets.is_some(), STREAM_TYPE_H264, VIDEO_PID), (self.aac.is_some(), STREAM_TYPE_AAC, AUDIO_PID)];
+        for (_, stream_type, pid) in streams.iter().filter(|(present, _, _)| *present) {
+            pmt.extend_from_slice(&[*stream_type, 0xe0 | (pid >> 8) as u8, *pid as u8, 0xf0, 0x00]);
+        }
+        self.section(out, PMT_PID, 0x02, 1, &pmt);
+    }
+
+    /// A PSI section in a packet of its own
+    fn section(&mut self, out: &mut Vec<u8>, pid: u16, table_id: u8, id: u16, body: &[u8]) {
+        let length = 5 + body.len() + 4;
+        let mut section = vec![
+            table_id,
+            0xb0 | (length >> 8) as u8,
+            length as u8,
+            (id >> 8) as u8,
+            id as u8,
+            // Version 0, current
+            0xc1,
+            0x00,
+            0x00,
+        ];
+        section.extend_from_slice(body);
+        let crc = crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        let mut packet = [0xff; PACKET_SIZE];
+        packet[..4].copy_from_slice(&[0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | self.next_continuity(pid)]);
+        // Pointer field
+        packet[4] = 0;
+        packet[5..5 + section.len()].copy_from_slice(&section);
+        out.extend_from_slice(&packet);
+    }
+
+    /// The PES packet of a frame, with the PCR in its first TS packet when `pcr` says whether the
+    /// frame is a random access point
+    #[allow(clippy::too_many_arguments)]
+    fn pes(&mut self, out: &mut Vec<u8>, pid: u16, stream_id: u8, pts: u64, dts: Option<u64>, es: &[u8], pcr: Option<bool>) {
+        let mut header = vec![0, 0, 1, stream_id, 0, 0, 0x80];
+        match dts {
+            Some(dts) if dts != pts => {
+                header.extend_from_slice(&[0xc0, 10]);
+                header.extend_from_slice(&timestamp(0x3, pts));
+                header.extend_from_slice(&timestamp(0x1, dts));
+            }
+            _ => {
+                header.extend_from_slice(&[0x80, 5]);
+                header.extend_from_slice(&timestamp(0x2, pts));
+            }
+        }
+        // Unbounded for video, which may not fit in 16 bits
+        let length = header.len() - 6 + es.len();
+        if stream_id != 0xe0 && length <= 0xffff {
+            header[4] = (length >> 8) as u8;
+            header[5] = length as u8;
+        }
+        let mut payload = header;
+        payload.extend_from_slice(es);
+
+        let mut rest = &payload[..];
+        let mut first = true;
+        while !rest.is_empty() {
+            let mut packet = Vec::with_capacity(PACKET_SIZE);
+            let start = if first { 0x40 } else { 0 };
+            packet.extend_from_slice(&[0x47, start | (pid >> 8) as u8, pid as u8, 0]);
+            let mut adaptation = Vec::new();
+            if let (true, Some(random_access)) = (first, pcr) {
+                let base = dts.unwrap_or(pts).saturating_sub(PTS_OFFSET);
+                // The PCR flag, and the random access indicator on keyframes
+                adaptation.push(if random_access { 0x50 } else { 0x10 });
+                adaptation.extend_from_slice(&[
+                    (base >> 25) as u8,
+                    (base >> 17) as u8,
+                    (base >> 9) as u8,
+                    (base >> 1) as u8,
+                    ((base & 1) << 7) as u8 | 0x7e,
+                    0,
+                ]);
+            }
+            let room = PACKET_SIZE - 4 - if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+            let take = rest.len().min(room);
+            if take < room {
+                // Stuffing in the adaptation field
+                let stuffing = if adaptation.is_empty() { room - take - 1 } else { room - take };
+                if adaptation.is_empty() && stuffing > 0 {
+                    adaptation.push(0x00);
+                    adaptation.extend(std::iter::repeat(0xff).take(stuffing - 1));
+                } else {
+                    adaptation.extend(std::iter::repeat(0xff).take(stuffing));
+                }
+            }
+            let continuity = self.next_continuity(pid);
+            if adaptation.is_empty() && take == room {
+                packet[3] = 0x10 | continuity;
+            } else {
+                packet[3] = 0x30 | continuity;
+                packet.push(adaptation.len() as u8);
+                packet.extend_from_slice(&adaptation);
+            }
+            packet.extend_from_slice(&rest[..take]);
+            out.extend_from_slice(&packet);
+            rest = &rest[take..];
+            first = false;
+        }
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        let slot = match pid {
+            PAT_PID => 0,
+            PMT_PID => 1,
+            VIDEO_PID => 2,
+            _ => 3,
+        };
+        let counter = self.continuity[slot];
+        self.continuity[slot] = (counter + 1) & 0x0f;
+        counter
+    }
+}
+
+/// The signed 24 bit composition time offset of an AVC video tag
+fn composition_time(bytes: &[u8]) -> i32 {
+    let value = i32::from(bytes[0]) << 16 | i32::from(bytes[1]) << 8 | i32::from(bytes[2]);
+    (value << 8) >> 8
+}
+
+/// A 33 bit PTS or DTS with its 4 bit prefix and marker bits
+fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        (prefix << 4) | (((ts >> 30) & 7) as u8) << 1 | 1,
+        (ts >> 22) as u8,
+        (((ts >> 15) & 0x7f) as u8) << 1 | 1,
+        (ts >> 7) as u8,
+        ((ts & 0x7f) as u8) << 1 | 1,
+    ]
+}
+
+/// CRC-32/MPEG-2 of the PSI sections
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= u32::from(*byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use rml_rtmp::time::RtmpTimestamp;
+
+    fn video(data: &[u8], ts: u32) -> PacketType {
+        PacketType::Video { data: Bytes::from(data.to_vec()), ts: RtmpTimestamp::new(ts) }
+    }
+
+    fn audio(data: &[u8], ts: u32) -> PacketType {
+        PacketType::Audio { data: Bytes::from(data.to_vec()), ts: RtmpTimestamp::new(ts) }
+    }
+
+    /// One SPS and one PPS, 4 byte NAL unit lengths
+    const AVC_SEQUENCE_HEADER: &[u8] = &[
+        0x17, 0x00, 0, 0, 0,
+        0x01, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0x00, 0x02, 0x67, 0x64, 0x01, 0x00, 0x02, 0x68, 0xee,
+    ];
+    /// AAC LC, 44.1 kHz, stereo
+    const AAC_SEQUENCE_HEADER: &[u8] = &[0xaf, 0x00, 0x12, 0x10];
+
+    fn packets(out: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(out.len() % PACKET_SIZE, 0);
+        let packets = out.chunks(PACKET_SIZE).collect::<Vec<_>>();
+        assert!(packets.iter().all(|p| p[0] == 0x47));
+        packets
+    }
+
+    fn pid(packet: &[u8]) -> u16 {
+        u16::from(packet[1] & 0x1f) << 8 | u16::from(packet[2])
+    }
+
+    /// After the adaptation field, if any
+    fn payload(packet: &[u8]) -> &[u8] {
+        if packet[3] & 0x20 != 0 { &packet[5 + usize::from(packet[4])..] } else { &packet[4..] }
+    }
+
+    /// The first TS packet of each PES packet of `pid`
+    fn pes_starts(out: &[u8], of: u16) -> Vec<&[u8]> {
+        packets(out).into_iter().filter(|p| pid(p) == of && p[1] & 0x40 != 0).collect()
+    }
+
+    #[test]
+    fn packets_are_aligned() {
+        let mut muxer = Muxer::default();
+        assert!(muxer.push(&video(AVC_SEQUENCE_HEADER, 0)).is_empty());
+        assert!(muxer.push(&audio(AAC_SEQUENCE_HEADER, 0)).is_empty());
+        // Payloads filling the first or a later packet exactly, leaving one byte or two, or many packets
+        for (i, size) in [1, 151, 152, 153, 162, 163, 335, 336, 5000].iter().enumerate() {
+            let mut frame = vec![0x27, 0x01, 0, 0, 0];
+            frame.extend_from_slice(&(*size as u32).to_be_bytes());
+            frame.extend(std::iter::repeat(0x41).take(*size));
+            let out = muxer.push(&video(&frame, i as u32 * 40));
+            let mut continuity = None;
+            for packet in packets(&out).into_iter().filter(|p| pid(p) == VIDEO_PID) {
+                if let Some(previous) = continuity {
+                    assert_eq!(packet[3] & 0x0f, (previous + 1) & 0x0f);
+                }
+                continuity = Some(packet[3] & 0x0f);
+            }
+            let mut raw = vec![0xaf, 0x01];
+            raw.extend(std::iter::repeat(0x21).take(*size));
+            packets(&muxer.push(&audio(&raw, i as u32 * 23)));
+        }
+    }
+
+    #[test]
+    fn tables_have_their_crc() {
+        let mut muxer = Muxer::default();
+        muxer.push(&video(AVC_SEQUENCE_HEADER, 0));
+        muxer.push(&audio(AAC_SEQUENCE_HEADER, 0));
+        let out = muxer.push(&video(&[0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88], 0));
+        let packets = packets(&out);
+        // As FFmpeg writes it, transport stream 1 with program 1 on PID 0x1000
+        assert_eq!(&packets[0][..21], &[
+            0x47, 0x40, 0x00, 0x10, 0x00,
+            0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00,
+            0x2a, 0xb1, 0x04, 0xb2,
+        ][..]);
+        assert!(packets[0][21..].iter().all(|b| *b == 0xff));
+        let pmt = packets[1];
+        assert_eq!(pid(pmt), PMT_PID);
+        let length = usize::from(pmt[6] & 0x0f) << 8 | usize::from(pmt[7]);
+        let section = &pmt[5..8 + length];
+        // PCR on the video PID, then H.264 and AAC
+        assert_eq!(&section[8..22], &[0xe1, 0x00, 0xf0, 0x00, 0x1b, 0xe1, 0x00, 0xf0, 0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00][..]);
+        // Over the section and its CRC, the CRC-32/MPEG-2 is zero
+        assert_eq!(crc32(section), 0);
+        assert_eq!(&crc32(&section[..section.len() - 4]).to_be_bytes()[..], &section[section.len() - 4..]);
+    }
+
+    #[test]
+    fn video_pes_of_a_keyframe() {
+        let mut muxer = Muxer::default();
+        muxer.push(&video(AVC_SEQUENCE_HEADER, 0));
+        let out = muxer.push(&video(&[0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88], 0));
+        let starts = pes_starts(&out, VIDEO_PID);
+        assert_eq!(starts.len(), 1);
+        // PCR and random access, the PCR base 0
+        assert_eq!(starts[0][3] & 0x30, 0x30);
+        assert_eq!(&starts[0][5..12], &[0x50, 0, 0, 0, 0, 0x7e, 0][..]);
+        let pes = payload(starts[0]);
+        // Unbounded, the PTS alone as it equals the DTS, 100 ms after the PCR
+        assert_eq!(&pes[..14], &[0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5, 0x21, 0x00, 0x01, 0x46, 0x51][..]);
+        // The access unit delimiter, the parameter sets, then the frame in Annex B
+        assert_eq!(&pes[14..], &[
+            0, 0, 0, 1, 0x09, 0xf0,
+            0, 0, 0, 1, 0x67, 0x64,
+            0, 0, 0, 1, 0x68, 0xee,
+            0, 0, 0, 1, 0x65, 0x88,
+        ][..]);
+    }
+
+    #[test]
+    fn random_access_only_on_keyframes() {
+        let mut muxer = Muxer::default();
+        muxer.push(&video(AVC_SEQUENCE_HEADER, 0));
+        let keyframe = muxer.push(&video(&[0x17, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x65, 0x88], 0));
+        let interframe = muxer.push(&video(&[0x27, 0x01, 0, 0, 0, 0, 0, 0, 2, 0x41, 0x9a], 40));
+        assert_eq!(pes_starts(&keyframe, VIDEO_PID)[0][5], 0x50);
+        let start = pes_starts(&interframe, VIDEO_PID)[0];
+        // Still the PCR, without the parameter sets
+        assert_eq!(start[5], 0x10);
+        assert_eq!(&payload(start)[14..], &[0, 0, 0, 1, 0x09, 0xf0, 0, 0, 0, 1, 0x41, 0x9a][..]);
+    }
+
+    #[test]
+    fn audio_pes_with_adts() {
+        let mut muxer = Muxer::default();
+        muxer.push(&audio(AAC_SEQUENCE_HEADER, 0));
+        let raw = [0xaf, 0x01, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let out = muxer.push(&audio(&raw, 0));
+        let starts = pes_starts(&out, AUDIO_PID);
+        assert_eq!(starts.len(), 1);
+        // Without video the PCR is on the audio, each frame a random access point
+        assert_eq!(starts[0][5], 0x50);
+        let pes = payload(starts[0]);
+        // 8 bytes of header after the length, then 17 of ADTS frame
+        assert_eq!(&pes[..14], &[0, 0, 1, 0xc0, 0, 25, 0x80, 0x80, 5, 0x21, 0x00, 0x01, 0x46, 0x51][..]);
+        // LC, 44.1 kHz, 2 channels, 17 bytes with the header, no CRC
+        assert_eq!(&pes[14..21], &[0xff, 0xf1, 0x50, 0x80, 0x02, 0x3f, 0xfc][..]);
+        assert_eq!(&pes[21..], &raw[2..]);
+    }
+}
@@ -0,0 +1,180 @@
+//! `--tui`: a live table of the clients in place of the scrolling logs.
+
+use std::collections::HashMap;
+use std::io::{self, Stdout};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Paragraph, Row, Table},
+    Terminal,
+};
+
+use crate::shutdown::Shutdown;
+use crate::stats::{ClientState, Stats};
+
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// The dashboard thread, restoring the terminal once stopped or dropped
+pub struct Dashboard {
+    done: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal. `q` or Ctrl-C shut the run down.
+    pub fn start(stats: Arc<Stats>, shutdown: Shutdown) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        let done = Arc::new(AtomicBool::new(false));
+        let stop = done.clone();
+        let thread = thread::Builder::new()
+            .name("dashboard".to_owned())
+            .spawn(move || {
+                let mut terminal = terminal;
+                let result = draw_loop(&mut terminal, &stats, &shutdown, &stop);
+                terminal::disable_raw_mode()?;
+                execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+                terminal.show_cursor()?;
+                result
+            })?;
+        Ok(Self { done, thread: Some(thread) })
+    }
+
+    /// Give the terminal back, before the final results are printed
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for Dashboard {
+    // Also on an early return of the run, e.g. an error or a panic of its task
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(Ok(Err(e))) = self.thread.take().map(thread::JoinHandle::join) {
+            eprintln!("Dashboard error: {}", e);
+        }
+    }
+}
+
+/// Counters of a client at the previous refresh
+#[derive(Clone, Copy, Default)]
+struct Previous {
+    bytes: u64,
+    frames: u64,
+}
+
+fn draw_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+             stats: &Stats,
+             shutdown: &Shutdown,
+             done: &AtomicBool) -> io::Result<()>
+{
+    let mut previous = HashMap::<usize, Previous>::new();
+    let mut last_refresh = Instant::now();
+    let mut scroll = 0usize;
+    while !done.load(Ordering::SeqCst) {
+        let elapsed = last_refresh.elapsed().as_secs_f64().max(0.001);
+        last_refresh = Instant::now();
+        let clients = stats.clients();
+
+        let mut counts = HashMap::<ClientState, usize>::new();
+        let (mut total_bytes, mut total_bps, mut total_reconnects) = (0, 0.0, 0);
+        let mut rows = Vec::with_capacity(clients.len());
+        for (i, client) in clients.iter().enumerate() {
+            let state = client.state();
+            *counts.entry(state).or_insert(0) += 1;
+            let (bytes, frames) = (client.bytes_sent(), client.video_frames());
+            let last = previous.insert(i, Previous { bytes, frames }).unwrap_or_default();
+            let bps = (bytes - last.bytes.min(bytes)) as f64 * 8.0 / elapsed;
+            let fps = (frames - last.frames.min(frames)) as f64 / elapsed;
+            total_bytes += bytes;
+            total_bps += bps;
+            total_reconnects += client.reconnects();
+            rows.push(Row::new(vec![
+                client.url().to_owned(),
+                format!("{:?}", state).to_lowercase(),
+                format!("{:.0}", bps / 1000.0),
+                format!("{:.1}", fps),
+                bytes.to_string(),
+                client.reconnects().to_string(),
+            ]).style(state_style(state)));
+        }
+
+        let count = |state| counts.get(&state).copied().unwrap_or(0);
+        let totals = format!(
+            "clients {}  connecting {}  publishing {}  idle {}  finished {}  failed {}  |  {:.0} kbps  {} bytes  {} reconnects  |  q quits",
+            clients.len(), count(ClientState::Connecting), count(ClientState::Publishing), count(ClientState::Idle),
+            count(ClientState::Finished), count(ClientState::Failed), total_bps / 1000.0, total_bytes, total_reconnects,
+        );
+
+        let mut visible = 0;
+        terminal.draw(|f| {
+            let areas = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Min(0)])
+                .split(f.size());
+            f.render_widget(Paragraph::new(totals).block(Block::default().borders(Borders::ALL).title("waterfall")), areas[0]);
+            // Borders and the header take three lines
+            visible = (areas[1].height as usize).saturating_sub(3);
+            scroll = scroll.min(rows.len().saturating_sub(visible));
+            let header = Row::new(vec!["destination", "state", "kbps", "frames/s", "bytes sent", "reconnects"])
+                .style(Style::default().add_modifier(Modifier::BOLD));
+            let widths = [
+                Constraint::Min(30),
+                Constraint::Length(11),
+                Constraint::Length(8),
+                Constraint::Length(9),
+                Constraint::Length(14),
+                Constraint::Length(10),
+            ];
+            let table = Table::new(rows.drain(..).skip(scroll).take(visible))
+                .header(header)
+                .block(Block::default().borders(Borders::ALL))
+                .widths(&widths);
+            f.render_widget(table, areas[1]);
+        })?;
+
+        // Wait for the next refresh, a scroll shows with it
+        while last_refresh.elapsed() < REFRESH && !done.load(Ordering::SeqCst) {
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            if let Event::Key(KeyEvent { code, modifiers, .. }) = event::read()? {
+                match code {
+                    KeyCode::Char('q') => shutdown.trigger(),
+                    // Raw mode swallows the signal
+                    KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => shutdown.trigger(),
+                    KeyCode::Down => scroll += 1,
+                    KeyCode::Up => scroll = scroll.saturating_sub(1),
+                    KeyCode::PageDown => scroll += visible.max(1),
+                    KeyCode::PageUp => scroll = scroll.saturating_sub(visible.max(1)),
+                    _ => (),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn state_style(state: ClientState) -> Style {
+    match state {
+        ClientState::Publishing => Style::default().fg(Color::Green),
+        ClientState::Failed => Style::default().fg(Color::Red),
+        ClientState::Connecting => Style::default().fg(Color::Yellow),
+        ClientState::Idle | ClientState::Finished => Style::default(),
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// Parse a duration like `500ms`, `30s`, `5m`, `2h`, `1d`, a bare number means seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = split_number(s)?;
+    let ms = match unit {
+        "ms" => value,
+        "" | "s" => value * 1_000.0,
+        "m" | "min" => value * 60_000.0,
+        "h" => value * 3_600_000.0,
+        "d" => value * 86_400_000.0,
+        _ => return Err(format!("unknown duration unit in `{}`", s)),
+    };
+    Ok(Duration::from_millis(ms.round() as u64))
+}
+
+/// Parse a byte size like `500MB`, `1.5GiB`, `64k`, a bare number means bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (value, unit) = split_number(s)?;
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown size unit in `{}`", s)),
+    };
+    Ok((value * multiplier as f64).round() as u64)
+}
+
+/// Parse a ratio like `1%`, or `0.01` without the percent sign.
+pub fn parse_ratio(s: &str) -> Result<f64, String> {
+    let (value, unit) = split_number(s)?;
+    match unit {
+        "%" => Ok(value / 100.0),
+        "" => Ok(value),
+        _ => Err(format!("unknown ratio unit in `{}`", s)),
+    }
+}
+
+fn split_number(s: &str) -> Result<(f64, &str), String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value = value.parse::<f64>().map_err(|_| format!("invalid number in `{}`", s))?;
+    Ok((value, unit.trim()))
+}
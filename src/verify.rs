@@ -0,0 +1,158 @@
+//! `--verify`: the media a publisher sends against what a paired player receives.
+//!
+//! Every audio and video payload the publisher of the first destination sends is hashed in
+//! order, and so is every one the companion player gets back from the server. A received payload
+//! matches the earliest one sent with the same hash after the last match, or before it, which
+//! makes it reordered, the first one the latest sent. One matching nothing sent, or nothing left
+//! to match, was corrupted on the way. Sent payloads between the first and the last match which
+//! never arrived are missing. What was sent before the player
+//! joined, or after it stopped, is not expected. Sequence headers are left out, servers replay
+//! them to every player. The AAC frames received are checked for gaps and overlaps against their
+//! durations as well.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::Hasher;
+use std::sync::Mutex;
+
+use crate::flv;
+use crate::report::IntegrityReport;
+use crate::PacketType;
+
+/// Sent payloads are given up on, and missing, this many messages behind the last match
+const REORDER_WINDOW: u64 = 4096;
+/// Or this many behind the publisher, for a player far behind or gone
+const MAX_PENDING: u64 = 65_536;
+
+#[derive(Debug, Default)]
+pub struct Ledger {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_seq: u64,
+    /// Sent and not received yet, by sequence number
+    pending: BTreeMap<u64, u64>,
+    by_digest: HashMap<u64, BTreeSet<u64>>,
+    /// The sequence numbers of the first and the latest match
+    first: Option<u64>,
+    cursor: Option<u64>,
+    report: IntegrityReport,
+}
+
+impl State {
+    fn remove(&mut self, seq: u64, digest: u64) {
+        self.pending.remove(&seq);
+        if let Some(seqs) = self.by_digest.get_mut(&digest) {
+            seqs.remove(&seq);
+            if seqs.is_empty() {
+                self.by_digest.remove(&digest);
+            }
+        }
+    }
+
+    /// Give up on the payloads too far behind the last match, or before the player joins
+    fn prune(&mut self) {
+        let behind_match = self.cursor.unwrap_or(self.next_seq).saturating_sub(REORDER_WINDOW);
+        let horizon = behind_match.max(self.next_seq.saturating_sub(MAX_PENDING));
+        while let Some((&seq, &digest)) = self.pending.iter().next() {
+            if seq >= horizon {
+                break;
+            }
+            if self.first.map_or(false, |first| seq > first) {
+                self.report.missing += 1;
+            }
+            self.remove(seq, digest);
+        }
+    }
+}
+
+/// The hash of an audio or video payload, `None` for the messages which are not verified
+fn digest(packet: &PacketType) -> Option<u64> {
+    let (kind, data) = match packet {
+        PacketType::Video{ data, .. } if !flv::is_video_sequence_header(data) => (flv::TAG_VIDEO, data),
+        PacketType::Audio{ data, .. } if !flv::is_audio_sequence_header(data) => (flv::TAG_AUDIO, data),
+        _ => return None,
+    };
+    let mut hasher = DefaultHasher::new();
+    hasher.write_u8(kind);
+    hasher.write(data);
+    Some(hasher.finish())
+}
+
+impl Ledger {
+    /// A message the publisher sends
+    pub fn sent(&self, packet: &PacketType) {
+        let digest = match digest(packet) {
+            Some(digest) => digest,
+            None => return,
+        };
+        if let Ok(mut state) = self.state.lock() {
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.pending.insert(seq, digest);
+            state.by_digest.entry(digest).or_default().insert(seq);
+            state.report.sent += 1;
+            state.prune();
+        }
+    }
+
+    /// A message the player receives
+    pub fn received(&self, packet: &PacketType) {
+        let digest = match digest(packet) {
+            Some(digest) => digest,
+            None => return,
+        };
+        if let Ok(mut state) = self.state.lock() {
+            state.report.received += 1;
+            let cursor = state.cursor;
+            let matched = state.by_digest.get(&digest).and_then(|seqs| {
+                match cursor {
+                    Some(c) => seqs.range(c + 1..).next().or_else(|| seqs.iter().next()).copied(),
+                    // The player joins the live stream, a payload sent more than once is the latest
+                    None => seqs.iter().next_back().copied(),
+                }
+            });
+            let seq = match matched {
+                Some(seq) => seq,
+                None => {
+                    state.report.corrupted += 1;
+                    return;
+                }
+            };
+            state.remove(seq, digest);
+            state.report.matched += 1;
+            state.first.get_or_insert(seq);
+            match cursor {
+                Some(cursor) if seq < cursor => state.report.reordered += 1,
+                _ => state.cursor = Some(seq),
+            }
+        }
+    }
+
+    /// A gap in the AAC frames the player receives, or with `gap` false an overlap
+    pub fn audio_discontinuity(&self, gap: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            if gap {
+                state.report.audio_gaps += 1;
+            } else {
+                state.report.audio_overlaps += 1;
+            }
+        }
+    }
+
+    /// The counts so far, the payloads still pending between the first and the last match count
+    /// as missing
+    pub fn report(&self) -> IntegrityReport {
+        let state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return IntegrityReport::default(),
+        };
+        let mut report = state.report;
+        if let (Some(first), Some(cursor)) = (state.first, state.cursor) {
+            report.missing += state.pending.range(first + 1..cursor).count() as u64;
+        }
+        report
+    }
+}
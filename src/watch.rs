@@ -0,0 +1,117 @@
+//! `--watch`: publish to the destinations appended to the list file while the run goes on.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use slog::{info, warn, Logger};
+
+use crate::{
+    fanout::{self, RecvError},
+    load::Spawner,
+    rtmp::client::HeaderCache,
+    rtmp_url::Url,
+    shutdown::Shutdown,
+    PacketType,
+};
+
+/// How often the list file is checked for new lines
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// The lines appended past `offset`, only complete ones, a line still being written waits for the next check.
+struct Tail {
+    path: PathBuf,
+    offset: u64,
+}
+
+impl Tail {
+    fn new(path: PathBuf) -> io::Result<Self> {
+        let offset = std::fs::metadata(&path)?.len();
+        Ok(Self { path, offset })
+    }
+
+    fn lines(&mut self) -> io::Result<Vec<String>> {
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        if len < self.offset {
+            // Truncated or replaced, its lines are taken as new
+            self.offset = 0;
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut appended = Vec::new();
+        file.take(len - self.offset).read_to_end(&mut appended)?;
+        let complete = match appended.iter().rposition(|b| *b == b'\n') {
+            Some(i) => i + 1,
+            None => return Ok(Vec::new()),
+        };
+        self.offset += complete as u64;
+        Ok(String::from_utf8_lossy(&appended[..complete])
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+}
+
+/// Start a client per line appended to `path` until the broadcast ends or the run is shut down.
+///
+/// The clients join mid-stream with the headers seen on `rx` so far. `parse` turns a line into
+/// its destination, lines it rejects are logged and skipped.
+pub async fn run<F>(path: PathBuf,
+                    mut spawner: Spawner,
+                    mut rx: fanout::Receiver,
+                    headers: Arc<Mutex<HeaderCache>>,
+                    parse: F,
+                    shutdown: Shutdown,
+                    logger: Logger)
+    where F: Fn(&str) -> Result<Url, String>,
+{
+    let mut tail = match Tail::new(path) {
+        Ok(tail) => tail,
+        Err(e) => {
+            warn!(logger, "Watch destination list error"; "error" => %e);
+            return;
+        }
+    };
+    let mut ticks = tokio::time::interval(INTERVAL);
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => {
+                    if let PacketType::End = *msg {
+                        break;
+                    }
+                    if let Ok(mut headers) = headers.lock() {
+                        headers.observe(&msg);
+                    }
+                }
+                // Only the headers matter here, they are never dropped
+                Err(RecvError::Lagged(_)) | Err(RecvError::Overflowed) => (),
+                Err(RecvError::Closed) => break,
+            },
+            _ = ticks.tick() => {
+                if shutdown.is_triggered() {
+                    break;
+                }
+                let lines = match tail.lines() {
+                    Ok(lines) => lines,
+                    Err(e) => {
+                        warn!(logger, "Read destination list error"; "error" => %e);
+                        continue;
+                    }
+                };
+                for line in lines {
+                    match parse(&line) {
+                        Ok(url) => {
+                            info!(logger, "Destination added"; "url" => %url);
+                            spawner.spawn(url);
+                        }
+                        Err(e) => warn!(logger, "Skip destination"; "line" => &line, "error" => e),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! `whip://` and `whips://` destinations, WebRTC ingest through the WHIP signalling over HTTP.
+//!
+//! Each publisher runs a GStreamer pipeline demuxing the TS from its stdin into the `whipsink` of
+//! gst-plugins-rs, which does the offer and answer with the endpoint and the ICE and DTLS that
+//! follow. H.264 goes through as it is, AAC is transcoded to Opus as WebRTC wants. The `token` of
+//! the url is sent as the bearer token, the other options stay in the endpoint query.
+
+use std::process::Stdio;
+
+use tokio::process::Command;
+
+use crate::rtmp::client::Config;
+use crate::rtmp_url::Url;
+use crate::transmit;
+
+/// The `gst-launch-1.0` of the `PATH` unless `--gst-launch` names another
+pub const DEFAULT_GST_LAUNCH: &str = "gst-launch-1.0";
+
+/// The elements of the pipeline, those of its audio branch
+const ELEMENTS: &[&str] = &["fdsrc", "tsdemux", "queue", "h264parse", "rtph264pay", "whipsink"];
+const AUDIO_ELEMENTS: &[&str] = &["aacparse", "avdec_aac", "audioconvert", "audioresample", "opusenc", "rtpopuspay"];
+
+fn program(config: &Config) -> String {
+    config.gst_launch.clone().unwrap_or_else(|| DEFAULT_GST_LAUNCH.to_owned())
+}
+
+/// Fails unless there is a `gst-launch-1.0` to run with the elements of the pipeline, as far as
+/// the `gst-inspect-1.0` next to it tells. Returns the audio elements which are missing, only
+/// inputs with audio need them.
+pub fn check(config: &Config) -> Result<Vec<&'static str>, String> {
+    let program = program(config);
+    let launch = transmit::find_program(&program).ok_or_else(|| {
+        format!("WHIP destinations need `{}` of GStreamer, it is not found: install GStreamer with the \
+                 `whipsink` of gst-plugins-rs or give its path with `--gst-launch`", program)
+    })?;
+    let inspect = match launch.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains("gst-launch") => launch.with_file_name(name.replace("gst-launch", "gst-inspect")),
+        // Nothing to ask
+        _ => return Ok(Vec::new()),
+    };
+    if !inspect.is_file() {
+        return Ok(Vec::new());
+    }
+    let has = |element: &str| std::process::Command::new(&inspect)
+        .arg(element)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |status| status.success());
+    let missing = ELEMENTS.iter().copied().filter(|e| !has(e)).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!("The GStreamer of `{}` has no {}, WHIP needs the `whipsink` of gst-plugins-rs \
+                            and the base and good plugins", program, missing.join(", ")));
+    }
+    Ok(AUDIO_ELEMENTS.iter().copied().filter(|e| !has(e)).collect())
+}
+
+/// The program and the pipeline reading the TS from stdin and publishing it to `url`, with a
+/// branch for each track the TS has: one never fed stalls `whipsink`
+pub fn command(url: &Url, config: &Config, video: bool, audio: bool) -> (String, Command) {
+    let program = program(config);
+    let mut command = Command::new(&program);
+    command.arg("-q")
+        .args(&["fdsrc", "fd=0", "!", "tsdemux", "name=demux"])
+        .args(&["whipsink", "name=whip"])
+        .arg(format!("whip-endpoint={}", url.whip_endpoint()));
+    if let Some(token) = url.whip_token() {
+        command.arg(format!("auth-token={}", token));
+    }
+    if video {
+        command.args(&["demux.", "!", "queue", "!", "h264parse", "!", "rtph264pay", "config-interval=-1", "!", "whip."]);
+    }
+    if audio {
+        command.args(&["demux.", "!", "queue", "!", "aacparse", "!", "avdec_aac", "!", "audioconvert", "!",
+                       "audioresample", "!", "opusenc", "!", "rtpopuspay", "!", "whip."]);
+    }
+    (program, command)
+}